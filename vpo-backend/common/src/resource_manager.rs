@@ -191,6 +191,20 @@ impl<A> ResourceManager<A> {
         index
     }
 
+    /// Swaps out the resource under `key` while keeping its index, so anything holding on to
+    /// the index sees the new resource. Adds it if it doesn't exist yet.
+    pub fn replace_resource(&mut self, key: String, resource: A) -> ResourceIndex {
+        if let Some(index) = self.resource_mapping.get(&key) {
+            if let Some(existing) = self.resources.get_mut(index.0) {
+                *existing = resource;
+
+                return *index;
+            }
+        }
+
+        self.add_resource(key, resource)
+    }
+
     pub fn remove_resource(&mut self, key: &str) -> Option<A> {
         self.resource_mapping
             .remove(key)
@@ -22,6 +22,18 @@ pub enum NodeError {
     GraphDoesNotExist { graph_index: GraphIndex },
     #[snafu(display("Graph has more than one parent, cannot remove"))]
     GraphHasOtherParents,
+    #[snafu(display("Module `{name}` does not exist"))]
+    ModuleDoesNotExist { name: String },
+    #[snafu(display("Module `{name}` already exists"))]
+    ModuleAlreadyExists { name: String },
+    #[snafu(display("Module `{name}` can't be used inside of itself"))]
+    ModuleWithinItself { name: String },
+    #[snafu(display("Node `{index:?}` isn't an instance of a module"))]
+    NotModuleInstance { index: GlobalNodeIndex },
+    #[snafu(display("Node `{index:?}` doesn't have a child graph"))]
+    NoChildGraph { index: GlobalNodeIndex },
+    #[snafu(display("There's no step {place} in the history"))]
+    HistoryPlaceOutOfBounds { place: usize },
     #[snafu(display("Graphs `{from:?}` and `{to:?}` not connected through `{through:?}`"))]
     GraphsNotConnected {
         from: GraphIndex,
@@ -100,6 +112,18 @@ pub enum NodeWarning {
     InternalErrorsAndWarnings { errors_and_warnings: ErrorsAndWarnings },
     #[snafu(display("Resource missing: {resource:?}"))]
     ResourceMissing { resource: ResourceId },
+    #[snafu(display(
+        "Connection from {from_node:?} ({from_socket:?}) to {to_socket:?} closes a loop, so it's delayed by a buffer"
+    ))]
+    FeedbackConnection {
+        from_node: NodeIndex,
+        from_socket: Socket,
+        to_socket: Socket,
+    },
+    #[snafu(display(
+        "Note {note} is placed in channel {channel}, but there are only {channels}, so it's in the last one"
+    ))]
+    ChannelOutOfRange { note: u8, channel: usize, channels: usize },
 }
 
 impl Serialize for NodeWarning {
@@ -1,3 +1,5 @@
+use std::collections::{BTreeMap, HashMap};
+use std::mem;
 use std::ops::{Index, IndexMut};
 
 use common::SeaHashMap;
@@ -7,22 +9,31 @@ use serde_json::Value;
 use snafu::{OptionExt, ResultExt};
 
 use crate::connection::{Socket, SocketDirection};
-use crate::errors::{GraphDoesNotExistSnafu, NodeDoesNotExistSnafu, NodeError, NodeOk, NodeResult};
-use crate::node::NodeGetIoContext;
+use crate::errors::{
+    GraphDoesNotExistSnafu, ModuleAlreadyExistsSnafu, ModuleDoesNotExistSnafu, ModuleWithinItselfSnafu,
+    NoChildGraphSnafu, NodeDoesNotExistSnafu, NodeError, NodeOk, NodeResult, NotModuleInstanceSnafu,
+};
+use crate::node::{with_property_inputs, NodeGetIoContext};
 use crate::node_graph::NodeGraphDiff;
 use crate::node_instance::NodeInstance;
 use crate::nodes::variant_io;
 use crate::state::ActionInvalidation;
 use crate::{node::NodeIndex, node_graph::NodeGraph};
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "variant", content = "data")]
 pub enum DiffElement {
     GraphManagerDiff(GraphDiff<NodeGraph, ConnectedThrough>),
     ChildGraphDiff(GraphIndex, NodeGraphDiff),
     ExtendUiData(GlobalNodeIndex, SeaHashMap<String, Value>),
+    SetModule {
+        name: String,
+        old: Option<GraphIndex>,
+        new: Option<GraphIndex>,
+    },
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct GraphManagerDiff(pub Vec<DiffElement>);
 
 impl GraphManagerDiff {
@@ -50,11 +61,23 @@ pub struct GlobalNodeIndex {
     pub node_index: NodeIndex,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+/// A module as it's stored on disk, its graph followed by any graphs nested inside of it. Nested
+/// graphs that are modules themselves are referred to by name instead.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct ModuleDefinition {
+    pub graphs: Vec<(GraphIndex, NodeGraph)>,
+    pub linked: Vec<(GraphIndex, String)>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct GraphManager {
     node_graphs: Graph<NodeGraph, ConnectedThrough>,
     root_index: GraphIndex,
+    /// graphs shared between every node using them, by name
+    #[serde(default)]
+    modules: BTreeMap<String, GraphIndex>,
     #[serde(skip)]
     default_channel_count: usize,
 }
@@ -67,6 +90,7 @@ impl GraphManager {
         GraphManager {
             node_graphs: graph,
             root_index: GraphIndex(root_index),
+            modules: BTreeMap::new(),
             default_channel_count,
         }
     }
@@ -104,7 +128,7 @@ impl GraphManager {
             let edge = self.node_graphs.get_edge(*edge_index).expect("edge to exist");
 
             if *edge.data() == through {
-                let (old_data, remove_diff) = self.node_graphs.remove_edge(shared_edges[0])?;
+                let (old_data, remove_diff) = self.node_graphs.remove_edge(*edge_index)?;
 
                 let diff = GraphManagerDiff(vec![DiffElement::GraphManagerDiff(remove_diff)]);
 
@@ -156,6 +180,32 @@ impl GraphManager {
             .with_context(|| GraphDoesNotExistSnafu { graph_index: index })?)
     }
 
+    /// Takes out every graph, leaving empty ones in their place, so they can be stored separately
+    pub fn take_graphs(&mut self) -> Vec<(GraphIndex, NodeGraph)> {
+        let indexes: Vec<_> = self.node_graphs.vertex_indexes().collect();
+
+        indexes
+            .into_iter()
+            .map(|index| {
+                let graph = self.node_graphs.get_vertex_data_mut(index).unwrap();
+
+                (
+                    GraphIndex(index),
+                    mem::replace(graph, NodeGraph::new(self.default_channel_count)),
+                )
+            })
+            .collect()
+    }
+
+    /// Puts back graphs taken out with `take_graphs`
+    pub fn put_graphs(&mut self, graphs: Vec<(GraphIndex, NodeGraph)>) -> Result<(), NodeError> {
+        for (index, graph) in graphs {
+            *self.get_graph_mut(index)? = graph;
+        }
+
+        Ok(())
+    }
+
     pub fn set_default_channel_count(&mut self, default_channel_count: usize) {
         self.default_channel_count = default_channel_count;
 
@@ -198,6 +248,256 @@ impl GraphManager {
         Ok(mapped)
     }
 
+    pub fn modules(&self) -> &BTreeMap<String, GraphIndex> {
+        &self.modules
+    }
+
+    pub fn get_module(&self, name: &str) -> Option<GraphIndex> {
+        self.modules.get(name).copied()
+    }
+
+    pub fn module_name(&self, graph_index: GraphIndex) -> Option<&String> {
+        self.modules
+            .iter()
+            .find(|(_, module)| **module == graph_index)
+            .map(|(name, _)| name)
+    }
+
+    pub fn export_module(&self, name: &str) -> Result<ModuleDefinition, NodeError> {
+        let root = self.get_module(name).context(ModuleDoesNotExistSnafu { name })?;
+
+        let mut graphs = vec![];
+        let mut linked: Vec<(GraphIndex, String)> = vec![];
+        let mut to_visit = vec![root];
+
+        while let Some(graph_index) = to_visit.pop() {
+            let graph = self.get_graph(graph_index)?;
+
+            for (_, node) in graph.nodes_data_iter() {
+                if let Some(child) = *node.get_child_graph() {
+                    if let Some(child_name) = self.module_name(child) {
+                        if !linked.iter().any(|(index, _)| *index == child) {
+                            linked.push((child, child_name.clone()));
+                        }
+                    } else {
+                        to_visit.push(child);
+                    }
+                }
+            }
+
+            graphs.push((graph_index, graph.clone()));
+        }
+
+        Ok(ModuleDefinition { graphs, linked })
+    }
+
+    /// Adds a module, or if there's already one by the same name, replaces it and moves all of
+    /// its instances over to the new one
+    pub fn import_module(&mut self, name: &str, definition: ModuleDefinition) -> Result<GraphManagerDiff, NodeError> {
+        let ModuleDefinition { graphs, linked } = definition;
+
+        let mut diff = vec![];
+        let mut mapping: HashMap<GraphIndex, GraphIndex> = HashMap::new();
+        let mut new_graphs: Vec<GraphIndex> = vec![];
+
+        for (old_index, module_name) in linked {
+            let module = self
+                .get_module(&module_name)
+                .context(ModuleDoesNotExistSnafu { name: module_name })?;
+
+            mapping.insert(old_index, module);
+        }
+
+        for (old_index, mut graph) in graphs {
+            graph.set_default_channel_count(self.default_channel_count);
+
+            let (new_index, add_diff) = self.node_graphs.add_vertex(graph);
+            diff.push(DiffElement::GraphManagerDiff(add_diff));
+
+            mapping.insert(old_index, GraphIndex(new_index));
+            new_graphs.push(GraphIndex(new_index));
+        }
+
+        // now that everything has a place, point the nodes at their new child graphs
+        for graph_index in &new_graphs {
+            let children: Vec<(NodeIndex, GraphIndex)> = self
+                .get_graph(*graph_index)?
+                .nodes_data_iter()
+                .filter_map(|(node_index, node)| node.get_child_graph().map(|child| (node_index, child)))
+                .collect();
+
+            for (node_index, old_child) in children {
+                let child = *mapping
+                    .get(&old_child)
+                    .context(GraphDoesNotExistSnafu { graph_index: old_child })?;
+
+                let graph = self.get_graph_mut(*graph_index)?;
+                let mut node = graph.get_node(node_index)?.clone();
+                node.set_child_graph(child);
+
+                diff.push(DiffElement::ChildGraphDiff(
+                    *graph_index,
+                    graph.update_node_no_row_updates(node_index, node)?,
+                ));
+
+                let (_, connect_diff) = self.connect_graphs(*graph_index, ConnectedThrough(node_index), child)?;
+                diff.extend(connect_diff.0);
+            }
+        }
+
+        // rows aren't stored, so work them out again (children first, as nodes with child graphs
+        // take their rows from them)
+        for graph_index in new_graphs.iter().rev() {
+            let nodes: Vec<NodeIndex> = self.get_graph(*graph_index)?.node_indexes().collect();
+
+            for node_index in nodes {
+                diff.extend(
+                    self.update_node_rows(GlobalNodeIndex {
+                        graph_index: *graph_index,
+                        node_index,
+                    })?
+                    .0,
+                );
+            }
+        }
+
+        let root = *new_graphs.first().context(ModuleDoesNotExistSnafu { name })?;
+
+        if let Some(old_root) = self.get_module(name) {
+            for instance in self.get_graph_parents(old_root)? {
+                diff.extend(self.set_instance_graph(instance, root)?.0);
+            }
+
+            // nothing's using it anymore
+            diff.extend(self.remove_graph_tree(old_root)?.0);
+        }
+
+        diff.push(self.set_module(name, Some(root)));
+
+        Ok(GraphManagerDiff(diff))
+    }
+
+    fn set_module(&mut self, name: &str, graph_index: Option<GraphIndex>) -> DiffElement {
+        let old = self.apply_module(name, graph_index);
+
+        DiffElement::SetModule {
+            name: name.to_string(),
+            old,
+            new: graph_index,
+        }
+    }
+
+    fn apply_module(&mut self, name: &str, graph_index: Option<GraphIndex>) -> Option<GraphIndex> {
+        match graph_index {
+            Some(graph_index) => self.modules.insert(name.to_string(), graph_index),
+            None => self.modules.remove(name),
+        }
+    }
+
+    /// Whether `graph_index` is nested somewhere inside of `ancestor`
+    fn is_within(&self, graph_index: GraphIndex, ancestor: GraphIndex) -> Result<bool, NodeError> {
+        for parent in self.get_graph_parents(graph_index)? {
+            if parent.graph_index == ancestor || self.is_within(parent.graph_index, ancestor)? {
+                return Ok(true);
+            }
+        }
+
+        Ok(false)
+    }
+
+    /// Copies a graph along with every graph nested inside of it. Modules used inside stay shared.
+    fn copy_graph(&mut self, graph_index: GraphIndex) -> Result<(GraphIndex, GraphManagerDiff), NodeError> {
+        let mut graph = self.get_graph(graph_index)?.clone();
+
+        let mut diff = vec![];
+        let mut children = vec![];
+
+        let nodes: Vec<NodeIndex> = graph.node_indexes().collect();
+        for node_index in nodes {
+            if let Some(child) = *graph.get_node(node_index)?.get_child_graph() {
+                let child = if self.module_name(child).is_some() {
+                    child
+                } else {
+                    let (child_copy, copy_diff) = self.copy_graph(child)?;
+                    diff.extend(copy_diff.0);
+
+                    graph.get_node_mut(node_index)?.set_child_graph(child_copy);
+
+                    child_copy
+                };
+
+                children.push((node_index, child));
+            }
+        }
+
+        let (new_index, add_diff) = self.node_graphs.add_vertex(graph);
+        diff.push(DiffElement::GraphManagerDiff(add_diff));
+
+        for (node_index, child) in children {
+            let (_, connect_diff) = self.connect_graphs(GraphIndex(new_index), ConnectedThrough(node_index), child)?;
+            diff.extend(connect_diff.0);
+        }
+
+        Ok((GraphIndex(new_index), GraphManagerDiff(diff)))
+    }
+
+    /// Removes a graph along with every graph nested inside of it. Modules used inside are left
+    /// alone.
+    fn remove_graph_tree(&mut self, graph_index: GraphIndex) -> Result<GraphManagerDiff, NodeError> {
+        let children: Vec<(NodeIndex, GraphIndex)> = self
+            .get_graph(graph_index)?
+            .nodes_data_iter()
+            .filter_map(|(node_index, node)| node.get_child_graph().map(|child| (node_index, child)))
+            .collect();
+
+        let mut diff = vec![];
+
+        for (node_index, child) in children {
+            let (_, disconnect_diff) = self.disconnect_graphs(graph_index, ConnectedThrough(node_index), child)?;
+            diff.extend(disconnect_diff.0);
+
+            if self.module_name(child).is_none() {
+                diff.extend(self.remove_graph_tree(child)?.0);
+            }
+        }
+
+        diff.extend(self.remove_graph(graph_index)?.1 .0);
+
+        Ok(GraphManagerDiff(diff))
+    }
+
+    /// Swaps out the child graph of a node. The old child graph is left as is.
+    fn set_instance_graph(
+        &mut self,
+        index: GlobalNodeIndex,
+        graph_index: GraphIndex,
+    ) -> Result<GraphManagerDiff, NodeError> {
+        let node = self.get_node(index)?;
+
+        if !node.uses_child_graph() {
+            return NoChildGraphSnafu { index }.fail();
+        }
+
+        let mut diff = vec![];
+
+        if let Some(old_child) = *node.get_child_graph() {
+            let (_, disconnect_diff) =
+                self.disconnect_graphs(index.graph_index, ConnectedThrough(index.node_index), old_child)?;
+            diff.extend(disconnect_diff.0);
+        }
+
+        let (_, connect_diff) =
+            self.connect_graphs(index.graph_index, ConnectedThrough(index.node_index), graph_index)?;
+        diff.extend(connect_diff.0);
+
+        let mut modified_node = self.get_node(index)?.clone();
+        modified_node.set_child_graph(graph_index);
+
+        diff.extend(self.update_node(index, modified_node)?.0);
+
+        Ok(GraphManagerDiff(diff))
+    }
+
     pub fn update_node(&mut self, index: GlobalNodeIndex, new: NodeInstance) -> Result<GraphManagerDiff, NodeError> {
         let mut diffs = vec![];
 
@@ -216,7 +516,10 @@ impl GraphManager {
         let node = graph.get_node(index.node_index)?;
 
         let ctx = self.create_get_io_context(index)?;
-        let new_rows = variant_io(&node.get_node_type(), ctx, node.get_properties().clone())?.node_rows;
+        let new_rows = with_property_inputs(
+            variant_io(&node.get_node_type(), ctx, node.get_properties().clone())?.node_rows,
+            node.get_default_overrides(),
+        );
 
         let mut diffs = vec![];
 
@@ -464,6 +767,88 @@ impl GraphManager {
         ))
     }
 
+    /// Turns the child graph of a node into a module. If it's already using a module, the module
+    /// is copied first.
+    pub(crate) fn create_module(
+        &mut self,
+        index: GlobalNodeIndex,
+        name: &str,
+    ) -> Result<(GraphManagerDiff, ActionInvalidation), NodeError> {
+        if self.modules.contains_key(name) {
+            return ModuleAlreadyExistsSnafu { name }.fail();
+        }
+
+        let child = (*self.get_node(index)?.get_child_graph()).context(NoChildGraphSnafu { index })?;
+
+        let mut diff = vec![];
+
+        let child = if self.module_name(child).is_some() {
+            let (child_copy, copy_diff) = self.copy_graph(child)?;
+            diff.extend(copy_diff.0);
+            diff.extend(self.set_instance_graph(index, child_copy)?.0);
+
+            child_copy
+        } else {
+            child
+        };
+
+        diff.push(self.set_module(name, Some(child)));
+
+        Ok((
+            GraphManagerDiff(diff),
+            ActionInvalidation::GraphReindexNeeded(index.graph_index),
+        ))
+    }
+
+    pub(crate) fn link_module(
+        &mut self,
+        index: GlobalNodeIndex,
+        name: &str,
+    ) -> Result<(GraphManagerDiff, ActionInvalidation), NodeError> {
+        let module = self.get_module(name).context(ModuleDoesNotExistSnafu { name })?;
+
+        if index.graph_index == module || self.is_within(index.graph_index, module)? {
+            return ModuleWithinItselfSnafu { name }.fail();
+        }
+
+        let diff = self.set_instance_graph(index, module)?;
+
+        Ok((diff, ActionInvalidation::GraphReindexNeeded(index.graph_index)))
+    }
+
+    /// Gives a module instance its own copy of the module, so it can be edited separately
+    pub(crate) fn unlink_module(
+        &mut self,
+        index: GlobalNodeIndex,
+    ) -> Result<(GraphManagerDiff, ActionInvalidation), NodeError> {
+        let module = (*self.get_node(index)?.get_child_graph())
+            .filter(|child| self.module_name(*child).is_some())
+            .context(NotModuleInstanceSnafu { index })?;
+
+        let (copy, mut diff) = self.copy_graph(module)?;
+        diff.0.extend(self.set_instance_graph(index, copy)?.0);
+
+        Ok((diff, ActionInvalidation::GraphReindexNeeded(index.graph_index)))
+    }
+
+    /// If `graph_index` is a module, updates the rows of all of its instances to match it
+    pub(crate) fn update_module_instances(
+        &mut self,
+        graph_index: GraphIndex,
+    ) -> Result<(GraphManagerDiff, Vec<ActionInvalidation>), NodeError> {
+        let mut diff = vec![];
+        let mut invalidations = vec![];
+
+        if self.module_name(graph_index).is_some() {
+            for instance in self.get_graph_parents(graph_index)? {
+                diff.extend(self.update_node_rows(instance)?.0);
+                invalidations.push(ActionInvalidation::GraphReindexNeeded(instance.graph_index));
+            }
+        }
+
+        Ok((GraphManagerDiff(diff), invalidations))
+    }
+
     pub(crate) fn reapply_action(&mut self, diff: GraphManagerDiff) -> Result<Vec<ActionInvalidation>, NodeError> {
         let mut invalidations = vec![];
 
@@ -482,6 +867,9 @@ impl GraphManager {
                 DiffElement::ExtendUiData(index, ui_data) => {
                     self.get_node_mut(index)?.extend_ui_data(ui_data.clone());
                 }
+                DiffElement::SetModule { name, new, .. } => {
+                    self.apply_module(&name, new);
+                }
             };
         }
 
@@ -504,6 +892,9 @@ impl GraphManager {
                     self.get_graph_mut(graph_index)?.rollback_diff(diff)?
                 }
                 DiffElement::ExtendUiData(..) => {}
+                DiffElement::SetModule { name, old, .. } => {
+                    self.apply_module(&name, old);
+                }
             }
         }
 
@@ -1,4 +1,30 @@
-use crate::graph_manager::GraphManager;
+use common::resource_manager::ResourceId;
+use common::SeaHashMap;
+use sound_engine::SoundConfig;
+
+use crate::connection::{Primitive, SocketType};
+use crate::graph_manager::{GlobalNodeIndex, GraphIndex, GraphManager};
+use crate::io_routing::IoRoutes;
+use crate::lint::{lint_graphs, LintKind};
+use crate::node::{osc_store::OscStore, property_socket, NodeRow, PropertyChange};
+use crate::property::{Property, PropertyType};
+use crate::resources::Resources;
+use crate::state::{Action, ActionBundle, ActionInvalidation, GraphState, SavedHistory};
+
+fn create_node(graph_manager: &mut GraphManager, node_type: &str, graph_index: GraphIndex) -> GlobalNodeIndex {
+    let (_, invalidations) = graph_manager
+        .create_node(node_type, graph_index, SeaHashMap::default())
+        .unwrap()
+        .value;
+
+    invalidations
+        .iter()
+        .find_map(|invalidation| match invalidation {
+            ActionInvalidation::NewNode(index) => Some(*index),
+            _ => None,
+        })
+        .unwrap()
+}
 
 #[test]
 fn create_graph() {
@@ -9,3 +35,341 @@ fn create_graph() {
     let graph = graph_manager.get_graph(index);
     assert!(graph.is_ok(), "Graph was none!");
 }
+
+#[test]
+fn module_instances_share_a_graph() {
+    let mut graph_manager = GraphManager::new(1);
+    let root = graph_manager.root_index();
+
+    let first = create_node(&mut graph_manager, "FunctionNode", root);
+    let second = create_node(&mut graph_manager, "FunctionNode", root);
+
+    graph_manager.create_module(first, "pipe chain").unwrap();
+    graph_manager.link_module(second, "pipe chain").unwrap();
+
+    let module = graph_manager.get_module("pipe chain").unwrap();
+    assert_eq!(*graph_manager[second].get_child_graph(), Some(module));
+    assert_eq!(graph_manager.get_graph_parents(module).unwrap().len(), 2);
+
+    // modules can't end up inside of themselves
+    let inner = create_node(&mut graph_manager, "FunctionNode", module);
+    assert!(graph_manager.link_module(inner, "pipe chain").is_err());
+
+    graph_manager.unlink_module(second).unwrap();
+
+    let local = graph_manager[second].get_child_graph().unwrap();
+    assert_ne!(local, module);
+    assert_eq!(graph_manager.get_graph_parents(module).unwrap(), vec![first]);
+    assert_eq!(graph_manager[local].len(), graph_manager[module].len());
+
+    // the function node inside got its own copy too
+    let inner_copy = graph_manager[local]
+        .nodes_data_iter()
+        .find_map(|(_, node)| *node.get_child_graph())
+        .unwrap();
+    assert_ne!(Some(inner_copy), *graph_manager[inner].get_child_graph());
+}
+
+#[test]
+fn reimported_module_replaces_its_graphs() {
+    let mut graph_manager = GraphManager::new(1);
+    let root = graph_manager.root_index();
+
+    let instance = create_node(&mut graph_manager, "FunctionNode", root);
+    graph_manager.create_module(instance, "pipe chain").unwrap();
+
+    let module = graph_manager.get_module("pipe chain").unwrap();
+    create_node(&mut graph_manager, "FunctionNode", module);
+
+    let graph_count = graph_manager.graphs().count();
+    let rows: Vec<_> = graph_manager[module]
+        .nodes_data_iter()
+        .map(|(_, node)| node.get_node_rows().clone())
+        .collect();
+
+    // as if it was read back from disk
+    let definition = serde_json::to_value(graph_manager.export_module("pipe chain").unwrap()).unwrap();
+    graph_manager
+        .import_module("pipe chain", serde_json::from_value(definition).unwrap())
+        .unwrap();
+
+    let reimported = graph_manager.get_module("pipe chain").unwrap();
+    assert_ne!(reimported, module);
+    assert_eq!(*graph_manager[instance].get_child_graph(), Some(reimported));
+
+    // the old module and the function node's graph inside of it are gone
+    assert_eq!(graph_manager.graphs().count(), graph_count);
+
+    let reimported_rows: Vec<_> = graph_manager[reimported]
+        .nodes_data_iter()
+        .map(|(_, node)| node.get_node_rows().clone())
+        .collect();
+    assert_eq!(reimported_rows, rows);
+}
+
+#[test]
+fn exposed_properties_become_inputs() {
+    let mut graph_manager = GraphManager::new(1);
+    let root = graph_manager.root_index();
+
+    let filter = create_node(&mut graph_manager, "BiquadFilterNode", root);
+    let sockets_before = graph_manager[filter].list_input_sockets().len();
+
+    let mut node = graph_manager[filter].clone();
+    node.set_default_overrides(vec![
+        NodeRow::Property(
+            "filter_type".into(),
+            PropertyType::MultipleChoice(vec![]),
+            Property::MultipleChoice("lowpass".into()),
+        ),
+        // only properties the node actually has get exposed
+        NodeRow::Property("nonexistent".into(), PropertyType::Float, Property::Float(0.0)),
+    ]);
+    graph_manager.update_node(filter, node).unwrap();
+
+    let inputs = graph_manager[filter].list_input_sockets();
+    assert_eq!(inputs.len(), sockets_before + 1);
+    assert_eq!(*inputs[sockets_before], property_socket("filter_type"));
+
+    let choices = PropertyType::MultipleChoice(vec!["lowpass".into(), "highpass".into()]);
+    assert_eq!(
+        Property::from_primitive(&choices, Primitive::Int(1)),
+        Some(Property::MultipleChoice("highpass".into()))
+    );
+
+    let ranks: Vec<ResourceId> = vec!["ranks:a.toml".parse().unwrap(), "ranks:b.toml".parse().unwrap()];
+    assert_eq!(
+        Property::resource_from_primitive(&ranks, Primitive::Float(1.0)),
+        Some(Property::Resource(ranks[1].clone()))
+    );
+    assert_eq!(Property::resource_from_primitive(&ranks, Primitive::Int(2)), None);
+}
+
+fn commit_node(state: &mut GraphState, node_type: &str) -> GlobalNodeIndex {
+    let root = state.get_root_graph_index();
+
+    state
+        .commit(
+            ActionBundle::new(vec![Action::CreateNode {
+                graph: root,
+                node_type: node_type.into(),
+                ui_data: SeaHashMap::default(),
+            }]),
+            false,
+        )
+        .unwrap()
+        .into_iter()
+        .find_map(|invalidation| match invalidation {
+            ActionInvalidation::NewNode(index) => Some(index),
+            _ => None,
+        })
+        .unwrap()
+}
+
+#[test]
+fn property_changes_from_inputs_skip_history() {
+    let mut state = GraphState::new(SoundConfig::default());
+    let root = state.get_root_graph_index();
+    let filter = commit_node(&mut state, "BiquadFilterNode");
+    let rank_player = commit_node(&mut state, "RankPlayerNode");
+
+    let change = |index: GlobalNodeIndex, name: &str, value: Property, applied: bool| PropertyChange {
+        index,
+        name: name.into(),
+        value,
+        applied,
+    };
+
+    let highpass = Property::MultipleChoice("highpass".into());
+    let invalidations = state
+        .apply_property_changes(vec![change(filter, "filter_type", highpass.clone(), true)])
+        .unwrap();
+
+    // the node already has it, so it's only stored
+    assert_eq!(invalidations, vec![ActionInvalidation::GraphModified(root)]);
+    assert_eq!(
+        state.get_graph_manager()[filter].get_property("filter_type"),
+        Some(highpass)
+    );
+    assert_eq!(state.get_history().len(), 2);
+
+    let invalidations = state
+        .apply_property_changes(vec![change(rank_player, "polyphony", Property::Integer(8), false)])
+        .unwrap();
+
+    // the rows are the same, so only the node itself needs to start over
+    assert_eq!(invalidations, vec![ActionInvalidation::NodeReinitNeeded(rank_player)]);
+    assert!(state
+        .create_node_replacement(filter.node_index, &Resources::default())
+        .is_ok());
+
+    let invalidations = state
+        .apply_property_changes(vec![change(filter, "channels", Property::Integer(2), false)])
+        .unwrap();
+    assert_eq!(invalidations, vec![ActionInvalidation::GraphReindexNeeded(root)]);
+    assert_eq!(state.get_history().len(), 2);
+}
+
+#[test]
+fn property_inputs_reach_the_graph_state() {
+    let mut state = GraphState::new(SoundConfig::default());
+    let root = state.get_root_graph_index();
+    let filter = commit_node(&mut state, "BiquadFilterNode");
+
+    state
+        .commit(
+            ActionBundle::new(vec![Action::ChangeNodeOverrides {
+                index: filter,
+                overrides: vec![NodeRow::Property(
+                    "filter_type".into(),
+                    PropertyType::MultipleChoice(vec![]),
+                    Property::MultipleChoice("lowpass".into()),
+                )],
+            }]),
+            false,
+        )
+        .unwrap();
+
+    let resources = Resources::default();
+    let mut osc_store = OscStore::new(256, 0);
+    let (_, mut traverser) = state.create_traverser(&resources).unwrap();
+
+    // "highpass"
+    traverser
+        .input_value_default(filter.node_index, &property_socket("filter_type"), Primitive::Int(1))
+        .unwrap();
+    let result = traverser.step(&resources, vec![], None, &mut osc_store);
+
+    let highpass = Property::MultipleChoice("highpass".into());
+    assert_eq!(
+        result.property_changes,
+        vec![PropertyChange {
+            index: filter,
+            name: "filter_type".into(),
+            value: highpass.clone(),
+            applied: true,
+        }]
+    );
+
+    // the filter switched in place, so the running one is kept, but a rebuild would still use it
+    let invalidations = state.apply_property_changes(result.property_changes).unwrap();
+    assert_eq!(invalidations, vec![ActionInvalidation::GraphModified(root)]);
+    assert_eq!(
+        state.get_graph_manager()[filter].get_property("filter_type"),
+        Some(highpass)
+    );
+    assert_eq!(state.get_history().len(), 2);
+
+    // unchanged inputs don't send it again
+    let result = traverser.step(&resources, vec![], None, &mut osc_store);
+    assert!(result.property_changes.is_empty());
+}
+
+#[test]
+fn lint_reports_unconnected_inputs() {
+    let mut graph_manager = GraphManager::new(1);
+    let root = graph_manager.root_index();
+
+    let gain = create_node(&mut graph_manager, "GainNode", root);
+    let outputs = create_node(&mut graph_manager, "OutputsNode", root);
+
+    let findings = lint_graphs(&graph_manager, root, &IoRoutes::default(), &Resources::default());
+
+    assert!(findings.iter().any(|finding| finding.index == gain
+        && matches!(&finding.kind, LintKind::UnconnectedInput { socket } if socket.socket_type() == SocketType::Stream)));
+    assert!(findings
+        .iter()
+        .any(|finding| finding.index == outputs && finding.kind == LintKind::UnroutedOutputs));
+}
+
+#[test]
+fn history_survives_saving() {
+    let mut state = GraphState::new(SoundConfig::default());
+    let root = state.get_root_graph_index();
+
+    state
+        .commit(
+            ActionBundle::new(vec![Action::CreateNode {
+                graph: root,
+                node_type: "GainNode".into(),
+                ui_data: SeaHashMap::default(),
+            }]),
+            false,
+        )
+        .unwrap();
+
+    let saved_graph = state.to_json();
+    let saved_history = serde_json::to_string(&state.export_history(10)).unwrap();
+
+    let mut reopened = GraphState::new(SoundConfig::default());
+    reopened.load_state(
+        serde_json::from_value(saved_graph["graphManager"].clone()).unwrap(),
+        root,
+        Default::default(),
+    );
+    reopened.import_history(serde_json::from_str::<SavedHistory>(&saved_history).unwrap());
+
+    assert_eq!(reopened.get_history()[0].description(), "Add GainNode");
+    assert_eq!(reopened.get_root_graph().len(), 1);
+
+    reopened.undo().unwrap();
+    assert_eq!(reopened.get_root_graph().len(), 0);
+}
+
+#[test]
+fn jump_to_checkpoint() {
+    let mut state = GraphState::new(SoundConfig::default());
+    let root = state.get_root_graph_index();
+
+    let add_gain = || {
+        ActionBundle::new(vec![Action::CreateNode {
+            graph: root,
+            node_type: "GainNode".into(),
+            ui_data: SeaHashMap::default(),
+        }])
+    };
+
+    let gain = state
+        .commit(add_gain(), false)
+        .unwrap()
+        .into_iter()
+        .find_map(|invalidation| match invalidation {
+            ActionInvalidation::NewNode(index) => Some(index),
+            _ => None,
+        })
+        .unwrap();
+
+    let move_gain = |x: f32| {
+        let mut ui_data = SeaHashMap::default();
+        ui_data.insert("x".to_string(), serde_json::json!(x));
+
+        ActionBundle::new(vec![Action::ChangeNodeUiData { index: gain, ui_data }])
+    };
+
+    state.commit(move_gain(10.0), false).unwrap();
+    state.set_checkpoint(2, Some("one gain".into())).unwrap();
+
+    // would normally be merged into the last step, but that's a checkpoint now
+    state.commit(move_gain(20.0), false).unwrap();
+    assert_eq!(state.get_history().len(), 3);
+    state.set_checkpoint(3, Some("moved".into())).unwrap();
+
+    // forcing it still merges into the checkpoint, which now includes the new gain
+    state.commit(add_gain(), true).unwrap();
+    assert_eq!(state.get_history().len(), 3);
+    assert_eq!(state.get_history()[2].checkpoint.as_deref(), Some("moved"));
+
+    state.commit(add_gain(), false).unwrap();
+    assert_eq!(state.get_history().len(), 4);
+
+    state.jump_to(2).unwrap();
+    assert_eq!(state.get_root_graph().len(), 1);
+
+    state.jump_to(3).unwrap();
+    assert_eq!(state.get_root_graph().len(), 2);
+
+    state.jump_to(4).unwrap();
+    assert_eq!(state.get_root_graph().len(), 3);
+
+    assert!(state.jump_to(5).is_err());
+}
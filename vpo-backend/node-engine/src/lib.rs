@@ -2,6 +2,7 @@ pub mod connection;
 pub mod errors;
 pub mod graph_manager;
 pub mod io_routing;
+pub mod lint;
 pub mod node;
 pub mod node_graph;
 pub mod node_instance;
@@ -0,0 +1,189 @@
+use common::resource_manager::ResourceId;
+use rhai::Engine;
+use serde::Serialize;
+
+use crate::connection::{Socket, SocketType, SocketValue};
+use crate::graph_manager::{GlobalNodeIndex, GraphIndex, GraphManager};
+use crate::io_routing::{DeviceDirection, IoRoutes};
+use crate::node::calculate_traversal_order::calculate_graph_traverse_order;
+use crate::node::{NodeIndex, NodeRow};
+use crate::node_graph::NodeGraph;
+use crate::property::Property;
+use crate::resources::Resources;
+
+#[derive(Debug, Clone, Serialize, PartialEq)]
+#[serde(tag = "variant", content = "data", rename_all_fields = "camelCase")]
+pub enum LintKind {
+    /// an input without a default value that nothing is connected to
+    UnconnectedInput {
+        socket: Socket,
+    },
+    /// an outputs node on the root graph that no route rule sends anywhere
+    UnroutedOutputs,
+    MissingResource {
+        resource: ResourceId,
+    },
+    ChannelMismatch {
+        from_node: NodeIndex,
+        from_socket: Socket,
+        to_socket: Socket,
+    },
+    /// a connection closing a loop, which will be delayed by a buffer
+    FeedbackConnection {
+        from_node: NodeIndex,
+        from_socket: Socket,
+        to_socket: Socket,
+    },
+    ExpressionCompileError {
+        message: String,
+    },
+}
+
+#[derive(Debug, Clone, Serialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct LintFinding {
+    pub index: GlobalNodeIndex,
+    pub kind: LintKind,
+}
+
+/// Walks every graph, looking for anything that's likely a mistake
+pub fn lint_graphs(
+    graph_manager: &GraphManager,
+    root_graph_index: GraphIndex,
+    io_routing: &IoRoutes,
+    resources: &Resources,
+) -> Vec<LintFinding> {
+    let script_engine = Engine::new();
+    let mut findings = vec![];
+
+    for graph_index in graph_manager.graphs() {
+        let graph = &graph_manager[graph_index];
+        let is_root = graph_index == root_graph_index;
+
+        lint_nodes(
+            graph,
+            graph_index,
+            is_root,
+            io_routing,
+            resources,
+            &script_engine,
+            &mut findings,
+        );
+        lint_connections(graph, graph_index, &mut findings);
+    }
+
+    findings
+}
+
+fn lint_nodes(
+    graph: &NodeGraph,
+    graph_index: GraphIndex,
+    is_root: bool,
+    io_routing: &IoRoutes,
+    resources: &Resources,
+    script_engine: &Engine,
+    findings: &mut Vec<LintFinding>,
+) {
+    for (node_index, node) in graph.nodes_data_iter() {
+        let index = GlobalNodeIndex {
+            graph_index,
+            node_index,
+        };
+
+        for row in node.get_node_rows() {
+            if let NodeRow::Input(socket, SocketValue::None) = row {
+                let connected = graph
+                    .get_input_connection_index(node_index, socket)
+                    .map_or(true, |connection| connection.is_some());
+
+                if !connected {
+                    findings.push(LintFinding {
+                        index,
+                        kind: LintKind::UnconnectedInput { socket: socket.clone() },
+                    });
+                }
+            }
+        }
+
+        if is_root && node.get_node_type() == "OutputsNode" {
+            let routed = io_routing
+                .rules
+                .iter()
+                .any(|rule| rule.node == node_index && rule.device_direction == DeviceDirection::Sink);
+
+            if !routed {
+                findings.push(LintFinding {
+                    index,
+                    kind: LintKind::UnroutedOutputs,
+                });
+            }
+        }
+
+        for property in node.get_properties().values() {
+            if let Property::Resource(resource) = property {
+                // an empty resource hasn't been picked yet
+                if !resource.resource.is_empty() && resources.get_resource_index(resource).is_none() {
+                    findings.push(LintFinding {
+                        index,
+                        kind: LintKind::MissingResource {
+                            resource: resource.clone(),
+                        },
+                    });
+                }
+            }
+        }
+
+        if matches!(node.get_node_type().as_str(), "ExpressionNode" | "StreamExpressionNode") {
+            if let Some(Property::String(expression)) = node.get_property("expression") {
+                if !expression.is_empty() {
+                    if let Err(err) = script_engine.compile(&expression) {
+                        findings.push(LintFinding {
+                            index,
+                            kind: LintKind::ExpressionCompileError {
+                                message: err.to_string(),
+                            },
+                        });
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn lint_connections(graph: &NodeGraph, graph_index: GraphIndex, findings: &mut Vec<LintFinding>) {
+    for (_, edge) in graph.edges_iter() {
+        let data = edge.data();
+
+        if data.from_socket.socket_type() == SocketType::Stream
+            && data.from_socket.channels() != data.to_socket.channels()
+        {
+            findings.push(LintFinding {
+                index: GlobalNodeIndex {
+                    graph_index,
+                    node_index: NodeIndex(edge.get_to()),
+                },
+                kind: LintKind::ChannelMismatch {
+                    from_node: NodeIndex(edge.get_from()),
+                    from_socket: data.from_socket.clone(),
+                    to_socket: data.to_socket.clone(),
+                },
+            });
+        }
+    }
+
+    for connection_index in calculate_graph_traverse_order(graph).feedback {
+        let edge = graph.get_graph().get_edge(connection_index.0).expect("edge to exist");
+
+        findings.push(LintFinding {
+            index: GlobalNodeIndex {
+                graph_index,
+                node_index: NodeIndex(edge.get_to()),
+            },
+            kind: LintKind::FeedbackConnection {
+                from_node: NodeIndex(edge.get_from()),
+                from_socket: edge.data().from_socket.clone(),
+                to_socket: edge.data().to_socket.clone(),
+            },
+        });
+    }
+}
@@ -18,14 +18,17 @@ use sound_engine::SoundConfig;
 use crate::{
     connection::{Primitive, Socket},
     errors::{ErrorsAndWarnings, NodeError},
-    graph_manager::{GraphIndex, GraphManager},
-    node::{Ins, NodeIndex, NodeProcessContext, NodeRuntime, NodeState, OscIndex, Outs, StateInterface},
+    graph_manager::{GlobalNodeIndex, GraphIndex, GraphManager},
+    node::{
+        Ins, NodeIndex, NodeProcessContext, NodeRuntime, NodeState, OscIndex, Outs, PropertyChange, StateInterface,
+    },
     nodes::NodeVariant,
+    property::Property,
     resources::{Resource, ResourceTypeAndIndex, Resources},
 };
 
 use super::{
-    calculate_traversal_order::{calc_indexes, generate_io_spec, Indexes},
+    calculate_traversal_order::{calc_indexes, generate_io_spec, Indexes, PropertyInput},
     osc_store::OscStore,
 };
 
@@ -72,14 +75,16 @@ struct TraverserRefs<'io> {
 fn build_io(config: SoundConfig, indexes: &Indexes) -> TraverserIo {
     let stream_io = build_chunked_buffer(
         repeat_with(|| UnsafeCell::new(0.0))
-            .take(config.buffer_size * indexes.stream_count)
+            .take(config.buffer_size * (indexes.stream_count + indexes.stream_delays.len()))
             .collect(),
         config.buffer_size,
     );
     let value_io = repeat_with(|| UnsafeCell::new(Primitive::None))
-        .take(indexes.value_count)
+        .take(indexes.value_count + indexes.value_delays.len())
+        .collect();
+    let osc_io = repeat_with(|| UnsafeCell::new(None))
+        .take(indexes.osc_count + indexes.osc_delays.len())
         .collect();
-    let osc_io = repeat_with(|| UnsafeCell::new(None)).take(indexes.osc_count).collect();
 
     let stream_default = ChunkedBuffer::new(
         repeat_with(|| UnsafeCell::new(0.0)).take(config.buffer_size).collect(),
@@ -151,6 +156,7 @@ self_cell!(
 
 #[derive(Debug)]
 struct TraverserNode {
+    pub index: NodeIndex,
     pub stream_in: Range<usize>,
     pub osc_in: Range<usize>,
     pub value_in: Range<usize>,
@@ -161,21 +167,38 @@ struct TraverserNode {
     pub node: NodeVariant,
     pub values_to_input: SmallVec<[(usize, Primitive); 1]>,
     pub socket_lookup: BTreeMap<Socket, usize>,
+    pub property_inputs: Vec<PropertyInput>,
 }
 
 pub struct StepResult {
     pub state_changes: Vec<(NodeIndex, NodeState)>,
     pub requested_state_updates: Vec<(NodeIndex, serde_json::Value)>,
     pub request_for_graph_state: bool,
+    /// properties changed through inputs, whether or not the node could apply them itself
+    pub property_changes: Vec<PropertyChange>,
+}
+
+/// A freshly initialized node to swap in for one that's running
+#[derive(Debug)]
+pub struct NodeReplacement {
+    pub index: NodeIndex,
+    pub node: NodeVariant,
+    pub needed_resources: Vec<ResourceId>,
 }
 
+unsafe impl Send for NodeReplacement {}
+
 pub struct BufferedTraverser {
+    graph_index: GraphIndex,
     nodes: Vec<TraverserNode>,
     nodes_with_state: Vec<(usize, NodeIndex)>,
     node_to_index_mapping: BTreeMap<NodeIndex, usize>,
     resource_tracking: Vec<(ResourceId, Option<ResourceTypeAndIndex>)>,
     io_and_refs: IoAndRefs,
     osc_tracking: Vec<Option<OscIndex>>,
+    stream_delays: Vec<(usize, usize)>,
+    osc_delays: Vec<(usize, usize)>,
+    value_delays: Vec<(usize, usize)>,
     config: SoundConfig,
     engine: Engine,
     time: Duration,
@@ -235,6 +258,7 @@ impl BufferedTraverser {
             }
 
             nodes.push(TraverserNode {
+                index: *index,
                 stream_in: indexes.stream_in,
                 osc_in: indexes.osc_in,
                 value_in: indexes.value_in,
@@ -245,13 +269,15 @@ impl BufferedTraverser {
                 node: spec.node,
                 values_to_input: spec.values_to_input,
                 socket_lookup: spec.socket_lookup,
+                property_inputs: spec.property_inputs,
             });
         }
 
         let osc_io = &io_and_refs.borrow_owner().osc_io;
-        let mut osc_tracking = Vec::with_capacity(osc_io.len());
+        let mut osc_tracking = Vec::with_capacity(indexes.osc_count);
 
-        for index in osc_io {
+        // delay slots only hold copies of outputs, so they're not tracked
+        for index in &osc_io[..indexes.osc_count] {
             let index = index.get();
             osc_tracking.push(unsafe { (*index).as_ref().map(|x| x.private_clone()) });
         }
@@ -261,12 +287,16 @@ impl BufferedTraverser {
         Ok((
             errors_and_warnings,
             BufferedTraverser {
+                graph_index,
                 nodes,
                 nodes_with_state,
                 node_to_index_mapping,
                 resource_tracking: io_spec.resources_tracking,
                 io_and_refs,
                 osc_tracking,
+                stream_delays: indexes.stream_delays,
+                osc_delays: indexes.osc_delays,
+                value_delays: indexes.value_delays,
                 config,
                 engine,
                 time: start_time,
@@ -329,6 +359,7 @@ impl BufferedTraverser {
 
         let mut requesting_graph_state = false;
         let mut requested_state_updates = vec![];
+        let mut property_changes = vec![];
 
         for node in self.nodes.iter_mut() {
             let mut value_ref_scratch: Vec<&[UnsafeCell<Primitive>]> =
@@ -353,6 +384,28 @@ impl BufferedTraverser {
                 &value_ref_scratch
             };
 
+            for property_input in node.property_inputs.iter_mut() {
+                // SAFETY: nothing is writing to the value io while we're reading it
+                let value = unsafe { (*value_inputs[property_input.value_index][0].get()).clone() };
+
+                if let Some(new_property) = property_input.to_property(value) {
+                    if new_property != property_input.current {
+                        // even if the node applied it, the graph state needs to know about it
+                        property_changes.push(PropertyChange {
+                            index: GlobalNodeIndex {
+                                graph_index: self.graph_index,
+                                node_index: node.index,
+                            },
+                            name: property_input.name.clone(),
+                            value: new_property.clone(),
+                            applied: node.node.update_property(&property_input.name, &new_property),
+                        });
+
+                        property_input.current = new_property;
+                    }
+                }
+            }
+
             node.node.process(
                 NodeProcessContext {
                     current_time: self.time,
@@ -382,6 +435,9 @@ impl BufferedTraverser {
                 &all_resources[node.resources.clone()],
             );
 
+            // nodes with graphs of their own pass on what changed in them
+            property_changes.extend(node.node.take_property_changes());
+
             self.value_ref_scratch = value_ref_scratch.recycle();
             self.value_input_scratch = value_input_scratch.recycle();
         }
@@ -392,6 +448,30 @@ impl BufferedTraverser {
             }
         }
 
+        // # Delayed connections
+        //
+        // Connections that close a loop read from their own slots, which get a copy of
+        // what was output this step to be read next step.
+        let io = self.io_and_refs.borrow_owner();
+        let stream_chunks = io.stream_io.chunks();
+
+        for (from, to) in &self.stream_delays {
+            for (sample_from, sample_to) in stream_chunks[*from].iter().zip(stream_chunks[*to].iter()) {
+                // SAFETY: same as below, nothing else is using io_and_refs
+                unsafe { *sample_to.get() = *sample_from.get() };
+            }
+        }
+
+        for (from, to) in &self.value_delays {
+            unsafe { *io.value_io[*to].get() = (*io.value_io[*from].get()).clone() };
+        }
+
+        for (from, to) in &self.osc_delays {
+            // the osc index is still owned by its node, so it'll be collected once it's replaced,
+            // after the delayed node has seen it
+            unsafe { *io.osc_io[*to].get() = (*io.osc_io[*from].get()).as_ref().map(|x| x.private_clone()) };
+        }
+
         // # Osc garbage collection
         //
         // As each osc bundle is "owned" by only the node that outputted it,
@@ -421,6 +501,7 @@ impl BufferedTraverser {
             state_changes,
             request_for_graph_state: requesting_graph_state,
             requested_state_updates: requested_state_updates,
+            property_changes,
         }
     }
 
@@ -458,18 +539,54 @@ impl BufferedTraverser {
             node.node.reset();
         }
     }
+
+    /// Swaps out a node without touching the rest of the graph. Its resources are looked up on
+    /// the next step.
+    pub fn replace_node(&mut self, replacement: NodeReplacement) {
+        let NodeReplacement {
+            index,
+            node,
+            needed_resources,
+        } = replacement;
+
+        let internal_index = match self.node_to_index_mapping.get(&index) {
+            Some(internal_index) => *internal_index,
+            None => return,
+        };
+
+        let old_range = self.nodes[internal_index].resources.clone();
+        let new_len = needed_resources.len();
+
+        self.resource_tracking.splice(
+            old_range.clone(),
+            needed_resources.into_iter().map(|resource| (resource, None)),
+        );
+
+        // everything after it moves along
+        for other in self.nodes.iter_mut() {
+            if other.resources.start >= old_range.end {
+                other.resources = (other.resources.start - old_range.len() + new_len)
+                    ..(other.resources.end - old_range.len() + new_len);
+            }
+        }
+
+        let replaced = &mut self.nodes[internal_index];
+        replaced.resources = old_range.start..(old_range.start + new_len);
+        replaced.node = node;
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use std::time::Duration;
+    use std::{collections::BTreeMap, time::Duration};
 
     use sound_engine::SoundConfig;
 
     use crate::{
-        connection::{Socket, SocketType},
-        graph_manager::GraphManager,
-        node::osc_store::OscStore,
+        connection::{Primitive, Socket, SocketType},
+        errors::NodeWarning,
+        graph_manager::{GlobalNodeIndex, GraphManager},
+        node::{osc_store::OscStore, NodeIndex},
         resources::Resources,
     };
 
@@ -520,4 +637,125 @@ mod tests {
         traverser.step(&Resources::default(), vec![], None, &mut osc_store);
         traverser.step(&Resources::default(), vec![], None, &mut osc_store);
     }
+
+    /// The first channel of a node's first stream output, from the last step
+    fn stream_output(traverser: &BufferedTraverser, node: NodeIndex) -> Vec<f32> {
+        let node = &traverser.nodes[traverser.node_to_index_mapping[&node]];
+        let stream_sockets = &traverser.io_and_refs.borrow_dependent().stream_sockets;
+
+        // SAFETY: the traverser isn't stepping
+        stream_sockets[node.stream_out.start][0]
+            .iter()
+            .map(|frame| unsafe { *frame.get() })
+            .collect()
+    }
+
+    #[test]
+    fn test_feedback_loop() {
+        let mut manager = GraphManager::new(1);
+        let (graph_index, _) = manager.new_graph().unwrap();
+        let graph = manager.get_graph_mut(graph_index).unwrap();
+        let mut osc_store = OscStore::new(256, 0);
+
+        // oscillator -> mixer -> gain_a -> gain_b -> back into the mixer
+        let (oscillator, _) = graph.add_node("OscillatorNode").unwrap().value;
+        let (mixer, _) = graph.add_node("MixerNode").unwrap().value;
+        let (gain_a, _) = graph.add_node("GainNode").unwrap().value;
+        let (gain_b, _) = graph.add_node("GainNode").unwrap().value;
+
+        let audio = Socket::Simple("audio".into(), SocketType::Stream, 1);
+        let mixer_input = |i: usize| Socket::WithData("input_numbered".into(), i.to_string(), SocketType::Stream, 1);
+
+        graph.connect(oscillator, &audio, mixer, &mixer_input(1)).unwrap();
+
+        // the mixer gets another input once the first is connected
+        manager
+            .update_node_rows(GlobalNodeIndex {
+                graph_index,
+                node_index: mixer,
+            })
+            .unwrap();
+
+        let graph = manager.get_graph_mut(graph_index).unwrap();
+        graph.connect(mixer, &audio, gain_a, &audio).unwrap();
+        graph.connect(gain_a, &audio, gain_b, &audio).unwrap();
+        graph.connect(gain_b, &audio, mixer, &mixer_input(2)).unwrap();
+
+        let sound_config = SoundConfig {
+            sample_rate: 48_000,
+            buffer_size: 4,
+        };
+
+        let (errors_and_warnings, mut traverser) = BufferedTraverser::new(
+            sound_config.clone(),
+            &manager,
+            graph_index,
+            &Resources::default(),
+            Duration::ZERO,
+        )
+        .unwrap();
+
+        let feedback: Vec<(NodeIndex, NodeIndex)> = errors_and_warnings
+            .warnings
+            .iter()
+            .filter_map(|(to_node, warning)| match warning {
+                NodeWarning::FeedbackConnection { from_node, .. } => Some((*from_node, *to_node)),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(feedback.len(), 1);
+
+        let delayed = feedback[0];
+
+        let gain = Socket::Simple("gain".into(), SocketType::Value, 1);
+        traverser
+            .input_value_default(gain_a, &gain, Primitive::Float(1.0))
+            .unwrap();
+        traverser
+            .input_value_default(gain_b, &gain, Primitive::Float(1.0))
+            .unwrap();
+
+        let connections = [(mixer, gain_a), (gain_a, gain_b), (gain_b, mixer)];
+        let mut last: BTreeMap<NodeIndex, Vec<f32>> =
+            BTreeMap::from([(mixer, vec![0.0; 4]), (gain_a, vec![0.0; 4]), (gain_b, vec![0.0; 4])]);
+        let mut fed_back = false;
+
+        for _ in 0..4 {
+            traverser.step(&Resources::default(), vec![], None, &mut osc_store);
+
+            let now: BTreeMap<NodeIndex, Vec<f32>> = [oscillator, mixer, gain_a, gain_b]
+                .into_iter()
+                .map(|node| (node, stream_output(&traverser, node)))
+                .collect();
+
+            for (from, to) in connections {
+                // the delayed connection gets what was sent exactly one buffer before, the
+                // others what was sent this buffer
+                let sent = if (from, to) == delayed {
+                    &last[&from]
+                } else {
+                    &now[&from]
+                };
+
+                let expected: Vec<f32> = if to == mixer {
+                    sent.iter()
+                        .zip(&now[&oscillator])
+                        .map(|(sent, osc)| osc + sent)
+                        .collect()
+                } else {
+                    sent.clone()
+                };
+
+                assert_eq!(now[&to], expected, "{:?} -> {:?}", from, to);
+
+                if (from, to) == delayed {
+                    fed_back |= sent.iter().any(|frame| *frame != 0.0);
+                }
+            }
+
+            last = now;
+        }
+
+        assert!(fed_back);
+    }
 }
@@ -2,12 +2,13 @@ use std::collections::{BTreeMap, HashMap};
 use std::ops::Range;
 use std::time::Duration;
 
-use crate::connection::{Connection, Primitive, Socket, SocketType};
+use crate::connection::{Primitive, Socket, SocketType};
 use crate::errors::{ErrorsAndWarnings, NodeError, NodeWarning};
 use crate::graph_manager::GraphManager;
 use crate::node::{NodeIndex, NodeInitParams, NodeRow, NodeRuntime};
-use crate::node_graph::{NodeConnectionData, NodeGraph};
+use crate::node_graph::{ConnectionIndex, NodeGraph};
 use crate::nodes::{new_variant, NodeVariant};
+use crate::property::{Property, PropertyType};
 use crate::resources::{ResourceTypeAndIndex, Resources};
 
 use common::resource_manager::ResourceId;
@@ -18,11 +19,21 @@ use rhai::Engine;
 use smallvec::SmallVec;
 use sound_engine::SoundConfig;
 
-pub fn calculate_graph_traverse_order(original_graph: &NodeGraph) -> Vec<NodeIndex> {
+/// The order nodes are processed in, and the connections that had to be broken to get there
+#[derive(Debug, Default)]
+pub struct TraversalOrder {
+    pub order: Vec<NodeIndex>,
+    /// connections that close a loop. The node on the receiving end of one of these runs
+    /// before the node sending, so it gets what was sent the buffer before.
+    pub feedback: Vec<ConnectionIndex>,
+}
+
+pub fn calculate_graph_traverse_order(original_graph: &NodeGraph) -> TraversalOrder {
     // traverse backward and build a traversal order
 
-    let mut graph = StableGraph::<NodeIndex, NodeConnectionData>::new();
+    let mut graph = StableGraph::<NodeIndex, ConnectionIndex>::new();
     let mut graph_lookup: HashMap<VertexIndex, petgraph::stable_graph::NodeIndex> = HashMap::new();
+    let mut feedback: Vec<ConnectionIndex> = Vec::new();
 
     for original_node_index in original_graph.node_indexes() {
         graph_lookup.insert(original_node_index.0, graph.add_node(original_node_index));
@@ -31,30 +42,24 @@ pub fn calculate_graph_traverse_order(original_graph: &NodeGraph) -> Vec<NodeInd
     for original_edge_index in original_graph.edge_indexes() {
         let edge = original_graph.get_graph().get_edge(original_edge_index.0).unwrap();
 
+        // a node feeding into itself is always a loop
+        if edge.get_from() == edge.get_to() {
+            feedback.push(original_edge_index);
+
+            continue;
+        }
+
         graph.add_edge(
             graph_lookup[&edge.get_from()],
             graph_lookup[&edge.get_to()],
-            edge.data().clone(),
+            original_edge_index,
         );
     }
 
-    let edges = greedy_feedback_arc_set(&graph);
-
-    let mut connections: Vec<Connection> = Vec::new();
     let mut edge_indexes: Vec<EdgeIndex> = Vec::new();
 
-    for edge in edges {
-        let weight = edge.weight();
-
-        connections.push(Connection {
-            from_node: graph[edge.source()],
-            to_node: graph[edge.target()],
-            data: NodeConnectionData {
-                from_socket: weight.from_socket.clone(),
-                to_socket: weight.to_socket.clone(),
-            },
-        });
-
+    for edge in greedy_feedback_arc_set(&graph) {
+        feedback.push(*edge.weight());
         edge_indexes.push(edge.id());
     }
 
@@ -64,10 +69,22 @@ pub fn calculate_graph_traverse_order(original_graph: &NodeGraph) -> Vec<NodeInd
 
     let node_order = toposort(&graph, None).unwrap();
 
-    node_order
-        .iter()
-        .map(|index| *graph.node_weight(*index).unwrap())
-        .collect::<Vec<crate::node::NodeIndex>>()
+    TraversalOrder {
+        order: node_order
+            .iter()
+            .map(|index| *graph.node_weight(*index).unwrap())
+            .collect::<Vec<crate::node::NodeIndex>>(),
+        feedback,
+    }
+}
+
+/// Sets aside `channels` slots for a delayed connection from the output at `from`, returning
+/// where they start. Delay slots go after every output, in the order they're set aside.
+fn delay_slots(output_count: usize, delays: &mut Vec<(usize, usize)>, from: usize, channels: usize) -> usize {
+    let start = output_count + delays.len();
+    delays.extend((0..channels).map(|channel| (from + channel, start + channel)));
+
+    start
 }
 
 #[derive(Debug)]
@@ -87,6 +104,27 @@ pub struct NodeIoCount {
     pub stream_index: usize,
     pub values_to_input: SmallVec<[(usize, Primitive); 1]>,
     pub socket_lookup: BTreeMap<Socket, usize>,
+    pub property_inputs: Vec<PropertyInput>,
+}
+
+/// A property exposed as a value input
+#[derive(Debug, Clone)]
+pub struct PropertyInput {
+    pub value_index: usize,
+    pub name: String,
+    pub property_type: PropertyType,
+    pub current: Property,
+    /// what a resource property can be set to, picked by index
+    pub resources: Vec<ResourceId>,
+}
+
+impl PropertyInput {
+    pub fn to_property(&self, value: Primitive) -> Option<Property> {
+        match self.property_type {
+            PropertyType::Resource(_) => Property::resource_from_primitive(&self.resources, value),
+            _ => Property::from_primitive(&self.property_type, value),
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -95,6 +133,7 @@ pub struct IoSpec {
     pub resources_tracking: Vec<(ResourceId, Option<ResourceTypeAndIndex>)>,
     pub nodes_linked_to_ui: Vec<(usize, NodeIndex)>,
     pub traversal_order: Vec<NodeIndex>,
+    pub feedback: Vec<ConnectionIndex>,
 }
 
 #[derive(Debug, Clone)]
@@ -119,6 +158,11 @@ pub struct Indexes {
     pub stream_count: usize,
     pub osc_count: usize,
     pub value_count: usize,
+    /// pairs of output slots and the delay slots (past the outputs) they're copied to after
+    /// every buffer, for connections that close a loop
+    pub stream_delays: Vec<(usize, usize)>,
+    pub osc_delays: Vec<(usize, usize)>,
+    pub value_delays: Vec<(usize, usize)>,
     pub node_io: BTreeMap<NodeIndex, NodeMappedIo>,
     pub resources_tracking: Vec<(ResourceId, Option<ResourceTypeAndIndex>)>,
 }
@@ -133,11 +177,28 @@ pub fn generate_io_spec(
     graph_manager: &GraphManager,
     default_channel_count: usize,
 ) -> Result<(ErrorsAndWarnings, IoSpec), NodeError> {
-    let traversal_order = calculate_graph_traverse_order(&graph);
+    let TraversalOrder {
+        order: traversal_order,
+        feedback,
+    } = calculate_graph_traverse_order(&graph);
 
     let mut errors: Vec<(NodeIndex, NodeError)> = vec![];
     let mut warnings: Vec<(NodeIndex, NodeWarning)> = vec![];
 
+    // loops are fine, but as they're a buffer late it's good to know where they are
+    for connection_index in &feedback {
+        let connection = graph.get_graph().get_edge(connection_index.0).expect("edge to exist");
+
+        warnings.push((
+            NodeIndex(connection.get_to()),
+            NodeWarning::FeedbackConnection {
+                from_node: NodeIndex(connection.get_from()),
+                from_socket: connection.data().from_socket.clone(),
+                to_socket: connection.data().to_socket.clone(),
+            },
+        ));
+    }
+
     let mut resources_tracking: Vec<(ResourceId, Option<ResourceTypeAndIndex>)> = vec![];
     let mut nodes_linked_to_ui: Vec<(usize, NodeIndex)> = vec![];
 
@@ -222,6 +283,7 @@ pub fn generate_io_spec(
         let mut to_input: Vec<(usize, Vec<Primitive>)> = vec![];
         let mut values_to_input = SmallVec::new();
         let mut socket_lookup = BTreeMap::new();
+        let mut property_inputs: Vec<PropertyInput> = vec![];
 
         // go through the node by all its inputs
         for socket in node_instance.list_input_sockets() {
@@ -238,6 +300,32 @@ pub fn generate_io_spec(
                         osc_inputs.push(socket.channels());
                     }
                     SocketType::Value => {
+                        if let Socket::WithData(kind, name, ..) = &socket {
+                            let property_row = node_instance.get_node_rows().iter().find_map(|row| match row {
+                                NodeRow::Property(prop_name, prop_type, prop_default)
+                                    if kind == "property" && prop_name == name =>
+                                {
+                                    Some((prop_type.clone(), prop_default.clone()))
+                                }
+                                _ => None,
+                            });
+
+                            if let Some((property_type, default)) = property_row {
+                                let property_resources = match &property_type {
+                                    PropertyType::Resource(namespace) => resources.resource_ids(namespace),
+                                    _ => vec![],
+                                };
+
+                                property_inputs.push(PropertyInput {
+                                    value_index: value_inputs.len(),
+                                    name: name.clone(),
+                                    property_type,
+                                    current: node_instance.get_property(name).unwrap_or(default),
+                                    resources: property_resources,
+                                });
+                            }
+                        }
+
                         values_to_input.push((value_inputs.len(), default.clone().as_value().unwrap()));
                         socket_lookup.insert(socket.clone(), value_inputs.len());
                         value_inputs.push(socket.channels());
@@ -287,6 +375,7 @@ pub fn generate_io_spec(
                 resources_index: resources_i,
                 socket_lookup,
                 values_to_input,
+                property_inputs,
             },
         );
 
@@ -302,13 +391,17 @@ pub fn generate_io_spec(
             resources_tracking,
             nodes_linked_to_ui,
             traversal_order,
+            feedback,
         },
     ))
 }
 
 pub fn calc_indexes(io_needed: &IoSpec, graph: &NodeGraph) -> Result<Indexes, NodeError> {
     let IoSpec {
-        nodes, traversal_order, ..
+        nodes,
+        traversal_order,
+        feedback,
+        ..
     } = io_needed;
 
     // figure out how big our io array needs to be
@@ -347,6 +440,10 @@ pub fn calc_indexes(io_needed: &IoSpec, graph: &NodeGraph) -> Result<Indexes, No
     let mut value_io: Vec<Option<Range<usize>>> = vec![];
     let mut node_mapped_io: BTreeMap<NodeIndex, NodeMappedIo> = BTreeMap::new();
 
+    let mut stream_delays: Vec<(usize, usize)> = vec![];
+    let mut osc_delays: Vec<(usize, usize)> = vec![];
+    let mut value_delays: Vec<(usize, usize)> = vec![];
+
     // # Step 2, populate mappings between nodes
     // Now we know where all the nodes are, so we can tell each node where its inputs are
     for index in traversal_order {
@@ -372,8 +469,11 @@ pub fn calc_indexes(io_needed: &IoSpec, graph: &NodeGraph) -> Result<Indexes, No
                 let connection = graph.get_graph().get_edge(connection_index.0).expect("edge to exist");
                 let from_index = NodeIndex(connection.get_from());
 
-                // make sure it's not being connected to itself
-                assert_ne!(connection.get_from(), connection.get_to());
+                // if it closes a loop, it reads a copy of the output from last buffer
+                let delayed = feedback.contains(&connection_index);
+
+                // make sure it's not being connected to itself, unless it's delayed
+                assert!(delayed || connection.get_from() != connection.get_to());
 
                 // ensure same channel length
                 assert_eq!(
@@ -393,6 +493,11 @@ pub fn calc_indexes(io_needed: &IoSpec, graph: &NodeGraph) -> Result<Indexes, No
                             .position(|other_socket| other_socket == &connection.data().from_socket)
                             .unwrap()
                             + io_setup_of_other.stream_index;
+                        let other_stream_pos = if delayed {
+                            delay_slots(stream_count, &mut stream_delays, other_stream_pos, input.channels())
+                        } else {
+                            other_stream_pos
+                        };
 
                         stream_io.push(Some(other_stream_pos..(other_stream_pos + input.channels())));
                         stream_io_inputs += 1;
@@ -404,6 +509,11 @@ pub fn calc_indexes(io_needed: &IoSpec, graph: &NodeGraph) -> Result<Indexes, No
                             .position(|other_socket| other_socket == &connection.data().from_socket)
                             .unwrap()
                             + io_setup_of_other.osc_index;
+                        let other_osc_pos = if delayed {
+                            delay_slots(osc_count, &mut osc_delays, other_osc_pos, input.channels())
+                        } else {
+                            other_osc_pos
+                        };
 
                         osc_io.push(Some(other_osc_pos..(other_osc_pos + input.channels())));
                         osc_io_inputs += 1;
@@ -415,6 +525,11 @@ pub fn calc_indexes(io_needed: &IoSpec, graph: &NodeGraph) -> Result<Indexes, No
                             .position(|other_socket| other_socket == &connection.data().from_socket)
                             .unwrap()
                             + io_setup_of_other.value_index;
+                        let other_value_pos = if delayed {
+                            delay_slots(value_count, &mut value_delays, other_value_pos, input.channels())
+                        } else {
+                            other_value_pos
+                        };
 
                         value_io.push(Some(other_value_pos..(other_value_pos + input.channels())));
                         value_io_inputs += 1;
@@ -513,6 +628,9 @@ pub fn calc_indexes(io_needed: &IoSpec, graph: &NodeGraph) -> Result<Indexes, No
         stream_count,
         osc_count,
         value_count,
+        stream_delays,
+        osc_delays,
+        value_delays,
         node_io: node_mapped_io,
         resources_tracking: io_needed.resources_tracking.clone(),
     })
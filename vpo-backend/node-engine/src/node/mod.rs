@@ -4,6 +4,7 @@ pub mod buffered_traverser;
 pub mod calculate_traversal_order;
 pub mod osc_store;
 
+use std::borrow::Cow;
 use std::cell::UnsafeCell;
 use std::collections::BTreeMap;
 use std::fmt::Debug;
@@ -19,10 +20,10 @@ use rhai::Engine;
 use serde::{Deserialize, Serialize};
 use sound_engine::SoundConfig;
 
-use crate::connection::{Primitive, Socket, SocketDirection, SocketValue};
+use crate::connection::{Primitive, Socket, SocketDirection, SocketType, SocketValue};
 
 use crate::errors::{NodeOk, NodeResult, NodeWarning};
-use crate::graph_manager::{GraphIndex, GraphManager};
+use crate::graph_manager::{GlobalNodeIndex, GraphIndex, GraphManager};
 use crate::node_graph::NodeGraph;
 use crate::property::{Property, PropertyType};
 use crate::resources::{Resource, Resources};
@@ -66,6 +67,34 @@ impl NodeRow {
     }
 }
 
+/// The value socket a property is exposed through
+pub fn property_socket(name: &str) -> Socket {
+    Socket::WithData(Cow::Borrowed("property"), name.to_string(), SocketType::Value, 1)
+}
+
+/// Appends a value input for every property the overrides expose. They go at the end so the
+/// indexes of the node's own sockets stay the same.
+pub fn with_property_inputs(mut rows: Vec<NodeRow>, overrides: &[NodeRow]) -> Vec<NodeRow> {
+    let exposed: Vec<NodeRow> = overrides
+        .iter()
+        .filter_map(|row| match row {
+            NodeRow::Property(name, ..) => Some(name),
+            _ => None,
+        })
+        .filter(|name| {
+            rows.iter().any(|row| match row {
+                NodeRow::Property(prop_name, prop_type, _) => prop_name == *name && prop_type.accepts_values(),
+                _ => false,
+            })
+        })
+        .map(|name| NodeRow::Input(property_socket(name), SocketValue::Value(Primitive::None)))
+        .collect();
+
+    rows.extend(exposed);
+
+    rows
+}
+
 #[derive(Debug)]
 pub struct NodeIo {
     pub node_rows: Vec<NodeRow>,
@@ -182,6 +211,17 @@ impl Default for NodeState {
     }
 }
 
+/// A property changed through an input, for [`crate::state::GraphState`] to store
+#[derive(Debug, Clone, PartialEq)]
+pub struct PropertyChange {
+    pub index: GlobalNodeIndex,
+    pub name: String,
+    pub value: Property,
+    /// whether the running node already applied it, in which case it doesn't need to be
+    /// initialized again
+    pub applied: bool,
+}
+
 /// All inputs provided to a node
 pub struct Ins<'a> {
     oscs: &'a [&'a [UnsafeCell<Option<OscIndex>>]],
@@ -540,6 +580,9 @@ impl OptionExt for Option<OscIndex> {
 /// `set_state`, `accept_osc_inputs`, and `accept_value_inputs` are called before `process`
 /// in an arbitrary order. They are also _only_ called if there is new incoming state.
 ///
+/// If a property is exposed as an input, `update_property` is called with the new value
+/// before `process`. Returning `false` falls back to a full `init`.
+///
 /// `process` is called after that, and this is where most of the work happens.
 ///
 /// After that, `get_osc_outputs`, `get_value_outputs` are called every time, and
@@ -570,6 +613,18 @@ pub trait NodeRuntime: Debug {
     /// reset params to initial state
     fn reset(&mut self) {}
 
+    /// Called when a property exposed as an input changes. Returns whether the node could apply
+    /// it in place
+    fn update_property(&mut self, name: &str, value: &Property) -> bool {
+        false
+    }
+
+    /// Properties changed through inputs inside of this node's child graph, collected while
+    /// processing
+    fn take_property_changes(&mut self) -> Vec<PropertyChange> {
+        vec![]
+    }
+
     /// Process all data in and out
     fn process<'a>(
         &mut self,
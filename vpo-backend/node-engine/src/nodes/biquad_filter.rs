@@ -12,6 +12,17 @@ pub struct BiquadFilterNode {
 }
 
 impl BiquadFilterNode {
+    fn filter_type(&self, filter_type: &str) -> Option<FilterType<f32>> {
+        match filter_type {
+            "lowpass" => Some(FilterType::LowPass { q: self.q }),
+            "highpass" => Some(FilterType::HighPass { q: self.q }),
+            "bandpass" => Some(FilterType::BandPass { bandwidth: self.q }),
+            "notch" => Some(FilterType::Notch { bandwidth: self.q }),
+            "allpass" => Some(FilterType::AllPass { q: self.q }),
+            _ => None,
+        }
+    }
+
     fn recompute(&mut self) {
         let coeffs = filter_coeffs(self.filter_spec.clone());
 
@@ -30,14 +41,9 @@ impl NodeRuntime for BiquadFilterNode {
         self.filter_spec = FilterSpec {
             f0: self.filter_spec.f0,
             fs: params.sound_config.sample_rate as f32,
-            filter_type: match filter_type.as_str() {
-                "lowpass" => FilterType::LowPass { q: self.q },
-                "highpass" => FilterType::HighPass { q: self.q },
-                "bandpass" => FilterType::BandPass { bandwidth: self.q },
-                "notch" => FilterType::Notch { bandwidth: self.q },
-                "allpass" => FilterType::AllPass { q: self.q },
-                _ => unreachable!("Type passed in was not a multiple choice option!"),
-            },
+            filter_type: self
+                .filter_type(&filter_type)
+                .expect("Type passed in was not a multiple choice option!"),
         };
 
         self.filters.resize(params.get_channel_count(), BiquadFilter::default());
@@ -46,6 +52,20 @@ impl NodeRuntime for BiquadFilterNode {
         InitResult::nothing()
     }
 
+    fn update_property(&mut self, name: &str, value: &Property) -> bool {
+        match (name, value) {
+            ("filter_type", Property::MultipleChoice(filter_type)) => {
+                if let Some(filter_type) = self.filter_type(filter_type) {
+                    self.filter_spec.filter_type = filter_type;
+                    self.recompute();
+                }
+
+                true
+            }
+            _ => false,
+        }
+    }
+
     fn process<'a>(
         &mut self,
         _context: NodeProcessContext,
@@ -60,6 +80,9 @@ impl NodeRuntime for BiquadFilterNode {
         }
 
         if let Some(resonance) = ins.value(1)[0].as_float() {
+            // so it carries over when the filter type changes
+            self.q = resonance;
+
             match &mut self.filter_spec.filter_type {
                 FilterType::LowPass { q } | FilterType::HighPass { q } | FilterType::AllPass { q } => {
                     *q = resonance;
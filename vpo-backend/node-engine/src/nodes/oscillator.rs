@@ -23,6 +23,19 @@ impl NodeRuntime for OscillatorNode {
         InitResult::nothing()
     }
 
+    fn update_property(&mut self, name: &str, value: &Property) -> bool {
+        match (name, value) {
+            ("waveform", Property::MultipleChoice(waveform)) => {
+                if let Some(waveform) = Waveform::from_string(waveform) {
+                    self.oscillator.set_waveform(waveform);
+                }
+
+                true
+            }
+            _ => false,
+        }
+    }
+
     fn process<'a>(
         &mut self,
         _context: NodeProcessContext,
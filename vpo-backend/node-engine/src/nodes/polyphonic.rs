@@ -1,8 +1,12 @@
+use std::mem;
 use std::time::Duration;
 
 use common::osc_midi::{get_channel, is_message_reset, NOTE_OFF_C, NOTE_ON_C};
 
-use crate::{node::buffered_traverser::BufferedTraverser, nodes::prelude::*};
+use crate::{
+    node::{buffered_traverser::BufferedTraverser, PropertyChange},
+    nodes::prelude::*,
+};
 
 use super::NodeVariant;
 
@@ -42,6 +46,7 @@ pub struct PolyphonicNode {
     input_node: Option<NodeIndex>,
     output_node: Option<NodeIndex>,
     scratch: Vec<u8>,
+    property_changes: Vec<PropertyChange>,
 }
 
 impl Clone for PolyphonicNode {
@@ -52,6 +57,7 @@ impl Clone for PolyphonicNode {
             input_node: self.input_node,
             output_node: self.output_node,
             scratch: default_osc(),
+            property_changes: vec![],
         }
     }
 }
@@ -125,6 +131,10 @@ impl NodeRuntime for PolyphonicNode {
         ))
     }
 
+    fn take_property_changes(&mut self) -> Vec<PropertyChange> {
+        mem::take(&mut self.property_changes)
+    }
+
     fn process<'a>(
         &mut self,
         context: NodeProcessContext,
@@ -284,7 +294,14 @@ impl NodeRuntime for PolyphonicNode {
         for voice in self.voices.iter_mut() {
             if voice.info.active {
                 // if it's active, process it
-                voice.traverser.step(&context.resources, vec![], None, osc_store);
+                let result = voice.traverser.step(&context.resources, vec![], None, osc_store);
+
+                // every voice sees the same change
+                for change in result.property_changes {
+                    if !self.property_changes.contains(&change) {
+                        self.property_changes.push(change);
+                    }
+                }
 
                 let child_graph_output = voice.traverser.get_node_mut(output_node).unwrap();
 
@@ -325,6 +342,7 @@ impl Node for PolyphonicNode {
             polyphony: 1,
             input_node: None,
             output_node: None,
+            property_changes: vec![],
         }
     }
 
@@ -5,7 +5,7 @@ use sound_engine::{
         rank::RankType,
         rank_player::RankPlayer,
     },
-    util::{cents_to_detune, db_to_gain},
+    util::{cents_to_detune, db_to_gain, interpolate::Interpolation},
 };
 
 use crate::nodes::prelude::*;
@@ -20,6 +20,8 @@ enum PlayerType {
 pub struct RankPlayerNode {
     player: Option<PlayerType>,
     polyphony: usize,
+    channels: usize,
+    interleaved: Vec<f32>,
 }
 
 impl NodeRuntime for RankPlayerNode {
@@ -29,12 +31,35 @@ impl NodeRuntime for RankPlayerNode {
         self.player = None;
 
         self.polyphony = props.get_int("polyphony")?.clamp(1, 255) as usize;
+        // older graphs don't have this property yet either
+        self.channels = props.get_int("channels").unwrap_or(1).clamp(1, 32) as usize;
+        self.interleaved = vec![0.0; params.sound_config.buffer_size * self.channels];
 
         let rank_id = props.get_resource("rank")?;
         let rank_type = props.get_multiple_choice("rank_type")?;
+        // older graphs don't have this property yet
+        let interpolation = props
+            .get_multiple_choice("interpolation")
+            .ok()
+            .and_then(|interpolation| Interpolation::from_string(&interpolation))
+            .unwrap_or_default();
 
         let rank = resources.ranks.borrow_resource_by_id(&rank_id.resource);
 
+        let out_of_range = match rank {
+            Some(RankType::Pipes(rank)) => rank.channels_out_of_range(self.channels),
+            Some(RankType::Percussion(rank)) => rank.channels_out_of_range(self.channels),
+            None => vec![],
+        };
+        let warnings = out_of_range
+            .into_iter()
+            .map(|(note, channel)| NodeWarning::ChannelOutOfRange {
+                note,
+                channel,
+                channels: self.channels,
+            })
+            .collect();
+
         let player_and_resources = rank.and_then(|rank| match rank {
             RankType::Pipes(pipe_rank) => {
                 // TODO: figure if there's a more elegant way to do this
@@ -42,36 +67,50 @@ impl NodeRuntime for RankPlayerNode {
                     return None;
                 }
 
-                let (player, needed_resources) =
+                let (mut player, needed_resources) =
                     RankPlayer::new(rank_id.clone(), pipe_rank, self.polyphony, params.sound_config.clone());
+                player.prepare(pipe_rank, &resources.samples);
 
-                Some((PlayerType::Pipe(player, PipeParam::default()), needed_resources))
+                let param = PipeParam {
+                    interpolation,
+                    ..PipeParam::default()
+                };
+                player.set_param(param.clone());
+
+                Some((PlayerType::Pipe(player, param), needed_resources))
             }
             RankType::Percussion(percussion_rank) => {
                 if rank_type != "percussion" {
                     return None;
                 }
 
-                let (player, needed_resources) = RankPlayer::new(
+                let (mut player, needed_resources) = RankPlayer::new(
                     rank_id.clone(),
                     percussion_rank,
                     self.polyphony,
                     params.sound_config.clone(),
                 );
+                player.prepare(percussion_rank, &resources.samples);
 
-                Some((
-                    PlayerType::Percussion(player, PercussionParam::default()),
-                    needed_resources,
-                ))
+                let param = PercussionParam {
+                    interpolation,
+                    ..PercussionParam::default()
+                };
+                player.set_param(param.clone());
+
+                Some((PlayerType::Percussion(player, param), needed_resources))
             }
         });
 
         if let Some((player, needed_resources)) = player_and_resources {
             self.player = Some(player);
 
-            NodeOk::no_warnings(InitResult {
-                changed_properties: None,
-                needed_resources,
+            Ok(NodeOk {
+                value: InitResult {
+                    changed_properties: None,
+                    needed_resources,
+                },
+                warnings,
             })
         } else {
             Ok(NodeOk {
@@ -81,6 +120,28 @@ impl NodeRuntime for RankPlayerNode {
         }
     }
 
+    fn update_property(&mut self, name: &str, value: &Property) -> bool {
+        match (name, value, &mut self.player) {
+            ("interpolation", Property::MultipleChoice(interpolation), Some(player)) => {
+                let interpolation = Interpolation::from_string(interpolation).unwrap_or_default();
+
+                match player {
+                    PlayerType::Pipe(player, param) => {
+                        param.interpolation = interpolation;
+                        player.set_param(param.clone());
+                    }
+                    PlayerType::Percussion(player, param) => {
+                        param.interpolation = interpolation;
+                        player.set_param(param.clone());
+                    }
+                }
+
+                true
+            }
+            _ => false,
+        }
+    }
+
     fn process<'a>(
         &mut self,
         context: NodeProcessContext,
@@ -94,9 +155,7 @@ impl NodeRuntime for RankPlayerNode {
             .and_then(|bytes| OscView::new(bytes))
             .unwrap_or_default();
 
-        for frame in outs.stream(0)[0].iter_mut() {
-            *frame = 0.0;
-        }
+        self.interleaved.fill(0.0);
 
         match &mut self.player {
             Some(PlayerType::Pipe(player, param)) => {
@@ -122,7 +181,8 @@ impl NodeRuntime for RankPlayerNode {
                 }
 
                 if let Some(Resource::Rank(RankType::Pipes(rank))) = resources.get(0) {
-                    player.next_buffered(messages, rank, &resources[1..], &mut outs.stream(0)[0]);
+                    player.handle_rank_updates(rank, &resources[1..]);
+                    player.next_buffered(messages, rank, &resources[1..], &mut self.interleaved, self.channels);
                 }
             }
             Some(PlayerType::Percussion(player, param)) => {
@@ -143,11 +203,18 @@ impl NodeRuntime for RankPlayerNode {
                         player.set_param(param.clone());
                     }
 
-                    player.next_buffered(messages, rank, &resources[1..], &mut outs.stream(0)[0]);
+                    player.handle_rank_updates(rank, &resources[1..]);
+                    player.next_buffered(messages, rank, &resources[1..], &mut self.interleaved, self.channels);
                 }
             }
             None => {}
         }
+
+        for (channel, out) in outs.stream(0).iter_mut().enumerate() {
+            for (frame, out) in out.iter_mut().enumerate() {
+                *out = self.interleaved[frame * self.channels + channel];
+            }
+        }
     }
 }
 
@@ -156,6 +223,8 @@ impl Node for RankPlayerNode {
         RankPlayerNode {
             player: None,
             polyphony: 64,
+            channels: 1,
+            interleaved: vec![],
         }
     }
 
@@ -164,6 +233,8 @@ impl Node for RankPlayerNode {
             multiple_choice("rank_type", &["pipe", "percussion"], "pipe"),
             resource("rank", "ranks"),
             property("polyphony", PropertyType::Integer, Property::Integer(64)),
+            property("channels", PropertyType::Integer, Property::Integer(1)),
+            multiple_choice("interpolation", &["linear", "hermite", "sinc"], "hermite"),
             osc_input("midi", 1),
             value_input("detune", Primitive::Float(0.0), 1),
             value_input("db_gain", Primitive::Float(0.0), 1),
@@ -178,7 +249,9 @@ impl Node for RankPlayerNode {
             _ => rows.push(value_input("shelf_db_gain", Primitive::Float(0.0), 1)),
         };
 
-        rows.push(stream_output("audio", 1));
+        let channels = props.get_int("channels").unwrap_or(1).clamp(1, 32) as usize;
+
+        rows.push(stream_output("audio", channels));
 
         NodeIo::simple(rows)
     }
@@ -1,4 +1,3 @@
-use common::traits::TryRef;
 use sound_engine::{node::wavetable_oscillator::WavetableOscillator, SoundConfig};
 
 use crate::nodes::prelude::*;
@@ -32,7 +31,7 @@ impl NodeRuntime for WavetableNode {
             self.oscillator.set_frequency(frequency);
         }
 
-        if let Some(wavetable) = resources.get(0).and_then(|x| x.try_ref().ok()) {
+        if let Some(wavetable) = resources.get(0).and_then(|x| x.as_sample()).map(|x| x.first_channel()) {
             for frame in outs.stream(0)[0].iter_mut() {
                 *frame = self.oscillator.get_next_sample(wavetable);
             }
@@ -1,5 +1,4 @@
 use common::resource_manager::ResourceId;
-use common::traits::TryRef;
 use sound_engine::{util::interpolate::lerp, SoundConfig};
 
 use crate::nodes::prelude::*;
@@ -34,7 +33,7 @@ impl NodeRuntime for WavetableSequencerNode {
             self.frequency = frequency;
         }
 
-        if let Ok(sample) = resources[0].try_ref() {
+        if let Some(sample) = resources[0].as_sample().map(|x| x.first_channel()) {
             let wavetable = &sample.audio_raw;
 
             let wavetable_pos = self.phase * wavetable.len() as f32;
@@ -2,7 +2,7 @@ use common::resource_manager::ResourceId;
 use serde::{Deserialize, Serialize};
 use strum::EnumDiscriminants;
 
-use crate::connection::Socket;
+use crate::connection::{Primitive, Socket};
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
 #[serde(tag = "variant", content = "data")]
@@ -15,6 +15,20 @@ pub enum PropertyType {
     Resource(String),
 }
 
+impl PropertyType {
+    /// Whether this kind of property can be driven by a value input
+    pub fn accepts_values(&self) -> bool {
+        matches!(
+            self,
+            PropertyType::Integer
+                | PropertyType::Float
+                | PropertyType::Bool
+                | PropertyType::MultipleChoice(_)
+                | PropertyType::Resource(_)
+        )
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, EnumDiscriminants)]
 #[serde(tag = "variant", content = "data")]
 pub enum Property {
@@ -28,6 +42,31 @@ pub enum Property {
 }
 
 impl Property {
+    /// Converts a value coming in through an input into a property of type `property_type`.
+    /// Multiple choice properties take the index of the choice.
+    pub fn from_primitive(property_type: &PropertyType, value: Primitive) -> Option<Property> {
+        match (property_type, value) {
+            (PropertyType::Integer, Primitive::Int(value)) => Some(Property::Integer(value)),
+            (PropertyType::Integer, Primitive::Float(value)) => Some(Property::Integer(value.round() as i32)),
+            (PropertyType::Float, Primitive::Float(value)) => Some(Property::Float(value)),
+            (PropertyType::Float, Primitive::Int(value)) => Some(Property::Float(value as f32)),
+            (PropertyType::Bool, Primitive::Boolean(value)) => Some(Property::Bool(value)),
+            (PropertyType::Bool, Primitive::Float(value)) => Some(Property::Bool(value >= 0.5)),
+            (PropertyType::MultipleChoice(choices), value) => choice_index(value)
+                .and_then(|index| choices.get(index))
+                .map(|choice| Property::MultipleChoice(choice.clone())),
+            _ => None,
+        }
+    }
+
+    /// Resource properties take the index of the resource in `resources`, the same way multiple
+    /// choice properties do
+    pub fn resource_from_primitive(resources: &[ResourceId], value: Primitive) -> Option<Property> {
+        choice_index(value)
+            .and_then(|index| resources.get(index))
+            .map(|resource| Property::Resource(resource.clone()))
+    }
+
     pub fn as_string(self) -> Option<String> {
         match self {
             Property::String(value) => Some(value),
@@ -70,3 +109,11 @@ impl Property {
         }
     }
 }
+
+fn choice_index(value: Primitive) -> Option<usize> {
+    match value {
+        Primitive::Int(index) => usize::try_from(index).ok(),
+        Primitive::Float(index) => usize::try_from(index.round() as i32).ok(),
+        _ => None,
+    }
+}
@@ -2,12 +2,12 @@ use common::resource_manager::{serialize_resource_content, ResourceId, ResourceI
 use common::traits::TryRef;
 use serde::Serialize;
 use sound_engine::sampling::rank::{Pipe, RankType};
-use sound_engine::{sampling::rank::Rank, MonoSample};
+use sound_engine::{sampling::rank::Rank, MultiSample};
 
 #[derive(Default, Serialize, Debug)]
 #[serde(rename_all = "camelCase")]
 pub struct Resources {
-    pub samples: ResourceManager<MonoSample>,
+    pub samples: ResourceManager<MultiSample>,
     pub ranks: ResourceManager<RankType>,
     #[serde(serialize_with = "serialize_resource_content")]
     pub ui: ResourceManager<String>,
@@ -15,14 +15,14 @@ pub struct Resources {
 
 #[derive(Debug)]
 pub enum Resource<'a> {
-    Sample(&'a MonoSample),
+    Sample(&'a MultiSample),
     Rank(&'a RankType),
     Ui(&'a String),
     NotFound,
 }
 
 impl<'a> Resource<'a> {
-    pub fn as_sample(&self) -> Option<&'a MonoSample> {
+    pub fn as_sample(&self) -> Option<&'a MultiSample> {
         match self {
             Resource::Sample(sample) => Some(sample),
             _ => None,
@@ -44,10 +44,10 @@ impl<'a> Resource<'a> {
     }
 }
 
-impl<'a> TryRef<MonoSample> for Resource<'a> {
+impl<'a> TryRef<MultiSample> for Resource<'a> {
     type Error = ();
 
-    fn try_ref(&self) -> Result<&MonoSample, Self::Error> {
+    fn try_ref(&self) -> Result<&MultiSample, Self::Error> {
         match self {
             Self::Sample(x) => Ok(&x),
             _ => Err(()),
@@ -84,6 +84,24 @@ impl Resources {
         }
     }
 
+    /// Every resource in `namespace`, sorted by name
+    pub fn resource_ids(&self, namespace: &str) -> Vec<ResourceId> {
+        let mut keys = match namespace {
+            "ranks" => self.ranks.as_keys(),
+            "samples" => self.samples.as_keys(),
+            "ui" => self.ui.as_keys(),
+            _ => vec![],
+        };
+        keys.sort();
+
+        keys.into_iter()
+            .map(|resource| ResourceId {
+                namespace: namespace.to_string(),
+                resource,
+            })
+            .collect()
+    }
+
     pub fn get_resource(&self, type_and_index: &ResourceTypeAndIndex) -> Option<Resource> {
         let ResourceTypeAndIndex(resource_type, resource_index) = &type_and_index;
 
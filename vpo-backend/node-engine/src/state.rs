@@ -12,13 +12,17 @@ use sound_engine::SoundConfig;
 
 use crate::{
     connection::{Socket, SocketValue},
-    errors::{ErrorsAndWarnings, NodeError, WarningExt},
-    graph_manager::{GlobalNodeIndex, GraphIndex, GraphManager, GraphManagerDiff},
+    errors::{ErrorsAndWarnings, NodeError, NodeOk, NodeResult, WarningExt},
+    graph_manager::{GlobalNodeIndex, GraphIndex, GraphManager, GraphManagerDiff, ModuleDefinition},
     io_routing::IoRoutes,
-    node::buffered_traverser::BufferedTraverser,
-    node::{NodeGetIoContext, NodeIndex, NodeRow, NodeState},
+    lint::{lint_graphs, LintFinding},
+    node::buffered_traverser::{BufferedTraverser, NodeReplacement},
+    node::{
+        with_property_inputs, NodeGetIoContext, NodeIndex, NodeInitParams, NodeRow, NodeRuntime, NodeState,
+        PropertyChange,
+    },
     node_graph::{NodeConnectionData, NodeGraph},
-    nodes::variant_io,
+    nodes::{new_variant, variant_io},
     property::Property,
     resources::Resources,
 };
@@ -71,6 +75,17 @@ pub enum Action {
         #[serde(rename = "newRules")]
         new_rules: IoRoutes,
     },
+    CreateModule {
+        index: GlobalNodeIndex,
+        name: String,
+    },
+    LinkModule {
+        index: GlobalNodeIndex,
+        name: String,
+    },
+    UnlinkModule {
+        index: GlobalNodeIndex,
+    },
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -88,14 +103,23 @@ impl ActionBundle {
 #[derive(Debug, PartialEq)]
 pub enum ActionInvalidation {
     GraphReindexNeeded(GraphIndex),
+    /// the node's properties changed, but its rows didn't, so it only needs to be initialized again
+    NodeReinitNeeded(GlobalNodeIndex),
     GraphModified(GraphIndex),
     NewDefaults(GlobalNodeIndex, Vec<(Socket, SocketValue)>),
-    NewRouteRules { last_rules: IoRoutes, new_rules: IoRoutes },
+    NewRouteRules {
+        last_rules: IoRoutes,
+        new_rules: IoRoutes,
+    },
     NewNode(GlobalNodeIndex),
     None,
 }
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+/// How many undo steps are kept around
+pub const HISTORY_LIMIT: usize = 500;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "variant", content = "data")]
 pub enum ActionCategory {
     Separate,
     Mergable,
@@ -106,9 +130,11 @@ pub enum FromNodeEngine {
     NodeStateUpdates(Vec<(NodeIndex, NodeState)>),
     RequestedStateUpdates(Vec<(NodeIndex, serde_json::Value)>),
     GraphStateRequested,
+    PropertyChanges(Vec<PropertyChange>),
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "variant", content = "data")]
 pub enum HistoryAction {
     GraphAction {
         diff: GraphManagerDiff,
@@ -129,9 +155,33 @@ impl HistoryAction {
     }
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct HistoryActionBundle {
     pub actions: Vec<HistoryAction>,
+    /// what the user did, for showing in the history
+    #[serde(default)]
+    pub descriptions: Vec<String>,
+    /// name given to the state after this step, to come back to later
+    #[serde(default)]
+    pub checkpoint: Option<String>,
+}
+
+impl HistoryActionBundle {
+    pub fn description(&self) -> String {
+        if self.descriptions.is_empty() {
+            "Unknown change".into()
+        } else {
+            self.descriptions.join(", ")
+        }
+    }
+}
+
+/// The undo history, in a form that can be stored with the project
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SavedHistory {
+    pub history: Vec<HistoryActionBundle>,
+    pub place_in_history: usize,
 }
 
 #[derive(Debug)]
@@ -178,6 +228,37 @@ impl GraphState {
         )
     }
 
+    /// Initializes a new runtime for a node in the root graph, to swap in for the running one
+    pub fn create_node_replacement(&self, node_index: NodeIndex, resources: &Resources) -> NodeResult<NodeReplacement> {
+        let node = self.graph_manager.get_node(GlobalNodeIndex {
+            graph_index: self.root_graph_index,
+            node_index,
+        })?;
+
+        let mut variant = new_variant(&node.get_node_type(), &self.sound_config)?;
+        let init_result = variant.init(NodeInitParams {
+            props: node.get_properties(),
+            script_engine: &rhai::Engine::new(),
+            resources,
+            graph_manager: &self.graph_manager,
+            current_time: Duration::ZERO,
+            sound_config: self.sound_config.clone(),
+            node_state: node.get_state(),
+            child_graph: *node.get_child_graph(),
+            // same as what the traverser uses
+            default_channel_count: 1,
+        })?;
+
+        Ok(NodeOk::new(
+            NodeReplacement {
+                index: node_index,
+                node: variant,
+                needed_resources: init_result.value.needed_resources,
+            },
+            init_result.warnings,
+        ))
+    }
+
     pub fn get_node_state(&self) -> BTreeMap<NodeIndex, NodeState> {
         let root = self
             .graph_manager
@@ -222,6 +303,11 @@ impl GraphState {
         self.io_routing.clone()
     }
 
+    /// Report anything that looks wrong across all graphs
+    pub fn lint(&self, resources: &Resources) -> Vec<LintFinding> {
+        lint_graphs(&self.graph_manager, self.root_graph_index, &self.io_routing, resources)
+    }
+
     pub fn set_route_rules(&mut self, routes: IoRoutes) {
         self.io_routing = routes;
     }
@@ -232,15 +318,52 @@ impl GraphState {
         &self.history
     }
 
+    /// Applies `actions` and adds them to the history. Mergable actions go in the last step, unless
+    /// it's a checkpoint. With `force_append` they always do, so if the last step is a checkpoint,
+    /// its name restores the state including them from then on.
     pub fn commit(&mut self, actions: ActionBundle, force_append: bool) -> Result<Vec<ActionInvalidation>, NodeError> {
+        let mut descriptions: Vec<String> = vec![];
+
         let (mut new_actions, action_results) = actions
             .actions
             .into_iter()
-            .map(|action| self.apply_action(action))
+            .map(|action| {
+                let description = self.describe_action(&action);
+                if !descriptions.contains(&description) {
+                    descriptions.push(description);
+                }
+
+                self.apply_action(action)
+            })
             .collect::<Result<Vec<(HistoryAction, Vec<ActionInvalidation>)>, NodeError>>()?
             .into_iter()
             .unzip::<HistoryAction, Vec<ActionInvalidation>, Vec<HistoryAction>, Vec<Vec<ActionInvalidation>>>();
 
+        let mut invalidations: Vec<ActionInvalidation> = action_results.into_iter().flatten().collect();
+
+        // if a module was edited, all of its instances need to know
+        let edited_graphs: Vec<GraphIndex> = invalidations
+            .iter()
+            .filter_map(|invalidation| match invalidation {
+                ActionInvalidation::GraphReindexNeeded(graph_index) => Some(*graph_index),
+                _ => None,
+            })
+            .unique()
+            .collect();
+
+        for graph_index in edited_graphs {
+            let (diff, instance_invalidations) = self.graph_manager.update_module_instances(graph_index)?;
+
+            if !diff.0.is_empty() {
+                new_actions.push(HistoryAction::GraphAction {
+                    diff,
+                    category: ActionCategory::Mergable,
+                });
+            }
+
+            invalidations.extend(instance_invalidations);
+        }
+
         if self.place_in_history < self.history.len() {
             self.history.truncate(self.place_in_history);
         }
@@ -253,23 +376,217 @@ impl GraphState {
                 .actions
                 .iter()
                 .all(|x| x.category() == ActionCategory::Mergable);
+            // a checkpoint marks the state as it was, so mergable actions don't go in it. Forced
+            // ones still do (moving the checkpoint along), as the caller knows they belong with
+            // the last step.
+            let is_checkpoint = self.history[self.place_in_history - 1].checkpoint.is_some();
 
-            let should_append = force_append || (is_current_bundle_mergable && is_new_bundle_mergable);
+            let should_append =
+                force_append || (!is_checkpoint && is_current_bundle_mergable && is_new_bundle_mergable);
 
             if should_append {
-                self.history[self.place_in_history - 1].actions.append(&mut new_actions);
+                let current = &mut self.history[self.place_in_history - 1];
+
+                current.actions.append(&mut new_actions);
+                for description in descriptions {
+                    if !current.descriptions.contains(&description) {
+                        current.descriptions.push(description);
+                    }
+                }
             } else {
-                self.history.push(HistoryActionBundle { actions: new_actions });
+                self.history.push(HistoryActionBundle {
+                    actions: new_actions,
+                    descriptions,
+                    checkpoint: None,
+                });
 
                 self.place_in_history += 1;
             }
         } else {
-            self.history.push(HistoryActionBundle { actions: new_actions });
+            self.history.push(HistoryActionBundle {
+                actions: new_actions,
+                descriptions,
+                checkpoint: None,
+            });
 
             self.place_in_history += 1;
         }
 
-        let invalidations = action_results.into_iter().flatten().collect();
+        // forget the oldest steps
+        if self.history.len() > HISTORY_LIMIT {
+            let excess = self.history.len() - HISTORY_LIMIT;
+
+            self.history.drain(..excess);
+            self.place_in_history -= excess;
+        }
+
+        Ok(invalidations)
+    }
+
+    pub fn get_place_in_history(&self) -> usize {
+        self.place_in_history
+    }
+
+    /// The last `limit` steps of history, centered around undoing
+    pub fn export_history(&self, limit: usize) -> SavedHistory {
+        let start = self.place_in_history.saturating_sub(limit);
+        let end = self.history.len().min(start + limit);
+
+        SavedHistory {
+            history: self.history[start..end].to_vec(),
+            place_in_history: self.place_in_history - start,
+        }
+    }
+
+    /// Restores history saved with `export_history`. It has to have been saved with the graph
+    /// as it is now, as the steps are applied on top of it.
+    pub fn import_history(&mut self, saved: SavedHistory) {
+        self.place_in_history = saved.place_in_history.min(saved.history.len());
+        self.history = saved.history;
+    }
+
+    /// Names the state after the step `place`, or removes the name if `name` is `None`
+    pub fn set_checkpoint(&mut self, place: usize, name: Option<String>) -> Result<(), NodeError> {
+        if place == 0 || place > self.history.len() {
+            return Err(NodeError::HistoryPlaceOutOfBounds { place });
+        }
+
+        self.history[place - 1].checkpoint = name;
+
+        Ok(())
+    }
+
+    /// Undoes or redoes until the history is at `place`
+    pub fn jump_to(&mut self, place: usize) -> Result<Vec<ActionInvalidation>, NodeError> {
+        if place > self.history.len() {
+            return Err(NodeError::HistoryPlaceOutOfBounds { place });
+        }
+
+        let mut invalidations = vec![];
+
+        while self.place_in_history > place {
+            invalidations.extend(self.undo()?);
+        }
+
+        while self.place_in_history < place {
+            invalidations.extend(self.redo()?);
+        }
+
+        Ok(invalidations)
+    }
+
+    fn describe_action(&self, action: &Action) -> String {
+        let node_type = |index: &GlobalNodeIndex| {
+            self.graph_manager
+                .get_node(*index)
+                .map(|node| node.get_node_type())
+                .unwrap_or_else(|_| "node".into())
+        };
+
+        match action {
+            Action::CreateNode { node_type, .. } => format!("Add {node_type}"),
+            Action::ConnectNodes { graph, from, to, .. } => format!(
+                "Connect {} to {}",
+                node_type(&GlobalNodeIndex {
+                    graph_index: *graph,
+                    node_index: *from
+                }),
+                node_type(&GlobalNodeIndex {
+                    graph_index: *graph,
+                    node_index: *to
+                })
+            ),
+            Action::DisconnectNodes { graph, from, to, .. } => format!(
+                "Disconnect {} from {}",
+                node_type(&GlobalNodeIndex {
+                    graph_index: *graph,
+                    node_index: *from
+                }),
+                node_type(&GlobalNodeIndex {
+                    graph_index: *graph,
+                    node_index: *to
+                })
+            ),
+            Action::RemoveNode { index } => format!("Remove {}", node_type(index)),
+            Action::ChangeNodeProperties { index, props } => {
+                let current = self.graph_manager.get_node(*index).map(|node| node.get_properties());
+
+                let changed: Vec<&str> = props
+                    .iter()
+                    .filter(|(name, value)| {
+                        current
+                            .as_ref()
+                            .map(|current| current.get(*name) != Some(*value))
+                            .unwrap_or(true)
+                    })
+                    .map(|(name, _)| name.as_str())
+                    .sorted()
+                    .collect();
+
+                if changed.is_empty() {
+                    format!("Change {}", node_type(index))
+                } else {
+                    format!("Change {} of {}", changed.join(", "), node_type(index))
+                }
+            }
+            Action::ChangeNodeUiData { index, .. } => format!("Edit {}", node_type(index)),
+            Action::ChangeNodeOverrides { index, .. } => format!("Change defaults of {}", node_type(index)),
+            Action::ChangeRouteRules { .. } => "Change routing".into(),
+            Action::CreateModule { name, .. } => format!("Create module {name}"),
+            Action::LinkModule { index, name } => format!("Use module {name} in {}", node_type(index)),
+            Action::UnlinkModule { index } => format!("Make {} local", node_type(index)),
+        }
+    }
+
+    /// Sets properties changed through inputs. As the graph is what's changing them, they don't
+    /// go in the history. Nodes that applied all of their changes in place are left running.
+    pub fn apply_property_changes(
+        &mut self,
+        changes: Vec<PropertyChange>,
+    ) -> Result<Vec<ActionInvalidation>, NodeError> {
+        let mut changed_nodes: Vec<(GlobalNodeIndex, SeaHashMap<String, Property>, bool)> = vec![];
+
+        for PropertyChange {
+            index,
+            name,
+            value,
+            applied,
+        } in changes
+        {
+            if let Some((_, props, all_applied)) = changed_nodes.iter_mut().find(|(changed, _, _)| *changed == index) {
+                props.insert(name, value);
+                *all_applied &= applied;
+            } else if let Ok(node) = self.graph_manager.get_node(index) {
+                // (the node may have been removed since the change was sent)
+                let mut props = node.get_properties().clone();
+                props.insert(name, value);
+
+                changed_nodes.push((index, props, applied));
+            }
+        }
+
+        let mut invalidations = vec![];
+
+        for (index, props, all_applied) in changed_nodes {
+            let mut modified_node = self.graph_manager.get_node(index)?.clone();
+            let old_rows = modified_node.get_node_rows().clone();
+
+            modified_node.set_properties(props);
+            self.graph_manager.update_node(index, modified_node)?;
+
+            if *self.graph_manager.get_node(index)?.get_node_rows() == old_rows {
+                invalidations.push(if all_applied {
+                    ActionInvalidation::GraphModified(index.graph_index)
+                } else {
+                    ActionInvalidation::NodeReinitNeeded(index)
+                });
+            } else {
+                invalidations.push(ActionInvalidation::GraphReindexNeeded(index.graph_index));
+
+                let (_, instance_invalidations) = self.graph_manager.update_module_instances(index.graph_index)?;
+                invalidations.extend(instance_invalidations);
+            }
+        }
 
         Ok(invalidations)
     }
@@ -456,21 +773,28 @@ impl GraphState {
                 let graph = self.graph_manager.get_graph_mut(index.graph_index)?;
                 let mut modified_node = graph.get_node(index.node_index)?.clone();
 
-                modified_node.set_default_overrides(overrides.clone());
+                let old_overrides = modified_node.set_default_overrides(overrides.clone());
 
                 let diffs = self.graph_manager.update_node(index, modified_node)?;
 
                 let new_defaults: Vec<_> = overrides
                     .iter()
-                    .map(|row| {
-                        let (socket, val) = row.to_socket_and_value().unwrap();
+                    .filter_map(|row| {
+                        let (socket, val) = row.to_socket_and_value()?;
 
-                        (socket.clone(), val)
+                        Some((socket.clone(), val))
                     })
                     .collect();
 
+                // exposing a property adds or removes a socket
+                let rows_changed = exposed_properties(&old_overrides) != exposed_properties(overrides);
+
                 let mut invalidations = vec![ActionInvalidation::NewDefaults(index, new_defaults)];
 
+                if rows_changed {
+                    invalidations.push(ActionInvalidation::GraphReindexNeeded(index.graph_index));
+                }
+
                 // TODO: shouldn't need this in the future
                 if index.graph_index != self.root_graph_index {
                     invalidations.push(ActionInvalidation::GraphReindexNeeded(self.root_graph_index));
@@ -511,6 +835,39 @@ impl GraphState {
                     }],
                 )
             }
+            Action::CreateModule { index, name } => {
+                let (diff, invalidation) = self.graph_manager.create_module(index, &name)?;
+
+                (
+                    HistoryAction::GraphAction {
+                        diff,
+                        category: ActionCategory::Separate,
+                    },
+                    vec![invalidation],
+                )
+            }
+            Action::LinkModule { index, name } => {
+                let (diff, invalidation) = self.graph_manager.link_module(index, &name)?;
+
+                (
+                    HistoryAction::GraphAction {
+                        diff,
+                        category: ActionCategory::Separate,
+                    },
+                    vec![invalidation],
+                )
+            }
+            Action::UnlinkModule { index } => {
+                let (diff, invalidation) = self.graph_manager.unlink_module(index)?;
+
+                (
+                    HistoryAction::GraphAction {
+                        diff,
+                        category: ActionCategory::Separate,
+                    },
+                    vec![invalidation],
+                )
+            }
         };
 
         Ok(new_action)
@@ -563,6 +920,78 @@ impl GraphState {
         })
     }
 
+    /// Like `to_json`, but with the graphs taken out to be stored on their own
+    pub fn to_json_split(&self) -> (Value, Vec<(GraphIndex, NodeGraph)>) {
+        let mut graph_manager = self.graph_manager.clone();
+        let graphs = graph_manager.take_graphs();
+
+        let json = json!({
+            "graphManager": graph_manager,
+            "rootGraphIndex": self.root_graph_index,
+            "defaultChannelCount": self.default_channel_count,
+            "ioRouting": self.io_routing
+        });
+
+        (json, graphs)
+    }
+
+    pub fn export_modules(&self) -> Result<Vec<(String, ModuleDefinition)>, NodeError> {
+        self.graph_manager
+            .modules()
+            .keys()
+            .map(|name| Ok((name.clone(), self.graph_manager.export_module(name)?)))
+            .collect()
+    }
+
+    /// Brings in modules stored outside of the project, replacing any that changed. As modules
+    /// can use each other, each is imported once the ones it uses exist. Returns whether any
+    /// module changed.
+    pub fn import_modules(&mut self, mut modules: Vec<(String, ModuleDefinition)>) -> Result<bool, NodeError> {
+        let mut any_changed = false;
+
+        while !modules.is_empty() {
+            let ready = modules.iter().position(|(_, definition)| {
+                definition
+                    .linked
+                    .iter()
+                    .all(|(_, name)| self.graph_manager.get_module(name).is_some())
+            });
+
+            let (name, definition) = match ready {
+                Some(ready) => modules.remove(ready),
+                None => {
+                    let missing = modules
+                        .iter()
+                        .flat_map(|(_, definition)| definition.linked.iter())
+                        .find(|(_, name)| self.graph_manager.get_module(name).is_none())
+                        .map(|(_, name)| name.clone())
+                        .unwrap_or_default();
+
+                    return Err(NodeError::ModuleDoesNotExist { name: missing });
+                }
+            };
+
+            // rows aren't read back in, so they can't be compared
+            let unchanged = self
+                .graph_manager
+                .export_module(&name)
+                .map(|current| comparable_module(&current) == comparable_module(&definition))
+                .unwrap_or(false);
+
+            if !unchanged {
+                self.graph_manager.import_module(&name, definition)?;
+                any_changed = true;
+            }
+        }
+
+        // there's no going back to before the modules were loaded
+        if any_changed {
+            self.clear_history();
+        }
+
+        Ok(any_changed)
+    }
+
     pub fn load_state(&mut self, graph_manager: GraphManager, root_graph_index: GraphIndex, routing: IoRoutes) {
         self.history.clear();
         self.place_in_history = 0;
@@ -592,9 +1021,42 @@ impl GraphState {
                 });
 
                 if let Ok(new_io) = new_io {
-                    self.graph_manager[node].set_node_rows(new_io.node_rows);
+                    let new_rows =
+                        with_property_inputs(new_io.node_rows, self.graph_manager[node].get_default_overrides());
+                    self.graph_manager[node].set_node_rows(new_rows);
                 }
             }
         }
     }
 }
+
+fn comparable_module(definition: &ModuleDefinition) -> Option<serde_json::Value> {
+    let mut value = serde_json::to_value(definition).ok()?;
+    strip_node_rows(&mut value);
+
+    Some(value)
+}
+
+fn strip_node_rows(value: &mut serde_json::Value) {
+    match value {
+        serde_json::Value::Object(map) => {
+            if map.contains_key("nodeType") {
+                map.remove("nodeRows");
+            }
+
+            map.values_mut().for_each(strip_node_rows);
+        }
+        serde_json::Value::Array(values) => values.iter_mut().for_each(strip_node_rows),
+        _ => {}
+    }
+}
+
+fn exposed_properties(overrides: &[NodeRow]) -> Vec<&String> {
+    overrides
+        .iter()
+        .filter_map(|row| match row {
+            NodeRow::Property(name, ..) => Some(name),
+            _ => None,
+        })
+        .collect()
+}
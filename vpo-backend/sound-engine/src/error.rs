@@ -5,3 +5,19 @@ pub enum NodeError {
     #[snafu(display("Ramp out of range: (from {ramp_from} to {ramp_to})"))]
     RampOutOfRange { ramp_from: f32, ramp_to: f32 },
 }
+
+#[derive(Clone, Debug, PartialEq, Snafu)]
+pub enum AnalysisError {
+    #[snafu(display("Sample is too short to analyze ({len} samples, needs at least {min_len})"))]
+    SampleTooShort { len: usize, min_len: usize },
+    #[snafu(display("Sample is silent"))]
+    SampleSilent,
+    #[snafu(display("Could not detect the sample's pitch"))]
+    PitchNotFound,
+    #[snafu(display("Frequency out of range: {freq} Hz"))]
+    FrequencyOutOfRange { freq: f64 },
+    #[snafu(display("Could not find a sustained section"))]
+    SustainNotFound,
+    #[snafu(display("Could not find a loop point between {from} and {to}"))]
+    LoopNotFound { from: usize, to: usize },
+}
@@ -2,6 +2,7 @@
 
 use std::fmt::Debug;
 
+use sampling::stream::StreamedTail;
 use serde::Serialize;
 
 pub mod error;
@@ -54,6 +55,96 @@ impl Default for MonoSample {
     }
 }
 
+/// A sample with one or more channels (stereo recordings, multiple microphone
+/// positions, etc). Every channel has the same length and sample rate.
+pub struct MultiSample {
+    pub channels: Vec<MonoSample>,
+    /// if set, `channels` only holds the start of the sample, and the rest is streamed from disk
+    pub tail: Option<StreamedTail>,
+    /// if the sample was resampled when loading, the sample rate of its file. Positions in rank
+    /// files are in the file's frames, see [`MultiSample::from_file_position`].
+    pub source_sample_rate: Option<u32>,
+}
+
+impl MultiSample {
+    pub fn from_mono(sample: MonoSample) -> MultiSample {
+        MultiSample {
+            channels: vec![sample],
+            tail: None,
+            source_sample_rate: None,
+        }
+    }
+
+    pub fn channel_count(&self) -> usize {
+        self.channels.len()
+    }
+
+    /// The first channel is what analysis (and anything that only supports mono) uses
+    pub fn first_channel(&self) -> &MonoSample {
+        &self.channels[0]
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.first_channel().sample_rate
+    }
+
+    /// Length in frames, including any streamed part
+    pub fn len(&self) -> usize {
+        match &self.tail {
+            Some(tail) => tail.len,
+            None => self.head_len(),
+        }
+    }
+
+    /// Length in frames of the part that's in memory
+    pub fn head_len(&self) -> usize {
+        self.first_channel().audio_raw.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Converts a position in the sample's file to one in `channels`
+    pub fn from_file_position(&self, position: usize) -> usize {
+        match self.source_sample_rate {
+            Some(source_rate) => (position as f64 * self.sample_rate() as f64 / source_rate as f64).round() as usize,
+            None => position,
+        }
+    }
+
+    /// Converts a position in `channels` to one in the sample's file
+    pub fn to_file_position(&self, position: usize) -> usize {
+        match self.source_sample_rate {
+            Some(source_rate) => (position as f64 * source_rate as f64 / self.sample_rate() as f64).round() as usize,
+            None => position,
+        }
+    }
+}
+
+impl AsRef<MultiSample> for MultiSample {
+    fn as_ref(&self) -> &MultiSample {
+        self
+    }
+}
+
+impl Debug for MultiSample {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "[{} channel audio sample, {:.2}s]",
+            self.channel_count(),
+            self.len() as f32 / self.sample_rate() as f32
+        )
+    }
+}
+
+impl Default for MultiSample {
+    fn default() -> Self {
+        MultiSample::from_mono(MonoSample::default())
+    }
+}
+
 #[cfg(test)]
 mod tests {
     #[test]
@@ -413,12 +413,20 @@ impl SimpleComb {
 
     #[inline]
     pub fn filter(&self, x: f32, sample: &[f32], position: f32) -> f32 {
+        self.filter_with(x, position, |tap_pos| {
+            lerp(sample[tap_pos as usize], sample[tap_pos as usize + 1], tap_pos.fract())
+        })
+    }
+
+    /// Like `filter`, but with the delayed value looked up by `tap` (for samples that aren't
+    /// entirely in memory)
+    #[inline]
+    pub fn filter_with(&self, x: f32, position: f32, mut tap: impl FnMut(f32) -> f32) -> f32 {
         if position < self.M {
-            0.0
+            // nothing to comb with yet
+            x
         } else {
-            let tap_pos = position - self.M;
-
-            x + lerp(sample[tap_pos as usize], sample[tap_pos as usize + 1], tap_pos.fract()) * self.α
+            x + tap(position - self.M) * self.α
         }
     }
 
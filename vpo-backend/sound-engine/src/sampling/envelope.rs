@@ -1,9 +1,8 @@
-use std::{fs::File, io::Write};
-
 use nalgebra::DVector;
 use pitch_detection::detector::{mcleod::McLeodDetector, PitchDetector};
+use serde::{Deserialize, Serialize};
 
-use crate::sampling::util::sq;
+use crate::{error::AnalysisError, sampling::util::sq};
 
 use super::{
     savitzky_golay::savgol_filter,
@@ -17,53 +16,122 @@ pub struct SampleMetadata {
     pub release_index: usize,
     pub loop_start: usize,
     pub loop_end: usize,
+    /// runner up loops, best first
+    pub extra_loops: Vec<(usize, usize)>,
     pub freq: f64,
     pub closest_note: u8,
+    pub confidence: AnalysisConfidence,
 }
 
-pub fn calc_amp(signal: &[f64], window_width: usize) -> Vec<f64> {
-    (0..(signal.len() - window_width))
-        .map(|i| rms(&signal[i..i + window_width]))
-        .collect()
+/// How much each part of the analysis can be trusted, from 0 (not at all) to 1
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AnalysisConfidence {
+    /// how well the sustained section repeats at the detected period
+    pub pitch: f64,
+    /// how closely the audio on either side of the loop point matches
+    pub loop_point: f64,
+    /// how far the envelope drops after the release point
+    pub release: f64,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct AnalysisSettings {
+    pub pitch: PitchSettings,
+    pub envelope: EnvelopeSettings,
+    pub loops: LoopSettings,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct PitchSettings {
+    pub power_threshold: f64,
+    pub clarity_threshold: f64,
+}
+
+impl Default for PitchSettings {
+    fn default() -> Self {
+        PitchSettings {
+            power_threshold: 5.0,
+            clarity_threshold: 0.5,
+        }
+    }
 }
 
-struct EnvelopeSettings {
-    pub peak_attack: usize,
-    pub peak_release: usize,
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct EnvelopeSettings {
+    /// half width (in samples) of the filter smoothing the envelope
+    pub smoothing: u32,
     pub sustain_sensitivity: f64,
     pub release_sensitivity: f64,
+    /// fraction of the sample the sustain has to start within
     pub too_far_in_attack: f64,
+    /// fraction of the sample after which to look for the release
     pub too_far_in_release: f64,
     pub sustain_shift: i32,
     pub release_shift: i32,
 }
 
-struct LoopSettings {
+impl Default for EnvelopeSettings {
+    fn default() -> Self {
+        EnvelopeSettings {
+            smoothing: 400,
+            sustain_sensitivity: 10.0,
+            release_sensitivity: 10.0,
+            too_far_in_attack: 0.2,
+            too_far_in_release: 0.5,
+            sustain_shift: 2000,
+            release_shift: 0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct LoopSettings {
     pub derivative_threshold: f64,
+    /// in seconds
     pub min_loop_length: f64,
+    /// in seconds
     pub distance_between_loops: f64,
     pub quality_factor: f64,
+    /// how many loops to return, including the best one
+    pub max_loops: usize,
 }
 
-fn search_for_sustain(env_db: &[f64], settings: &EnvelopeSettings) -> usize {
-    let EnvelopeSettings {
-        peak_attack,
-        peak_release,
-        sustain_sensitivity,
-        release_sensitivity: _,
-        too_far_in_attack,
-        too_far_in_release: _,
-        sustain_shift: decay_shift,
-        release_shift: _,
-    } = settings;
+impl Default for LoopSettings {
+    fn default() -> Self {
+        LoopSettings {
+            derivative_threshold: 0.06,
+            min_loop_length: 1.5,
+            distance_between_loops: 0.2,
+            quality_factor: 10.0,
+            max_loops: 4,
+        }
+    }
+}
 
-    let search_start = *peak_attack;
-    let search_end = (env_db.len() as f64 * too_far_in_attack) as usize;
+/// how many samples on each side of a loop point are compared
+const LOOP_COMPARE_WIDTH: usize = 10;
+/// how far after the release point to look for a zero crossing
+const RELEASE_SEARCH_WIDTH: usize = 1000;
 
-    let env_db_std = std(&env_db[*peak_attack..*peak_release]);
-    let env_db_median = median(&env_db[*peak_attack..*peak_release]);
+pub fn calc_amp(signal: &[f64], window_width: usize) -> Vec<f64> {
+    (0..(signal.len().saturating_sub(window_width)))
+        .map(|i| rms(&signal[i..i + window_width]))
+        .collect()
+}
+
+fn search_for_sustain(env_db: &[f64], peak_attack: usize, peak_release: usize, settings: &EnvelopeSettings) -> usize {
+    let search_start = peak_attack;
+    let search_end = ((env_db.len() as f64 * settings.too_far_in_attack) as usize).min(env_db.len());
 
-    let threshold = env_db_std * sustain_sensitivity;
+    let env_db_std = std(&env_db[peak_attack..peak_release]);
+    let env_db_median = median(&env_db[peak_attack..peak_release]);
+
+    let threshold = env_db_std * settings.sustain_sensitivity;
 
     let mut outside_of_threshold_last_time = false;
     let mut sustain_index: usize = 0;
@@ -79,32 +147,28 @@ fn search_for_sustain(env_db: &[f64], settings: &EnvelopeSettings) -> usize {
         }
     }
 
-    (sustain_index as i32 + decay_shift) as usize
+    (sustain_index as i64 + settings.sustain_shift as i64).max(0) as usize
 }
 
-fn search_for_release(sample: &[f64], env_db: &[f64], settings: &EnvelopeSettings) -> usize {
-    let EnvelopeSettings {
-        peak_attack,
-        peak_release,
-        sustain_sensitivity: _,
-        release_sensitivity,
-        too_far_in_attack: _,
-        too_far_in_release,
-        sustain_shift: _,
-        release_shift,
-    } = settings;
-
-    let search_start = (env_db.len() as f64 * too_far_in_release) as usize;
+fn search_for_release(
+    sample: &[f64],
+    env_db: &[f64],
+    peak_attack: usize,
+    peak_release: usize,
+    settings: &EnvelopeSettings,
+) -> usize {
+    let search_start = (env_db.len() as f64 * settings.too_far_in_release) as usize;
 
-    let env_db_std = std(&env_db[*peak_attack..*peak_release]);
-    let env_db_median = median(&env_db[*peak_attack..*peak_release]);
+    let env_db_std = std(&env_db[peak_attack..peak_release]);
+    let env_db_median = median(&env_db[peak_attack..peak_release]);
 
-    let threshold = env_db_std * release_sensitivity;
+    let threshold = env_db_std * settings.release_sensitivity;
 
     let mut outside_of_threshold_last_time = false;
-    let mut release_index: usize = 0;
+    // if the envelope never leaves the threshold, the steepest drop is the best guess
+    let mut release_index: usize = peak_release;
 
-    for i in search_start..*peak_release {
+    for i in search_start..peak_release {
         if (env_db[i] - env_db_median).abs() > threshold {
             if !outside_of_threshold_last_time {
                 release_index = i;
@@ -115,28 +179,31 @@ fn search_for_release(sample: &[f64], env_db: &[f64], settings: &EnvelopeSetting
         }
     }
 
-    release_index = (release_index as i32 + release_shift) as usize;
+    release_index = (release_index as i64 + settings.release_shift as i64).clamp(0, sample.len() as i64 - 1) as usize;
 
     // find part in sample close to 0
-    let search_area = &sample[release_index..(release_index + 1000)];
-    release_index += argmin(&abs(search_area)).unwrap();
+    let search_area = &sample[release_index..(release_index + RELEASE_SEARCH_WIDTH).min(sample.len())];
+    release_index += argmin(&abs(search_area)).unwrap_or(0);
 
     release_index
 }
 
-fn find_loop_point(
+/// Returns the best loops found (best first), along with how far apart the audio around the
+/// loop points is
+fn find_loop_points(
     loop_settings: &LoopSettings,
     sample: &[f64],
     freq: f64,
     sample_rate: u32,
     attack_index: usize,
     release_index: usize,
-) -> (usize, usize) {
+) -> Vec<((usize, usize), f64)> {
     let LoopSettings {
         derivative_threshold,
         min_loop_length,
         distance_between_loops,
-        quality_factor,
+        max_loops,
+        ..
     } = loop_settings;
 
     let sample_deriv = gradient(sample);
@@ -146,13 +213,18 @@ fn find_loop_point(
     let slice_width = ((sample_rate as f64 / freq) * 2.0).max(512.0) as usize;
     let min_loop_length = (min_loop_length * sample_rate as f64) as usize;
     let distance_between_loops = (distance_between_loops * sample_rate as f64) as usize;
-    let quality_factor = (quality_factor * quality_factor) / 32767.0;
+    let quality_threshold = loop_quality_threshold(loop_settings);
 
     let indicies_passed: Vec<usize> = sample_deriv
         .iter()
         .enumerate()
         .filter_map(|(i, &val)| {
-            if i > attack_index && i < release_index && val.abs() < derivative_threshold {
+            if i > attack_index
+                && i >= LOOP_COMPARE_WIDTH
+                && i < release_index
+                && i + slice_width < sample.len()
+                && val.abs() < derivative_threshold
+            {
                 Some(i)
             } else {
                 None
@@ -167,57 +239,118 @@ fn find_loop_point(
                 continue;
             }
 
-            if from_index + slice_width >= sample.len() || to_index + slice_width >= sample.len() {
-                continue;
-            }
-
             if !found_loops.is_empty() && from_index - found_loops.last().unwrap().0 .0 < distance_between_loops {
                 continue;
             }
 
-            let cross = (-10..10).fold(0.0, |acc, i| {
-                acc + sq(sample[(i + *from_index as i64) as usize] - sample[(i + *to_index as i64) as usize])
+            let cross = (0..(LOOP_COMPARE_WIDTH * 2)).fold(0.0, |acc, i| {
+                acc + sq(sample[from_index - LOOP_COMPARE_WIDTH + i] - sample[to_index - LOOP_COMPARE_WIDTH + i])
             });
-            let correlation_value = cross / 10.0;
+            let correlation_value = cross / LOOP_COMPARE_WIDTH as f64;
 
-            if correlation_value < quality_factor {
+            if correlation_value < quality_threshold {
                 found_loops.push(((*from_index, *to_index), correlation_value));
             }
         }
     }
 
     found_loops.sort_by(|x, y| x.1.total_cmp(&y.1));
+    found_loops.truncate((*max_loops).max(1));
 
-    found_loops[0].0
+    found_loops
+}
+
+fn loop_quality_threshold(loop_settings: &LoopSettings) -> f64 {
+    (loop_settings.quality_factor * loop_settings.quality_factor) / 32767.0
+}
+
+/// Normalized autocorrelation of `section` at a lag of `period`, 1 meaning it repeats perfectly
+fn periodicity(section: &[f64], period: usize) -> f64 {
+    if period == 0 || section.len() <= period {
+        return 0.0;
+    }
+
+    let (mut cross, mut energy_a, mut energy_b) = (0.0, 0.0, 0.0);
+
+    for (a, b) in section.iter().zip(&section[period..]) {
+        cross += a * b;
+        energy_a += a * a;
+        energy_b += b * b;
+    }
+
+    if energy_a == 0.0 || energy_b == 0.0 {
+        0.0
+    } else {
+        (cross / (energy_a * energy_b).sqrt()).clamp(0.0, 1.0)
+    }
 }
 
-pub fn calc_sample_metadata(sample_raw: &[f32], sample_rate: u32, freq: Option<f64>) -> SampleMetadata {
+pub fn calc_sample_metadata(
+    sample_raw: &[f32],
+    sample_rate: u32,
+    freq: Option<f64>,
+    settings: &AnalysisSettings,
+) -> Result<SampleMetadata, AnalysisError> {
     let sample: Vec<f64> = sample_raw.iter().map(|&x| x as f64).collect();
 
+    let smoothing_width = settings.envelope.smoothing as usize * 2 + 1;
+
+    // the envelope needs room for at least two smoothing windows
+    let min_len = smoothing_width * 2 + 2;
+    if sample.len() < min_len {
+        return Err(AnalysisError::SampleTooShort {
+            len: sample.len(),
+            min_len,
+        });
+    }
+
+    if sample.iter().all(|&x| x == sample[0]) {
+        return Err(AnalysisError::SampleSilent);
+    }
+
     let freq = if let Some(freq) = freq {
         freq
     } else {
         let size: usize = sample.len();
         let padding: usize = size / 2;
-        let power_threshold: f64 = 5.0;
-        let clarity_threshold: f64 = 0.5;
 
         let mut detector = McLeodDetector::new(size, padding);
 
         let pitch = detector
-            .get_pitch(&sample, sample_rate as usize, power_threshold, clarity_threshold)
-            .unwrap();
+            .get_pitch(
+                &sample,
+                sample_rate as usize,
+                settings.pitch.power_threshold,
+                settings.pitch.clarity_threshold,
+            )
+            .ok_or(AnalysisError::PitchNotFound)?;
 
         pitch.frequency
     };
 
+    if !freq.is_finite() || freq <= 0.0 || freq >= sample_rate as f64 / 2.0 {
+        return Err(AnalysisError::FrequencyOutOfRange { freq });
+    }
+
+    let period = ((sample_rate as f64 / freq) as usize).max(1);
+    let min_len = min_len + period;
+    if sample.len() < min_len {
+        return Err(AnalysisError::SampleTooShort {
+            len: sample.len(),
+            min_len,
+        });
+    }
+
     let (sample_norm, ..) = norm_signal(&DVector::from_row_slice(&sample));
 
-    let envelope = calc_amp(&abs(sample_norm.as_slice()), (sample_rate as f64 / freq) as usize);
+    let envelope = calc_amp(&abs(sample_norm.as_slice()), period);
 
     let envelope_db: Vec<f64> = savgol_filter(
-        &envelope.iter().map(|&x| x.log10() * 20.0).collect::<Vec<f64>>(),
-        400,
+        &envelope
+            .iter()
+            .map(|&x| x.max(f64::MIN_POSITIVE).log10() * 20.0)
+            .collect::<Vec<f64>>(),
+        settings.envelope.smoothing,
         20,
         2,
     );
@@ -225,23 +358,19 @@ pub fn calc_sample_metadata(sample_raw: &[f32], sample_rate: u32, freq: Option<f
     let envelope_deriv = resample_to(
         &savgol_filter(
             &resample_to(
-                &gradient(&envelope_db[0..(envelope_db.len() - 801)]),
-                envelope.len() / 10,
+                &gradient(&envelope_db[0..(envelope_db.len() - smoothing_width)]),
+                (envelope.len() / 10).max(1),
             ),
-            ((freq.log2() - 4.0) * 80.0) as u32,
+            ((freq.log2() - 4.0) * 80.0).max(1.0) as u32,
             20,
             2,
         ),
         envelope.len(),
     );
 
-    let mut f = File::create("/tmp/test.json").expect("Unable to create file");
-    f.write_all(serde_json::to_string(&envelope_db).unwrap().as_bytes())
-        .expect("Unable to write data");
-
-    let peak_attack = argmax(&envelope_deriv[0..(envelope_deriv.len() / 2)]).unwrap();
-    let possible_peak_release =
-        argmin(&envelope_deriv[(envelope_deriv.len() / 2)..envelope_deriv.len()]).unwrap() + envelope_deriv.len() / 2;
+    let half = envelope_deriv.len() / 2;
+    let peak_attack = argmax(&envelope_deriv[0..half]).ok_or(AnalysisError::SustainNotFound)?;
+    let possible_peak_release = argmin(&envelope_deriv[half..]).ok_or(AnalysisError::SustainNotFound)? + half;
     let mut peak_release = possible_peak_release;
 
     // are there any points after peak_release that reach at least `0.7*envelope_deriv[peak_release]`?
@@ -251,52 +380,170 @@ pub fn calc_sample_metadata(sample_raw: &[f32], sample_rate: u32, freq: Option<f
         }
     }
 
-    let search_settings = EnvelopeSettings {
+    if peak_release <= peak_attack + 1 {
+        return Err(AnalysisError::SustainNotFound);
+    }
+
+    let release_index = search_for_release(
+        sample_norm.as_slice(),
+        &envelope_db,
         peak_attack,
         peak_release,
-        sustain_sensitivity: 10.0,
-        release_sensitivity: 10.0,
-        too_far_in_attack: 0.2,
-        too_far_in_release: 0.5,
-        sustain_shift: 2000,
-        release_shift: 0,
-    };
+        &settings.envelope,
+    );
 
-    let sustain_index = search_for_sustain(&envelope_db, &search_settings);
-    let decay_index = argmax(&envelope_db[0..sustain_index]).unwrap();
+    let sustain_index = search_for_sustain(&envelope_db, peak_attack, peak_release, &settings.envelope);
+    if sustain_index == 0 || sustain_index >= release_index {
+        return Err(AnalysisError::SustainNotFound);
+    }
 
-    let release_index = search_for_release(sample_norm.as_slice(), &envelope_db, &search_settings);
+    let decay_index = argmax(&envelope_db[0..sustain_index.min(envelope_db.len())]).unwrap_or(0);
 
-    println!(
-        "Indexes: decay index: {}, sustain index: {}, release index: {}",
-        decay_index, sustain_index, release_index
-    );
+    let mut loops = find_loop_points(&settings.loops, &sample, freq, sample_rate, decay_index, release_index);
+    if loops.is_empty() {
+        return Err(AnalysisError::LoopNotFound {
+            from: decay_index,
+            to: release_index,
+        });
+    }
+
+    let ((loop_start, loop_end), loop_error) = loops.remove(0);
+    let extra_loops = loops.into_iter().map(|(points, _)| points).collect();
 
-    let loop_search_settings = LoopSettings {
-        derivative_threshold: 0.06,
-        min_loop_length: 1.5,
-        distance_between_loops: 0.2,
-        quality_factor: 10.0,
+    let sustain_env = &envelope_db[sustain_index.min(envelope_db.len())..release_index.min(envelope_db.len())];
+    let release_drop = if sustain_env.is_empty() || release_index >= envelope_db.len() {
+        0.0
+    } else {
+        let sustain_level = median(sustain_env);
+        let release_floor = envelope_db[release_index..]
+            .iter()
+            .fold(f64::INFINITY, |a, &b| a.min(b));
+
+        sustain_level - release_floor
     };
 
-    let loop_point = find_loop_point(
-        &loop_search_settings,
-        &sample,
-        freq,
-        sample_rate,
-        decay_index,
-        release_index,
-    );
+    let confidence = AnalysisConfidence {
+        pitch: periodicity(&sample[sustain_index..release_index], period),
+        loop_point: (1.0 - loop_error / loop_quality_threshold(&settings.loops)).clamp(0.0, 1.0),
+        // a drop of 40 dB is about as clear as a release gets
+        release: (release_drop / 40.0).clamp(0.0, 1.0),
+    };
 
-    let closest_note = (12.0 * f64::log2(freq / 440.0) + 69.0).round() as u8;
+    let closest_note = (12.0 * f64::log2(freq / 440.0) + 69.0).round().clamp(0.0, 127.0) as u8;
 
-    SampleMetadata {
+    Ok(SampleMetadata {
         decay_index,
         sustain_index,
         release_index,
-        loop_start: loop_point.0,
-        loop_end: loop_point.1,
+        loop_start,
+        loop_end,
+        extra_loops,
         freq,
         closest_note,
+        confidence,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f64::consts::PI;
+
+    use super::*;
+    use crate::error::AnalysisError;
+
+    const SAMPLE_RATE: u32 = 44_100;
+    // exactly 100 samples per period
+    const FREQ: f64 = 441.0;
+
+    /// A sine with a linear attack, a steady sustain, and an exponential release
+    fn synthetic_pipe(attack: f64, sustain: f64, release: f64) -> (Vec<f32>, usize) {
+        let attack_len = (attack * SAMPLE_RATE as f64) as usize;
+        let release_start = attack_len + (sustain * SAMPLE_RATE as f64) as usize;
+        let len = release_start + (release * SAMPLE_RATE as f64) as usize;
+
+        let sample = (0..len)
+            .map(|i| {
+                let amp = if i < attack_len {
+                    i as f64 / attack_len as f64
+                } else if i < release_start {
+                    1.0
+                } else {
+                    (-((i - release_start) as f64) / (SAMPLE_RATE as f64 * 0.05)).exp()
+                };
+
+                (amp * 0.5 * (2.0 * PI * FREQ * i as f64 / SAMPLE_RATE as f64).sin()) as f32
+            })
+            .collect();
+
+        (sample, release_start)
+    }
+
+    #[test]
+    fn finds_loop_and_release_in_synthetic_pipe() {
+        let (sample, release_start) = synthetic_pipe(0.2, 3.0, 0.6);
+
+        let metadata = calc_sample_metadata(&sample, SAMPLE_RATE, Some(FREQ), &AnalysisSettings::default()).unwrap();
+
+        assert_eq!(metadata.closest_note, 69);
+        assert!(metadata.decay_index < metadata.sustain_index);
+        assert!(metadata.sustain_index < metadata.release_index);
+
+        // the release should land near where the signal starts decaying
+        let release_error = (metadata.release_index as i64 - release_start as i64).abs();
+        assert!(
+            release_error < SAMPLE_RATE as i64 / 10,
+            "release off by {}",
+            release_error
+        );
+
+        // loops have to be inside the sustain, and span a whole number of periods
+        assert!(!metadata.extra_loops.is_empty());
+
+        let period = (SAMPLE_RATE as f64 / FREQ) as usize;
+        let loops = [(metadata.loop_start, metadata.loop_end)];
+
+        for (loop_start, loop_end) in loops.iter().chain(&metadata.extra_loops) {
+            assert!(*loop_start > metadata.decay_index);
+            assert!(*loop_end < metadata.release_index);
+            assert!(loop_end - loop_start >= (1.5 * SAMPLE_RATE as f64) as usize);
+
+            let offset = (loop_end - loop_start) % period;
+            assert!(
+                offset <= 1 || offset >= period - 1,
+                "loop is {} samples off a period",
+                offset
+            );
+        }
+
+        assert!(metadata.confidence.pitch > 0.9);
+        assert!(metadata.confidence.loop_point > 0.9);
+        assert!(metadata.confidence.release > 0.5);
+    }
+
+    #[test]
+    fn short_sample_is_an_error() {
+        let (sample, _) = synthetic_pipe(0.001, 0.005, 0.001);
+
+        let result = calc_sample_metadata(&sample, SAMPLE_RATE, Some(FREQ), &AnalysisSettings::default());
+
+        assert!(matches!(result, Err(AnalysisError::SampleTooShort { .. })));
+    }
+
+    #[test]
+    fn silent_sample_is_an_error() {
+        let sample = vec![0.0; SAMPLE_RATE as usize];
+
+        let result = calc_sample_metadata(&sample, SAMPLE_RATE, None, &AnalysisSettings::default());
+
+        assert_eq!(result.unwrap_err(), AnalysisError::SampleSilent);
+    }
+
+    #[test]
+    fn sustain_shorter_than_loop_is_an_error() {
+        let (sample, _) = synthetic_pipe(0.2, 1.0, 0.6);
+
+        let result = calc_sample_metadata(&sample, SAMPLE_RATE, Some(FREQ), &AnalysisSettings::default());
+
+        assert!(matches!(result, Err(AnalysisError::LoopNotFound { .. })));
     }
 }
@@ -2,38 +2,87 @@ use std::fmt::Debug;
 
 use common::resource_manager::ResourceId;
 
-use crate::SoundConfig;
+use crate::{MultiSample, SoundConfig};
+
+use self::stream::TailCursor;
 
 pub mod double_buffer;
 pub mod envelope;
+pub mod overview;
 pub mod percussion_player;
 pub mod phase_calculator;
 pub mod pipe_player;
 pub mod rank;
 pub mod rank_player;
+pub mod resample;
 pub mod savitzky_golay;
+pub mod stream;
 pub mod util;
 
 pub trait Resource: Debug {
     fn resource_id(&self) -> &ResourceId;
+
+    /// Every sample this can play, starting with `resource_id`. Only layered resources have
+    /// more than one.
+    fn sample_ids(&self) -> &[ResourceId] {
+        std::slice::from_ref(self.resource_id())
+    }
+
+    /// Which of `sample_ids` a note on at `velocity` plays. There can be two when crossfading
+    /// between velocity layers. `round_robin` counts up with every note on.
+    fn pick(&self, _velocity: u8, _round_robin: usize) -> [Option<SamplePick>; 2] {
+        [Some(SamplePick { slot: 0, gain: 1.0 }), None]
+    }
+
+    /// Notes in the same choke group cut each other off
+    fn choke_group(&self) -> Option<u32> {
+        None
+    }
+}
+
+/// One of a resource's samples, picked to be played
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SamplePick {
+    /// index into `Resource::sample_ids`
+    pub slot: usize,
+    pub gain: f32,
 }
 
 pub trait Voice: Default {
-    type Sample;
+    type Sample: AsRef<MultiSample>;
     type Resource: Resource;
     type Param: Default + Debug;
 
     fn new(resource: &Self::Resource, sample: &Self::Sample, sound_config: SoundConfig) -> Self;
 
+    /// Sets the voice up for a new note, like [`Voice::new`]. Voices with buffers override this
+    /// to keep them, as it's called on the audio thread.
+    fn renew(&mut self, resource: &Self::Resource, sample: &Self::Sample, sound_config: SoundConfig) {
+        *self = Self::new(resource, sample, sound_config);
+    }
+
+    /// Sizes the voice's buffers for samples with up to `channels` channels. It's called off the
+    /// audio thread, so it may allocate.
+    fn prepare(&mut self, _channels: usize) {}
+
     fn set_param(&mut self, param: &Self::Param);
 
-    fn attack(&mut self, resource: &Self::Resource, sample: &Self::Sample);
+    fn attack(&mut self, resource: &Self::Resource, sample: &Self::Sample, tail: &mut TailCursor);
 
     fn release(&mut self, resource: &Self::Resource, sample: &Self::Sample);
 
-    fn step(&mut self, resource: &Self::Resource, sample: &Self::Sample) -> f32;
+    /// Like `release`, but for being cut off by another note in the same choke group
+    fn choke(&mut self, resource: &Self::Resource, sample: &Self::Sample) {
+        self.release(resource, sample);
+    }
+
+    /// Writes the next frame into `out`, one value per channel of `sample`
+    fn step(&mut self, resource: &Self::Resource, sample: &Self::Sample, tail: &mut TailCursor, out: &mut [f32]);
 
     fn reset(&mut self);
 
     fn active(&self) -> bool;
+
+    /// Called on a playing voice when its rank was edited, to pick up the new settings
+    fn update_resource(&mut self, _resource: &Self::Resource, _sample: &Self::Sample) {}
 }
@@ -0,0 +1,60 @@
+use super::{envelope::calc_amp, util::abs};
+
+/// Splits `audio` into `resolution` buckets, and gives the lowest and highest point of each.
+/// Enough to draw a waveform at that width.
+pub fn peak_overview(audio: &[f32], resolution: usize) -> Vec<[f32; 2]> {
+    buckets(audio.len(), resolution)
+        .map(|(start, end)| {
+            audio[start..end]
+                .iter()
+                .fold([f32::INFINITY, f32::NEG_INFINITY], |[min, max], &x| {
+                    [min.min(x), max.max(x)]
+                })
+        })
+        .collect()
+}
+
+/// The amplitude envelope of `audio` (the same one the sample analysis works from), with
+/// `window_width` being about a period of the sample. Each of the `resolution` points is the
+/// highest value in its bucket.
+pub fn envelope_overview(audio: &[f32], window_width: usize, resolution: usize) -> Vec<f32> {
+    let audio: Vec<f64> = audio.iter().map(|&x| x as f64).collect();
+    let envelope = calc_amp(&abs(&audio), window_width.max(1));
+
+    buckets(envelope.len(), resolution)
+        .map(|(start, end)| envelope[start..end].iter().fold(0.0_f64, |max, &x| max.max(x)) as f32)
+        .collect()
+}
+
+/// Start and end of each bucket. Never more buckets than there are points, and none are empty.
+fn buckets(len: usize, resolution: usize) -> impl Iterator<Item = (usize, usize)> {
+    let resolution = resolution.min(len);
+
+    (0..resolution).map(move |i| (i * len / resolution, (i + 1) * len / resolution))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn peaks_cover_the_whole_sample() {
+        let audio: Vec<f32> = (0..1000)
+            .map(|i| if i == 999 { 1.0 } else { (i as f32 / 1000.0) - 0.5 })
+            .collect();
+
+        let peaks = peak_overview(&audio, 10);
+
+        assert_eq!(peaks.len(), 10);
+        assert_eq!(peaks[0], [-0.5, -0.401]);
+        assert_eq!(peaks[9][1], 1.0);
+        assert!(peaks.windows(2).all(|pair| pair[0][0] < pair[1][0]));
+    }
+
+    #[test]
+    fn short_samples_are_not_stretched() {
+        assert_eq!(peak_overview(&[0.25, -0.25], 100), vec![[0.25, 0.25], [-0.25, -0.25]]);
+        assert!(peak_overview(&[], 100).is_empty());
+        assert!(envelope_overview(&[0.5; 10], 20, 100).is_empty());
+    }
+}
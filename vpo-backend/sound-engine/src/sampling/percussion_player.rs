@@ -1,6 +1,9 @@
-use crate::{util::interpolate::hermite_lookup, MonoSample, SoundConfig};
+use crate::{util::interpolate::Interpolation, MultiSample, SoundConfig};
 
-use super::{rank::Percussion, Voice};
+use super::{rank::Percussion, stream::TailCursor, Voice};
+
+/// How long it takes a choked note to fade out, in seconds
+const CHOKE_DURATION: f32 = 0.01;
 
 #[derive(Default, Debug, Clone)]
 enum State {
@@ -24,11 +27,16 @@ enum QueuedAction {
 pub struct PercussionParam {
     pub gain: f32,
     pub detune: f32,
+    pub interpolation: Interpolation,
 }
 
 impl Default for PercussionParam {
     fn default() -> Self {
-        PercussionParam { gain: 1.0, detune: 1.0 }
+        PercussionParam {
+            gain: 1.0,
+            detune: 1.0,
+            interpolation: Interpolation::default(),
+        }
     }
 }
 
@@ -40,6 +48,7 @@ pub struct PercussionPlayer {
 
     audio_position: f32,
     resample_ratio: f32,
+    interpolation: Interpolation,
     fs: f32,
 
     fade_out_position: f32,
@@ -51,10 +60,12 @@ pub struct PercussionPlayer {
 
     voicing_gain: f32,
     release_gain: f32,
+    /// how much `release_gain` drops every frame while releasing
+    release_step: f32,
 }
 
 impl Voice for PercussionPlayer {
-    type Sample = MonoSample;
+    type Sample = MultiSample;
     type Resource = Percussion;
     type Param = PercussionParam;
 
@@ -67,7 +78,8 @@ impl Voice for PercussionPlayer {
             queued_action: QueuedAction::None,
 
             audio_position: 0.0,
-            resample_ratio: sample.sample_rate as f32 / fs,
+            resample_ratio: sample.sample_rate() as f32 / fs,
+            interpolation: Interpolation::default(),
             fs,
 
             gain: 1.0,
@@ -79,10 +91,11 @@ impl Voice for PercussionPlayer {
 
             voicing_gain: resource.gain,
             release_gain: 1.0,
+            release_step: 1.0 / (fs * resource.release_duration),
         }
     }
 
-    fn attack(&mut self, _resource: &Self::Resource, _sample: &Self::Sample) {
+    fn attack(&mut self, _resource: &Self::Resource, sample: &Self::Sample, tail: &mut TailCursor) {
         self.release_gain = 1.0;
 
         match self.state {
@@ -97,15 +110,17 @@ impl Voice for PercussionPlayer {
                 // attack
                 self.state = State::Playing;
                 self.audio_position = 0.0;
+                tail.rewind(sample);
             }
             State::Uninitialized => {}
         }
     }
 
-    fn release(&mut self, _resource: &Self::Resource, _sample: &Self::Sample) {
+    fn release(&mut self, resource: &Self::Resource, _sample: &Self::Sample) {
         match self.state {
             State::Playing => {
                 self.state = State::Releasing;
+                self.release_step = 1.0 / (self.fs * resource.release_duration);
             }
             State::FadingOut => {
                 self.queued_action = QueuedAction::Release;
@@ -117,6 +132,17 @@ impl Voice for PercussionPlayer {
         }
     }
 
+    fn choke(&mut self, _resource: &Self::Resource, _sample: &Self::Sample) {
+        match self.state {
+            State::Playing | State::FadingOut | State::Releasing => {
+                self.state = State::Releasing;
+                self.queued_action = QueuedAction::None;
+                self.release_step = self.release_step.max(1.0 / (self.fs * CHOKE_DURATION));
+            }
+            State::Stopped | State::Uninitialized => {}
+        }
+    }
+
     fn active(&self) -> bool {
         matches!(self.state, State::Playing | State::FadingOut | State::Releasing)
     }
@@ -124,83 +150,102 @@ impl Voice for PercussionPlayer {
     fn set_param(&mut self, param: &Self::Param) {
         self.gain = param.gain;
         self.detune = param.detune;
+        self.interpolation = param.interpolation;
     }
 
     fn reset(&mut self) {
         self.state = State::Stopped;
     }
 
-    fn step(&mut self, resource: &Self::Resource, sample: &Self::Sample) -> f32 {
+    fn update_resource(&mut self, resource: &Self::Resource, _sample: &Self::Sample) {
+        self.voicing_gain = resource.gain;
+        self.fade_out_length = resource.release_duration * self.fs;
+    }
+
+    fn step(&mut self, resource: &Self::Resource, sample: &Self::Sample, tail: &mut TailCursor, out: &mut [f32]) {
         match self.state {
             State::Playing => {
-                if self.audio_position >= sample.audio_raw.len() as f32 {
+                if self.audio_position >= sample.len() as f32 {
                     self.state = State::Stopped;
                 }
 
-                self.next_sample_normal(sample) * self.voicing_gain
+                let gain = self.voicing_gain;
+                self.next_sample_normal(sample, tail, out, gain);
             }
             State::FadingOut => {
-                let (out, done) = self.next_sample_fade_out(sample);
+                let gain = self.voicing_gain * self.gain;
+                let done = self.next_sample_fade_out(sample, tail, out, gain);
 
                 if done {
                     self.state = self.next_state.clone();
 
+                    // the faded out position was the last thing reading the tail
+                    tail.rewind(sample);
+
                     match self.queued_action {
-                        QueuedAction::Play => self.attack(resource, sample),
+                        QueuedAction::Play => self.attack(resource, sample, tail),
                         QueuedAction::Release => self.release(resource, sample),
                         QueuedAction::None => {}
                     }
 
                     self.queued_action = QueuedAction::None;
                 }
-
-                out * self.voicing_gain * self.gain
             }
             State::Releasing => {
-                self.release_gain -= 1.0 / (self.fs * resource.release_duration);
+                self.release_gain -= self.release_step;
                 self.release_gain = self.release_gain.max(0.0);
 
-                let out = self.next_sample_normal(sample) * self.release_gain;
+                let gain = self.release_gain * self.voicing_gain * self.gain;
+                self.next_sample_normal(sample, tail, out, gain);
 
-                if self.release_gain <= 0.0 || self.audio_position >= sample.audio_raw.len() as f32 {
+                if self.release_gain <= 0.0 || self.audio_position >= sample.len() as f32 {
                     self.state = State::Stopped;
                 }
-
-                out * self.voicing_gain * self.gain
             }
-            State::Stopped => 0.0,
-            State::Uninitialized => 0.0,
+            State::Stopped | State::Uninitialized => out.fill(0.0),
         }
     }
 }
 
 impl PercussionPlayer {
-    fn next_sample_normal(&mut self, sample: &MonoSample) -> f32 {
-        let out = hermite_lookup(&sample.audio_raw, self.audio_position);
+    fn next_sample_normal(&mut self, sample: &MultiSample, tail: &mut TailCursor, out: &mut [f32], gain: f32) {
+        let step = self.resample_ratio * self.detune;
 
-        self.audio_position += self.resample_ratio * self.detune;
+        for (channel, out) in out.iter_mut().enumerate().take(sample.channel_count()) {
+            *out = tail.interpolate(sample, channel, self.audio_position, step, self.interpolation) * gain;
+        }
+
+        self.audio_position += step;
 
-        if self.audio_position >= sample.audio_raw.len() as f32 {
+        if self.audio_position >= sample.len() as f32 {
             self.state = State::Stopped;
         }
-
-        out
     }
 
     /// This is for when it's reattacking, and simultainously playing the start of the new sample while
     /// finishing up the release
-    fn next_sample_fade_out(&mut self, sample: &MonoSample) -> (f32, bool) {
+    fn next_sample_fade_out(
+        &mut self,
+        sample: &MultiSample,
+        tail: &mut TailCursor,
+        out: &mut [f32],
+        gain: f32,
+    ) -> bool {
         let crossfade_factor = ((self.fade_out_position - self.fade_out_start) / self.fade_out_length).min(1.0);
 
-        let old = hermite_lookup(&sample.audio_raw, self.fade_out_position);
-        let new = hermite_lookup(&sample.audio_raw, self.audio_position);
+        let step = self.resample_ratio * self.detune;
 
-        let interpolated = old * (1.0 - crossfade_factor) + new;
+        for (channel, out) in out.iter_mut().enumerate().take(sample.channel_count()) {
+            let old = tail.interpolate(sample, channel, self.fade_out_position, step, self.interpolation);
+            let new = tail.interpolate(sample, channel, self.audio_position, step, self.interpolation);
+
+            *out = (old * (1.0 - crossfade_factor) + new) * gain;
+        }
 
-        self.audio_position += self.resample_ratio * self.detune;
-        self.fade_out_position += self.resample_ratio * self.detune;
+        self.audio_position += step;
+        self.fade_out_position += step;
 
-        (interpolated, crossfade_factor >= 1.0)
+        crossfade_factor >= 1.0
     }
 
     fn fade_out_to(&mut self, next_state: State, new_location: f32) {
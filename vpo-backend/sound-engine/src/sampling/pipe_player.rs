@@ -1,11 +1,13 @@
+use std::f32::consts::FRAC_1_SQRT_2;
+
 use crate::{
     node::filter::{FilterSpec, FilterType, NthBiquadFilter, SimpleComb},
     sampling::util::rms32,
-    util::interpolate::hermite_lookup,
-    MonoSample, SoundConfig,
+    util::interpolate::Interpolation,
+    MonoSample, MultiSample, SoundConfig,
 };
 
-use super::{rank::Pipe, Voice};
+use super::{rank::Pipe, stream::TailCursor, Voice};
 
 const PHASE_DEBUGGING: bool = false;
 
@@ -31,7 +33,7 @@ enum QueuedAction {
     None,
 }
 
-const ENVELOPE_POINTS: usize = 16;
+pub const ENVELOPE_POINTS: usize = 16;
 
 #[derive(Debug)]
 pub struct EnvelopeIndexes {
@@ -49,15 +51,23 @@ pub struct PipePlayer {
     // basic player values
     audio_position: f32,
     resample_ratio: f32,
+    interpolation: Interpolation,
+    /// which of the pipe's loops is playing
+    current_loop: usize,
 
     // voicing
     voicing_amp: f32,
     voicing_comb: SimpleComb,
+    brightness_filters: Vec<NthBiquadFilter<1>>,
+    low_cut_filters: Vec<NthBiquadFilter<2>>,
+    /// whether either of the above are doing anything, so they can be skipped
+    brightness_enabled: bool,
+    low_cut_enabled: bool,
 
     // dynamic air values
     detune: f32,
     gain: f32,
-    third_harm_filter: NthBiquadFilter<4>,
+    third_harm_filters: Vec<NthBiquadFilter<4>>,
     third_db_gain: f32,
     third_spec: FilterSpec<f32>,
 
@@ -71,6 +81,7 @@ pub struct PipeParam {
     pub gain: f32,
     pub detune: f32,
     pub third_db_gain: f32,
+    pub interpolation: Interpolation,
 }
 
 impl Default for PipeParam {
@@ -79,28 +90,57 @@ impl Default for PipeParam {
             gain: 1.0,
             detune: 1.0,
             third_db_gain: 0.0,
+            interpolation: Interpolation::default(),
         }
     }
 }
 
 impl Voice for PipePlayer {
     type Resource = Pipe;
-    type Sample = MonoSample;
+    type Sample = MultiSample;
     type Param = PipeParam;
 
-    fn new(pipe: &Pipe, sample: &MonoSample, config: SoundConfig) -> PipePlayer {
+    fn new(pipe: &Pipe, sample: &MultiSample, config: SoundConfig) -> PipePlayer {
+        let mut new_player = PipePlayer::default();
+
+        new_player.prepare(sample.channel_count());
+        new_player.renew(pipe, sample, config);
+
+        new_player
+    }
+
+    fn renew(&mut self, pipe: &Pipe, sample: &MultiSample, config: SoundConfig) {
         let fs = config.sample_rate as f32;
 
-        let mut new_player = PipePlayer {
+        // the filters keep their allocation from `prepare`, only their state starts over
+        let mut brightness_filters = std::mem::take(&mut self.brightness_filters);
+        let mut low_cut_filters = std::mem::take(&mut self.low_cut_filters);
+        let mut third_harm_filters = std::mem::take(&mut self.third_harm_filters);
+
+        brightness_filters.fill(NthBiquadFilter::new(FilterSpec::none()));
+        low_cut_filters.fill(NthBiquadFilter::new(FilterSpec::none()));
+        third_harm_filters.fill(NthBiquadFilter::new(FilterSpec {
+            f0: fs / 2.0,
+            fs,
+            filter_type: FilterType::None,
+        }));
+
+        *self = PipePlayer {
             state: State::Looping,
             next_state: State::Stopped,
             queued_action: QueuedAction::None,
 
             audio_position: 0.0,
-            resample_ratio: sample.sample_rate as f32 / fs,
+            resample_ratio: sample.sample_rate() as f32 / fs,
+            interpolation: Interpolation::default(),
+            current_loop: 0,
 
             voicing_amp: pipe.amplitude,
             voicing_comb: SimpleComb::default(),
+            brightness_filters,
+            low_cut_filters,
+            brightness_enabled: false,
+            low_cut_enabled: false,
 
             crossfade_position: 0.0,
             crossfade_start: 0.0,
@@ -108,11 +148,7 @@ impl Voice for PipePlayer {
 
             detune: 1.0,
             gain: 1.0,
-            third_harm_filter: NthBiquadFilter::new(FilterSpec {
-                f0: fs / 2.0,
-                fs,
-                filter_type: FilterType::None,
-            }),
+            third_harm_filters,
             third_db_gain: 0.0,
             third_spec: FilterSpec::new(
                 pipe.freq,
@@ -124,27 +160,44 @@ impl Voice for PipePlayer {
             ),
         };
 
-        new_player.calculate_voicing(pipe, sample);
+        self.calculate_voicing(pipe, sample);
+    }
 
-        new_player
+    fn prepare(&mut self, channels: usize) {
+        if self.third_harm_filters.len() < channels {
+            self.brightness_filters
+                .resize(channels, NthBiquadFilter::new(FilterSpec::none()));
+            self.low_cut_filters
+                .resize(channels, NthBiquadFilter::new(FilterSpec::none()));
+            self.third_harm_filters
+                .resize(channels, NthBiquadFilter::new(FilterSpec::none()));
+        }
     }
 
-    fn attack(&mut self, pipe: &Pipe, sample: &MonoSample) {
+    fn attack(&mut self, pipe: &Pipe, sample: &MultiSample, tail: &mut TailCursor) {
         let current_location = self.audio_position as usize;
 
         match self.state {
             State::Uninitialized => {}
             // Since we were just releasing, this is a case of reattacking
             State::Releasing => {
-                let audio = &sample.audio_raw;
+                let audio = &sample.first_channel().audio_raw;
+
+                // too far along (or out of memory, if streaming) to find the phase
+                if current_location + pipe.phase_calculator.window() >= audio.len() {
+                    self.restart();
+                    tail.rewind(sample);
+                    return;
+                }
 
                 // what's our current amplitude?
                 let location_bounded = current_location.max(pipe.amp_window_size);
                 let current_amp = rms32(&audio[(location_bounded - pipe.amp_window_size)..location_bounded]);
 
                 // quiet enough that we should just restart
-                if current_amp < 0.01 || current_location + pipe.phase_calculator.window() >= audio.len() {
+                if current_amp < 0.01 {
                     self.restart();
+                    tail.rewind(sample);
                     return;
                 }
 
@@ -152,6 +205,7 @@ impl Voice for PipePlayer {
                 let new_location = envelope_lookup(&pipe.attack_envelope, current_amp);
 
                 self.jump_to_in_phase(pipe, sample, State::Looping, pipe.crossfade as f32, new_location);
+                tail.rewind(sample);
             }
             State::Crossfading => {
                 self.queued_action = QueuedAction::Play;
@@ -159,6 +213,7 @@ impl Voice for PipePlayer {
             State::Stopped => {
                 // start over
                 self.restart();
+                tail.rewind(sample);
             }
             State::Looping => {
                 // playing when already playing doesn't do anything
@@ -166,7 +221,7 @@ impl Voice for PipePlayer {
         }
     }
 
-    fn release(&mut self, pipe: &Pipe, sample: &MonoSample) {
+    fn release(&mut self, pipe: &Pipe, sample: &MultiSample) {
         match self.state {
             State::Uninitialized => {}
             State::Crossfading => {
@@ -174,7 +229,7 @@ impl Voice for PipePlayer {
             }
             State::Looping => {
                 let current_location = self.audio_position as usize;
-                let audio = &sample.audio_raw;
+                let audio = &sample.first_channel().audio_raw;
 
                 // what's our current amplitude?
                 let location_bounded = current_location.max(pipe.amp_window_size);
@@ -189,8 +244,8 @@ impl Voice for PipePlayer {
         }
     }
 
-    fn step(&mut self, resource: &Pipe, sample: &MonoSample) -> f32 {
-        self.next_sample(resource, sample)
+    fn step(&mut self, resource: &Pipe, sample: &MultiSample, tail: &mut TailCursor, out: &mut [f32]) {
+        self.next_sample(resource, sample, tail, out)
     }
 
     fn active(&self) -> bool {
@@ -205,118 +260,146 @@ impl Voice for PipePlayer {
         self.gain = param.gain;
         self.detune = param.detune;
         self.third_db_gain = param.third_db_gain;
+        self.interpolation = param.interpolation;
+    }
+
+    fn update_resource(&mut self, pipe: &Pipe, sample: &MultiSample) {
+        if self.current_loop >= pipe.loop_count() {
+            self.current_loop = 0;
+        }
+
+        // the filters were sized for the rank's samples in `prepare`, so they're only respecified
+        // here. If the rank now has samples with more channels, `RankPlayer` drops the extra ones.
+        self.calculate_voicing(pipe, sample);
     }
 }
 
 impl PipePlayer {
-    pub fn next_sample(&mut self, pipe: &Pipe, sample: &MonoSample) -> f32 {
+    pub fn next_sample(&mut self, pipe: &Pipe, sample: &MultiSample, tail: &mut TailCursor, out: &mut [f32]) {
         match self.state {
-            State::Uninitialized => 0.0,
+            State::Uninitialized => out.fill(0.0),
             State::Crossfading => {
-                if self.audio_position < sample.audio_raw.len() as f32 {
-                    let (out, done) = self.next_sample_crossfade(sample);
+                if self.audio_position < sample.len() as f32 {
+                    let done = self.next_sample_crossfade(sample, tail, out);
 
                     if done {
                         self.state = self.next_state.clone();
 
                         match self.queued_action {
-                            QueuedAction::Play => self.attack(pipe, sample),
+                            QueuedAction::Play => self.attack(pipe, sample, tail),
                             QueuedAction::Release => self.release(pipe, sample),
                             QueuedAction::None => {}
                         }
 
                         self.queued_action = QueuedAction::None;
                     }
-
-                    out
                 } else {
                     self.state = State::Stopped;
 
-                    0.0
+                    out.fill(0.0);
                 }
             }
             State::Looping => {
-                let out = self.next_sample_normal(sample);
+                self.next_sample_normal(sample, tail, out);
 
                 // loop and crossfade
-                if self.audio_position > pipe.loop_end as f32 {
-                    let loop_start = pipe.loop_start;
-                    let loop_end = pipe.loop_end;
+                let (loop_start, loop_end) = pipe.loop_points(self.current_loop);
 
-                    let new_location = self.audio_position - (loop_end - loop_start) as f32;
+                if self.audio_position > loop_end as f32 {
+                    let next_loop = (self.current_loop + 1) % pipe.loop_count();
 
-                    self.crossfade_to(State::Looping, pipe.crossfade as f32, new_location);
-                }
+                    if next_loop == self.current_loop {
+                        let new_location = self.audio_position - (loop_end - loop_start) as f32;
+
+                        self.crossfade_to(State::Looping, pipe.crossfade as f32, new_location);
+                    } else {
+                        // hop over to the start of the next loop, lined up with where we are now
+                        self.current_loop = next_loop;
+                        let (next_start, _) = pipe.loop_points(next_loop);
 
-                out
+                        self.jump_to_in_phase(pipe, sample, State::Looping, pipe.crossfade as f32, next_start);
+                    }
+                }
             }
             State::Releasing => {
-                if self.audio_position < sample.audio_raw.len() as f32 {
-                    self.next_sample_normal(sample)
+                if self.audio_position < sample.len() as f32 {
+                    self.next_sample_normal(sample, tail, out);
                 } else {
                     self.state = State::Stopped;
 
-                    0.0
+                    out.fill(0.0);
                 }
             }
-            State::Stopped => 0.0,
+            State::Stopped => out.fill(0.0),
         }
     }
 
-    fn next_sample_normal(&mut self, sample: &MonoSample) -> f32 {
-        let comb_pass = self.voicing_comb.filter(
-            hermite_lookup(&sample.audio_raw, self.audio_position),
-            &sample.audio_raw,
-            self.audio_position,
-        );
+    fn next_sample_normal(&mut self, sample: &MultiSample, tail: &mut TailCursor, out: &mut [f32]) {
+        for (i, out) in out.iter_mut().enumerate().take(sample.channel_count()) {
+            let comb_pass = self.comb_lookup(sample, tail, i, self.audio_position);
 
-        let out = self.voice_filtering(comb_pass);
+            *out = self.voice_filtering(i, comb_pass);
+        }
 
         self.audio_position += self.detune * self.resample_ratio;
-
-        out
     }
 
-    fn next_sample_crossfade(&mut self, sample: &MonoSample) -> (f32, bool) {
+    fn next_sample_crossfade(&mut self, sample: &MultiSample, tail: &mut TailCursor, out: &mut [f32]) -> bool {
         let crossfade_factor = (self.crossfade_position - self.crossfade_start) / self.crossfade_length;
 
-        let old = self.voicing_comb.filter(
-            hermite_lookup(&sample.audio_raw, self.crossfade_position),
-            &sample.audio_raw,
-            self.crossfade_position,
-        );
-
-        let new = self.voicing_comb.filter(
-            hermite_lookup(&sample.audio_raw, self.audio_position),
-            &sample.audio_raw,
-            self.audio_position,
-        );
+        for (i, out) in out.iter_mut().enumerate().take(sample.channel_count()) {
+            let old = self.comb_lookup(sample, tail, i, self.crossfade_position);
+            let new = self.comb_lookup(sample, tail, i, self.audio_position);
 
-        let interpolated = old * (1.0 - crossfade_factor) + new * crossfade_factor;
+            let interpolated = old * (1.0 - crossfade_factor) + new * crossfade_factor;
 
-        let out = self.voice_filtering(interpolated);
+            *out = self.voice_filtering(i, interpolated);
+        }
 
         self.audio_position += self.detune * self.resample_ratio;
         self.crossfade_position += self.detune * self.resample_ratio;
 
-        (out, crossfade_factor >= 1.0)
+        crossfade_factor >= 1.0
     }
 
-    fn voice_filtering(&mut self, sample: f32) -> f32 {
-        self.third_harm_filter.filter_sample(sample) * self.gain * self.voicing_amp
+    fn comb_lookup(&self, sample: &MultiSample, tail: &mut TailCursor, channel: usize, position: f32) -> f32 {
+        let value = tail.interpolate(
+            sample,
+            channel,
+            position,
+            self.detune * self.resample_ratio,
+            self.interpolation,
+        );
+
+        self.voicing_comb
+            .filter_with(value, position, |tap_pos| tail.lerp(sample, channel, tap_pos))
+    }
+
+    fn voice_filtering(&mut self, channel: usize, mut sample: f32) -> f32 {
+        if self.low_cut_enabled {
+            sample = self.low_cut_filters[channel].filter_sample(sample);
+        }
+
+        if self.brightness_enabled {
+            sample = self.brightness_filters[channel].filter_sample(sample);
+        }
+
+        self.third_harm_filters[channel].filter_sample(sample) * self.gain * self.voicing_amp
     }
 
     fn jump_to_in_phase(
         &mut self,
         pipe: &Pipe,
-        sample: &MonoSample,
+        sample: &MultiSample,
         next_state: State,
         crossfade_length: f32,
         new_location: usize,
     ) {
-        let release_shift =
-            pipe.phase_calculator
-                .calc_phase_shift(self.audio_position as usize, new_location, &sample.audio_raw);
+        let release_shift = pipe.phase_calculator.calc_phase_shift(
+            self.audio_position as usize,
+            new_location,
+            &sample.first_channel().audio_raw,
+        );
 
         self.crossfade_to(next_state, crossfade_length, (new_location as f32) + release_shift);
     }
@@ -359,9 +442,12 @@ impl PipePlayer {
         // avoid unnecessary calculations if they're too small to hear
         // TODO: this 0.5 should probably be a constant
         if (db_gain - self.third_db_gain).abs() > 0.5 {
-            self.third_spec
-                .set_db_gain(db_gain / self.third_harm_filter.get_order_multiplier() as f32);
-            self.third_harm_filter.set_spec(self.third_spec.clone());
+            for filter in &mut self.third_harm_filters {
+                self.third_spec
+                    .set_db_gain(db_gain / filter.get_order_multiplier() as f32);
+                filter.set_spec(self.third_spec.clone());
+            }
+
             self.third_db_gain = db_gain;
         }
     }
@@ -388,11 +474,51 @@ impl PipePlayer {
 
         self.audio_position = 1.0;
         self.crossfade_position = 1.0;
+        self.current_loop = 0;
     }
 
-    fn calculate_voicing(&mut self, pipe: &Pipe, sample: &MonoSample) {
-        self.voicing_comb = SimpleComb::new(pipe.freq * 2.0, sample.sample_rate as f32, -pipe.comb_coeff);
-        self.voicing_amp = 1.0 / self.voicing_comb.response(pipe.freq, sample.sample_rate as f32) * pipe.amplitude;
+    fn calculate_voicing(&mut self, pipe: &Pipe, sample: &MultiSample) {
+        let sample_rate = sample.sample_rate() as f32;
+
+        let voicing = &pipe.voicing;
+        let fs = self.third_spec.fs;
+
+        self.voicing_comb = SimpleComb::new(pipe.freq * 2.0, sample_rate, -voicing.comb_coeff);
+        self.voicing_amp = 1.0 / self.voicing_comb.response(pipe.freq, sample_rate) * pipe.amplitude;
+
+        // this is called on the audio thread when a rank is edited, so the filters are only
+        // respecified here, never reallocated
+        let nyquist = fs / 2.0;
+
+        self.brightness_enabled =
+            voicing.brightness != 0.0 && voicing.brightness_freq > 0.0 && voicing.brightness_freq < nyquist;
+        let brightness_spec = if self.brightness_enabled {
+            FilterSpec::new(
+                voicing.brightness_freq,
+                fs,
+                FilterType::HighShelf {
+                    slope: 1.0,
+                    db_gain: voicing.brightness,
+                },
+            )
+        } else {
+            FilterSpec::none()
+        };
+
+        self.low_cut_enabled = voicing.low_cut > 0.0 && voicing.low_cut < nyquist;
+        let low_cut_spec = if self.low_cut_enabled {
+            FilterSpec::new(voicing.low_cut, fs, FilterType::HighPass { q: FRAC_1_SQRT_2 })
+        } else {
+            FilterSpec::none()
+        };
+
+        for filter in &mut self.brightness_filters {
+            filter.set_spec(brightness_spec.clone());
+        }
+
+        for filter in &mut self.low_cut_filters {
+            filter.set_spec(low_cut_spec.clone());
+        }
 
         self.third_spec.f0 = pipe.freq;
         // recalculate the filter coefficients
@@ -409,13 +535,19 @@ impl Default for PipePlayer {
 
             audio_position: 0.0,
             resample_ratio: 0.0,
+            interpolation: Interpolation::default(),
+            current_loop: 0,
 
             voicing_amp: 1.0,
             voicing_comb: SimpleComb::default(),
+            brightness_filters: vec![],
+            low_cut_filters: vec![],
+            brightness_enabled: false,
+            low_cut_enabled: false,
 
             detune: 1.0,
             gain: 1.0,
-            third_harm_filter: NthBiquadFilter::empty(),
+            third_harm_filters: vec![],
             third_db_gain: 0.0,
             third_spec: FilterSpec::default(),
 
@@ -1,9 +1,13 @@
 use std::collections::BTreeMap;
+use std::f32::consts::FRAC_PI_2;
 use std::fmt::Debug;
+use std::ops::Range;
 
 use common::resource_manager::ResourceId;
 
-use super::{phase_calculator::PhaseCalculator, pipe_player::EnvelopeIndexes, Resource};
+use crate::util::db_to_gain;
+
+use super::{phase_calculator::PhaseCalculator, pipe_player::EnvelopeIndexes, Resource, SamplePick};
 
 #[derive(Debug)]
 pub struct Pipe {
@@ -14,10 +18,12 @@ pub struct Pipe {
     pub freq: f32,
 
     pub amplitude: f32,
-    pub comb_coeff: f32,
+    pub voicing: Voicing,
 
     pub loop_start: usize,
     pub loop_end: usize,
+    /// other loops to hop between, so long notes don't sound static
+    pub extra_loops: Vec<(usize, usize)>,
     pub decay_index: usize,
     pub release_index: usize,
 
@@ -29,6 +35,48 @@ pub struct Pipe {
     pub release_envelope: EnvelopeIndexes,
 }
 
+impl Pipe {
+    pub fn loop_count(&self) -> usize {
+        1 + self.extra_loops.len()
+    }
+
+    /// Start and end of loop `index`, where 0 is the main loop
+    pub fn loop_points(&self, index: usize) -> (usize, usize) {
+        match index {
+            0 => (self.loop_start, self.loop_end),
+            _ => self
+                .extra_loops
+                .get(index - 1)
+                .copied()
+                .unwrap_or((self.loop_start, self.loop_end)),
+        }
+    }
+}
+
+/// Filtering a pipe gets on top of its sample, to even out a rank note by note
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Voicing {
+    /// attenuates even harmonics relative to odd ones if positive (boosts if negative),
+    /// through a comb filter. See [`Voicing::comb_coeff_from_db`].
+    pub comb_coeff: f32,
+    /// gain of the brightness high shelf in dB, zero for none
+    pub brightness: f32,
+    /// center of the brightness shelf, in Hz
+    pub brightness_freq: f32,
+    /// highpass cutoff in Hz, zero for none
+    pub low_cut: f32,
+}
+
+impl Voicing {
+    /// The comb coefficient that leaves even harmonics `db` quieter than the odd ones
+    pub fn comb_coeff_from_db(db: f32) -> f32 {
+        let ratio = db_to_gain(-db);
+
+        // the comb's even/odd response is (1 - coeff) / (1 + coeff)
+        ((1.0 - ratio) / (1.0 + ratio)).clamp(-0.95, 0.95)
+    }
+}
+
 impl Resource for Pipe {
     fn resource_id(&self) -> &ResourceId {
         &self.resource
@@ -37,15 +85,137 @@ impl Resource for Pipe {
 
 #[derive(Debug)]
 pub struct Percussion {
-    pub resource: ResourceId,
+    /// every sample of every layer, never empty
+    pub samples: Vec<ResourceId>,
+    /// sorted by velocity
+    pub layers: Vec<PercussionLayer>,
     /// in seconds
     pub release_duration: f32,
     pub gain: f32,
+    pub choke_group: Option<u32>,
+}
+
+/// The samples a percussion note plays over a range of velocities
+#[derive(Debug, Clone)]
+pub struct PercussionLayer {
+    /// lowest and highest velocity the layer plays at
+    pub velocity: (u8, u8),
+    /// velocities over which the layer fades in from the one below it, and out into the
+    /// one above it. Where layers don't overlap, these are empty.
+    pub fade_in: (u8, u8),
+    pub fade_out: (u8, u8),
+    /// round robin alternates, as indexes into `Percussion::samples`
+    pub alternates: Range<usize>,
+    pub gain: f32,
+}
+
+impl PercussionLayer {
+    /// Sorts `layers` by velocity and works out where they crossfade into each other
+    pub fn crossfade(mut layers: Vec<PercussionLayer>) -> Vec<PercussionLayer> {
+        layers.sort_by_key(|layer| layer.velocity);
+
+        for i in 0..layers.len() {
+            let (low, high) = layers[i].velocity;
+
+            layers[i].fade_in = match i.checked_sub(1).map(|below| layers[below].velocity.1) {
+                Some(below_high) if below_high >= low => (low, below_high.min(high)),
+                _ => (low, low),
+            };
+
+            layers[i].fade_out = match layers.get(i + 1).map(|above| above.velocity.0) {
+                Some(above_low) if above_low <= high => (above_low.max(low), high),
+                _ => (high, high),
+            };
+        }
+
+        layers
+    }
+
+    pub fn velocity_gain(&self, velocity: u8) -> f32 {
+        if velocity < self.velocity.0 || velocity > self.velocity.1 {
+            return 0.0;
+        }
+
+        let (fade_in_start, fade_in_end) = self.fade_in;
+        let (fade_out_start, fade_out_end) = self.fade_out;
+
+        ramp(velocity, fade_in_start, fade_in_end) * ramp(velocity, fade_out_end, fade_out_start)
+    }
+}
+
+/// Goes from 0 at `from` to 1 at `to` (which can be below `from`). Always 1 if they're the same.
+fn ramp(velocity: u8, from: u8, to: u8) -> f32 {
+    if from == to {
+        return 1.0;
+    }
+
+    ((velocity as f32 - from as f32) / (to as f32 - from as f32)).clamp(0.0, 1.0)
 }
 
 impl Resource for Percussion {
     fn resource_id(&self) -> &ResourceId {
-        &self.resource
+        &self.samples[0]
+    }
+
+    fn sample_ids(&self) -> &[ResourceId] {
+        &self.samples
+    }
+
+    fn pick(&self, velocity: u8, round_robin: usize) -> [Option<SamplePick>; 2] {
+        let mut picked = self.layers.iter().filter_map(|layer| {
+            let gain = layer.velocity_gain(velocity) * layer.gain;
+
+            if gain > 0.0 && !layer.alternates.is_empty() {
+                let slot = layer.alternates.start + round_robin % layer.alternates.len();
+
+                Some(SamplePick { slot, gain })
+            } else {
+                None
+            }
+        });
+
+        [picked.next(), picked.next()]
+    }
+
+    fn choke_group(&self) -> Option<u32> {
+        self.choke_group
+    }
+}
+
+/// A microphone position, made up of one or more channels of the rank's samples
+#[derive(Debug, Clone)]
+pub struct MicPosition {
+    pub name: String,
+    /// sample channels, in the order they're routed to the output channels
+    pub channels: Vec<usize>,
+    pub gain: f32,
+}
+
+/// Where in the output channels a note sounds from
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Placement {
+    /// from 0 (the first output channel) to 1 (the last), panned between the two closest channels
+    Pan(f32),
+    /// straight into one output channel, like a pipe on a chest of its own
+    Channel(usize),
+}
+
+impl Placement {
+    /// The output channels (at most two) this sounds from, with their gains. A channel past the
+    /// last output channel goes into the last one.
+    pub fn gains(&self, channels: usize) -> [(usize, f32); 2] {
+        match *self {
+            Placement::Channel(channel) => [(channel.min(channels.max(1) - 1), 1.0), (0, 0.0)],
+            Placement::Pan(_) if channels < 2 => [(0, 1.0), (0, 0.0)],
+            Placement::Pan(pan) => {
+                let position = pan.clamp(0.0, 1.0) * (channels - 1) as f32;
+                let left = (position as usize).min(channels - 2);
+                let angle = (position - left as f32) * FRAC_PI_2;
+
+                // equal power, so notes don't dip in the middle of two channels
+                [(left, angle.cos()), (left + 1, angle.sin())]
+            }
+        }
     }
 }
 
@@ -53,6 +223,99 @@ impl Resource for Percussion {
 pub struct Rank<T: Debug> {
     pub notes: BTreeMap<u8, T>,
     pub name: String,
+    /// if empty, sample channels are routed straight to the output channels
+    pub mics: Vec<MicPosition>,
+    /// if empty, notes are routed like the mics say. Otherwise, each note is mixed down and
+    /// placed in the output channels (notes that are left out go in the middle).
+    pub layout: BTreeMap<u8, Placement>,
+    /// changes whenever the rank is reloaded, so players know to pick up edits
+    pub revision: u64,
+}
+
+impl<T: Debug> Rank<T> {
+    /// Where `note` goes in `channels` output channels, if the rank has a layout. Meant to be
+    /// looked up once a buffer, and handed to `mix_frame`.
+    pub fn placement_gains(&self, note: u8, channels: usize) -> Option<[(usize, f32); 2]> {
+        if self.layout.is_empty() {
+            return None;
+        }
+
+        let placement = self.layout.get(&note).copied().unwrap_or(Placement::Pan(0.5));
+
+        Some(placement.gains(channels))
+    }
+
+    /// Notes placed in an output channel past the last of `channels`, along with that channel
+    pub fn channels_out_of_range(&self, channels: usize) -> Vec<(u8, usize)> {
+        self.layout
+            .iter()
+            .filter_map(|(note, placement)| match *placement {
+                Placement::Channel(channel) if channel >= channels => Some((*note, channel)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Mixes one frame of a sample (one value per sample channel) into `out`, either through
+    /// the mics or at the note's place in the layout (see `placement_gains`)
+    pub fn mix_frame(&self, frame: &[f32], placement: Option<&[(usize, f32); 2]>, out: &mut [f32]) {
+        if let Some(placement) = placement {
+            let mono = self.mix_down(frame);
+
+            for (channel, gain) in placement {
+                if let Some(out) = out.get_mut(*channel) {
+                    *out += mono * gain;
+                }
+            }
+        } else if self.mics.is_empty() {
+            spread(frame.iter().copied(), frame.len(), out);
+        } else {
+            for mic in &self.mics {
+                let values = mic
+                    .channels
+                    .iter()
+                    .map(|channel| frame.get(*channel).copied().unwrap_or(0.0) * mic.gain);
+
+                spread(values, mic.channels.len(), out);
+            }
+        }
+    }
+
+    /// Mixes a frame down to one value, averaging the channels of each mic
+    fn mix_down(&self, frame: &[f32]) -> f32 {
+        if self.mics.is_empty() {
+            return frame.iter().sum::<f32>() / frame.len().max(1) as f32;
+        }
+
+        self.mics
+            .iter()
+            .map(|mic| {
+                let sum: f32 = mic
+                    .channels
+                    .iter()
+                    .map(|channel| frame.get(*channel).copied().unwrap_or(0.0))
+                    .sum();
+
+                sum / mic.channels.len().max(1) as f32 * mic.gain
+            })
+            .sum()
+    }
+}
+
+/// Adds `width` channels to `out`. A mono source is copied to every output channel,
+/// otherwise source channels wrap around if there are more of them than outputs.
+fn spread(values: impl Iterator<Item = f32>, width: usize, out: &mut [f32]) {
+    let out_len = out.len();
+
+    for (i, value) in values.enumerate() {
+        if width == 1 {
+            for output in out.iter_mut() {
+                *output += value;
+            }
+        } else {
+            out[i % out_len] += value;
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -69,3 +332,73 @@ impl RankType {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn layered(layers: &[((u8, u8), usize)]) -> Percussion {
+        let mut samples = vec![];
+        let layers = layers
+            .iter()
+            .map(|(velocity, alternates)| {
+                let start = samples.len();
+                samples.extend((0..*alternates).map(|i| ResourceId {
+                    namespace: "samples".into(),
+                    resource: format!("{}-{}.wav", velocity.0, i),
+                }));
+
+                PercussionLayer {
+                    velocity: *velocity,
+                    fade_in: (0, 0),
+                    fade_out: (0, 0),
+                    alternates: start..samples.len(),
+                    gain: 1.0,
+                }
+            })
+            .collect();
+
+        Percussion {
+            samples,
+            layers: PercussionLayer::crossfade(layers),
+            release_duration: 0.1,
+            gain: 1.0,
+            choke_group: None,
+        }
+    }
+
+    #[test]
+    fn overlapping_layers_crossfade() {
+        let drum = layered(&[((64, 127), 1), ((0, 80), 1)]);
+
+        assert_eq!(drum.pick(10, 0), [Some(SamplePick { slot: 1, gain: 1.0 }), None]);
+        assert_eq!(drum.pick(100, 0), [Some(SamplePick { slot: 0, gain: 1.0 }), None]);
+
+        let [Some(soft), Some(loud)] = drum.pick(72, 0) else {
+            panic!("both layers should play in the overlap");
+        };
+        assert_eq!((soft.slot, loud.slot), (1, 0));
+        assert_eq!(soft.gain + loud.gain, 1.0);
+    }
+
+    #[test]
+    fn round_robin_cycles_through_alternates() {
+        let drum = layered(&[((0, 63), 2), ((64, 127), 3)]);
+
+        let slots: Vec<usize> = (0..4).map(|hit| drum.pick(100, hit)[0].unwrap().slot).collect();
+        assert_eq!(slots, vec![2, 3, 4, 2]);
+        assert_eq!(drum.pick(20, 3)[0].unwrap().slot, 1);
+    }
+
+    #[test]
+    fn pans_between_neighboring_channels() {
+        let [(left, left_gain), (right, right_gain)] = Placement::Pan(0.5).gains(2);
+        assert_eq!((left, right), (0, 1));
+        assert!((left_gain.powi(2) + right_gain.powi(2) - 1.0).abs() < 1e-6);
+
+        assert_eq!(Placement::Pan(0.5).gains(3)[0], (1, 1.0));
+        assert_eq!(Placement::Pan(1.0).gains(3)[1], (2, 1.0));
+        assert_eq!(Placement::Pan(0.3).gains(1), [(0, 1.0), (0, 0.0)]);
+        assert_eq!(Placement::Channel(5).gains(4)[0], (3, 1.0));
+    }
+}
@@ -6,16 +6,23 @@ use common::traits::TryRef;
 use std::collections::BTreeMap;
 use std::fmt::Debug;
 
-use crate::{MonoSample, SoundConfig};
+use crate::{util::interpolate::prepare_sinc, MultiSample, SoundConfig};
 
-use super::{rank::Rank, Resource, Voice};
+use super::{rank::Rank, stream::TailCursor, Resource, Voice};
 use common::resource_manager::{ResourceId, ResourceManager};
 
-#[derive(Debug, Clone)]
+#[derive(Debug)]
 struct VoiceInfo<V: Voice> {
     player: V,
     active: bool,
     note: u8,
+    /// which of the note's samples is playing
+    slot: usize,
+    /// from crossfading between velocity layers
+    gain: f32,
+    /// whether the voice was picked by a note on that hasn't been handled yet
+    attack_pending: bool,
+    tail: TailCursor,
 }
 
 impl<V: Voice> Default for VoiceInfo<V> {
@@ -24,17 +31,49 @@ impl<V: Voice> Default for VoiceInfo<V> {
             player: V::default(),
             active: false,
             note: 255,
+            slot: 0,
+            gain: 1.0,
+            attack_pending: false,
+            tail: TailCursor::default(),
         }
     }
 }
 
-#[derive(Debug, Clone)]
+/// Looks up the resource of `note`, and its sample at `slot`
+fn lookup<'a, R: Resource, S, E>(
+    note_to_sample_map: &BTreeMap<u8, usize>,
+    rank: &'a Rank<R>,
+    note: u8,
+    slot: usize,
+    samples: &'a [impl TryRef<S, Error = E>],
+) -> Option<(&'a R, &'a S)> {
+    let resource = rank.notes.get(&note)?;
+
+    if slot >= resource.sample_ids().len() {
+        return None;
+    }
+
+    let sample = samples.get(note_to_sample_map.get(&note)? + slot)?.try_ref().ok()?;
+
+    Some((resource, sample))
+}
+
+/// Channels the frame buffer has room for at least, before `prepare` is called
+const MIN_FRAME_CHANNELS: usize = 2;
+
+#[derive(Debug)]
 pub struct RankPlayer<V: Voice> {
     polyphony: usize,
     voices: Vec<VoiceInfo<V>>,
+    /// where each note's samples start in the sample list
     note_to_sample_map: BTreeMap<u8, usize>,
+    /// note ons so far, by note, for picking round robin alternates
+    round_robin: [usize; 128],
+    /// revision of the rank the voices were set up with
+    rank_revision: u64,
     param: V::Param,
     sound_config: SoundConfig,
+    frame: Vec<f32>,
 }
 
 impl<V: Voice> RankPlayer<V> {
@@ -44,74 +83,108 @@ impl<V: Voice> RankPlayer<V> {
         polyphony: usize,
         sound_config: SoundConfig,
     ) -> (RankPlayer<V>, Vec<ResourceId>) {
-        let mut needed_samples: Vec<(u8, ResourceId)> = rank
-            .notes
-            .iter()
-            .map(|(note, resource)| (*note, resource.resource_id().clone()))
-            .collect();
-
-        needed_samples.sort_by_key(|x| x.0);
-
-        let note_to_sample_map: BTreeMap<u8, usize> = needed_samples
-            .iter()
-            .enumerate()
-            .map(|(i, (note, _))| (*note, i))
-            .collect();
-
+        let mut note_to_sample_map: BTreeMap<u8, usize> = BTreeMap::new();
         let mut resource_list: Vec<ResourceId> = vec![rank_id];
-        resource_list.extend(needed_samples.into_iter().map(|(_, resource)| resource));
+
+        // `notes` is sorted, and each note's samples are kept together
+        for (note, resource) in &rank.notes {
+            note_to_sample_map.insert(*note, resource_list.len() - 1);
+            resource_list.extend(resource.sample_ids().iter().cloned());
+        }
 
         (
             RankPlayer {
                 polyphony,
                 voices: Vec::with_capacity(polyphony),
                 note_to_sample_map,
+                round_robin: [0; 128],
+                rank_revision: rank.revision,
                 param: V::Param::default(),
                 sound_config,
+                frame: vec![0.0; MIN_FRAME_CHANNELS],
             },
             resource_list,
         )
     }
 
+    /// Sets up every voice, with buffers sized for the rank's samples, and streaming if any of
+    /// them are streamed. It needs to be called off the audio thread, as it allocates.
+    pub fn prepare(&mut self, rank: &Rank<V::Resource>, samples: &ResourceManager<MultiSample>) {
+        // might be needed as soon as the player is on the audio thread
+        prepare_sinc();
+
+        let rank_samples: Vec<&MultiSample> = rank
+            .notes
+            .values()
+            .flat_map(|resource| resource.sample_ids())
+            .filter_map(|sample_id| samples.borrow_resource_by_id(&sample_id.resource))
+            .collect();
+
+        let max_channels = rank_samples
+            .iter()
+            .map(|sample| sample.channel_count())
+            .max()
+            .unwrap_or(0);
+        self.frame = vec![0.0; max_channels.max(MIN_FRAME_CHANNELS)];
+
+        let streamed = rank_samples.iter().any(|sample| sample.tail.is_some());
+
+        // every voice is set up front, so none have to be created on the audio thread
+        self.voices = (0..self.polyphony)
+            .map(|_| {
+                let mut voice = VoiceInfo::default();
+                voice.player.prepare(self.frame.len());
+
+                if streamed {
+                    voice.tail = TailCursor::new();
+                }
+
+                voice
+            })
+            .collect();
+    }
+
     pub fn reset(&mut self) {
         for voice in &mut self.voices {
             voice.active = false;
             voice.note = 255;
+            voice.attack_pending = false;
+            voice.tail.stop();
         }
     }
 
-    pub fn handle_rank_updates(&mut self, rank: &Rank<V::Resource>, samples: &ResourceManager<MonoSample>) {
-        let reset_necessary = self.voices.iter().any(|voice| {
-            // only check active voices to see if they have broken invariants
-            if voice.active {
-                if let Some(resource) = rank.notes.get(&voice.note) {
-                    if samples
-                        .borrow_resource_by_id(&resource.resource_id().resource)
-                        .is_some()
-                    {
-                        // the resource still exists
-                        false
-                    } else {
-                        // reset is needed if a sample was removed
-                        true
-                    }
-                } else {
-                    // reset is needed if a voice was removed from the rank config
-                    true
-                }
-            } else {
-                false
+    /// Brings playing voices up to date if the rank was reloaded since the last call. Cheap
+    /// to call every buffer, as it returns right away if nothing changed.
+    pub fn handle_rank_updates<E>(&mut self, rank: &Rank<V::Resource>, samples: &[impl TryRef<V::Sample, Error = E>]) {
+        if rank.revision == self.rank_revision {
+            return;
+        }
+
+        self.rank_revision = rank.revision;
+
+        let mut reset_necessary = false;
+
+        // only check active voices to see if they have broken invariants
+        for voice in self.voices.iter_mut().filter(|voice| voice.active) {
+            match lookup(&self.note_to_sample_map, rank, voice.note, voice.slot, samples) {
+                Some((resource, sample)) => voice.player.update_resource(resource, sample),
+                // reset is needed if a voice or its sample was removed
+                None => reset_necessary = true,
             }
-        });
+        }
 
         if reset_necessary {
             self.reset();
         }
     }
 
-    fn find_open_voice(&mut self, note: u8) -> usize {
+    fn find_open_voice(&mut self, note: u8, slot: usize) -> usize {
         // first, look if the voice is already active
-        if let Some(existing_voice) = self.voices.iter().position(|voice| voice.note == note) {
+        if let Some(existing_voice) = self
+            .voices
+            .iter()
+            .position(|voice| voice.note == note && voice.slot == slot)
+        {
             return existing_voice;
         }
 
@@ -122,7 +195,11 @@ impl<V: Voice> RankPlayer<V> {
 
         // else, see if we're at full capacity yet
         if self.voices.len() < self.polyphony {
-            self.voices.push(VoiceInfo::default());
+            // only happens if `prepare` wasn't called
+            let mut voice = VoiceInfo::default();
+            voice.player.prepare(self.frame.len());
+
+            self.voices.push(voice);
 
             return self.voices.len() - 1;
         }
@@ -132,32 +209,76 @@ impl<V: Voice> RankPlayer<V> {
         0
     }
 
-    fn allocate_note<E>(&mut self, rank: &Rank<V::Resource>, note: u8, samples: &[impl TryRef<V::Sample, Error = E>]) {
-        let resource_and_sample = self
-            .note_to_sample_map
-            .get(&note)
-            .and_then(|sample_index| samples[*sample_index].try_ref().ok())
-            .and_then(|sample| rank.notes.get(&note).map(|pipe| (pipe, sample)));
+    fn allocate_note<E>(
+        &mut self,
+        rank: &Rank<V::Resource>,
+        note: u8,
+        velocity: u8,
+        samples: &[impl TryRef<V::Sample, Error = E>],
+    ) {
+        let Some(resource) = rank.notes.get(&note) else {
+            return;
+        };
+
+        if let Some(group) = resource.choke_group() {
+            self.choke(rank, note, group, samples);
+        }
+
+        let round_robin = &mut self.round_robin[note as usize % 128];
+        let picks = resource.pick(velocity, *round_robin);
+        *round_robin = round_robin.wrapping_add(1);
 
-        if let Some((pipe, sample)) = resource_and_sample {
-            let open_voice_index = self.find_open_voice(note);
+        for pick in picks.into_iter().flatten() {
+            let Some((pipe, sample)) = lookup(&self.note_to_sample_map, rank, note, pick.slot, samples) else {
+                continue;
+            };
+
+            let open_voice_index = self.find_open_voice(note, pick.slot);
             let open_voice = &mut self.voices[open_voice_index];
 
             open_voice.active = true;
+            open_voice.slot = pick.slot;
+            open_voice.gain = pick.gain;
+            open_voice.attack_pending = true;
 
             if !open_voice.player.active() {
-                let mut player = V::new(pipe, sample, self.sound_config.clone());
-                player.set_param(&self.param);
+                open_voice.player.renew(pipe, sample, self.sound_config.clone());
+                open_voice.player.set_param(&self.param);
 
-                open_voice.player = player;
                 open_voice.note = note;
+                open_voice.tail.rewind(sample.as_ref());
             } else if note == open_voice.note {
                 // nothing to do
             } else {
-                open_voice.player = V::new(pipe, sample, self.sound_config.clone());
+                open_voice.player.renew(pipe, sample, self.sound_config.clone());
                 open_voice.player.set_param(&self.param);
 
                 open_voice.note = note;
+                open_voice.tail.rewind(sample.as_ref());
+            }
+        }
+    }
+
+    /// Cuts off every other note in choke group `group`
+    fn choke<E>(
+        &mut self,
+        rank: &Rank<V::Resource>,
+        note: u8,
+        group: u32,
+        samples: &[impl TryRef<V::Sample, Error = E>],
+    ) {
+        for voice in self
+            .voices
+            .iter_mut()
+            .filter(|voice| voice.active && voice.note != note)
+        {
+            let Some((resource, sample)) = lookup(&self.note_to_sample_map, rank, voice.note, voice.slot, samples)
+            else {
+                continue;
+            };
+
+            if resource.choke_group() == Some(group) {
+                voice.player.choke(resource, sample);
             }
         }
     }
@@ -166,33 +287,35 @@ impl<V: Voice> RankPlayer<V> {
         self.param = param;
     }
 
+    /// Renders the next buffer into `out`, which is interleaved with `channels` channels
     pub fn next_buffered<'a, E>(
         &mut self,
         osc: OscView,
         rank: &Rank<V::Resource>,
         samples: &[impl TryRef<V::Sample, Error = E>],
         out: &mut [f32],
+        channels: usize,
     ) where
         E: std::fmt::Debug,
     {
-        for output in out.iter_mut() {
-            *output = 0.0;
-        }
+        out.fill(0.0);
 
         // allocate any needed voices
         osc.all_messages(|_, _, message| {
             let addr = message.address();
 
             if addr == NOTE_ON_C {
-                if let Some((_, note, _)) = read_osc!(message.arg_iter(), as_int, as_int, as_int) {
-                    self.allocate_note(rank, note as u8, samples);
+                if let Some((_, note, velocity)) = read_osc!(message.arg_iter(), as_int, as_int, as_int) {
+                    self.allocate_note(rank, note as u8, velocity.clamp(0, 127) as u8, samples);
                 }
             }
 
             if is_message_reset(message) {
                 for voice in &mut self.voices {
                     voice.active = false;
+                    voice.attack_pending = false;
                     voice.player.reset();
+                    voice.tail.stop();
                 }
             }
         });
@@ -200,13 +323,7 @@ impl<V: Voice> RankPlayer<V> {
         let active_voices = self.voices.iter_mut().filter(|voice| voice.active);
 
         for voice in active_voices {
-            let pipe_and_sample = self
-                .note_to_sample_map
-                .get(&voice.note)
-                .and_then(|sample_index| samples[*sample_index].try_ref().ok())
-                .and_then(|sample| rank.notes.get(&voice.note).map(|pipe| (pipe, sample)));
-
-            let Some((pipe, sample)) = pipe_and_sample else {
+            let Some((pipe, sample)) = lookup(&self.note_to_sample_map, rank, voice.note, voice.slot, samples) else {
                 continue;
             };
 
@@ -215,8 +332,10 @@ impl<V: Voice> RankPlayer<V> {
 
                 if addr == NOTE_ON_C {
                     if let Some((_, note, _)) = read_osc!(message.arg_iter(), as_int, as_int, as_int) {
-                        if voice.note == note as u8 {
-                            voice.player.attack(pipe, sample);
+                        // other voices of the note (like an earlier round robin hit) ring on
+                        if voice.note == note as u8 && voice.attack_pending {
+                            voice.attack_pending = false;
+                            voice.player.attack(pipe, sample, &mut voice.tail);
                         }
                     }
                 } else if addr == NOTE_OFF_C {
@@ -230,12 +349,29 @@ impl<V: Voice> RankPlayer<V> {
 
             voice.player.set_param(&self.param);
 
-            for output in out.iter_mut() {
-                *output += voice.player.step(pipe, sample);
+            let placement = rank.placement_gains(voice.note, channels);
+
+            // the frame is sized in `prepare`, so a rank reloaded with more channels than it had
+            // loses the extra ones until the node is set up again
+            let sample_channels = sample.as_ref().channel_count().min(self.frame.len());
+
+            for out_frame in out.chunks_exact_mut(channels) {
+                let frame = &mut self.frame[..sample_channels];
+
+                voice.player.step(pipe, sample, &mut voice.tail, frame);
+
+                if voice.gain != 1.0 {
+                    for value in frame.iter_mut() {
+                        *value *= voice.gain;
+                    }
+                }
+
+                rank.mix_frame(frame, placement.as_ref(), out_frame);
 
                 if !voice.player.active() {
                     voice.active = false;
                     voice.player.reset();
+                    voice.tail.stop();
 
                     break;
                 }
@@ -250,8 +386,11 @@ impl<V: Voice> Default for RankPlayer<V> {
             polyphony: 0,
             voices: vec![],
             note_to_sample_map: BTreeMap::new(),
+            round_robin: [0; 128],
+            rank_revision: 0,
             sound_config: SoundConfig::default(),
             param: V::Param::default(),
+            frame: vec![],
         }
     }
 }
@@ -0,0 +1,166 @@
+use std::f64::consts::PI;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{MonoSample, MultiSample};
+
+/// how finely the sinc kernel is tabulated, in points per zero crossing
+const TABLE_RESOLUTION: usize = 512;
+/// where the lowpass starts, relative to the lower of the two nyquist frequencies. A bit under
+/// one, so the window's transition band doesn't let anything through above nyquist.
+const ROLLOFF: f64 = 0.95;
+
+/// Trades resampling speed for how steep the anti-aliasing filter is
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum ResampleQuality {
+    Fast,
+    #[default]
+    Good,
+    Best,
+}
+
+impl ResampleQuality {
+    fn zero_crossings(&self) -> usize {
+        match self {
+            ResampleQuality::Fast => 8,
+            ResampleQuality::Good => 24,
+            ResampleQuality::Best => 64,
+        }
+    }
+}
+
+/// Blackman windowed sinc, tabulated from 0 to `zero_crossings`
+struct Kernel {
+    table: Vec<f64>,
+}
+
+impl Kernel {
+    fn new(zero_crossings: usize) -> Kernel {
+        let table = (0..=zero_crossings * TABLE_RESOLUTION)
+            .map(|i| {
+                let x = i as f64 / TABLE_RESOLUTION as f64;
+                let sinc = if i == 0 { 1.0 } else { (PI * x).sin() / (PI * x) };
+
+                // window goes from 1 at the center to 0 at the last zero crossing
+                let w = PI * x / zero_crossings as f64;
+                let window = 0.42 + 0.5 * w.cos() + 0.08 * (2.0 * w).cos();
+
+                sinc * window
+            })
+            .collect();
+
+        Kernel { table }
+    }
+
+    #[inline]
+    fn at(&self, x: f64) -> f64 {
+        let position = x.abs() * TABLE_RESOLUTION as f64;
+        let index = position as usize;
+
+        match (self.table.get(index), self.table.get(index + 1)) {
+            (Some(a), Some(b)) => a + (b - a) * position.fract(),
+            _ => 0.0,
+        }
+    }
+}
+
+/// Band limited resampling of `audio` from `from` to `to` Hz. Slow compared to interpolating
+/// while playing, but doesn't alias, so it's meant to be done when loading.
+pub fn resample(audio: &[f32], from: u32, to: u32, quality: ResampleQuality) -> Vec<f32> {
+    if from == to || from == 0 || to == 0 || audio.is_empty() {
+        return audio.to_vec();
+    }
+
+    let kernel = Kernel::new(quality.zero_crossings());
+
+    // source frames per output frame
+    let step = from as f64 / to as f64;
+    let cutoff = (to as f64 / from as f64).min(1.0) * ROLLOFF;
+    // how many source frames the kernel spans on each side
+    let reach = quality.zero_crossings() as f64 / cutoff;
+
+    let out_len = resampled_len(audio.len(), from, to);
+    let last = audio.len() as f64 - 1.0;
+
+    (0..out_len)
+        .map(|n| {
+            let center = n as f64 * step;
+            let start = (center - reach).ceil().max(0.0) as usize;
+            let end = (center + reach).floor().min(last) as usize;
+
+            let sum: f64 = (start..=end)
+                .map(|k| audio[k] as f64 * kernel.at((center - k as f64) * cutoff))
+                .sum();
+
+            (sum * cutoff) as f32
+        })
+        .collect()
+}
+
+/// How long `len` frames at `from` Hz are at `to` Hz
+pub fn resampled_len(len: usize, from: u32, to: u32) -> usize {
+    (len as u64 * to as u64).div_ceil(from as u64) as usize
+}
+
+/// Resamples every channel of `sample` to `to` Hz, remembering the rate of the file so
+/// positions in it can still be converted
+pub fn resample_sample(sample: &MultiSample, to: u32, quality: ResampleQuality) -> MultiSample {
+    let from = sample.sample_rate();
+
+    MultiSample {
+        channels: sample
+            .channels
+            .iter()
+            .map(|channel| MonoSample {
+                audio_raw: resample(&channel.audio_raw, from, to, quality),
+                sample_rate: to,
+            })
+            .collect(),
+        tail: None,
+        source_sample_rate: sample.source_sample_rate.or(Some(from)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sine(freq: f64, rate: u32, len: usize) -> Vec<f32> {
+        (0..len)
+            .map(|i| (2.0 * PI * freq * i as f64 / rate as f64).sin() as f32)
+            .collect()
+    }
+
+    fn rms(audio: &[f32]) -> f32 {
+        (audio.iter().map(|x| x * x).sum::<f32>() / audio.len() as f32).sqrt()
+    }
+
+    #[test]
+    fn tones_survive_resampling() {
+        for (from, to) in [(96_000, 48_000), (44_100, 48_000), (48_000, 44_100)] {
+            let resampled = resample(&sine(1000.0, from, from as usize), from, to, ResampleQuality::Good);
+            let expected = sine(1000.0, to, to as usize);
+
+            assert_eq!(resampled.len(), expected.len());
+
+            // edges don't have a full kernel's worth of audio around them
+            let middle = to as usize / 4..to as usize * 3 / 4;
+            let error = resampled[middle.clone()]
+                .iter()
+                .zip(&expected[middle])
+                .map(|(a, b)| (a - b).abs())
+                .fold(0.0, f32::max);
+
+            assert!(error < 0.01, "{} -> {}: error of {}", from, to, error);
+        }
+    }
+
+    #[test]
+    fn downsampling_does_not_alias() {
+        // would fold down to 18 kHz if not filtered out
+        let resampled = resample(&sine(30_000.0, 96_000, 96_000), 96_000, 48_000, ResampleQuality::Good);
+
+        assert!(rms(&resampled[12_000..36_000]) < 0.01);
+    }
+}
@@ -0,0 +1,457 @@
+//! Disk streaming for samples that are too large to keep in memory
+//!
+//! A streamed sample only keeps its head (everything a voice might jump around in, plus
+//! some preload) in memory. The rest of it, the tail, is only ever played front to back,
+//! so it's read on a background thread into per-voice ring buffers. Voices never wait on
+//! the streamer; if a chunk hasn't arrived yet, silence is played in its place. Those
+//! underruns are counted and logged by the streaming thread.
+
+use std::{
+    collections::VecDeque,
+    fmt::Debug,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::Duration,
+};
+
+use lazy_static::lazy_static;
+use log::warn;
+use rtrb::{Consumer, Producer, RingBuffer};
+
+use crate::{
+    util::interpolate::{
+        hermite_interpolate, hermite_lookup, lerp, sinc_interpolate, sinc_lookup, Interpolation, SINC_TAPS,
+    },
+    MultiSample,
+};
+
+/// Frames in a single streamed chunk
+pub const CHUNK_FRAMES: usize = 2048;
+/// Chunks per voice, both the ones buffered ahead and the ones kept around for lookbehind
+const CHUNK_COUNT: usize = 16;
+/// How many chunks a voice keeps after reading into them (for interpolation and the voicing comb)
+const WINDOW_CHUNKS: usize = 3;
+const COMMAND_CAPACITY: usize = 8;
+const IDLE_SLEEP: Duration = Duration::from_millis(1);
+
+/// Reads frames of a sample from wherever it's stored. Only called from the streaming thread.
+pub trait SampleReader: Send + Sync + Debug {
+    /// Reads interleaved frames starting at frame `start` into `out`, returning how many frames
+    /// were read (zero at the end of the sample)
+    fn read(&self, start: usize, out: &mut [f32]) -> usize;
+}
+
+/// The part of a sample that wasn't loaded into memory
+#[derive(Debug, Clone)]
+pub struct StreamedTail {
+    pub reader: Arc<dyn SampleReader>,
+    /// total length of the sample in frames, including the head
+    pub len: usize,
+}
+
+#[derive(Debug, Default)]
+struct Chunk {
+    generation: u32,
+    /// first frame of the chunk, counted from the start of the sample
+    start: usize,
+    frames: usize,
+    channels: usize,
+    data: Vec<f32>,
+}
+
+impl Chunk {
+    fn contains(&self, index: usize) -> bool {
+        index >= self.start && index < self.start + self.frames
+    }
+}
+
+enum Command {
+    Seek {
+        generation: u32,
+        reader: Arc<dyn SampleReader>,
+        start: usize,
+        end: usize,
+        channels: usize,
+    },
+    Stop,
+}
+
+struct Job {
+    generation: u32,
+    reader: Arc<dyn SampleReader>,
+    position: usize,
+    end: usize,
+    channels: usize,
+}
+
+/// The streaming thread's side of a `TailCursor`
+struct StreamerSlot {
+    commands: Consumer<Command>,
+    filled: Producer<Chunk>,
+    returned: Consumer<Chunk>,
+    free: Vec<Chunk>,
+    job: Option<Job>,
+}
+
+impl StreamerSlot {
+    /// Returns whether any chunks were read
+    fn service(&mut self) -> bool {
+        while let Ok(command) = self.commands.pop() {
+            self.job = match command {
+                Command::Seek {
+                    generation,
+                    reader,
+                    start,
+                    end,
+                    channels,
+                } => Some(Job {
+                    generation,
+                    reader,
+                    position: start,
+                    end,
+                    channels,
+                }),
+                Command::Stop => None,
+            };
+        }
+
+        while let Ok(chunk) = self.returned.pop() {
+            self.free.push(chunk);
+        }
+
+        let Some(job) = &mut self.job else {
+            return false;
+        };
+
+        let mut did_work = false;
+
+        while job.position < job.end && !self.filled.is_full() {
+            let Some(mut chunk) = self.free.pop() else {
+                break;
+            };
+
+            chunk.data.resize(CHUNK_FRAMES * job.channels, 0.0);
+
+            let frames = job
+                .reader
+                .read(job.position, &mut chunk.data)
+                .min(job.end - job.position);
+
+            if frames == 0 {
+                // the file is shorter than expected, nothing more to read
+                self.free.push(chunk);
+                job.position = job.end;
+
+                break;
+            }
+
+            chunk.generation = job.generation;
+            chunk.start = job.position;
+            chunk.frames = frames;
+            chunk.channels = job.channels;
+
+            job.position += frames;
+
+            // can't fail, as we checked there's room
+            let _ = self.filled.push(chunk);
+            did_work = true;
+        }
+
+        did_work
+    }
+
+    fn abandoned(&self) -> bool {
+        self.commands.is_abandoned()
+    }
+}
+
+lazy_static! {
+    static ref NEW_SLOTS: Mutex<Vec<StreamerSlot>> = Mutex::new(Vec::new());
+}
+
+static STREAMER_RUNNING: AtomicBool = AtomicBool::new(false);
+/// lookups into the tail that played silence, because the chunk hadn't been read yet
+static UNDERRUNS: AtomicUsize = AtomicUsize::new(0);
+/// chunks that couldn't be handed back to the streaming thread
+static DROPPED_CHUNKS: AtomicUsize = AtomicUsize::new(0);
+
+fn report_problems() {
+    let underruns = UNDERRUNS.swap(0, Ordering::Relaxed);
+    if underruns > 0 {
+        warn!("Streaming fell behind, {} tail lookups played silence", underruns);
+    }
+
+    let dropped = DROPPED_CHUNKS.swap(0, Ordering::Relaxed);
+    if dropped > 0 {
+        warn!("{} streaming chunks were dropped", dropped);
+    }
+}
+
+fn ensure_streamer_running() {
+    if STREAMER_RUNNING.swap(true, Ordering::SeqCst) {
+        return;
+    }
+
+    thread::spawn(|| {
+        let mut slots: Vec<StreamerSlot> = vec![];
+
+        loop {
+            slots.extend(NEW_SLOTS.lock().unwrap().drain(..));
+            slots.retain(|slot| !slot.abandoned());
+
+            let mut did_work = false;
+
+            for slot in &mut slots {
+                did_work |= slot.service();
+            }
+
+            report_problems();
+
+            if !did_work {
+                thread::sleep(IDLE_SLEEP);
+            }
+        }
+    });
+}
+
+struct CursorQueues {
+    commands: Producer<Command>,
+    filled: Consumer<Chunk>,
+    /// has room for every chunk of the slot, so handing one back can't fail
+    returned: Producer<Chunk>,
+}
+
+impl CursorQueues {
+    fn return_chunk(&mut self, chunk: Chunk) {
+        if self.returned.push(chunk).is_err() {
+            DROPPED_CHUNKS.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// A voice's view into a streamed sample. Everything but `new` is realtime safe.
+#[derive(Default)]
+pub struct TailCursor {
+    queues: Option<CursorQueues>,
+    generation: u32,
+    window: VecDeque<Chunk>,
+    pending: Option<Command>,
+}
+
+impl Debug for TailCursor {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TailCursor")
+            .field("streaming", &self.queues.is_some())
+            .field("generation", &self.generation)
+            .finish()
+    }
+}
+
+impl TailCursor {
+    /// Creates a cursor and registers it with the streaming thread. This allocates, so it
+    /// shouldn't be called on the audio thread.
+    pub fn new() -> TailCursor {
+        ensure_streamer_running();
+
+        let (commands, commands_consumer) = RingBuffer::new(COMMAND_CAPACITY);
+        let (filled_producer, filled) = RingBuffer::new(CHUNK_COUNT);
+        let (returned, returned_consumer) = RingBuffer::new(CHUNK_COUNT);
+
+        NEW_SLOTS.lock().unwrap().push(StreamerSlot {
+            commands: commands_consumer,
+            filled: filled_producer,
+            returned: returned_consumer,
+            free: (0..CHUNK_COUNT).map(|_| Chunk::default()).collect(),
+            job: None,
+        });
+
+        TailCursor {
+            queues: Some(CursorQueues {
+                commands,
+                filled,
+                returned,
+            }),
+            generation: 0,
+            window: VecDeque::with_capacity(WINDOW_CHUNKS + 1),
+            pending: None,
+        }
+    }
+
+    pub fn is_streaming(&self) -> bool {
+        self.queues.is_some()
+    }
+
+    /// Starts streaming `sample`'s tail from its beginning. Call this whenever the voice
+    /// jumps back into the head of the sample.
+    pub fn rewind(&mut self, sample: &MultiSample) {
+        if self.queues.is_none() {
+            return;
+        }
+
+        self.generation = self.generation.wrapping_add(1);
+        self.return_window();
+
+        self.pending = sample.tail.as_ref().map(|tail| Command::Seek {
+            generation: self.generation,
+            reader: tail.reader.clone(),
+            start: sample.head_len(),
+            end: tail.len,
+            channels: sample.channel_count(),
+        });
+
+        self.flush_pending();
+    }
+
+    /// Stops reading ahead, for when the voice isn't playing anymore
+    pub fn stop(&mut self) {
+        if self.queues.is_none() {
+            return;
+        }
+
+        self.generation = self.generation.wrapping_add(1);
+        self.return_window();
+
+        self.pending = Some(Command::Stop);
+        self.flush_pending();
+    }
+
+    /// Looks up frame `index` of `channel`, whether it's in memory or streamed
+    pub fn frame(&mut self, sample: &MultiSample, channel: usize, index: usize) -> f32 {
+        if let Some(value) = sample.channels[channel].audio_raw.get(index) {
+            return *value;
+        }
+
+        if index >= sample.len() {
+            return 0.0;
+        }
+
+        self.tail_frame(channel, index).unwrap_or(0.0)
+    }
+
+    pub fn hermite(&mut self, sample: &MultiSample, channel: usize, position: f32) -> f32 {
+        let index = position as usize;
+        let audio = &sample.channels[channel].audio_raw;
+
+        if sample.tail.is_none() || index + 2 < audio.len() {
+            return hermite_lookup(audio, position);
+        }
+
+        let x_minus_1 = if index > 0 {
+            self.frame(sample, channel, index - 1)
+        } else {
+            0.0
+        };
+
+        hermite_interpolate(
+            x_minus_1,
+            self.frame(sample, channel, index),
+            self.frame(sample, channel, index + 1),
+            self.frame(sample, channel, index + 2),
+            position.fract(),
+        )
+    }
+
+    pub fn lerp(&mut self, sample: &MultiSample, channel: usize, position: f32) -> f32 {
+        let index = position as usize;
+
+        lerp(
+            self.frame(sample, channel, index),
+            self.frame(sample, channel, index + 1),
+            position.fract(),
+        )
+    }
+
+    pub fn sinc(&mut self, sample: &MultiSample, channel: usize, position: f32, step: f32) -> f32 {
+        let index = position as usize;
+        let audio = &sample.channels[channel].audio_raw;
+
+        if sample.tail.is_none() || index + SINC_TAPS < audio.len() {
+            return sinc_lookup(audio, position, step);
+        }
+
+        let mut frames = [0.0; SINC_TAPS * 2];
+        for (i, frame) in frames.iter_mut().enumerate() {
+            *frame = match (index + i).checked_sub(SINC_TAPS - 1) {
+                Some(index) => self.frame(sample, channel, index),
+                None => 0.0,
+            };
+        }
+
+        sinc_interpolate(&frames, position.fract(), step)
+    }
+
+    /// `step` is how many frames `position` moves each output frame
+    pub fn interpolate(
+        &mut self,
+        sample: &MultiSample,
+        channel: usize,
+        position: f32,
+        step: f32,
+        interpolation: Interpolation,
+    ) -> f32 {
+        match interpolation {
+            Interpolation::Linear => self.lerp(sample, channel, position),
+            Interpolation::Hermite => self.hermite(sample, channel, position),
+            Interpolation::Sinc => self.sinc(sample, channel, position, step),
+        }
+    }
+
+    fn tail_frame(&mut self, channel: usize, index: usize) -> Option<f32> {
+        self.flush_pending();
+
+        loop {
+            if let Some(chunk) = self.window.iter().find(|chunk| chunk.contains(index)) {
+                return chunk
+                    .data
+                    .get((index - chunk.start) * chunk.channels + channel)
+                    .copied();
+            }
+
+            // the tail is only read front to back, so anything before the window is gone
+            if self.window.front().map(|chunk| index < chunk.start).unwrap_or(false) {
+                return None;
+            }
+
+            let queues = self.queues.as_mut()?;
+            let Ok(chunk) = queues.filled.pop() else {
+                UNDERRUNS.fetch_add(1, Ordering::Relaxed);
+
+                return None;
+            };
+
+            if chunk.generation != self.generation {
+                // left over from before a rewind
+                queues.return_chunk(chunk);
+                continue;
+            }
+
+            self.window.push_back(chunk);
+
+            if self.window.len() > WINDOW_CHUNKS {
+                if let Some(old) = self.window.pop_front() {
+                    queues.return_chunk(old);
+                }
+            }
+        }
+    }
+
+    fn return_window(&mut self) {
+        if let Some(queues) = &mut self.queues {
+            for chunk in self.window.drain(..) {
+                queues.return_chunk(chunk);
+            }
+        }
+    }
+
+    fn flush_pending(&mut self) {
+        if let (Some(queues), Some(command)) = (&mut self.queues, self.pending.take()) {
+            if let Err(rtrb::PushError::Full(command)) = queues.commands.push(command) {
+                // try again next time
+                self.pending = Some(command);
+            }
+        }
+    }
+}
@@ -1,3 +1,4 @@
+use lazy_static::lazy_static;
 use num::Float;
 use std::f32::consts::PI;
 
@@ -47,3 +48,158 @@ pub fn lerp_lookup(sample: &[f32], position: f32) -> f32 {
 pub fn cos_erp(start: f32, end: f32, x: f32) -> f32 {
     f32::cos(x * PI / 2.0) * start + f32::cos((1.0 - x) * PI / 2.0) * end
 }
+
+/// How samples are read between frames, from cheapest to best sounding
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Interpolation {
+    Linear,
+    #[default]
+    Hermite,
+    Sinc,
+}
+
+impl Interpolation {
+    pub fn from_string(interpolation: &str) -> Option<Interpolation> {
+        match interpolation {
+            "linear" => Some(Interpolation::Linear),
+            "hermite" => Some(Interpolation::Hermite),
+            "sinc" => Some(Interpolation::Sinc),
+            _ => None,
+        }
+    }
+}
+
+/// frames used on each side of the position for sinc interpolation
+pub const SINC_TAPS: usize = 8;
+/// how many positions between two frames have a precomputed kernel
+const SINC_PHASES: usize = 256;
+/// how many kernels there are with lower cutoffs, for playing samples back faster than they were
+/// recorded. Each is a quarter of an octave lower than the last.
+const SINC_BANDS: usize = 8;
+
+lazy_static! {
+    /// Blackman windowed sinc kernels, for positions `0 / SINC_PHASES` through `SINC_PHASES / SINC_PHASES`
+    /// of the way between two frames, one table per band. Tap `i` is for the frame `i - (SINC_TAPS - 1)`
+    /// away from the frame before the position.
+    static ref SINC_TABLES: Vec<Vec<[f32; SINC_TAPS * 2]>> = (0..SINC_BANDS)
+        .map(|band| {
+            let cutoff = 2_f64.powf(-(band as f64) / 4.0);
+
+            (0..=SINC_PHASES)
+                .map(|phase| {
+                    let t = phase as f64 / SINC_PHASES as f64;
+                    let mut kernel = [0.0; SINC_TAPS * 2];
+
+                    for (i, tap) in kernel.iter_mut().enumerate() {
+                        let x = i as f64 - (SINC_TAPS - 1) as f64 - t;
+                        let sinc = if x == 0.0 {
+                            1.0
+                        } else {
+                            (std::f64::consts::PI * x * cutoff).sin() / (std::f64::consts::PI * x * cutoff)
+                        };
+
+                        let w = std::f64::consts::PI * x / SINC_TAPS as f64;
+                        let window = 0.42 + 0.5 * w.cos() + 0.08 * (2.0 * w).cos();
+
+                        *tap = sinc * window;
+                    }
+
+                    // so a constant signal comes out unchanged
+                    let sum: f64 = kernel.iter().sum();
+                    kernel.map(|tap| (tap / sum) as f32)
+                })
+                .collect()
+        })
+        .collect();
+}
+
+/// Computes the sinc kernels, so it doesn't happen the first time they're used on the audio thread
+pub fn prepare_sinc() {
+    lazy_static::initialize(&SINC_TABLES);
+}
+
+/// Which kernels to use when moving `step` frames per output frame. Above 1 the sample is being sped
+/// up, so the cutoff has to drop by `1 / step` to keep what's above nyquist from aliasing.
+#[inline]
+fn sinc_band(step: f32) -> usize {
+    if step <= 1.0 {
+        0
+    } else {
+        ((step.log2() * 4.0).ceil() as usize).min(SINC_BANDS - 1)
+    }
+}
+
+/// Interpolates at `t` (0 to 1) between the two middle frames of `frames`, moving `step` frames
+/// per output frame
+#[inline]
+pub fn sinc_interpolate(frames: &[f32; SINC_TAPS * 2], t: f32, step: f32) -> f32 {
+    let table = &SINC_TABLES[sinc_band(step)];
+
+    let phase = t * SINC_PHASES as f32;
+    let index = (phase as usize).min(SINC_PHASES - 1);
+    let amount = phase - index as f32;
+
+    let (a, b) = (&table[index], &table[index + 1]);
+
+    frames
+        .iter()
+        .zip(a.iter().zip(b.iter()))
+        .map(|(frame, (a, b))| frame * lerp(*a, *b, amount))
+        .sum()
+}
+
+#[inline]
+pub fn sinc_lookup(sample: &[f32], position: f32, step: f32) -> f32 {
+    let pos_usize = position as usize;
+    let mut frames = [0.0; SINC_TAPS * 2];
+
+    if pos_usize + 1 >= SINC_TAPS && pos_usize + SINC_TAPS < sample.len() {
+        frames.copy_from_slice(&sample[(pos_usize + 1 - SINC_TAPS)..=(pos_usize + SINC_TAPS)]);
+    } else {
+        for (i, frame) in frames.iter_mut().enumerate() {
+            *frame = (pos_usize + i)
+                .checked_sub(SINC_TAPS - 1)
+                .and_then(|index| sample.get(index))
+                .copied()
+                .unwrap_or(0.0);
+        }
+    }
+
+    sinc_interpolate(&frames, position.fract(), step)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn interpolation_modes_agree_on_smooth_signals() {
+        let sample: Vec<f32> = (0..256).map(|i| (i as f32 * 0.05).sin()).collect();
+
+        for position in [20.0, 20.25, 100.5, 200.9] {
+            let expected = (position * 0.05).sin();
+
+            assert!((sinc_lookup(&sample, position, 1.0) - expected).abs() < 1e-3);
+            assert!((hermite_lookup(&sample, position) - expected).abs() < 1e-3);
+            assert!((lerp_lookup(&sample, position) - expected).abs() < 1e-2);
+        }
+
+        // right on a frame, it's just that frame
+        assert!((sinc_lookup(&sample, 50.0, 1.0) - sample[50]).abs() < 1e-6);
+    }
+
+    #[test]
+    fn sinc_filters_when_sped_up() {
+        // close to nyquist, so it'd alias when played back twice as fast
+        let sample: Vec<f32> = (0..256).map(|i| (i as f32 * PI * 0.9).sin()).collect();
+
+        let peak = |step: f32| {
+            (0..100)
+                .map(|i| sinc_lookup(&sample, 50.0 + i as f32 * 1.37, step).abs())
+                .fold(0.0, f32::max)
+        };
+
+        assert!(peak(1.0) > 0.9);
+        assert!(peak(2.0) < 0.25);
+    }
+}
@@ -17,12 +17,17 @@ use node_engine::node::osc_store::OscStore;
 use node_engine::node::{NodeIndex, NodeState};
 use node_engine::nodes::NodeVariant;
 use node_engine::resources::Resources;
-use node_engine::{io_routing::IoRoutes, node::buffered_traverser::BufferedTraverser, state::FromNodeEngine};
+use node_engine::{
+    io_routing::IoRoutes,
+    node::buffered_traverser::{BufferedTraverser, NodeReplacement},
+    state::FromNodeEngine,
+};
 use sound_engine::SoundConfig;
 
 #[derive(Debug)]
 pub enum ToAudioThread {
     NewTraverser(BufferedTraverser),
+    ReplaceNode(NodeReplacement),
     NewDefaults(Vec<(NodeIndex, Socket, Primitive)>),
     NewNodeStates(Vec<(NodeIndex, serde_json::Value)>),
     CurrentNodeStates(BTreeMap<NodeIndex, NodeState>),
@@ -75,6 +80,11 @@ pub fn start_sound_engine(
                 ToAudioThread::NewTraverser(new_traverser) => {
                     traverser = Some(new_traverser);
                 }
+                ToAudioThread::ReplaceNode(replacement) => {
+                    if let Some(traverser) = &mut traverser {
+                        traverser.replace_node(replacement);
+                    }
+                }
                 ToAudioThread::NewDefaults(defaults) => {
                     new_defaults = defaults;
                 }
@@ -284,6 +294,10 @@ pub fn start_sound_engine(
             if !result.requested_state_updates.is_empty() {
                 let _ = msg_out.send(FromNodeEngine::RequestedStateUpdates(result.requested_state_updates));
             }
+
+            if !result.property_changes.is_empty() {
+                let _ = msg_out.send(FromNodeEngine::PropertyChanges(result.property_changes));
+            }
         }
 
         for (_, (sink, buffer)) in stream_sinks.iter_mut() {
@@ -16,6 +16,8 @@ pub enum EngineError {
     AudioParserError,
     #[snafu(display("Node error: {source}"))]
     NodeError { source: node_engine::errors::NodeError },
+    #[snafu(display("Sample analysis error: {source}"))]
+    AnalysisError { source: sound_engine::error::AnalysisError },
     #[snafu(display("Cpal error: {source}"))]
     CpalError { source: Box<dyn std::error::Error> },
     #[snafu(display("Symphonia error: {source}"))]
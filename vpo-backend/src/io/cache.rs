@@ -0,0 +1,453 @@
+//! A per project cache of decoded samples and pipe envelope tables, so unchanged resources
+//! don't have to be decoded and analyzed again on every load. It lives in `.cache` next to the
+//! project file, and can be deleted at any time.
+//!
+//! Every cache file starts with the same header: magic bytes, the cache version, and the length,
+//! modification time and hash of the file it was made from. A cache file is used if the length
+//! and modification time still match, or failing that, the hash does. Anything that fails to
+//! read is treated as a miss, so the cache can never stop a project from loading.
+
+use std::{
+    fs::{self, File},
+    io::{self, BufReader, BufWriter, Cursor, Read, Seek, Write},
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicUsize, Ordering},
+    time::UNIX_EPOCH,
+};
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use common::resource_manager::ResourceId;
+use log::{trace, warn};
+use sound_engine::{
+    sampling::pipe_player::{envelope_indexes, EnvelopeIndexes, EnvelopeType, ENVELOPE_POINTS},
+    MonoSample, MultiSample, SoundConfig,
+};
+
+use crate::{
+    errors::EngineError,
+    resource::{
+        rank::EnvelopeSource,
+        sample::{decode_sample, load_sample},
+    },
+};
+
+pub const CACHE_DIRECTORY: &str = ".cache";
+
+/// bump whenever the layout of a cache file changes
+const CACHE_VERSION: u32 = 1;
+const MAGIC: &[u8; 4] = b"VPOC";
+
+const SAMPLE_HEADER_ONLY: u8 = 0;
+const SAMPLE_WITH_AUDIO: u8 = 1;
+
+/// What a cache file was made from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Source {
+    len: u64,
+    /// in nanoseconds since the unix epoch
+    modified: u64,
+    hash: u64,
+}
+
+pub struct ResourceCache {
+    project_directory: PathBuf,
+    directory: PathBuf,
+}
+
+impl ResourceCache {
+    pub fn new(project_directory: &Path) -> ResourceCache {
+        ResourceCache {
+            project_directory: project_directory.to_path_buf(),
+            directory: project_directory.join(CACHE_DIRECTORY),
+        }
+    }
+
+    /// Loads a sample from the cache if it's there, otherwise decodes it and caches the result
+    pub fn load_sample(&self, file: &Path, sound_config: &SoundConfig) -> Result<MultiSample, EngineError> {
+        let cache_file = self.sample_cache_file(file);
+        let mut contents = None;
+
+        match read_cached_sample(file, &cache_file, &mut contents) {
+            Ok(Some(sample)) => {
+                trace!("sample {:?} loaded from cache", file);
+
+                return Ok(sample);
+            }
+            Ok(None) => {}
+            Err(err) => trace!("could not read cached sample {:?}: {}", cache_file, err),
+        }
+
+        // the file is read once, both to decode it and to hash it for the cache
+        let contents = match contents {
+            Some(contents) => contents,
+            None => match fs::read(file) {
+                Ok(contents) => contents,
+                Err(_) => return load_sample(file, sound_config),
+            },
+        };
+        let hash = seahash::hash(&contents);
+
+        let sample = decode_sample(Box::new(Cursor::new(contents)), file)?;
+
+        let cached = len_and_modified(file).and_then(|(len, modified)| {
+            write_cached_sample(&cache_file, Source { len, modified, hash }, Some(&sample))
+        });
+        if let Err(err) = cached {
+            warn!("could not cache sample {:?}: {}", file, err);
+        }
+
+        Ok(sample)
+    }
+
+    fn sample_cache_file(&self, file: &Path) -> PathBuf {
+        let relative = file.strip_prefix(&self.project_directory).unwrap_or(file);
+
+        self.directory.join("samples").join(format!(
+            "{:016x}.bin",
+            seahash::hash(relative.to_string_lossy().as_bytes())
+        ))
+    }
+
+    /// Hash of a sample file's contents. Goes by the sample's cache file if it's up to date,
+    /// as hashing means reading the whole file.
+    fn sample_hash(&self, file: &Path) -> io::Result<u64> {
+        let cache_file = self.sample_cache_file(file);
+        let (len, modified) = len_and_modified(file)?;
+
+        let cached = File::open(&cache_file)
+            .and_then(|cached| read_header(&mut BufReader::new(cached)))
+            .ok()
+            .flatten();
+
+        if let Some(cached) = cached {
+            if cached.len == len && cached.modified == modified {
+                return Ok(cached.hash);
+            }
+        }
+
+        let source = source_of(file)?;
+
+        // remember the hash for next time, without the audio in case it's being streamed
+        if cached.map(|cached| cached.hash != source.hash).unwrap_or(true) {
+            let _ = write_cached_sample(&cache_file, source, None);
+        }
+
+        Ok(source.hash)
+    }
+}
+
+impl EnvelopeSource for ResourceCache {
+    fn envelopes(
+        &self,
+        resource: &ResourceId,
+        sample: &MonoSample,
+        decay_index: usize,
+        release_index: usize,
+        window_size: usize,
+    ) -> (EnvelopeIndexes, EnvelopeIndexes) {
+        let calculate = || {
+            (
+                envelope_indexes(decay_index, release_index, sample, window_size, EnvelopeType::Attack),
+                envelope_indexes(decay_index, release_index, sample, window_size, EnvelopeType::Release),
+            )
+        };
+
+        let sample_file = self
+            .project_directory
+            .join(&resource.namespace)
+            .join(&resource.resource);
+        let Ok(sample_hash) = self.sample_hash(&sample_file) else {
+            return calculate();
+        };
+
+        // the tables only depend on the audio and these, so they're all the key needs (the
+        // sample rate and length change if the sample was resampled when loading)
+        let mut key = Vec::with_capacity(48);
+        for part in [
+            sample_hash,
+            sample.sample_rate as u64,
+            sample.audio_raw.len() as u64,
+            decay_index as u64,
+            release_index as u64,
+            window_size as u64,
+        ] {
+            key.extend_from_slice(&part.to_le_bytes());
+        }
+
+        let cache_file = self
+            .directory
+            .join("envelopes")
+            .join(format!("{:016x}.bin", seahash::hash(&key)));
+
+        if let Ok(envelopes) = read_cached_envelopes(&cache_file) {
+            return envelopes;
+        }
+
+        let envelopes = calculate();
+
+        if let Err(err) = write_cached_envelopes(&cache_file, &envelopes) {
+            warn!("could not cache envelopes {:?}: {}", cache_file, err);
+        }
+
+        envelopes
+    }
+}
+
+fn len_and_modified(file: &Path) -> io::Result<(u64, u64)> {
+    let metadata = fs::metadata(file)?;
+    let modified = metadata
+        .modified()?
+        .duration_since(UNIX_EPOCH)
+        .map(|since| since.as_nanos() as u64)
+        .unwrap_or(0);
+
+    Ok((metadata.len(), modified))
+}
+
+fn source_of(file: &Path) -> io::Result<Source> {
+    let (len, modified) = len_and_modified(file)?;
+    let hash = seahash::hash(&fs::read(file)?);
+
+    Ok(Source { len, modified, hash })
+}
+
+fn write_header(writer: &mut impl Write, source: Source) -> io::Result<()> {
+    writer.write_all(MAGIC)?;
+    writer.write_u32::<LittleEndian>(CACHE_VERSION)?;
+    writer.write_u64::<LittleEndian>(source.len)?;
+    writer.write_u64::<LittleEndian>(source.modified)?;
+    writer.write_u64::<LittleEndian>(source.hash)
+}
+
+/// `None` if the file is from another version of the cache
+fn read_header(reader: &mut impl Read) -> io::Result<Option<Source>> {
+    let mut magic = [0; 4];
+    reader.read_exact(&mut magic)?;
+
+    if &magic != MAGIC || reader.read_u32::<LittleEndian>()? != CACHE_VERSION {
+        return Ok(None);
+    }
+
+    Ok(Some(Source {
+        len: reader.read_u64::<LittleEndian>()?,
+        modified: reader.read_u64::<LittleEndian>()?,
+        hash: reader.read_u64::<LittleEndian>()?,
+    }))
+}
+
+/// Writes to a temporary file first, so a half written cache file is never read
+fn write_atomically(cache_file: &Path, write: impl FnOnce(&mut BufWriter<File>) -> io::Result<()>) -> io::Result<()> {
+    if let Some(parent) = cache_file.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    // ranks load in parallel, and might race to cache the same thing
+    static NEXT_TEMPORARY: AtomicUsize = AtomicUsize::new(0);
+    let temporary = cache_file.with_extension(format!("{}.tmp", NEXT_TEMPORARY.fetch_add(1, Ordering::Relaxed)));
+
+    let mut writer = BufWriter::new(File::create(&temporary)?);
+    write(&mut writer)?;
+    writer.flush()?;
+    drop(writer);
+
+    fs::rename(temporary, cache_file)
+}
+
+fn write_cached_sample(cache_file: &Path, source: Source, sample: Option<&MultiSample>) -> io::Result<()> {
+    write_atomically(cache_file, |writer| {
+        write_header(writer, source)?;
+
+        let Some(sample) = sample else {
+            return writer.write_u8(SAMPLE_HEADER_ONLY);
+        };
+
+        writer.write_u8(SAMPLE_WITH_AUDIO)?;
+        writer.write_u32::<LittleEndian>(sample.sample_rate())?;
+        writer.write_u32::<LittleEndian>(sample.channel_count() as u32)?;
+        writer.write_u64::<LittleEndian>(sample.head_len() as u64)?;
+
+        for channel in &sample.channels {
+            for point in &channel.audio_raw {
+                writer.write_f32::<LittleEndian>(*point)?;
+            }
+        }
+
+        Ok(())
+    })
+}
+
+/// `None` if there's no usable cached audio for `file`. If `file` had to be read to check its
+/// hash, its contents are left in `contents`.
+fn read_cached_sample(
+    file: &Path,
+    cache_file: &Path,
+    contents: &mut Option<Vec<u8>>,
+) -> io::Result<Option<MultiSample>> {
+    if !cache_file.exists() {
+        return Ok(None);
+    }
+
+    let cache_file = File::open(cache_file)?;
+    let cache_len = cache_file.metadata()?.len();
+    let mut reader = BufReader::new(cache_file);
+
+    let Some(cached) = read_header(&mut reader)? else {
+        return Ok(None);
+    };
+
+    let (len, modified) = len_and_modified(file)?;
+    if cached.len != len || cached.modified != modified {
+        let read = fs::read(file)?;
+        let hash = seahash::hash(&read);
+        *contents = Some(read);
+
+        if cached.hash != hash {
+            return Ok(None);
+        }
+    }
+
+    if reader.read_u8()? != SAMPLE_WITH_AUDIO {
+        return Ok(None);
+    }
+
+    let sample_rate = reader.read_u32::<LittleEndian>()?;
+    let channels = reader.read_u32::<LittleEndian>()? as usize;
+    let frames = reader.read_u64::<LittleEndian>()?;
+
+    // a corrupt header shouldn't be able to ask for more memory than the file holds
+    let audio_len = (channels.max(1) as u64)
+        .checked_mul(frames)
+        .and_then(|points| points.checked_mul(4));
+    if audio_len != Some(cache_len.saturating_sub(reader.stream_position()?)) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "cached sample has the wrong length",
+        ));
+    }
+
+    let frames = frames as usize;
+
+    let channels = (0..channels.max(1))
+        .map(|_| {
+            let mut audio_raw = vec![0.0; frames];
+            reader.read_f32_into::<LittleEndian>(&mut audio_raw)?;
+
+            Ok(MonoSample { audio_raw, sample_rate })
+        })
+        .collect::<io::Result<Vec<MonoSample>>>()?;
+
+    Ok(Some(MultiSample {
+        channels,
+        tail: None,
+        source_sample_rate: None,
+    }))
+}
+
+fn write_cached_envelopes(cache_file: &Path, envelopes: &(EnvelopeIndexes, EnvelopeIndexes)) -> io::Result<()> {
+    write_atomically(cache_file, |writer| {
+        // envelope files are keyed by everything they depend on, so there's no source to go by
+        write_header(
+            writer,
+            Source {
+                len: 0,
+                modified: 0,
+                hash: 0,
+            },
+        )?;
+
+        for envelope in [&envelopes.0, &envelopes.1] {
+            for index in envelope.indexes {
+                writer.write_u64::<LittleEndian>(index as u64)?;
+            }
+
+            writer.write_f32::<LittleEndian>(envelope.peak_amp)?;
+        }
+
+        Ok(())
+    })
+}
+
+fn read_cached_envelopes(cache_file: &Path) -> io::Result<(EnvelopeIndexes, EnvelopeIndexes)> {
+    let mut reader = BufReader::new(File::open(cache_file)?);
+
+    if read_header(&mut reader)?.is_none() {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "outdated cache file"));
+    }
+
+    let mut read_envelope = || -> io::Result<EnvelopeIndexes> {
+        let mut indexes = [0; ENVELOPE_POINTS];
+        for index in indexes.iter_mut() {
+            *index = reader.read_u64::<LittleEndian>()? as usize;
+        }
+
+        Ok(EnvelopeIndexes {
+            indexes,
+            peak_amp: reader.read_f32::<LittleEndian>()?,
+        })
+    };
+
+    Ok((read_envelope()?, read_envelope()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs};
+
+    use sound_engine::{MonoSample, MultiSample};
+
+    use super::*;
+
+    fn sample() -> MultiSample {
+        MultiSample {
+            channels: vec![
+                MonoSample {
+                    audio_raw: vec![0.0, 0.5, -0.5, 1.0],
+                    sample_rate: 44_100,
+                },
+                MonoSample {
+                    audio_raw: vec![0.25, 0.0, -1.0, 0.75],
+                    sample_rate: 44_100,
+                },
+            ],
+            tail: None,
+            source_sample_rate: None,
+        }
+    }
+
+    #[test]
+    fn samples_are_only_used_while_their_source_is_unchanged() {
+        let directory = env::temp_dir().join(format!("vpo-cache-test-{}", std::process::id()));
+        fs::create_dir_all(&directory).unwrap();
+
+        let source_file = directory.join("source.wav");
+        let cache_file = directory.join("cache.bin");
+        fs::write(&source_file, b"original").unwrap();
+
+        write_cached_sample(&cache_file, source_of(&source_file).unwrap(), Some(&sample())).unwrap();
+
+        let cached = read_cached_sample(&source_file, &cache_file, &mut None)
+            .unwrap()
+            .unwrap();
+        assert_eq!(cached.channel_count(), 2);
+        assert_eq!(cached.channels[1].audio_raw, sample().channels[1].audio_raw);
+        assert_eq!(cached.sample_rate(), 44_100);
+
+        // a truncated cache file is an error, rather than a read past its end
+        let cached_bytes = fs::read(&cache_file).unwrap();
+        fs::write(&cache_file, &cached_bytes[..cached_bytes.len() - 4]).unwrap();
+        assert!(read_cached_sample(&source_file, &cache_file, &mut None).is_err());
+
+        fs::write(&source_file, b"modified sample").unwrap();
+        assert!(read_cached_sample(&source_file, &cache_file, &mut None)
+            .unwrap()
+            .is_none());
+
+        // only the header is there to go by
+        write_cached_sample(&cache_file, source_of(&source_file).unwrap(), None).unwrap();
+        assert!(read_cached_sample(&source_file, &cache_file, &mut None)
+            .unwrap()
+            .is_none());
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+}
@@ -1,10 +1,16 @@
+pub mod cache;
 pub mod clocked;
 pub mod file_watcher;
+pub mod presets;
+pub mod recovery;
 pub mod scoped_pool;
+pub mod settings;
+pub mod split;
 
+use std::collections::BTreeMap;
 use std::fmt::Debug;
 use std::fs;
-use std::path::{Path, PathBuf};
+use std::path::{Component, Path, PathBuf};
 use std::sync::mpsc;
 use std::time::Instant;
 
@@ -12,48 +18,158 @@ use lazy_static::lazy_static;
 
 use common::resource_manager::ResourceManager;
 use log::{info, trace};
+use node_engine::graph_manager::ModuleDefinition;
 use node_engine::resources::Resources;
-use node_engine::state::GraphState;
+use node_engine::state::{GraphState, SavedHistory};
 use notify::{Config, Error, Event, RecommendedWatcher, RecursiveMode, Watcher};
 #[cfg(windows)]
 use path_slash::PathExt;
 use rayon::iter::{ParallelBridge, ParallelIterator};
 use semver::Version;
 use serde_json::{json, Value};
-use snafu::{OptionExt, ResultExt};
-use sound_engine::SoundConfig;
+use snafu::{ensure_whatever, OptionExt, ResultExt};
+use sound_engine::{sampling::resample::resample_sample, MultiSample, SoundConfig};
 use walkdir::WalkDir;
 
-use crate::errors::{IoSnafu, JsonParserInContextSnafu, JsonParserSnafu};
+use crate::errors::{IoSnafu, JsonParserInContextSnafu, JsonParserSnafu, NodeSnafu};
 
 use crate::errors::EngineError;
-use crate::migrations::migrate;
-use crate::resource::rank::load_rank_from_file;
-use crate::resource::sample::load_sample;
+use crate::io::cache::ResourceCache;
+use crate::io::settings::{ProjectFormat, ProjectSettings};
+use crate::io::split::{load_split, save_split, SplitProject};
+use crate::migrations::{file_version, migrate};
+use crate::resource::rank::{load_rank_from_file, resident_frames, EnvelopeSource, Uncached};
+use crate::resource::sample::{load_sample, load_sample_streamed};
 use crate::resource::ui::load_ui_from_file;
 
-const AUDIO_EXTENSIONS: &[&str] = &["ogg", "wav", "mp3", "flac"];
+pub(crate) const AUDIO_EXTENSIONS: &[&str] = &["ogg", "wav", "mp3", "flac"];
 lazy_static! {
     pub static ref VERSION: Version = Version::parse("0.5.0").unwrap();
 }
 
-pub fn save(state: &GraphState, path: &Path) -> Result<(), EngineError> {
-    let state = json!({
-        "version": VERSION.to_string(),
-        "state": state.to_json()
-    });
+pub const HISTORY_FILE: &str = "history.json";
 
+pub fn save(state: &GraphState, path: &Path, settings: &ProjectSettings) -> Result<(), EngineError> {
     let parent = path
         .parent()
         .whatever_context(format!("path {:?} has no parent", path))?;
 
     fs::create_dir_all(parent.join("samples")).context(IoSnafu)?;
     fs::create_dir_all(parent.join("ranks")).context(IoSnafu)?;
-    fs::write(path, serde_json::to_string_pretty(&state).context(JsonParserSnafu)?).context(IoSnafu)?;
+
+    let project_raw = match settings.format {
+        ProjectFormat::Single => {
+            let project = json!({
+                "version": VERSION.to_string(),
+                "state": state.to_json()
+            });
+
+            let project_raw = serde_json::to_string_pretty(&project).context(JsonParserSnafu)?;
+            fs::write(path, &project_raw).context(IoSnafu)?;
+
+            project_raw
+        }
+        ProjectFormat::Split => save_split(state, path)?,
+    };
+
+    save_modules(state, &parent.join("modules"))?;
+
+    if settings.history.persistent {
+        save_history(
+            state,
+            &parent.join(HISTORY_FILE),
+            &project_raw,
+            settings.history.max_entries,
+        )?;
+    }
 
     Ok(())
 }
 
+/// The history only makes sense on top of the project it was saved with, so it keeps a hash of
+/// the project files to check against
+fn save_history(
+    state: &GraphState,
+    path: &Path,
+    project_contents: &str,
+    max_entries: usize,
+) -> Result<(), EngineError> {
+    let history = json!({
+        "version": VERSION.to_string(),
+        "projectHash": seahash::hash(project_contents.as_bytes()),
+        "history": state.export_history(max_entries)
+    });
+
+    fs::write(path, serde_json::to_string(&history).context(JsonParserSnafu)?).context(IoSnafu)?;
+
+    Ok(())
+}
+
+fn load_history(path: &Path, project_contents: &str) -> Option<SavedHistory> {
+    let mut json: Value = serde_json::from_str(&fs::read_to_string(path).ok()?).ok()?;
+
+    if json["version"].as_str()? != VERSION.to_string()
+        || json["projectHash"].as_u64()? != seahash::hash(project_contents.as_bytes())
+    {
+        info!("Project changed since the history was saved, not loading it");
+
+        return None;
+    }
+
+    serde_json::from_value(json["history"].take()).ok()
+}
+
+fn save_modules(state: &GraphState, directory: &Path) -> Result<(), EngineError> {
+    fs::create_dir_all(directory).context(IoSnafu)?;
+
+    for (name, definition) in state.export_modules().context(NodeSnafu)? {
+        let module = json!({
+            "version": VERSION.to_string(),
+            "module": definition
+        });
+
+        let module_path = module_path(directory, &name)?;
+
+        // module names can have folders in them
+        if let Some(module_directory) = module_path.parent() {
+            fs::create_dir_all(module_directory).context(IoSnafu)?;
+        }
+
+        fs::write(
+            module_path,
+            serde_json::to_string_pretty(&module).context(JsonParserSnafu)?,
+        )
+        .context(IoSnafu)?;
+    }
+
+    Ok(())
+}
+
+/// Where the module `name` is saved in `directory`
+fn module_path(directory: &Path, name: &str) -> Result<PathBuf, EngineError> {
+    // folders are fine, as long as the module stays inside of `directory`
+    ensure_whatever!(is_relative_name(name), "Invalid module name `{}`", name);
+
+    Ok(directory.join(format!("{name}.json")))
+}
+
+/// Reads the module definitions in a directory, named by their path without the extension
+fn load_modules(directory: &Path) -> Vec<(String, ModuleDefinition)> {
+    WalkDir::new(directory)
+        .follow_links(true)
+        .into_iter()
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.path().extension().map(|ext| ext == "json").unwrap_or(false))
+        .filter_map(|entry| {
+            let mut json: Value = serde_json::from_str(&fs::read_to_string(entry.path()).ok()?).ok()?;
+            let definition = serde_json::from_value(json["module"].take()).ok()?;
+            let name = get_resource_key(&entry.path().strip_prefix(directory).ok()?.with_extension(""));
+
+            Some((name, definition))
+        })
+        .collect()
+}
+
 fn load_resources<T, F>(
     path: &Path,
     valid_extensions: &[&str],
@@ -111,12 +227,80 @@ where
     Ok(resources)
 }
 
+/// Collects how much of each sample needs to stay in memory when streaming, across all ranks
+fn collect_resident_frames(ranks_directory: &Path) -> BTreeMap<String, usize> {
+    let mut resident: BTreeMap<String, usize> = BTreeMap::new();
+
+    let configs = WalkDir::new(ranks_directory)
+        .follow_links(true)
+        .into_iter()
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.path().extension().map(|ext| ext == "toml").unwrap_or(false))
+        .filter_map(|entry| fs::read_to_string(entry.path()).ok());
+
+    for config in configs {
+        if let Ok(frames) = resident_frames(&config) {
+            for (sample, frames) in frames {
+                let entry = resident.entry(sample).or_insert(0);
+                *entry = (*entry).max(frames);
+            }
+        }
+    }
+
+    resident
+}
+
+/// Loads a sample, streaming it if the project asks for it and a rank uses it. Samples
+/// not used by any rank (wavetables and such) are always loaded completely.
+fn load_project_sample(
+    samples_directory: &Path,
+    file: &Path,
+    resident: Option<&BTreeMap<String, usize>>,
+    settings: &ProjectSettings,
+    cache: Option<&ResourceCache>,
+    config: &SoundConfig,
+) -> Result<MultiSample, EngineError> {
+    let resident_frames = resident.and_then(|resident| {
+        let key = get_resource_key(file.strip_prefix(samples_directory).ok()?);
+
+        resident.get(&key).copied()
+    });
+
+    let sample = match (resident_frames, cache) {
+        (Some(frames), _) => return load_sample_streamed(file, frames, settings.streaming.preload_ms, config),
+        (None, Some(cache)) => cache.load_sample(file, config)?,
+        (None, None) => load_sample(file, config)?,
+    };
+
+    if settings.resampling.enabled && sample.sample_rate() != config.sample_rate {
+        Ok(resample_sample(
+            &sample,
+            config.sample_rate,
+            settings.resampling.quality,
+        ))
+    } else {
+        Ok(sample)
+    }
+}
+
+fn project_cache(root: &Path, settings: &ProjectSettings) -> Option<ResourceCache> {
+    settings.cache.enabled.then(|| ResourceCache::new(root))
+}
+
+fn envelope_source(cache: &Option<ResourceCache>) -> &dyn EnvelopeSource {
+    match cache {
+        Some(cache) => cache,
+        None => &Uncached,
+    }
+}
+
 /// Make sure samples are loaded before ranks!
 pub fn load_single(
     root: &Path,
     file: &Path,
     resources: &mut Resources,
     config: SoundConfig,
+    settings: &ProjectSettings,
 ) -> Result<(), EngineError> {
     let relative_file = file
         .strip_prefix(root)
@@ -127,21 +311,32 @@ pub fn load_single(
 
     info!("loading resource: `{:?}` of type {:?}", resource_key, resource_type);
 
+    let cache = project_cache(root, settings);
+
     match resource_type.to_string_lossy().as_ref() {
         "ranks" => {
-            if resources.ranks.get_index(resource_key.as_ref()).is_some() {
-                resources.ranks.remove_resource(resource_key.as_ref());
-            }
-
-            let rank = load_rank_from_file(file, &resources.samples)?;
-            resources.ranks.add_resource(resource_key, rank);
+            // replaced in place, so running rank players pick up the changes
+            let rank = load_rank_from_file(file, &resources.samples, envelope_source(&cache))?;
+            resources.ranks.replace_resource(resource_key, rank);
         }
         "samples" => {
             if resources.samples.get_index(resource_key.as_ref()).is_some() {
                 resources.samples.remove_resource(resource_key.as_ref());
             }
 
-            let sample = load_sample(file, &config)?;
+            let resident = settings
+                .streaming
+                .enabled
+                .then(|| collect_resident_frames(&root.join("ranks")));
+
+            let sample = load_project_sample(
+                &root.join("samples"),
+                file,
+                resident.as_ref(),
+                settings,
+                cache.as_ref(),
+                &config,
+            )?;
             resources.samples.add_resource(resource_key, sample);
         }
         "ui" => {
@@ -161,6 +356,7 @@ pub fn load_single(
 pub fn load_state(
     path: &Path,
     config: SoundConfig,
+    settings: &ProjectSettings,
     state: &mut GraphState,
     resources: &mut Resources,
 ) -> Result<mpsc::Receiver<Result<Event, Error>>, EngineError> {
@@ -171,26 +367,49 @@ pub fn load_state(
     let json_raw = fs::read_to_string(path).context(IoSnafu)?;
     let json: Value = serde_json::from_str(&json_raw).context(JsonParserSnafu)?;
 
-    let file_version = json["version"]
-        .as_str()
-        .whatever_context("Version number missing in file".to_string())?;
+    let file_version = file_version(&json)?;
 
-    if file_version != VERSION.to_string() {
-        migrate(PathBuf::from(path))?;
-    }
+    let (json_raw, mut json) = if file_version != *VERSION {
+        info!("Migrating project from {} to {}", file_version, *VERSION);
 
-    // read again after migrating (TODO: only do when necessary)
-    let json_raw = fs::read_to_string(path).context(IoSnafu)?;
-    let mut json: Value = serde_json::from_str(&json_raw).context(JsonParserSnafu)?;
+        let migrated = migrate(json)?;
+        let migrated_raw = serde_json::to_string_pretty(&migrated).context(JsonParserSnafu)?;
+
+        // keep the original around, in case the migration got something wrong
+        let mut backup = path.as_os_str().to_owned();
+        backup.push(format!(".{}.bak", file_version));
+
+        fs::copy(path, backup).context(IoSnafu)?;
+        fs::write(path, &migrated_raw).context(IoSnafu)?;
+
+        (migrated_raw, migrated)
+    } else {
+        (json_raw, json)
+    };
 
     info!("Loading resources...");
     let time = Instant::now();
 
-    let samples = load_resources(&parent.join("samples"), AUDIO_EXTENSIONS, &|path| {
-        load_sample(path, &config)
+    let resident = settings
+        .streaming
+        .enabled
+        .then(|| collect_resident_frames(&parent.join("ranks")));
+
+    let cache = project_cache(parent, settings);
+
+    let samples_directory = parent.join("samples");
+    let samples = load_resources(&samples_directory, AUDIO_EXTENSIONS, &|path| {
+        load_project_sample(
+            &samples_directory,
+            path,
+            resident.as_ref(),
+            settings,
+            cache.as_ref(),
+            &config,
+        )
     })?;
     let ranks = load_resources(&parent.join("ranks"), &["toml"], &|path| {
-        load_rank_from_file(path, &samples)
+        load_rank_from_file(path, &samples, envelope_source(&cache))
     })?;
     let ui = load_resources(&parent.join("ui"), &["toml"], &load_ui_from_file)?;
 
@@ -200,15 +419,37 @@ pub fn load_state(
 
     info!("Loaded! Took {:?}", time.elapsed());
 
-    let json_state = &mut json["state"];
-
-    let graph_manager = serde_json::from_value(json_state["graphManager"].take()).context(JsonParserSnafu)?;
-    let root_graph_index = serde_json::from_value(json_state["rootGraphIndex"].take()).context(JsonParserSnafu)?;
-    let io_routing = serde_json::from_value(json_state["ioRouting"].take()).context(JsonParserInContextSnafu {
-        context: "state.ioRouting".to_string(),
-    })?;
+    // the split format can be opened no matter what the settings say
+    let SplitProject {
+        graph_manager,
+        root_graph_index,
+        io_routing,
+        contents,
+    } = if json["format"].as_str() == Some("split") {
+        load_split(parent, &json_raw)?
+    } else {
+        let json_state = &mut json["state"];
+
+        SplitProject {
+            graph_manager: serde_json::from_value(json_state["graphManager"].take()).context(JsonParserSnafu)?,
+            root_graph_index: serde_json::from_value(json_state["rootGraphIndex"].take()).context(JsonParserSnafu)?,
+            io_routing: serde_json::from_value(json_state["ioRouting"].take()).context(JsonParserInContextSnafu {
+                context: "state.ioRouting".to_string(),
+            })?,
+            contents: json_raw,
+        }
+    };
 
     state.load_state(graph_manager, root_graph_index, io_routing);
+    let modules_changed = state
+        .import_modules(load_modules(&parent.join("modules")))
+        .context(NodeSnafu)?;
+
+    if settings.history.persistent && !modules_changed {
+        if let Some(history) = load_history(&parent.join(HISTORY_FILE), &contents) {
+            state.import_history(history);
+        }
+    }
 
     let (tx, rx) = mpsc::channel();
     let mut watcher =
@@ -221,6 +462,14 @@ pub fn load_state(
     Ok(rx)
 }
 
+/// Whether `name` is only made of plain path components, so joining it onto a directory stays
+/// inside of it
+pub fn is_relative_name(name: &str) -> bool {
+    let mut components = Path::new(name).components().peekable();
+
+    components.peek().is_some() && components.all(|component| matches!(component, Component::Normal(_)))
+}
+
 fn get_resource_key(path: &Path) -> String {
     #[cfg(windows)]
     let asset_key = path.to_slash_lossy().to_string();
@@ -230,3 +479,80 @@ fn get_resource_key(path: &Path) -> String {
 
     asset_key
 }
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use common::SeaHashMap;
+    use node_engine::state::{Action, ActionBundle, ActionInvalidation};
+
+    use super::*;
+
+    #[test]
+    fn modules_and_history_survive_reopening() {
+        let directory = env::temp_dir().join(format!("vpo-modules-test-{}", std::process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        let path = directory.join("project.mjuo");
+
+        let mut settings = ProjectSettings::default();
+        settings.history.persistent = true;
+
+        let mut state = GraphState::new(SoundConfig::default());
+        let root = state.get_root_graph_index();
+
+        let function_node = state
+            .commit(
+                ActionBundle::new(vec![Action::CreateNode {
+                    graph: root,
+                    node_type: "FunctionNode".into(),
+                    ui_data: SeaHashMap::default(),
+                }]),
+                false,
+            )
+            .unwrap()
+            .into_iter()
+            .find_map(|invalidation| match invalidation {
+                ActionInvalidation::NewNode(index) => Some(index),
+                _ => None,
+            })
+            .unwrap();
+
+        state
+            .commit(
+                ActionBundle::new(vec![Action::CreateModule {
+                    index: function_node,
+                    name: "effects/pipe chain".into(),
+                }]),
+                false,
+            )
+            .unwrap();
+
+        save(&state, &path, &settings).unwrap();
+        assert!(directory
+            .join("modules")
+            .join("effects")
+            .join("pipe chain.json")
+            .exists());
+
+        let mut reopened = GraphState::new(SoundConfig::default());
+        load_state(
+            &path,
+            SoundConfig::default(),
+            &settings,
+            &mut reopened,
+            &mut Resources::default(),
+        )
+        .unwrap();
+
+        // the module on disk is the one the project was saved with, so nothing got reimported
+        assert_eq!(reopened.get_history().len(), 2);
+        assert!(reopened.create_traverser(&Resources::default()).is_ok());
+
+        assert!(module_path(&directory, "effects/pipe chain").is_ok());
+        assert!(module_path(&directory, "../escape").is_err());
+        assert!(module_path(&directory, "/escape").is_err());
+
+        fs::remove_dir_all(directory).unwrap();
+    }
+}
@@ -0,0 +1,199 @@
+//! Named sets of properties and default overrides for a node type. Presets live either in the
+//! user's config directory, shared between projects, or in the project's `presets/` directory.
+//! Each is stored as `<node type>/<name>.json`.
+
+use std::env;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+
+use common::SeaHashMap;
+use node_engine::node::NodeRow;
+use node_engine::node_instance::NodeInstance;
+use node_engine::property::Property;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use snafu::{ensure_whatever, OptionExt, ResultExt};
+
+use crate::errors::{EngineError, IoSnafu, JsonParserInContextSnafu, JsonParserSnafu};
+
+use super::VERSION;
+
+pub const PRESETS_DIRECTORY: &str = "presets";
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct Preset {
+    pub node_type: String,
+    pub properties: SeaHashMap<String, Property>,
+    pub default_overrides: Vec<NodeRow>,
+}
+
+impl Preset {
+    pub fn from_node(node: &NodeInstance) -> Preset {
+        Preset {
+            node_type: node.get_node_type(),
+            properties: node.get_properties().clone(),
+            default_overrides: node.get_default_overrides().clone(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum PresetLocation {
+    /// shared between all projects
+    User,
+    Project,
+}
+
+#[derive(Debug, Clone, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct PresetSummary {
+    pub name: String,
+    pub node_type: String,
+    pub location: PresetLocation,
+}
+
+pub fn user_presets_directory() -> Option<PathBuf> {
+    let config_directory = if cfg!(windows) {
+        env::var_os("APPDATA").map(PathBuf::from)
+    } else {
+        env::var_os("XDG_CONFIG_HOME")
+            .map(PathBuf::from)
+            .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))
+    };
+
+    config_directory.map(|directory| directory.join("mjuo").join(PRESETS_DIRECTORY))
+}
+
+pub fn presets_directory(location: PresetLocation, project_directory: Option<&Path>) -> Result<PathBuf, EngineError> {
+    match location {
+        PresetLocation::User => user_presets_directory().whatever_context("Could not find the user config directory"),
+        PresetLocation::Project => Ok(project_directory
+            .whatever_context("Save the project before adding presets to it")?
+            .join(PRESETS_DIRECTORY)),
+    }
+}
+
+fn preset_path(directory: &Path, node_type: &str, name: &str) -> Result<PathBuf, EngineError> {
+    // keep presets from being written anywhere else
+    for part in [node_type, name] {
+        let mut components = Path::new(part).components();
+
+        ensure_whatever!(
+            matches!(
+                (components.next(), components.next()),
+                (Some(Component::Normal(_)), None)
+            ),
+            "Invalid preset name `{}`",
+            part
+        );
+    }
+
+    Ok(directory.join(node_type).join(format!("{name}.json")))
+}
+
+pub fn save_preset(directory: &Path, name: &str, preset: &Preset) -> Result<(), EngineError> {
+    let path = preset_path(directory, &preset.node_type, name)?;
+
+    fs::create_dir_all(path.parent().unwrap()).context(IoSnafu)?;
+
+    let preset = json!({
+        "version": VERSION.to_string(),
+        "preset": preset
+    });
+
+    fs::write(path, serde_json::to_string_pretty(&preset).context(JsonParserSnafu)?).context(IoSnafu)?;
+
+    Ok(())
+}
+
+pub fn load_preset(directory: &Path, node_type: &str, name: &str) -> Result<Preset, EngineError> {
+    let path = preset_path(directory, node_type, name)?;
+
+    let mut json: Value =
+        serde_json::from_str(&fs::read_to_string(&path).context(IoSnafu)?).context(JsonParserSnafu)?;
+
+    serde_json::from_value(json["preset"].take()).context(JsonParserInContextSnafu {
+        context: format!("{PRESETS_DIRECTORY}/{node_type}/{name}.json"),
+    })
+}
+
+pub fn delete_preset(directory: &Path, node_type: &str, name: &str) -> Result<(), EngineError> {
+    fs::remove_file(preset_path(directory, node_type, name)?).context(IoSnafu)
+}
+
+/// Lists the presets in `directory`, optionally only for one node type
+pub fn list_presets(directory: &Path, location: PresetLocation, node_type: Option<&str>) -> Vec<PresetSummary> {
+    let Ok(node_types) = fs::read_dir(directory) else {
+        return vec![];
+    };
+
+    let mut presets = vec![];
+
+    for node_type_entry in node_types.flatten() {
+        let entry_node_type = node_type_entry.file_name().to_string_lossy().to_string();
+
+        if node_type.is_some_and(|node_type| node_type != entry_node_type) {
+            continue;
+        }
+
+        let Ok(files) = fs::read_dir(node_type_entry.path()) else {
+            continue;
+        };
+
+        for file in files.flatten() {
+            let path = file.path();
+
+            if path.extension().is_some_and(|extension| extension == "json") {
+                if let Some(name) = path.file_stem() {
+                    presets.push(PresetSummary {
+                        name: name.to_string_lossy().to_string(),
+                        node_type: entry_node_type.clone(),
+                        location,
+                    });
+                }
+            }
+        }
+    }
+
+    presets.sort_by(|a, b| (&a.node_type, &a.name).cmp(&(&b.node_type, &b.name)));
+
+    presets
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn presets_round_trip() {
+        let directory = env::temp_dir().join(format!("vpo-presets-test-{}", std::process::id()));
+
+        let mut properties = SeaHashMap::default();
+        properties.insert("filter_type".to_string(), Property::MultipleChoice("highpass".into()));
+
+        let preset = Preset {
+            node_type: "BiquadFilterNode".into(),
+            properties,
+            default_overrides: vec![],
+        };
+
+        save_preset(&directory, "Bright", &preset).unwrap();
+
+        assert_eq!(
+            list_presets(&directory, PresetLocation::Project, Some("BiquadFilterNode")),
+            vec![PresetSummary {
+                name: "Bright".into(),
+                node_type: "BiquadFilterNode".into(),
+                location: PresetLocation::Project,
+            }]
+        );
+        assert!(list_presets(&directory, PresetLocation::Project, Some("ReverbNode")).is_empty());
+        assert_eq!(load_preset(&directory, "BiquadFilterNode", "Bright").unwrap(), preset);
+
+        assert!(save_preset(&directory, "../escape", &preset).is_err());
+
+        fs::remove_dir_all(directory).unwrap();
+    }
+}
@@ -0,0 +1,252 @@
+//! Periodically saving the graph state somewhere safe, so that work isn't lost if the backend
+//! crashes. Projects get a recovery file next to them (`<project>.recovery.mjuo`), and untitled
+//! projects get one in the temp directory.
+
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, UNIX_EPOCH};
+
+use log::{info, warn};
+use node_engine::state::GraphState;
+use serde::Serialize;
+use serde_json::{json, Value};
+use snafu::{ensure_whatever, ResultExt};
+
+use crate::errors::{EngineError, IoSnafu, JsonParserInContextSnafu, JsonParserSnafu};
+use crate::migrations::{file_version, migrate};
+use crate::state::GlobalState;
+
+use super::VERSION;
+
+pub const RECOVERY_EXTENSION: &str = "recovery.mjuo";
+pub const AUTOSAVE_INTERVAL: Duration = Duration::from_secs(30);
+
+/// A recovery file newer than its project, which can be restored
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RecoveryInfo {
+    pub path: PathBuf,
+    /// seconds since the unix epoch
+    pub saved_at: u64,
+}
+
+pub fn recovery_path(active_project: Option<&Path>) -> PathBuf {
+    let project = active_project.and_then(|project| Some((project.parent()?, project.file_stem()?)));
+
+    match project {
+        Some((directory, stem)) => {
+            let mut file_name = stem.to_owned();
+            file_name.push(format!(".{RECOVERY_EXTENSION}"));
+
+            directory.join(file_name)
+        }
+        None => env::temp_dir().join(format!("mjuo-untitled.{RECOVERY_EXTENSION}")),
+    }
+}
+
+/// Whether the recovery file was written for `active_project`
+fn is_recovery_of(recovery: &Value, active_project: Option<&Path>) -> bool {
+    recovery["project"].as_str().map(Path::new) == active_project
+}
+
+pub fn state_hash(state: &GraphState) -> u64 {
+    seahash::hash(state.to_json().to_string().as_bytes())
+}
+
+/// Writes the recovery file, if anything changed since the state was last written (`written_hash`)
+pub fn autosave(
+    state: &GraphState,
+    active_project: Option<&Path>,
+    written_hash: &mut Option<u64>,
+) -> Result<(), EngineError> {
+    let state_json = state.to_json();
+    let hash = seahash::hash(state_json.to_string().as_bytes());
+
+    if *written_hash == Some(hash) {
+        return Ok(());
+    }
+
+    let path = recovery_path(active_project);
+    let recovery = json!({
+        "version": VERSION.to_string(),
+        "project": active_project,
+        "state": state_json
+    });
+
+    // write then rename, so a crash while writing doesn't leave half a file
+    let mut partial = path.as_os_str().to_owned();
+    partial.push(".partial");
+
+    fs::write(&partial, serde_json::to_string(&recovery).context(JsonParserSnafu)?).context(IoSnafu)?;
+    fs::rename(&partial, &path).context(IoSnafu)?;
+
+    info!("Autosaved to {:?}", path);
+    *written_hash = Some(hash);
+
+    Ok(())
+}
+
+/// Finds a recovery file that was written after its project was last saved
+pub fn find_recovery(active_project: Option<&Path>) -> Option<RecoveryInfo> {
+    let path = recovery_path(active_project);
+    let recovery_modified = fs::metadata(&path).and_then(|metadata| metadata.modified()).ok()?;
+
+    let recovery: Value = serde_json::from_str(&fs::read_to_string(&path).ok()?).ok()?;
+    if !is_recovery_of(&recovery, active_project) {
+        return None;
+    }
+
+    if let Some(project) = active_project {
+        let project_modified = fs::metadata(project).and_then(|metadata| metadata.modified()).ok()?;
+
+        if project_modified >= recovery_modified {
+            return None;
+        }
+    }
+
+    Some(RecoveryInfo {
+        path,
+        saved_at: recovery_modified
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_secs()),
+    })
+}
+
+/// Loads the recovery file into `state`
+pub fn load_recovery(path: &Path, active_project: Option<&Path>, state: &mut GraphState) -> Result<(), EngineError> {
+    let mut json: Value = serde_json::from_str(&fs::read_to_string(path).context(IoSnafu)?).context(JsonParserSnafu)?;
+
+    ensure_whatever!(
+        is_recovery_of(&json, active_project),
+        "The autosave at {:?} belongs to another project",
+        path
+    );
+
+    if file_version(&json)? != *VERSION {
+        json = migrate(json)?;
+    }
+
+    let json_state = &mut json["state"];
+
+    state.load_state(
+        serde_json::from_value(json_state["graphManager"].take()).context(JsonParserInContextSnafu {
+            context: "state.graphManager".to_string(),
+        })?,
+        serde_json::from_value(json_state["rootGraphIndex"].take()).context(JsonParserSnafu)?,
+        serde_json::from_value(json_state["ioRouting"].take()).context(JsonParserInContextSnafu {
+            context: "state.ioRouting".to_string(),
+        })?,
+    );
+
+    Ok(())
+}
+
+pub fn discard_recovery(active_project: Option<&Path>) {
+    let path = recovery_path(active_project);
+
+    if path.exists() {
+        if let Err(err) = fs::remove_file(&path) {
+            warn!("Could not remove recovery file {:?}: {}", path, err);
+        }
+    }
+}
+
+/// Called whenever the project is written to disk, since there's nothing to recover anymore
+pub fn mark_saved(state: &GraphState, global_state: &mut GlobalState) {
+    discard_recovery(global_state.active_project.as_deref());
+
+    global_state.state_hash = Some(state_hash(state));
+    global_state.recovery = None;
+}
+
+/// Sends a tick every `AUTOSAVE_INTERVAL`, until the receiver is dropped
+pub fn start_autosave_timer() -> flume::Receiver<()> {
+    let (sender, receiver) = flume::unbounded();
+
+    std::thread::Builder::new()
+        .name("autosave_timer".into())
+        .spawn(move || loop {
+            std::thread::sleep(AUTOSAVE_INTERVAL);
+
+            if sender.send(()).is_err() {
+                break;
+            }
+        })
+        .unwrap();
+
+    receiver
+}
+
+#[cfg(test)]
+mod tests {
+    use common::SeaHashMap;
+    use node_engine::state::{Action, ActionBundle};
+    use sound_engine::SoundConfig;
+
+    use super::*;
+
+    #[test]
+    fn recovery_restores_unsaved_changes() {
+        let directory = env::temp_dir().join(format!("vpo-recovery-test-{}", std::process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        let project = directory.join("project.mjuo");
+        fs::write(&project, "{}").unwrap();
+
+        // make sure the recovery file will be newer than the project
+        fs::File::options()
+            .write(true)
+            .open(&project)
+            .unwrap()
+            .set_modified(UNIX_EPOCH + Duration::from_secs(60))
+            .unwrap();
+
+        let mut state = GraphState::new(SoundConfig::default());
+        let mut written_hash = Some(state_hash(&state));
+
+        // nothing changed yet, so nothing to write
+        autosave(&state, Some(&project), &mut written_hash).unwrap();
+        assert!(find_recovery(Some(&project)).is_none());
+
+        let root = state.get_root_graph_index();
+        state
+            .commit(
+                ActionBundle::new(vec![Action::CreateNode {
+                    graph: root,
+                    node_type: "GainNode".into(),
+                    ui_data: SeaHashMap::default(),
+                }]),
+                false,
+            )
+            .unwrap();
+
+        autosave(&state, Some(&project), &mut written_hash).unwrap();
+
+        let recovery = find_recovery(Some(&project)).expect("recovery to be offered");
+
+        assert_eq!(recovery.path, directory.join("project.recovery.mjuo"));
+
+        let mut restored = GraphState::new(SoundConfig::default());
+        load_recovery(&recovery.path, Some(&project), &mut restored).unwrap();
+        assert_eq!(restored.to_json(), state.to_json());
+
+        // another project's autosave isn't offered, even if it ends up under this one's name
+        let other = directory.join("other.mjuo");
+        fs::write(&other, "{}").unwrap();
+        fs::File::options()
+            .write(true)
+            .open(&other)
+            .unwrap()
+            .set_modified(UNIX_EPOCH + Duration::from_secs(60))
+            .unwrap();
+
+        fs::copy(&recovery.path, recovery_path(Some(&other))).unwrap();
+        assert!(find_recovery(Some(&other)).is_none());
+        assert!(load_recovery(&recovery_path(Some(&other)), Some(&other), &mut restored).is_err());
+
+        discard_recovery(Some(&project));
+        assert!(find_recovery(Some(&project)).is_none());
+
+        fs::remove_dir_all(directory).unwrap();
+    }
+}
@@ -0,0 +1,122 @@
+use std::{fs::read_to_string, path::Path};
+
+use serde::{Deserialize, Serialize};
+use snafu::ResultExt;
+use sound_engine::sampling::resample::ResampleQuality;
+
+use crate::errors::{EngineError, IoSnafu, TomlParserDeSnafu};
+
+pub const SETTINGS_FILE: &str = "settings.toml";
+
+/// Per project settings, read from `settings.toml` next to the project file
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ProjectSettings {
+    /// how the project is laid out on disk
+    #[serde(default)]
+    pub format: ProjectFormat,
+    #[serde(default)]
+    pub streaming: StreamingSettings,
+    #[serde(default)]
+    pub cache: CacheSettings,
+    #[serde(default)]
+    pub resampling: ResamplingSettings,
+    #[serde(default)]
+    pub history: HistorySettings,
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ProjectFormat {
+    /// everything in the project file
+    #[default]
+    Single,
+    /// one file per graph, with stable ordering, for keeping projects in version control
+    Split,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StreamingSettings {
+    /// only keep the start of each rank sample in memory, and stream the rest from disk
+    #[serde(default)]
+    pub enabled: bool,
+    /// how much to keep in memory past what the rank needs to loop and release
+    #[serde(default = "preload_ms_default")]
+    pub preload_ms: u32,
+}
+
+fn preload_ms_default() -> u32 {
+    500
+}
+
+impl Default for StreamingSettings {
+    fn default() -> Self {
+        StreamingSettings {
+            enabled: false,
+            preload_ms: preload_ms_default(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CacheSettings {
+    /// keep decoded samples and envelope tables in `.cache`, to speed up loading
+    #[serde(default = "cache_enabled_default")]
+    pub enabled: bool,
+}
+
+fn cache_enabled_default() -> bool {
+    true
+}
+
+impl Default for CacheSettings {
+    fn default() -> Self {
+        CacheSettings {
+            enabled: cache_enabled_default(),
+        }
+    }
+}
+
+/// Resampling samples to the engine's sample rate when loading, instead of while playing
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ResamplingSettings {
+    /// streamed samples are always resampled while playing
+    #[serde(default)]
+    pub enabled: bool,
+    #[serde(default)]
+    pub quality: ResampleQuality,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HistorySettings {
+    /// save the undo history next to the project, so it's there when it's opened again
+    #[serde(default)]
+    pub persistent: bool,
+    /// how many undo steps to save
+    #[serde(default = "max_entries_default")]
+    pub max_entries: usize,
+}
+
+fn max_entries_default() -> usize {
+    100
+}
+
+impl Default for HistorySettings {
+    fn default() -> Self {
+        HistorySettings {
+            persistent: false,
+            max_entries: max_entries_default(),
+        }
+    }
+}
+
+pub fn load_settings(project_directory: &Path) -> Result<ProjectSettings, EngineError> {
+    let path = project_directory.join(SETTINGS_FILE);
+
+    if !path.exists() {
+        return Ok(ProjectSettings::default());
+    }
+
+    let file = read_to_string(path).context(IoSnafu)?;
+
+    toml_edit::de::from_str(&file).context(TomlParserDeSnafu)
+}
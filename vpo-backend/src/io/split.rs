@@ -0,0 +1,243 @@
+//! A project layout that's friendlier to version control. The project file only holds how the
+//! graphs fit together, and each graph gets its own file in `graphs/`. Everything is written with
+//! sorted keys, and without node rows (which are recalculated when loading anyway), so saving the
+//! same project twice gives the same bytes.
+
+use std::collections::BTreeSet;
+use std::fs;
+use std::path::Path;
+
+use node_engine::graph_manager::{GraphIndex, GraphManager};
+use node_engine::io_routing::IoRoutes;
+use node_engine::node_graph::NodeGraph;
+use node_engine::state::GraphState;
+use serde::Serialize;
+use serde_json::{json, Value};
+use snafu::{OptionExt, ResultExt};
+
+use crate::errors::{EngineError, IoSnafu, JsonParserInContextSnafu, JsonParserSnafu, NodeSnafu};
+
+use super::VERSION;
+
+pub const GRAPHS_DIRECTORY: &str = "graphs";
+
+/// What a split project loads into
+pub struct SplitProject {
+    pub graph_manager: GraphManager,
+    pub root_graph_index: GraphIndex,
+    pub io_routing: IoRoutes,
+    /// everything that was read, to check whether the project changed
+    pub contents: String,
+}
+
+/// Saves the project at `path`, with the graphs next to it. Returns everything that was written.
+pub fn save_split(state: &GraphState, path: &Path) -> Result<String, EngineError> {
+    let parent = path
+        .parent()
+        .whatever_context(format!("path {:?} has no parent", path))?;
+    let graphs_directory = parent.join(GRAPHS_DIRECTORY);
+
+    fs::create_dir_all(&graphs_directory).context(IoSnafu)?;
+
+    let (mut state_json, graphs) = state.to_json_split();
+
+    let mut graph_files = vec![];
+    let mut graph_contents = vec![];
+
+    for (graph_index, graph) in graphs {
+        let file_name = format!("{}.json", graph_file_stem(graph_index)?);
+
+        graph_contents.push((file_name.clone(), to_canonical_string(&graph)?));
+        graph_files.push(json!([graph_index, file_name]));
+    }
+
+    state_json["graphFiles"] = Value::Array(graph_files);
+
+    let project = json!({
+        "version": VERSION.to_string(),
+        "format": "split",
+        "state": state_json
+    });
+    let project_raw = to_canonical_string(&project)?;
+
+    fs::write(path, &project_raw).context(IoSnafu)?;
+
+    let mut contents = project_raw;
+    let mut written = BTreeSet::new();
+
+    for (file_name, graph_raw) in graph_contents {
+        fs::write(graphs_directory.join(&file_name), &graph_raw).context(IoSnafu)?;
+
+        contents += &graph_raw;
+        written.insert(file_name);
+    }
+
+    // clean up graphs that were deleted since the last save
+    for entry in fs::read_dir(&graphs_directory).context(IoSnafu)? {
+        let entry = entry.context(IoSnafu)?;
+        let file_name = entry.file_name().to_string_lossy().to_string();
+
+        if file_name.ends_with(".json") && !written.contains(&file_name) {
+            fs::remove_file(entry.path()).context(IoSnafu)?;
+        }
+    }
+
+    Ok(contents)
+}
+
+/// Reads a split project, given the project file's contents
+pub fn load_split(project_directory: &Path, project_raw: &str) -> Result<SplitProject, EngineError> {
+    let mut json: Value = serde_json::from_str(project_raw).context(JsonParserSnafu)?;
+    let state = &mut json["state"];
+
+    let mut graph_manager: GraphManager =
+        serde_json::from_value(state["graphManager"].take()).context(JsonParserInContextSnafu {
+            context: "state.graphManager".to_string(),
+        })?;
+    let root_graph_index = serde_json::from_value(state["rootGraphIndex"].take()).context(JsonParserSnafu)?;
+    let io_routing = serde_json::from_value(state["ioRouting"].take()).context(JsonParserInContextSnafu {
+        context: "state.ioRouting".to_string(),
+    })?;
+    let graph_files: Vec<(GraphIndex, String)> =
+        serde_json::from_value(state["graphFiles"].take()).context(JsonParserInContextSnafu {
+            context: "state.graphFiles".to_string(),
+        })?;
+
+    let mut contents = project_raw.to_string();
+    let mut graphs = vec![];
+
+    for (graph_index, file_name) in graph_files {
+        let graph_raw =
+            fs::read_to_string(project_directory.join(GRAPHS_DIRECTORY).join(&file_name)).context(IoSnafu)?;
+        let graph: NodeGraph = serde_json::from_str(&graph_raw).context(JsonParserInContextSnafu {
+            context: format!("{GRAPHS_DIRECTORY}/{file_name}"),
+        })?;
+
+        contents += &graph_raw;
+        graphs.push((graph_index, graph));
+    }
+
+    graph_manager.put_graphs(graphs).context(NodeSnafu)?;
+
+    Ok(SplitProject {
+        graph_manager,
+        root_graph_index,
+        io_routing,
+        contents,
+    })
+}
+
+fn graph_file_stem(graph_index: GraphIndex) -> Result<String, EngineError> {
+    let stem = match serde_json::to_value(graph_index).context(JsonParserSnafu)? {
+        Value::String(index) => index,
+        index => index.to_string(),
+    };
+
+    Ok(stem
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect())
+}
+
+/// Going through `Value` sorts every map by key
+fn to_canonical_string<T: Serialize>(value: &T) -> Result<String, EngineError> {
+    let mut value = serde_json::to_value(value).context(JsonParserSnafu)?;
+    strip_node_rows(&mut value);
+
+    let mut raw = serde_json::to_string_pretty(&value).context(JsonParserSnafu)?;
+    raw.push('\n');
+
+    Ok(raw)
+}
+
+fn strip_node_rows(value: &mut Value) {
+    match value {
+        Value::Object(map) => {
+            if map.contains_key("nodeType") {
+                map.remove("nodeRows");
+            }
+
+            map.values_mut().for_each(strip_node_rows);
+        }
+        Value::Array(values) => values.iter_mut().for_each(strip_node_rows),
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs};
+
+    use common::SeaHashMap;
+    use node_engine::state::{Action, ActionBundle, GraphState};
+    use sound_engine::SoundConfig;
+
+    use super::*;
+
+    fn read_all(directory: &Path) -> Vec<(String, String)> {
+        let mut files = vec![(
+            "project.mjuo".to_string(),
+            fs::read_to_string(directory.join("project.mjuo")).unwrap(),
+        )];
+
+        let mut graphs: Vec<_> = fs::read_dir(directory.join(GRAPHS_DIRECTORY))
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect();
+        graphs.sort();
+
+        for graph in graphs {
+            files.push((
+                graph.file_name().unwrap().to_string_lossy().to_string(),
+                fs::read_to_string(graph).unwrap(),
+            ));
+        }
+
+        files
+    }
+
+    #[test]
+    fn load_then_save_is_identical() {
+        let directory = env::temp_dir().join(format!("vpo-split-test-{}", std::process::id()));
+        let first = directory.join("first");
+        let second = directory.join("second");
+        fs::create_dir_all(&first).unwrap();
+        fs::create_dir_all(&second).unwrap();
+
+        let mut state = GraphState::new(SoundConfig::default());
+        let root = state.get_root_graph_index();
+
+        let create = |node_type: &str| Action::CreateNode {
+            graph: root,
+            node_type: node_type.into(),
+            ui_data: SeaHashMap::default(),
+        };
+
+        state
+            .commit(
+                ActionBundle::new(vec![
+                    create("GainNode"),
+                    create("OscillatorNode"),
+                    create("PolyphonicNode"),
+                ]),
+                false,
+            )
+            .unwrap();
+
+        let saved = save_split(&state, &first.join("project.mjuo")).unwrap();
+
+        let project = load_split(&first, &fs::read_to_string(first.join("project.mjuo")).unwrap()).unwrap();
+        assert_eq!(project.contents, saved);
+
+        let mut reopened = GraphState::new(SoundConfig::default());
+        reopened.load_state(project.graph_manager, project.root_graph_index, project.io_routing);
+
+        save_split(&reopened, &second.join("project.mjuo")).unwrap();
+
+        assert_eq!(read_all(&first), read_all(&second));
+        // the polyphonic node has a graph of its own
+        assert_eq!(read_all(&first).len(), 3);
+
+        fs::remove_dir_all(directory).unwrap();
+    }
+}
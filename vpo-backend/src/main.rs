@@ -9,6 +9,7 @@ use futures::join;
 use futures::task::LocalSpawnExt;
 use futures::StreamExt;
 use ipc::file_server::{start_file_server, start_file_server_in};
+use log::error;
 
 use node_engine::resources::Resources;
 use node_engine::state::{FromNodeEngine, GraphState};
@@ -18,8 +19,9 @@ use thread_priority::{ThreadBuilderExt, ThreadPriority};
 use vpo_backend::engine::{start_sound_engine, ToAudioThread};
 use vpo_backend::io::file_watcher::FileWatcher;
 use vpo_backend::io::load_single;
+use vpo_backend::io::recovery::{autosave, find_recovery, start_autosave_timer, state_hash};
 use vpo_backend::state::GlobalState;
-use vpo_backend::util::{send_graph_updates, send_resource_updates};
+use vpo_backend::util::{apply_property_changes, send_graph_updates, send_resource_updates};
 use vpo_backend::{handle_msg, start_ipc};
 
 fn main() {
@@ -29,6 +31,13 @@ fn main() {
 
     let global_state = RefCell::new(GlobalState::new());
     let graph_state = RefCell::new(GraphState::new(SoundConfig::default()));
+
+    {
+        // offer whatever the last untitled project was, if it wasn't saved
+        let mut global_state = global_state.borrow_mut();
+        global_state.state_hash = Some(state_hash(&graph_state.borrow()));
+        global_state.recovery = find_recovery(None);
+    }
     let resources = Arc::new(RwLock::new(Resources::default()));
 
     let (to_realtime, from_main) = flume::unbounded();
@@ -117,6 +126,20 @@ fn main() {
                                 .send(ToAudioThread::CurrentNodeStates(graph_state.borrow().get_node_state()))
                                 .unwrap();
                         }
+                        FromNodeEngine::PropertyChanges(changes) => {
+                            let res = apply_property_changes(
+                                &mut *graph_state.borrow_mut(),
+                                &mut *global_state.borrow_mut(),
+                                changes,
+                                &*resources.read().unwrap(),
+                                &to_realtime,
+                                &to_server,
+                            );
+
+                            if let Err(err) = res {
+                                error!("Failed to apply property changes: {}", err);
+                            }
+                        }
                     }
                 }
             };
@@ -140,6 +163,7 @@ fn main() {
                                     &e.path,
                                     &mut *resources_lock,
                                     graph_state.get_sound_config(),
+                                    &global_state.settings,
                                 );
 
                                 send_resource_updates(&*resources_lock, &to_server).unwrap();
@@ -150,7 +174,36 @@ fn main() {
                 }
             };
 
-            join!(client_communication, sound_engine_communication, file_watcher);
+            let autosave_timer = async {
+                let ticks = start_autosave_timer();
+
+                while ticks.recv_async().await.is_ok() {
+                    // skip this one if a route is in the middle of changing things
+                    let (Ok(graph_state), Ok(mut global_state)) =
+                        (graph_state.try_borrow(), global_state.try_borrow_mut())
+                    else {
+                        continue;
+                    };
+
+                    let global_state = &mut *global_state;
+                    let res = autosave(
+                        &graph_state,
+                        global_state.active_project.as_deref(),
+                        &mut global_state.state_hash,
+                    );
+
+                    if let Err(err) = res {
+                        error!("Autosave failed: {}", err);
+                    }
+                }
+            };
+
+            join!(
+                client_communication,
+                sound_engine_communication,
+                file_watcher,
+                autosave_timer
+            );
         })
         .unwrap();
 
@@ -0,0 +1,16 @@
+use serde_json::{json, Value};
+
+use crate::errors::EngineError;
+
+use super::for_each_node;
+
+/// Polyphonic nodes didn't have a `polyphony` property yet, so give them the default
+pub fn migrate(mut json: Value) -> Result<Value, EngineError> {
+    for_each_node(&mut json, &mut |node| {
+        if node["nodeType"] == "PolyphonicNode" && node["properties"].get("polyphony").is_none() {
+            node["properties"]["polyphony"] = json!({ "variant": "Integer", "data": 64 });
+        }
+    });
+
+    Ok(json)
+}
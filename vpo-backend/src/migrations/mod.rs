@@ -1,58 +1,172 @@
-use std::{fs, path::PathBuf};
+//! Brings project files from older versions up to date. Each migration is a transform on the
+//! project's json, and they're chained together until the file reaches the current version, all
+//! before anything gets deserialized.
 
 use lazy_static::lazy_static;
-use semver::Version;
-use serde_json::Value;
-use snafu::ResultExt;
+use semver::{Version, VersionReq};
+use serde_json::{json, Value};
 
-use crate::errors::{EngineError, IoSnafu, JsonParserSnafu};
+use crate::errors::EngineError;
+use crate::io::VERSION;
 
-type MigrationFn = Box<dyn Fn(PathBuf) -> Result<(), EngineError> + Send + Sync + 'static>;
+mod m_0000_add_polyphonic_prop;
+
+type MigrationFn = Box<dyn Fn(Value) -> Result<Value, EngineError> + Send + Sync + 'static>;
 
 pub struct Migration {
-    pub version_from: Version,
+    /// which versions this migration applies to
+    pub version_from: VersionReq,
     pub version_to: Version,
     pub migrate: MigrationFn,
 }
 
 lazy_static! {
-    pub static ref MIGRATIONS: [Migration; 0] = {
+    pub static ref MIGRATIONS: [Migration; 2] = {
         [
-            // Migration {
-            //     version_from: Version::parse("0.3.0").unwrap(),
-            //     version_to: Version::parse("0.4.0").unwrap(),
-            //     migrate: Box::new(m_0000_add_polyphonic_prop::migrate),
-            // }
+            Migration {
+                version_from: VersionReq::parse(">=0.3.0, <0.4.0").unwrap(),
+                version_to: Version::parse("0.4.0").unwrap(),
+                migrate: Box::new(m_0000_add_polyphonic_prop::migrate),
+            },
+            Migration {
+                version_from: VersionReq::parse(">=0.4.0, <0.5.0").unwrap(),
+                version_to: Version::parse("0.5.0").unwrap(),
+                // the format didn't change, only the version
+                migrate: Box::new(Ok::<Value, EngineError>),
+            },
         ]
     };
 }
 
-pub fn migrate(file: PathBuf) -> Result<(), EngineError> {
-    // get version
-    let json_raw = fs::read_to_string(&file).context(IoSnafu)?;
-    let json: Value = serde_json::from_str(&json_raw).context(JsonParserSnafu)?;
-
-    let version_str = json["version"]
+pub fn file_version(json: &Value) -> Result<Version, EngineError> {
+    json["version"]
         .as_str()
+        .and_then(|version| Version::parse(version).ok())
         .ok_or(EngineError::PropertyMissingOrMalformed {
             property_name: "version".into(),
-        })?;
+        })
+}
+
+/// Applies every migration needed to bring `json` up to the current version.
+///
+/// Split projects were added in 0.5.0, so only the project file is migrated, not the graph files.
+pub fn migrate(mut json: Value) -> Result<Value, EngineError> {
+    let mut version = file_version(&json)?;
+
+    if version > *VERSION {
+        return Err(EngineError::VersionError { version });
+    }
+
+    while version < *VERSION {
+        let migration = MIGRATIONS
+            .iter()
+            .find(|migration| migration.version_from.matches(&version))
+            .ok_or(EngineError::VersionError {
+                version: version.clone(),
+            })?;
+
+        json = (migration.migrate)(json)?;
+        json["version"] = json!(migration.version_to.to_string());
+
+        version = migration.version_to.clone();
+    }
+
+    Ok(json)
+}
+
+/// Calls `f` with every node instance in the project, in every graph
+pub fn for_each_node(json: &mut Value, f: &mut impl FnMut(&mut Value)) {
+    for_each_vertex(&mut json["state"]["graphManager"]["nodeGraphs"], &mut |graph| {
+        for_each_vertex(&mut graph["nodes"], f);
+    });
+}
+
+fn for_each_vertex(graph: &mut Value, f: &mut impl FnMut(&mut Value)) {
+    if let Some(verticies) = graph["verticies"].as_array_mut() {
+        for element in verticies {
+            if element["variant"] == "Occupied" {
+                f(&mut element["data"][0]["data"]);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use node_engine::graph_manager::GraphManager;
+    use node_engine::io_routing::IoRoutes;
+    use node_engine::property::Property;
+
+    use super::*;
 
-    let version = Version::parse(version_str).map_err(|_| EngineError::PropertyMissingOrMalformed {
-        property_name: "version".into(),
-    })?;
+    const FIXTURES: &[(&str, &str)] = &[
+        ("0.3.0", include_str!("fixtures/project-0.3.0.mjuo")),
+        ("0.4.0", include_str!("fixtures/project-0.4.0.mjuo")),
+    ];
 
-    // find this version in the list
-    let version_index = MIGRATIONS
-        .iter()
-        .position(|migration| migration.version_from == version)
-        .ok_or(EngineError::VersionError { version })?;
+    fn polyphony(json: &mut Value) -> Vec<Value> {
+        let mut found = vec![];
 
-    let migrations_to_apply = &MIGRATIONS[version_index..MIGRATIONS.len()];
+        for_each_node(json, &mut |node| {
+            if node["nodeType"] == "PolyphonicNode" {
+                found.push(node["properties"]["polyphony"].clone());
+            }
+        });
 
-    for migration in migrations_to_apply {
-        (migration.migrate)(file.clone())?;
+        found
     }
 
-    Ok(())
+    #[test]
+    fn fixtures_migrate_to_current_version() {
+        for (version, raw) in FIXTURES {
+            let json: Value = serde_json::from_str(raw).unwrap();
+            assert_eq!(file_version(&json).unwrap().to_string(), *version);
+
+            let mut migrated = migrate(json).unwrap();
+            assert_eq!(file_version(&migrated).unwrap(), *VERSION);
+
+            let state = &migrated["state"];
+            serde_json::from_value::<GraphManager>(state["graphManager"].clone()).unwrap();
+            serde_json::from_value::<IoRoutes>(state["ioRouting"].clone()).unwrap();
+
+            for value in polyphony(&mut migrated) {
+                assert!(matches!(
+                    serde_json::from_value::<Property>(value).unwrap(),
+                    Property::Integer(_)
+                ));
+            }
+        }
+    }
+
+    #[test]
+    fn old_polyphonic_nodes_get_polyphony() {
+        let json: Value = serde_json::from_str(FIXTURES[0].1).unwrap();
+        let mut migrated = migrate(json).unwrap();
+
+        assert_eq!(
+            polyphony(&mut migrated),
+            vec![serde_json::to_value(Property::Integer(64)).unwrap()]
+        );
+
+        // and existing values are kept
+        let json: Value = serde_json::from_str(FIXTURES[1].1).unwrap();
+        let mut migrated = migrate(json).unwrap();
+
+        assert_eq!(
+            polyphony(&mut migrated),
+            vec![serde_json::to_value(Property::Integer(8)).unwrap()]
+        );
+    }
+
+    #[test]
+    fn unknown_versions_are_rejected() {
+        assert!(matches!(
+            migrate(json!({ "version": "0.1.0" })),
+            Err(EngineError::VersionError { .. })
+        ));
+        assert!(matches!(
+            migrate(json!({ "version": "99.0.0" })),
+            Err(EngineError::VersionError { .. })
+        ));
+    }
 }
@@ -1,6 +1,11 @@
 #[cfg(any(unix, windows))]
 pub mod decode_audio;
+#[cfg(any(unix, windows))]
+pub mod organ;
 pub mod rank;
 pub mod sample;
+pub mod sfz;
 pub mod ui;
-mod util;
+pub(crate) mod util;
+#[cfg(any(unix, windows))]
+pub mod wav_stream;
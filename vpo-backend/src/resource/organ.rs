@@ -0,0 +1,582 @@
+//! Reading GrandOrgue organ definition files (`.organ`)
+//!
+//! Only the parts that map onto ranks are read: manuals, stops, ranks, pipes and windchest
+//! groups. Couplers, divisionals, panels and the like are ignored.
+
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    io::{Read, Seek, SeekFrom},
+    path::{Path, PathBuf},
+};
+
+use snafu::ResultExt;
+
+use crate::errors::{EngineError, FileSnafu};
+
+/// The raw `[Section]` / `key=value` contents of an ODF. Section and key names are compared
+/// case insensitively, as organ builders aren't consistent about it.
+#[derive(Debug, Default)]
+pub struct OdfSections {
+    sections: BTreeMap<String, BTreeMap<String, String>>,
+}
+
+impl OdfSections {
+    pub fn parse(source: &str) -> OdfSections {
+        let mut sections: BTreeMap<String, BTreeMap<String, String>> = BTreeMap::new();
+        let mut current: Option<String> = None;
+
+        for line in source.lines() {
+            let line = line.trim();
+
+            if line.is_empty() || line.starts_with(';') {
+                continue;
+            }
+
+            if line.starts_with('[') && line.ends_with(']') {
+                let name = line[1..line.len() - 1].trim().to_lowercase();
+                sections.entry(name.clone()).or_default();
+                current = Some(name);
+
+                continue;
+            }
+
+            if let (Some(section), Some((key, value))) = (&current, line.split_once('=')) {
+                sections
+                    .get_mut(section)
+                    .unwrap()
+                    .insert(key.trim().to_lowercase(), value.trim().to_string());
+            }
+        }
+
+        OdfSections { sections }
+    }
+
+    pub fn has_section(&self, section: &str) -> bool {
+        self.sections.contains_key(&section.to_lowercase())
+    }
+
+    pub fn get(&self, section: &str, key: &str) -> Option<&str> {
+        self.sections
+            .get(&section.to_lowercase())
+            .and_then(|entries| entries.get(&key.to_lowercase()))
+            .map(|value| value.as_str())
+    }
+
+    fn int(&self, section: &str, key: &str) -> Option<i32> {
+        self.get(section, key).and_then(|value| value.parse().ok())
+    }
+
+    fn float(&self, section: &str, key: &str) -> Option<f32> {
+        self.get(section, key).and_then(|value| value.parse().ok())
+    }
+
+    fn bool(&self, section: &str, key: &str) -> Option<bool> {
+        self.get(section, key).map(|value| value.eq_ignore_ascii_case("y"))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct OdfPipe {
+    pub attack: PathBuf,
+    pub releases: Vec<PathBuf>,
+    /// loop points given in the ODF, overriding the ones in the WAV file
+    pub loops: Vec<(usize, usize)>,
+    pub gain_db: f32,
+    pub tuning_cents: f32,
+}
+
+#[derive(Debug, Clone)]
+enum OdfPipeEntry {
+    Sample(OdfPipe),
+    /// `REF:manual:stop:pipe`, borrowing a pipe from another stop
+    Reference(usize, usize, usize),
+    Dummy,
+}
+
+#[derive(Debug, Clone)]
+pub struct OdfRank {
+    pub name: String,
+    /// index into `OdfOrgan::windchests`
+    pub windchest: usize,
+    /// MIDI note of the first pipe's pitch
+    pub first_midi_note: i32,
+    pub gain_db: f32,
+    pub tuning_cents: f32,
+    pub percussive: bool,
+    /// `None` for dummy pipes, or references that couldn't be resolved
+    pub pipes: Vec<Option<OdfPipe>>,
+}
+
+impl OdfRank {
+    /// The MIDI note of pipe `index` (zero based)
+    pub fn pipe_note(&self, index: usize) -> i32 {
+        self.first_midi_note + index as i32
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct OdfStopRank {
+    /// index into `OdfOrgan::ranks`
+    pub rank: usize,
+    /// how far incoming notes need to be transposed to land on the right pipes
+    pub transpose: i32,
+}
+
+#[derive(Debug, Clone)]
+pub struct OdfStop {
+    pub name: String,
+    pub ranks: Vec<OdfStopRank>,
+}
+
+#[derive(Debug, Clone)]
+pub struct OdfManual {
+    pub name: String,
+    pub stops: Vec<OdfStop>,
+}
+
+#[derive(Debug, Clone)]
+pub struct OdfOrgan {
+    pub name: String,
+    pub manuals: Vec<OdfManual>,
+    pub ranks: Vec<OdfRank>,
+    pub windchests: Vec<String>,
+    /// tremulants aren't imported, but it's useful to tell the user about them
+    pub tremulant_count: usize,
+}
+
+fn section_name(prefix: &str, number: i32) -> String {
+    format!("{}{:03}", prefix, number)
+}
+
+fn gain_db(odf: &OdfSections, section: &str, prefix: &str) -> f32 {
+    let amplitude_level = odf
+        .float(section, &format!("{prefix}AmplitudeLevel"))
+        .unwrap_or(100.0)
+        .max(0.001);
+
+    odf.float(section, &format!("{prefix}Gain")).unwrap_or(0.0) + 20.0 * (amplitude_level / 100.0).log10()
+}
+
+/// ODF paths are relative to the ODF, and usually use Windows separators
+fn sample_path(odf_directory: &Path, path: &str) -> PathBuf {
+    path.split(['\\', '/'])
+        .filter(|part| !part.is_empty() && *part != ".")
+        .fold(odf_directory.to_path_buf(), |path, part| path.join(part))
+}
+
+fn parse_pipe(odf: &OdfSections, section: &str, pipe: i32, odf_directory: &Path) -> OdfPipeEntry {
+    let prefix = section_name("Pipe", pipe);
+
+    let Some(value) = odf.get(section, &prefix) else {
+        return OdfPipeEntry::Dummy;
+    };
+
+    if value.eq_ignore_ascii_case("DUMMY") {
+        return OdfPipeEntry::Dummy;
+    }
+
+    if let Some(reference) = value.strip_prefix("REF:") {
+        let parts: Vec<usize> = reference
+            .split(':')
+            .filter_map(|part| part.trim().parse().ok())
+            .collect();
+
+        return match parts[..] {
+            [manual, stop, pipe] => OdfPipeEntry::Reference(manual, stop, pipe),
+            _ => OdfPipeEntry::Dummy,
+        };
+    }
+
+    let loop_count = odf.int(section, &format!("{prefix}LoopCount")).unwrap_or(0);
+    let loops = (1..=loop_count)
+        .filter_map(|i| {
+            let start = odf.int(section, &format!("{prefix}Loop{:03}Start", i))?;
+            let end = odf.int(section, &format!("{prefix}Loop{:03}End", i))?;
+
+            (start >= 0 && end > start).then_some((start as usize, end as usize))
+        })
+        .collect();
+
+    let release_count = odf.int(section, &format!("{prefix}ReleaseCount")).unwrap_or(0);
+    let releases = (1..=release_count)
+        .filter_map(|i| odf.get(section, &format!("{prefix}Release{:03}", i)))
+        .map(|path| sample_path(odf_directory, path))
+        .collect();
+
+    OdfPipeEntry::Sample(OdfPipe {
+        attack: sample_path(odf_directory, value),
+        releases,
+        loops,
+        gain_db: gain_db(odf, section, &prefix),
+        tuning_cents: odf.float(section, &format!("{prefix}PitchTuning")).unwrap_or(0.0),
+    })
+}
+
+fn parse_rank(
+    odf: &OdfSections,
+    section: &str,
+    default_first_note: i32,
+    windchest_count: usize,
+    odf_directory: &Path,
+) -> (OdfRank, Vec<OdfPipeEntry>) {
+    let percussive = odf.bool(section, "Percussive").unwrap_or(false);
+    let pipe_count = odf.int(section, "NumberOfLogicalPipes").unwrap_or(0);
+
+    let pipes = (1..=pipe_count)
+        .map(|pipe| parse_pipe(odf, section, pipe, odf_directory))
+        .collect();
+
+    let windchest = odf.int(section, "WindchestGroup").unwrap_or(1) - 1;
+
+    (
+        OdfRank {
+            name: odf.get(section, "Name").unwrap_or(section).to_string(),
+            windchest: (windchest.max(0) as usize).min(windchest_count.saturating_sub(1)),
+            first_midi_note: odf.int(section, "FirstMidiNoteNumber").unwrap_or(default_first_note),
+            gain_db: gain_db(odf, section, ""),
+            tuning_cents: odf.float(section, "PitchTuning").unwrap_or(0.0),
+            percussive,
+            pipes: vec![],
+        },
+        pipes,
+    )
+}
+
+/// Reads an ODF, resolving sample paths relative to `odf_directory`
+pub fn parse_organ(source: &str, odf_directory: &Path) -> Result<OdfOrgan, EngineError> {
+    let odf = OdfSections::parse(source);
+
+    if !odf.has_section("Organ") {
+        return Err(EngineError::ParserError {
+            error: "organ definition is missing its [Organ] section".into(),
+        });
+    }
+
+    let windchest_count = odf.int("Organ", "NumberOfWindchestGroups").unwrap_or(0).max(1);
+    let windchests = (1..=windchest_count)
+        .map(|i| {
+            odf.get(&section_name("WindchestGroup", i), "Name")
+                .map(|name| name.to_string())
+                .unwrap_or_else(|| format!("Windchest {}", i))
+        })
+        .collect::<Vec<_>>();
+
+    let mut ranks: Vec<OdfRank> = vec![];
+    let mut rank_pipes: Vec<Vec<OdfPipeEntry>> = vec![];
+    // section name -> index in `ranks`, so stops sharing a rank share it here too
+    let mut rank_lookup: BTreeMap<String, usize> = BTreeMap::new();
+
+    for i in 1..=odf.int("Organ", "NumberOfRanks").unwrap_or(0) {
+        let section = section_name("Rank", i);
+        let (rank, pipes) = parse_rank(&odf, &section, 36, windchests.len(), odf_directory);
+
+        rank_lookup.insert(section, ranks.len());
+        ranks.push(rank);
+        rank_pipes.push(pipes);
+    }
+
+    let has_pedals = odf.bool("Organ", "HasPedals").unwrap_or(false);
+    let manual_count = odf.int("Organ", "NumberOfManuals").unwrap_or(0);
+
+    let mut manuals = vec![];
+    // (manual, stop within manual) -> (rank, first pipe), for resolving `REF:` pipes
+    let mut stop_lookup: BTreeMap<(usize, usize), (usize, usize)> = BTreeMap::new();
+
+    for manual_number in (if has_pedals { 0 } else { 1 })..=manual_count {
+        let manual_section = section_name("Manual", manual_number);
+        let first_key_note = odf
+            .int(&manual_section, "FirstAccessibleKeyMIDINoteNumber")
+            .unwrap_or(36);
+
+        let mut stops = vec![];
+
+        for stop_number in 1..=odf.int(&manual_section, "NumberOfStops").unwrap_or(0) {
+            let Some(stop_section) = odf
+                .int(&manual_section, &section_name("Stop", stop_number))
+                .map(|global| section_name("Stop", global))
+            else {
+                continue;
+            };
+
+            let mut stop_ranks = vec![];
+
+            if let Some(rank_count) = odf.int(&stop_section, "NumberOfRanks") {
+                for i in 1..=rank_count {
+                    let prefix = section_name("Rank", i);
+
+                    let Some(&rank) = odf
+                        .int(&stop_section, &prefix)
+                        .and_then(|number| rank_lookup.get(&section_name("Rank", number)))
+                    else {
+                        continue;
+                    };
+
+                    let first_pipe = odf.int(&stop_section, &format!("{prefix}FirstPipeNumber")).unwrap_or(1);
+                    let first_key = odf
+                        .int(&stop_section, &format!("{prefix}FirstAccessibleKeyNumber"))
+                        .unwrap_or(1);
+
+                    stop_ranks.push(OdfStopRank {
+                        rank,
+                        transpose: ranks[rank].first_midi_note + first_pipe - first_key - first_key_note,
+                    });
+                    stop_lookup
+                        .entry((manual_number as usize, stop_number as usize))
+                        .or_insert((rank, first_pipe.max(1) as usize - 1));
+                }
+            } else {
+                // older ODFs have the pipes directly in the stop
+                let first_key = odf
+                    .int(&stop_section, "FirstAccessiblePipeLogicalKeyNumber")
+                    .unwrap_or(1);
+                let first_pipe = odf
+                    .int(&stop_section, "FirstAccessiblePipeLogicalPipeNumber")
+                    .unwrap_or(1);
+
+                let rank = match rank_lookup.get(&stop_section) {
+                    Some(rank) => *rank,
+                    None => {
+                        let default_first_note = first_key_note + first_key - first_pipe;
+                        let (rank, pipes) =
+                            parse_rank(&odf, &stop_section, default_first_note, windchests.len(), odf_directory);
+
+                        rank_lookup.insert(stop_section.clone(), ranks.len());
+                        ranks.push(rank);
+                        rank_pipes.push(pipes);
+
+                        ranks.len() - 1
+                    }
+                };
+
+                stop_ranks.push(OdfStopRank {
+                    rank,
+                    transpose: ranks[rank].first_midi_note + first_pipe - first_key - first_key_note,
+                });
+                stop_lookup.insert(
+                    (manual_number as usize, stop_number as usize),
+                    (rank, first_pipe.max(1) as usize - 1),
+                );
+            }
+
+            stops.push(OdfStop {
+                name: odf.get(&stop_section, "Name").unwrap_or(&stop_section).to_string(),
+                ranks: stop_ranks,
+            });
+        }
+
+        manuals.push(OdfManual {
+            name: odf
+                .get(&manual_section, "Name")
+                .map(|name| name.to_string())
+                .unwrap_or_else(|| format!("Manual {}", manual_number)),
+            stops,
+        });
+    }
+
+    // now that all the ranks are known, resolve the pipes
+    for (rank, pipes) in rank_pipes.iter().enumerate() {
+        ranks[rank].pipes = pipes
+            .iter()
+            .map(|entry| resolve_pipe(entry, &rank_pipes, &stop_lookup, 0))
+            .collect();
+    }
+
+    Ok(OdfOrgan {
+        name: odf
+            .get("Organ", "ChurchName")
+            .or_else(|| odf.get("Organ", "OrganName"))
+            .unwrap_or("Untitled organ")
+            .to_string(),
+        manuals,
+        ranks,
+        windchests,
+        tremulant_count: odf.int("Organ", "NumberOfTremulants").unwrap_or(0).max(0) as usize,
+    })
+}
+
+fn resolve_pipe(
+    entry: &OdfPipeEntry,
+    rank_pipes: &[Vec<OdfPipeEntry>],
+    stop_lookup: &BTreeMap<(usize, usize), (usize, usize)>,
+    depth: usize,
+) -> Option<OdfPipe> {
+    match entry {
+        OdfPipeEntry::Sample(pipe) => Some(pipe.clone()),
+        OdfPipeEntry::Dummy => None,
+        // references to references are allowed, but don't follow them forever
+        OdfPipeEntry::Reference(_, _, _) if depth > 8 => None,
+        OdfPipeEntry::Reference(manual, stop, pipe) => {
+            let (rank, first_pipe) = stop_lookup.get(&(*manual, *stop))?;
+            let referenced = rank_pipes[*rank].get((first_pipe + pipe).checked_sub(1)?)?;
+
+            resolve_pipe(referenced, rank_pipes, stop_lookup, depth + 1)
+        }
+    }
+}
+
+/// Loop and release points stored in a WAV file's `smpl` and `cue ` chunks
+#[derive(Debug, Default, Clone)]
+pub struct WavMarkers {
+    pub loops: Vec<(usize, usize)>,
+    pub release: Option<usize>,
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> Option<u32> {
+    bytes
+        .get(offset..offset + 4)
+        .map(|bytes| u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+/// Reads the loops and release marker out of a WAV file, which is where most sample sets
+/// keep them. Files that aren't WAVs (or have no markers) give back empty markers.
+pub fn read_wav_markers(path: &Path) -> Result<WavMarkers, EngineError> {
+    let mut file = File::open(path).context(FileSnafu)?;
+    let file_len = file.metadata().context(FileSnafu)?.len();
+    let mut markers = WavMarkers::default();
+
+    let mut header = [0_u8; 12];
+    if file.read_exact(&mut header).is_err() || &header[0..4] != b"RIFF" || &header[8..12] != b"WAVE" {
+        return Ok(markers);
+    }
+
+    let mut chunk_header = [0_u8; 8];
+    while file.read_exact(&mut chunk_header).is_ok() {
+        let size = read_u32(&chunk_header, 4).unwrap_or(0) as usize;
+
+        match &chunk_header[0..4] {
+            b"smpl" | b"cue " => {
+                // the size comes from the file, so don't trust it further than the file goes
+                let remaining = file_len.saturating_sub(file.stream_position().context(FileSnafu)?);
+                if size as u64 > remaining {
+                    break;
+                }
+
+                let mut chunk = vec![0_u8; size];
+                file.read_exact(&mut chunk).context(FileSnafu)?;
+
+                if &chunk_header[0..4] == b"smpl" {
+                    let loop_count = (read_u32(&chunk, 28).unwrap_or(0) as usize).min(size.saturating_sub(36) / 24);
+
+                    markers.loops = (0..loop_count)
+                        .filter_map(|i| {
+                            let start = read_u32(&chunk, 36 + i * 24 + 8)? as usize;
+                            let end = read_u32(&chunk, 36 + i * 24 + 12)? as usize;
+
+                            (end > start).then_some((start, end))
+                        })
+                        .collect();
+                } else {
+                    let cue_count = (read_u32(&chunk, 0).unwrap_or(0) as usize).min(size.saturating_sub(4) / 24);
+
+                    // GrandOrgue uses the last cue point as where the release starts
+                    markers.release = (0..cue_count)
+                        .filter_map(|i| read_u32(&chunk, 4 + i * 24 + 20))
+                        .map(|offset| offset as usize)
+                        .max();
+                }
+
+                if size % 2 == 1 {
+                    file.seek(SeekFrom::Current(1)).context(FileSnafu)?;
+                }
+            }
+            _ => {
+                file.seek(SeekFrom::Current((size + size % 2) as i64))
+                    .context(FileSnafu)?;
+            }
+        }
+    }
+
+    Ok(markers)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+    use std::{env, fs};
+
+    use super::{parse_organ, read_wav_markers};
+
+    const ODF: &str = r"
+[Organ]
+ChurchName=Test Organ
+HasPedals=N
+NumberOfManuals=1
+NumberOfWindchestGroups=1
+NumberOfRanks=1
+NumberOfTremulants=1
+
+[WindchestGroup001]
+Name=Main
+
+[Manual001]
+Name=Great
+FirstAccessibleKeyMIDINoteNumber=36
+NumberOfStops=2
+Stop001=001
+Stop002=002
+
+[Stop001]
+Name=Principal 8'
+NumberOfRanks=1
+Rank001=001
+
+[Stop002]
+Name=Octave 4'
+NumberOfRanks=1
+Rank001=001
+Rank001FirstPipeNumber=13
+
+[Rank001]
+Name=Principal
+FirstMidiNoteNumber=36
+NumberOfLogicalPipes=3
+WindchestGroup=1
+Pipe001=.\Principal\036-c.wav
+Pipe001ReleaseCount=1
+Pipe001Release001=Principal\rel\036-c.wav
+Pipe002=DUMMY
+Pipe003=REF:001:001:001
+";
+
+    #[test]
+    fn parses_ranks_and_stops() {
+        let organ = parse_organ(ODF, Path::new("organ")).unwrap();
+
+        assert_eq!(organ.name, "Test Organ");
+        assert_eq!(organ.windchests, vec!["Main".to_string()]);
+        assert_eq!(organ.tremulant_count, 1);
+        assert_eq!(organ.ranks.len(), 1);
+
+        let rank = &organ.ranks[0];
+        assert_eq!(rank.pipes.len(), 3);
+        assert!(rank.pipes[1].is_none());
+
+        let pipe = rank.pipes[0].as_ref().unwrap();
+        assert_eq!(pipe.attack, Path::new("organ").join("Principal").join("036-c.wav"));
+        assert_eq!(pipe.releases.len(), 1);
+
+        // the reference resolves to the first pipe
+        assert_eq!(rank.pipes[2].as_ref().unwrap().attack, pipe.attack);
+
+        let stops = &organ.manuals[0].stops;
+        assert_eq!(stops[0].ranks[0].transpose, 0);
+        assert_eq!(stops[1].ranks[0].transpose, 12);
+    }
+
+    #[test]
+    fn oversized_marker_chunks_are_ignored() {
+        let path = env::temp_dir().join(format!("vpo-markers-test-{}.wav", std::process::id()));
+
+        let mut wav = b"RIFF\0\0\0\0WAVE".to_vec();
+        wav.extend(b"smpl");
+        wav.extend(u32::MAX.to_le_bytes());
+        wav.extend([0; 16]);
+        fs::write(&path, wav).unwrap();
+
+        let markers = read_wav_markers(&path).unwrap();
+        assert!(markers.loops.is_empty());
+
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -1,91 +1,233 @@
-use std::{collections::BTreeMap, fs::read_to_string, path::Path};
+use std::{
+    collections::BTreeMap,
+    fs::read_to_string,
+    path::Path,
+    sync::atomic::{AtomicU64, Ordering},
+};
 
 use common::resource_manager::{ResourceId, ResourceManager};
 use serde::{Deserialize, Serialize};
-use snafu::ResultExt;
+use snafu::{ensure_whatever, whatever, ResultExt};
 use sound_engine::{
     sampling::{
         phase_calculator::PhaseCalculator,
-        pipe_player::{envelope_indexes, EnvelopeType},
-        rank::{Percussion, Pipe, Rank, RankType},
+        pipe_player::{envelope_indexes, EnvelopeIndexes, EnvelopeType},
+        rank::{MicPosition, Percussion, PercussionLayer, Pipe, Placement, Rank, RankType, Voicing},
     },
     util::db_to_gain,
-    MonoSample,
+    MonoSample, MultiSample,
 };
 
 use crate::errors::{EngineError, TomlParserDeSnafu};
 
 #[derive(Debug, Serialize, Deserialize)]
-struct PipesRankEntry {
-    cents: i16,
-    decay_index: usize,
-    loop_start: usize,
-    loop_end: usize,
-    release_index: usize,
+pub struct PipesRankEntry {
+    pub cents: i16,
+    pub decay_index: usize,
+    pub loop_start: usize,
+    pub loop_end: usize,
+    pub release_index: usize,
 
     // optional parameters
+    /// other loops the player can hop between, as `[start, end]`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub extra_loops: Vec<(usize, usize)>,
+    #[serde(default)]
+    pub crossfade: Option<usize>,
     #[serde(default)]
-    crossfade: Option<usize>,
+    pub file: Option<String>,
     #[serde(default)]
-    file: Option<String>,
+    pub attenuation: f32,
     #[serde(default)]
-    attenuation: f32,
+    pub even_harm_atten: f32,
+    /// added to the rank's brightness
     #[serde(default)]
-    even_harm_atten: f32,
+    pub brightness: f32,
+    /// replaces the rank's low cut
+    #[serde(default)]
+    pub low_cut: Option<f32>,
+}
+
+impl PipesRankEntry {
+    /// How many frames of the sample playback can jump around in
+    pub fn resident_frames(&self, default_crossfade: usize) -> usize {
+        let loop_end = self
+            .extra_loops
+            .iter()
+            .fold(self.loop_end, |end, (_, extra)| end.max(*extra));
+
+        loop_end.max(self.release_index).max(self.decay_index) + self.crossfade.unwrap_or(default_crossfade)
+    }
 }
 
 fn crossfade_default() -> usize {
     256
 }
 
+fn brightness_harmonic_default() -> f32 {
+    4.0
+}
+
 #[derive(Debug, Serialize, Deserialize)]
-struct PercussionRankEntry {
+pub struct PercussionRankEntry {
     // optional parameters
     #[serde(default)]
-    file: Option<String>,
+    pub file: Option<String>,
+    #[serde(default)]
+    pub attenuation: f32,
+    pub release: f32,
+    /// velocity layers, in place of `file`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub layer: Vec<PercussionLayerEntry>,
+    /// playing a note cuts off any others in its choke group
     #[serde(default)]
-    attenuation: f32,
-    release: f32,
+    pub choke_group: Option<u32>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
-struct RankInfo {
-    rank_type: String,
+pub struct PercussionLayerEntry {
+    /// lowest and highest velocity the layer plays at. Overlapping layers crossfade.
+    pub velocity: (u8, u8),
+    /// round robin alternates
+    pub files: Vec<String>,
+
+    // optional parameters
+    #[serde(default)]
+    pub attenuation: f32,
+}
+
+impl PercussionRankEntry {
+    /// Every sample file of the entry, if it has layers
+    fn layer_files(&self) -> impl Iterator<Item = &String> {
+        self.layer.iter().flat_map(|layer| layer.files.iter())
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MicEntry {
+    pub channels: Vec<usize>,
+
+    // optional parameters
+    #[serde(default)]
+    pub attenuation: f32,
+}
+
+/// How pipes are laid out, going from the lowest note to the highest
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LayoutType {
+    /// lowest on the left, highest on the right
+    Chromatic,
+    /// C, D, E, F#, G# and A# on the left, the rest on the right, with the lowest pipes on
+    /// the outside of each side
+    #[serde(rename = "c_cs")]
+    CSides,
+    /// alternating sides like `c_cs`, but with the lowest pipes in the middle
+    Mitre,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct LayoutEntry {
+    #[serde(default, rename = "type")]
+    pub layout_type: Option<LayoutType>,
+
+    // optional parameters
+    /// how much of the space between the first and last output channel the layout spans
+    #[serde(default = "layout_width_default")]
+    pub width: f32,
+    /// pan positions (0 to 1) by note, for any notes that don't follow the layout
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub pan: BTreeMap<String, f32>,
+    /// output channels by note, for pipes on chests of their own
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub channel: BTreeMap<String, usize>,
+}
+
+fn layout_width_default() -> f32 {
+    1.0
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RankInfo {
+    pub rank_type: String,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct PipeRankConfig {
-    name: String,
-    rank_type: String,
-    sample_location: ResourceId,
-    pipe: BTreeMap<String, PipesRankEntry>,
+    pub name: String,
+    pub rank_type: String,
+    pub sample_location: ResourceId,
+    pub pipe: BTreeMap<String, PipesRankEntry>,
 
     // optional parameters
     #[serde(default)]
-    attenuation: f32,
+    pub attenuation: f32,
     #[serde(default = "crossfade_default")]
-    crossfade: usize,
+    pub crossfade: usize,
+    #[serde(default)]
+    pub even_harm_atten: f32,
+    /// gain in dB of a high shelf over every pipe
+    #[serde(default)]
+    pub brightness: f32,
+    /// which harmonic of each pipe the brightness shelf is centered on
+    #[serde(default = "brightness_harmonic_default")]
+    pub brightness_harmonic: f32,
+    /// highpass cutoff in Hz, zero for none
     #[serde(default)]
-    even_harm_atten: f32,
+    pub low_cut: f32,
     #[serde(default)]
-    sample_format: Option<String>,
+    pub sample_format: Option<String>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub mic: BTreeMap<String, MicEntry>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub layout: Option<LayoutEntry>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct PercussionRankConfig {
-    name: String,
-    rank_type: String,
-    sample_location: ResourceId,
-    percussion: BTreeMap<String, PercussionRankEntry>,
+    pub name: String,
+    pub rank_type: String,
+    pub sample_location: ResourceId,
+    pub percussion: BTreeMap<String, PercussionRankEntry>,
 
     // optional parameters
     #[serde(default)]
-    attenuation: f32,
+    pub attenuation: f32,
     #[serde(default)]
-    sample_format: Option<String>,
+    pub sample_format: Option<String>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub mic: BTreeMap<String, MicEntry>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub layout: Option<LayoutEntry>,
 }
 
 impl PipeRankConfig {
+    pub fn new(
+        name: String,
+        sample_location: ResourceId,
+        attenuation: f32,
+        pipes: BTreeMap<u8, PipesRankEntry>,
+    ) -> Self {
+        PipeRankConfig {
+            name,
+            rank_type: "pipes".to_string(),
+            sample_location,
+            pipe: pipes
+                .into_iter()
+                .map(|(note, entry)| (note.to_string(), entry))
+                .collect(),
+            attenuation,
+            crossfade: crossfade_default(),
+            even_harm_atten: 0.0,
+            brightness: 0.0,
+            brightness_harmonic: brightness_harmonic_default(),
+            low_cut: 0.0,
+            sample_format: None,
+            mic: BTreeMap::new(),
+            layout: None,
+        }
+    }
+
     pub fn from_pipes_rank(rank: Rank<Pipe>, sample_location: ResourceId) -> Self {
         let entries: BTreeMap<String, PipesRankEntry> = rank
             .notes
@@ -99,10 +241,13 @@ impl PipeRankConfig {
                         loop_start: pipe.loop_start,
                         loop_end: pipe.loop_end,
                         release_index: pipe.release_index,
+                        extra_loops: pipe.extra_loops,
                         crossfade: Some(pipe.crossfade),
                         file: None,
                         attenuation: 0.0,
                         even_harm_atten: 0.0,
+                        brightness: 0.0,
+                        low_cut: None,
                     },
                 )
             })
@@ -117,6 +262,34 @@ impl PipeRankConfig {
             crossfade: 0,
             sample_format: None,
             even_harm_atten: 0.0,
+            brightness: 0.0,
+            brightness_harmonic: brightness_harmonic_default(),
+            low_cut: 0.0,
+            mic: BTreeMap::new(),
+            layout: None,
+        }
+    }
+}
+
+impl PercussionRankConfig {
+    pub fn new(
+        name: String,
+        sample_location: ResourceId,
+        attenuation: f32,
+        percussion: BTreeMap<u8, PercussionRankEntry>,
+    ) -> Self {
+        PercussionRankConfig {
+            name,
+            rank_type: "percussion".to_string(),
+            sample_location,
+            percussion: percussion
+                .into_iter()
+                .map(|(note, entry)| (note.to_string(), entry))
+                .collect(),
+            attenuation,
+            sample_format: None,
+            mic: BTreeMap::new(),
+            layout: None,
         }
     }
 }
@@ -127,8 +300,434 @@ pub fn expected_sample_location(note: u8, sample_format: &str) -> String {
     format!("{:0>3}-{}.{}", note, NOTE_LOOKUP[(note % 12) as usize], sample_format)
 }
 
+/// Where the sample for `note` lives, either given explicitly by the entry's `file` or
+/// going by the expected naming scheme
+pub fn entry_resource(
+    sample_location: &ResourceId,
+    sample_format: &Option<String>,
+    note: u8,
+    file: &Option<String>,
+) -> ResourceId {
+    match file {
+        Some(file) => sample_location.concat(file),
+        None => sample_location.concat(&expected_sample_location(
+            note,
+            sample_format.as_deref().unwrap_or("wav"),
+        )),
+    }
+}
+
+fn mic_positions(mics: BTreeMap<String, MicEntry>) -> Vec<MicPosition> {
+    mics.into_iter()
+        .map(|(name, entry)| MicPosition {
+            name,
+            channels: entry.channels,
+            gain: db_to_gain(-entry.attenuation),
+        })
+        .collect()
+}
+
+/// Places each of `notes` according to `layout`, if there is one
+fn note_layout(
+    layout: Option<LayoutEntry>,
+    notes: impl Iterator<Item = u8> + Clone,
+) -> Result<BTreeMap<u8, Placement>, EngineError> {
+    let Some(layout) = layout else {
+        return Ok(BTreeMap::new());
+    };
+
+    ensure_whatever!(
+        (0.0..=1.0).contains(&layout.width),
+        "Layout width must be between 0 and 1"
+    );
+
+    let lowest = notes.clone().min().unwrap_or(0);
+    let highest = notes.clone().max().unwrap_or(0);
+
+    let mut placements: BTreeMap<u8, Placement> = notes
+        .map(|note| {
+            // how far up the rank the note is, from 0 to 1
+            let height = (note - lowest) as f32 / (highest - lowest).max(1) as f32;
+            let side = if note % 2 == 0 { -1.0 } else { 1.0 };
+            let half_width = layout.width / 2.0;
+
+            let pan = match layout.layout_type {
+                Some(LayoutType::Chromatic) => 0.5 + layout.width * (height - 0.5),
+                Some(LayoutType::CSides) => 0.5 + side * half_width * (1.0 - height),
+                Some(LayoutType::Mitre) => 0.5 + side * half_width * height,
+                None => 0.5,
+            };
+
+            (note, Placement::Pan(pan))
+        })
+        .collect();
+
+    for (note, pan) in layout.pan {
+        let note: u8 = parse_layout_note(&note)?;
+        ensure_whatever!(
+            (0.0..=1.0).contains(&pan),
+            "Layout pan of note {note} must be between 0 and 1"
+        );
+
+        placements.insert(note, Placement::Pan(pan));
+    }
+
+    for (note, channel) in layout.channel {
+        placements.insert(parse_layout_note(&note)?, Placement::Channel(channel));
+    }
+
+    Ok(placements)
+}
+
+fn parse_layout_note(note: &str) -> Result<u8, EngineError> {
+    note.parse().map_err(|_| EngineError::ParserError {
+        error: format!("layout note '{note}' is not a number"),
+    })
+}
+
+fn next_revision() -> u64 {
+    static REVISION: AtomicU64 = AtomicU64::new(1);
+
+    REVISION.fetch_add(1, Ordering::Relaxed)
+}
+
+/// A change to one pipe of a `[rank].toml` file. Fields that are left out stay as they are.
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PipeEdit {
+    pub cents: Option<i16>,
+    pub decay_index: Option<usize>,
+    pub loop_start: Option<usize>,
+    pub loop_end: Option<usize>,
+    pub release_index: Option<usize>,
+    pub extra_loops: Option<Vec<(usize, usize)>>,
+    pub crossfade: Option<usize>,
+    pub attenuation: Option<f32>,
+    pub even_harm_atten: Option<f32>,
+    pub brightness: Option<f32>,
+    /// `null` clears the pipe's own low cut, so it goes back to the rank's
+    #[serde(default, deserialize_with = "present")]
+    pub low_cut: Option<Option<f32>>,
+}
+
+/// Tells apart a field that's `null` (`Some(None)`) from one that was left out (`None`)
+fn present<'de, D, T>(deserializer: D) -> Result<Option<Option<T>>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    Option::<T>::deserialize(deserializer).map(Some)
+}
+
+impl PipeEdit {
+    fn apply(&self, entry: &mut PipesRankEntry) {
+        if let Some(cents) = self.cents {
+            entry.cents = cents;
+        }
+        if let Some(decay_index) = self.decay_index {
+            entry.decay_index = decay_index;
+        }
+        if let Some(loop_start) = self.loop_start {
+            entry.loop_start = loop_start;
+        }
+        if let Some(loop_end) = self.loop_end {
+            entry.loop_end = loop_end;
+        }
+        if let Some(release_index) = self.release_index {
+            entry.release_index = release_index;
+        }
+        if let Some(extra_loops) = &self.extra_loops {
+            entry.extra_loops = extra_loops.clone();
+        }
+        if let Some(crossfade) = self.crossfade {
+            entry.crossfade = Some(crossfade);
+        }
+        if let Some(attenuation) = self.attenuation {
+            entry.attenuation = attenuation;
+        }
+        if let Some(even_harm_atten) = self.even_harm_atten {
+            entry.even_harm_atten = even_harm_atten;
+        }
+        if let Some(brightness) = self.brightness {
+            entry.brightness = brightness;
+        }
+        if let Some(low_cut) = self.low_cut {
+            entry.low_cut = low_cut;
+        }
+    }
+}
+
+fn validate_pipe(note: u8, entry: &PipesRankEntry, crossfade: usize, len: Option<usize>) -> Result<(), EngineError> {
+    ensure_whatever!(
+        (-1200..=1200).contains(&entry.cents),
+        "Pipe {note}: cents must be within an octave"
+    );
+    ensure_whatever!(
+        entry.attenuation.is_finite() && entry.even_harm_atten.is_finite(),
+        "Pipe {note}: attenuation must be a number"
+    );
+    ensure_whatever!(entry.brightness.is_finite(), "Pipe {note}: brightness must be a number");
+    ensure_whatever!(
+        entry.low_cut.map(|low_cut| low_cut >= 0.0).unwrap_or(true),
+        "Pipe {note}: low cut must be zero (for none) or a frequency"
+    );
+
+    let loops = std::iter::once((entry.loop_start, entry.loop_end)).chain(entry.extra_loops.iter().copied());
+
+    for (loop_start, loop_end) in loops {
+        ensure_whatever!(
+            loop_start < loop_end,
+            "Pipe {note}: loop start ({loop_start}) must come before loop end ({loop_end})"
+        );
+        ensure_whatever!(
+            crossfade < loop_end - loop_start,
+            "Pipe {note}: crossfade ({crossfade}) must be shorter than the loop ({loop_start} to {loop_end})"
+        );
+    }
+
+    if let Some(len) = len {
+        let last = entry.resident_frames(crossfade);
+
+        ensure_whatever!(
+            last <= len,
+            "Pipe {note}: indexes (and crossfade) reach frame {last}, but the sample is only {len} frames long"
+        );
+    }
+
+    Ok(())
+}
+
+fn set_value(table: &mut toml_edit::Item, key: &str, value: impl Into<toml_edit::Value>) {
+    let mut value = value.into();
+
+    // keep any comments around the old value
+    if let Some(existing) = table.get(key).and_then(|item| item.as_value()) {
+        *value.decor_mut() = existing.decor().clone();
+    }
+
+    table[key] = toml_edit::Item::Value(value);
+}
+
+fn float(value: f32) -> f64 {
+    // go through the shortest representation, so 0.1 doesn't turn into 0.10000000149011612
+    value.to_string().parse().unwrap_or(value as f64)
+}
+
+/// Applies `edits` (by note) to a pipe rank's `[rank].toml`, keeping its formatting and
+/// comments. `sample_len` looks up the length in frames of a sample, which the edited
+/// indexes are checked against.
+pub fn edit_pipes(
+    config: &str,
+    edits: &BTreeMap<u8, PipeEdit>,
+    sample_len: impl Fn(&ResourceId) -> Option<usize>,
+) -> Result<String, EngineError> {
+    let info: RankInfo = toml_edit::de::from_str(config).context(TomlParserDeSnafu)?;
+    ensure_whatever!(info.rank_type == "pipes", "Only pipe ranks can be edited");
+
+    let mut parsed: PipeRankConfig = toml_edit::de::from_str(config).context(TomlParserDeSnafu)?;
+    let mut document: toml_edit::Document = config
+        .parse()
+        .map_err(|err: toml_edit::TomlError| EngineError::ParserError { error: err.to_string() })?;
+
+    for (note, edit) in edits {
+        let key = note.to_string();
+
+        let Some(entry) = parsed.pipe.get_mut(&key) else {
+            whatever!("Rank has no pipe for note {note}");
+        };
+
+        edit.apply(entry);
+
+        let resource = entry_resource(&parsed.sample_location, &parsed.sample_format, *note, &entry.file);
+        let crossfade = entry.crossfade.unwrap_or(parsed.crossfade);
+        validate_pipe(*note, entry, crossfade, sample_len(&resource))?;
+
+        let table = &mut document["pipe"][key.as_str()];
+
+        if edit.cents.is_some() {
+            set_value(table, "cents", entry.cents as i64);
+        }
+        if edit.decay_index.is_some() {
+            set_value(table, "decay_index", entry.decay_index as i64);
+        }
+        if edit.loop_start.is_some() {
+            set_value(table, "loop_start", entry.loop_start as i64);
+        }
+        if edit.loop_end.is_some() {
+            set_value(table, "loop_end", entry.loop_end as i64);
+        }
+        if edit.release_index.is_some() {
+            set_value(table, "release_index", entry.release_index as i64);
+        }
+        if edit.extra_loops.is_some() {
+            if entry.extra_loops.is_empty() {
+                if let Some(table) = table.as_table_like_mut() {
+                    table.remove("extra_loops");
+                }
+            } else {
+                let loops: toml_edit::Array = entry
+                    .extra_loops
+                    .iter()
+                    .map(|(start, end)| toml_edit::Array::from_iter([*start as i64, *end as i64]))
+                    .collect();
+
+                set_value(table, "extra_loops", loops);
+            }
+        }
+        if let Some(crossfade) = edit.crossfade {
+            set_value(table, "crossfade", crossfade as i64);
+        }
+        if edit.attenuation.is_some() {
+            set_value(table, "attenuation", float(entry.attenuation));
+        }
+        if edit.even_harm_atten.is_some() {
+            set_value(table, "even_harm_atten", float(entry.even_harm_atten));
+        }
+        if edit.brightness.is_some() {
+            set_value(table, "brightness", float(entry.brightness));
+        }
+        match edit.low_cut {
+            Some(Some(low_cut)) => set_value(table, "low_cut", float(low_cut)),
+            Some(None) => {
+                if let Some(table) = table.as_table_like_mut() {
+                    table.remove("low_cut");
+                }
+            }
+            None => {}
+        }
+    }
+
+    Ok(document.to_string())
+}
+
+/// A change to the voicing of a whole pipe rank, on top of which each pipe's own voicing goes
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RankVoicingEdit {
+    pub brightness: Option<f32>,
+    pub brightness_harmonic: Option<f32>,
+    pub low_cut: Option<f32>,
+    pub even_harm_atten: Option<f32>,
+}
+
+/// Like `edit_pipes`, but for the voicing of the whole rank
+pub fn edit_rank_voicing(config: &str, edit: &RankVoicingEdit) -> Result<String, EngineError> {
+    let info: RankInfo = toml_edit::de::from_str(config).context(TomlParserDeSnafu)?;
+    ensure_whatever!(info.rank_type == "pipes", "Only pipe ranks can be edited");
+
+    let values = [
+        ("brightness", edit.brightness),
+        ("brightness_harmonic", edit.brightness_harmonic),
+        ("low_cut", edit.low_cut),
+        ("even_harm_atten", edit.even_harm_atten),
+    ];
+
+    for (key, value) in values {
+        if let Some(value) = value {
+            ensure_whatever!(value.is_finite(), "Rank {key} must be a number");
+        }
+    }
+    if let Some(brightness_harmonic) = edit.brightness_harmonic {
+        ensure_whatever!(brightness_harmonic > 0.0, "Brightness harmonic must be above zero");
+    }
+    if let Some(low_cut) = edit.low_cut {
+        ensure_whatever!(low_cut >= 0.0, "Low cut must be zero (for none) or a frequency");
+    }
+
+    let mut document: toml_edit::Document = config
+        .parse()
+        .map_err(|err: toml_edit::TomlError| EngineError::ParserError { error: err.to_string() })?;
+
+    for (key, value) in values {
+        if let Some(value) = value {
+            set_value(document.as_item_mut(), key, float(value));
+        }
+    }
+
+    Ok(document.to_string())
+}
+
+/// Finds out how much of each sample in a `[rank].toml` file needs to stay in memory when
+/// streaming, by sample resource key. Pipes need everything up to their loop and release
+/// points, as playback can jump around in there; percussion is only ever played front to back.
+pub fn resident_frames(config: &str) -> Result<BTreeMap<String, usize>, EngineError> {
+    let info: RankInfo = toml_edit::de::from_str(config).context(TomlParserDeSnafu)?;
+
+    let mut resident = BTreeMap::new();
+
+    match info.rank_type.as_ref() {
+        "pipes" => {
+            let parsed: PipeRankConfig = toml_edit::de::from_str(config).context(TomlParserDeSnafu)?;
+
+            for (note, entry) in &parsed.pipe {
+                let Ok(note) = note.parse::<u8>() else {
+                    continue;
+                };
+
+                let resource = entry_resource(&parsed.sample_location, &parsed.sample_format, note, &entry.file);
+
+                resident.insert(resource.resource, entry.resident_frames(parsed.crossfade));
+            }
+        }
+        "percussion" => {
+            let parsed: PercussionRankConfig = toml_edit::de::from_str(config).context(TomlParserDeSnafu)?;
+
+            for (note, entry) in &parsed.percussion {
+                let Ok(note) = note.parse::<u8>() else {
+                    continue;
+                };
+
+                let resource = entry_resource(&parsed.sample_location, &parsed.sample_format, note, &entry.file);
+                resident.insert(resource.resource, 0);
+
+                for file in entry.layer_files() {
+                    resident.insert(parsed.sample_location.concat(file).resource, 0);
+                }
+            }
+        }
+        _ => {}
+    }
+
+    Ok(resident)
+}
+
+/// Works out the attack and release envelope tables of a pipe. Lets rank loading go through a cache.
+pub trait EnvelopeSource: Sync {
+    fn envelopes(
+        &self,
+        resource: &ResourceId,
+        sample: &MonoSample,
+        decay_index: usize,
+        release_index: usize,
+        window_size: usize,
+    ) -> (EnvelopeIndexes, EnvelopeIndexes);
+}
+
+/// Calculates envelope tables every time
+pub struct Uncached;
+
+impl EnvelopeSource for Uncached {
+    fn envelopes(
+        &self,
+        _resource: &ResourceId,
+        sample: &MonoSample,
+        decay_index: usize,
+        release_index: usize,
+        window_size: usize,
+    ) -> (EnvelopeIndexes, EnvelopeIndexes) {
+        (
+            envelope_indexes(decay_index, release_index, sample, window_size, EnvelopeType::Attack),
+            envelope_indexes(decay_index, release_index, sample, window_size, EnvelopeType::Release),
+        )
+    }
+}
+
 /// Parses a `[rank].toml` file and converts it into a `Rank`
-pub fn parse_rank(config: &str, samples: &ResourceManager<MonoSample>) -> Result<RankType, EngineError> {
+pub fn parse_rank(
+    config: &str,
+    samples: &ResourceManager<MultiSample>,
+    envelopes: &dyn EnvelopeSource,
+) -> Result<RankType, EngineError> {
     let info: RankInfo = toml_edit::de::from_str(config).context(TomlParserDeSnafu)?;
 
     match info.rank_type.as_ref() {
@@ -153,25 +752,24 @@ pub fn parse_rank(config: &str, samples: &ResourceManager<MonoSample>) -> Result
                 };
 
                 if let Some(sample) = samples.borrow_resource_by_id(&resource.resource) {
-                    let buffer_rate = sample.sample_rate;
+                    let buffer_rate = sample.sample_rate();
                     let freq = (440.0 / 32.0) * 2_f32.powf((note - 9) as f32 / 12.0 + (entry.cents as f32 / 1200.0));
                     let amp_window_size = (buffer_rate as f32 / freq) as usize * 2;
 
                     let phase_calculator = PhaseCalculator::new(freq, buffer_rate);
 
-                    let attack_envelope = envelope_indexes(
-                        entry.decay_index,
-                        entry.release_index,
-                        sample,
-                        amp_window_size,
-                        EnvelopeType::Attack,
-                    );
-                    let release_envelope = envelope_indexes(
-                        entry.decay_index,
-                        entry.release_index,
-                        sample,
+                    // the config's positions are in the file's frames, which differ if the
+                    // sample was resampled when loading
+                    let position = |position: usize| sample.from_file_position(position);
+                    let decay_index = position(entry.decay_index);
+                    let release_index = position(entry.release_index);
+
+                    let (attack_envelope, release_envelope) = envelopes.envelopes(
+                        &resource,
+                        sample.first_channel(),
+                        decay_index,
+                        release_index,
                         amp_window_size,
-                        EnvelopeType::Release,
                     );
 
                     pipes.insert(
@@ -180,12 +778,23 @@ pub fn parse_rank(config: &str, samples: &ResourceManager<MonoSample>) -> Result
                             resource,
                             freq,
                             amplitude: db_to_gain(-(entry.attenuation + parsed.attenuation)),
-                            loop_start: entry.loop_start,
-                            loop_end: entry.loop_end,
-                            decay_index: entry.decay_index,
-                            release_index: entry.release_index,
-                            crossfade: entry.crossfade.unwrap_or(parsed.crossfade),
-                            comb_coeff: 0.0,
+                            loop_start: position(entry.loop_start),
+                            loop_end: position(entry.loop_end),
+                            extra_loops: entry
+                                .extra_loops
+                                .into_iter()
+                                .map(|(start, end)| (position(start), position(end)))
+                                .filter(|(start, end)| start < end)
+                                .collect(),
+                            decay_index,
+                            release_index,
+                            crossfade: position(entry.crossfade.unwrap_or(parsed.crossfade)),
+                            voicing: Voicing {
+                                comb_coeff: Voicing::comb_coeff_from_db(entry.even_harm_atten + parsed.even_harm_atten),
+                                brightness: entry.brightness + parsed.brightness,
+                                brightness_freq: freq * parsed.brightness_harmonic,
+                                low_cut: entry.low_cut.unwrap_or(parsed.low_cut),
+                            },
                             amp_window_size,
                             phase_calculator,
                             attack_envelope: attack_envelope,
@@ -195,9 +804,14 @@ pub fn parse_rank(config: &str, samples: &ResourceManager<MonoSample>) -> Result
                 }
             }
 
+            let layout = note_layout(parsed.layout, pipes.keys().copied())?;
+
             Ok(RankType::Pipes(Rank {
                 notes: pipes,
                 name: parsed.name,
+                layout,
+                mics: mic_positions(parsed.mic),
+                revision: next_revision(),
             }))
         }
         "percussion" => {
@@ -216,31 +830,76 @@ pub fn parse_rank(config: &str, samples: &ResourceManager<MonoSample>) -> Result
                     }
                 };
 
-                // figure out what the sample id is
-                let sample_id = if let Some(sample_id) = entry.file {
-                    parsed.sample_location.concat(&sample_id)
+                let mut sample_ids: Vec<ResourceId> = vec![];
+                let mut layers: Vec<PercussionLayer> = vec![];
+
+                if entry.layer.is_empty() {
+                    // figure out what the sample id is
+                    let sample_id = if let Some(sample_id) = &entry.file {
+                        parsed.sample_location.concat(sample_id)
+                    } else {
+                        parsed
+                            .sample_location
+                            .concat(&expected_sample_location(note, &sample_format))
+                    };
+
+                    sample_ids.push(sample_id);
+                    layers.push(PercussionLayer {
+                        velocity: (0, 127),
+                        fade_in: (0, 0),
+                        fade_out: (127, 127),
+                        alternates: 0..1,
+                        gain: 1.0,
+                    });
                 } else {
-                    parsed
-                        .sample_location
-                        .concat(&expected_sample_location(note, &sample_format))
-                };
+                    for layer in &entry.layer {
+                        let (low, high) = layer.velocity;
+                        if low > high {
+                            return Err(EngineError::ParserError {
+                                error: format!("note {note}: layer velocity {low} is above {high}"),
+                            });
+                        }
+
+                        let start = sample_ids.len();
+                        sample_ids.extend(layer.files.iter().map(|file| parsed.sample_location.concat(file)));
 
-                // make sure the sample exists
-                if let Some(_) = samples.borrow_resource_by_id(&sample_id.resource) {
+                        layers.push(PercussionLayer {
+                            velocity: layer.velocity,
+                            fade_in: (low, low),
+                            fade_out: (high, high),
+                            alternates: start..sample_ids.len(),
+                            gain: db_to_gain(-layer.attenuation),
+                        });
+                    }
+                }
+
+                // make sure the samples exist
+                let all_exist = sample_ids
+                    .iter()
+                    .all(|sample_id| samples.borrow_resource_by_id(&sample_id.resource).is_some());
+
+                if all_exist && !sample_ids.is_empty() {
                     percussion.insert(
                         note,
                         Percussion {
-                            resource: sample_id,
+                            samples: sample_ids,
+                            layers: PercussionLayer::crossfade(layers),
                             gain: db_to_gain(-(entry.attenuation + parsed.attenuation)),
                             release_duration: entry.release,
+                            choke_group: entry.choke_group,
                         },
                     );
                 }
             }
 
+            let layout = note_layout(parsed.layout, percussion.keys().copied())?;
+
             Ok(RankType::Percussion(Rank {
                 notes: percussion,
                 name: parsed.name,
+                layout,
+                mics: mic_positions(parsed.mic),
+                revision: next_revision(),
             }))
         }
         _ => Err(EngineError::ParserError {
@@ -250,10 +909,144 @@ pub fn parse_rank(config: &str, samples: &ResourceManager<MonoSample>) -> Result
 }
 
 #[cfg(any(unix, windows))]
-pub fn load_rank_from_file(path: &Path, samples: &ResourceManager<MonoSample>) -> Result<RankType, EngineError> {
+pub fn load_rank_from_file(
+    path: &Path,
+    samples: &ResourceManager<MultiSample>,
+    envelopes: &dyn EnvelopeSource,
+) -> Result<RankType, EngineError> {
     use crate::errors::IoSnafu;
 
     let file = read_to_string(path).context(IoSnafu)?;
 
-    parse_rank(&file, samples)
+    parse_rank(&file, samples, envelopes)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use sound_engine::sampling::rank::Placement;
+
+    use super::{edit_pipes, edit_rank_voicing, note_layout, LayoutEntry, LayoutType, PipeEdit, RankVoicingEdit};
+
+    const RANK: &str = r#"name = "Principal 8"
+rank_type = "pipes"
+sample_location = { namespace = "samples", resource = "principal" }
+
+# voiced by ear
+[pipe.60]
+cents = 0 # close enough
+decay_index = 100
+loop_start = 1000
+loop_end = 5000
+release_index = 6000
+"#;
+
+    #[test]
+    fn edits_keep_comments() {
+        let edits = BTreeMap::from([(
+            60,
+            PipeEdit {
+                cents: Some(-3),
+                attenuation: Some(1.5),
+                extra_loops: Some(vec![(2000, 5500)]),
+                ..PipeEdit::default()
+            },
+        )]);
+
+        let edited = edit_pipes(RANK, &edits, |_| Some(10_000)).unwrap();
+
+        assert!(edited.contains("# voiced by ear"));
+        assert!(edited.contains("cents = -3 # close enough"));
+        assert!(edited.contains("attenuation = 1.5"));
+        assert!(edited.contains("extra_loops = [[2000, 5500]]"));
+        assert!(edited.contains("loop_start = 1000"));
+    }
+
+    #[test]
+    fn edits_are_validated() {
+        let backwards = BTreeMap::from([(
+            60,
+            PipeEdit {
+                loop_end: Some(500),
+                ..PipeEdit::default()
+            },
+        )]);
+        assert!(edit_pipes(RANK, &backwards, |_| None).is_err());
+
+        let too_long = BTreeMap::from([(
+            60,
+            PipeEdit {
+                release_index: Some(20_000),
+                ..PipeEdit::default()
+            },
+        )]);
+        assert!(edit_pipes(RANK, &too_long, |_| Some(10_000)).is_err());
+        assert!(edit_pipes(RANK, &too_long, |_| None).is_ok());
+
+        let missing = BTreeMap::from([(61, PipeEdit::default())]);
+        assert!(edit_pipes(RANK, &missing, |_| None).is_err());
+    }
+
+    #[test]
+    fn low_cut_can_be_cleared() {
+        let set: BTreeMap<u8, PipeEdit> = serde_json::from_str(r#"{ "60": { "lowCut": 80.0 } }"#).unwrap();
+        let edited = edit_pipes(RANK, &set, |_| None).unwrap();
+        assert!(edited.contains("low_cut = 80"));
+
+        // leaving it out keeps it
+        let other: BTreeMap<u8, PipeEdit> = serde_json::from_str(r#"{ "60": { "cents": 2 } }"#).unwrap();
+        let edited = edit_pipes(&edited, &other, |_| None).unwrap();
+        assert!(edited.contains("low_cut = 80"));
+
+        let clear: BTreeMap<u8, PipeEdit> = serde_json::from_str(r#"{ "60": { "lowCut": null } }"#).unwrap();
+        let edited = edit_pipes(&edited, &clear, |_| None).unwrap();
+        assert!(!edited.contains("low_cut"));
+    }
+
+    #[test]
+    fn rank_voicing_stays_above_the_pipes() {
+        let edit = RankVoicingEdit {
+            brightness: Some(-2.5),
+            low_cut: Some(40.0),
+            ..RankVoicingEdit::default()
+        };
+
+        let edited = edit_rank_voicing(RANK, &edit).unwrap();
+        let (rank, pipes) = edited.split_once("[pipe.60]").unwrap();
+
+        assert!(rank.contains("brightness = -2.5"));
+        assert!(rank.contains("low_cut = 40"));
+        assert!(!pipes.contains("brightness"));
+
+        let negative = RankVoicingEdit {
+            low_cut: Some(-10.0),
+            ..RankVoicingEdit::default()
+        };
+        assert!(edit_rank_voicing(RANK, &negative).is_err());
+    }
+
+    #[test]
+    fn c_sides_layout() {
+        let layout = LayoutEntry {
+            layout_type: Some(LayoutType::CSides),
+            width: 1.0,
+            pan: BTreeMap::new(),
+            channel: BTreeMap::from([("41".to_string(), 3)]),
+        };
+
+        let placements = note_layout(Some(layout), 36..=41).unwrap();
+        let pan = |note: u8| match placements[&note] {
+            Placement::Pan(pan) => pan,
+            Placement::Channel(_) => panic!("note {note} should be panned"),
+        };
+
+        // the lowest pipes are on the outside, and the sides alternate
+        assert!(pan(36).abs() < 1e-6);
+        assert!((pan(37) - 0.9).abs() < 1e-6);
+        assert!((pan(40) - 0.4).abs() < 1e-6);
+        assert_eq!(placements[&41], Placement::Channel(3));
+
+        assert!(note_layout(None, 36..=41).unwrap().is_empty());
+    }
 }
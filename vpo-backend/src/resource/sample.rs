@@ -1,40 +1,79 @@
 use std::path::Path;
 
 use regex::Regex;
-use sound_engine::MonoSample;
 use sound_engine::SoundConfig;
+use sound_engine::{MonoSample, MultiSample};
+use symphonia::core::io::MediaSource;
 
 use crate::errors::EngineError;
 
-pub fn load_sample(location: &Path, sound_config: &SoundConfig) -> Result<MonoSample, EngineError> {
-    use super::decode_audio::decode_audio;
-    use crate::{errors::FileSnafu, resource::util::first_channel_only};
+pub fn load_sample(location: &Path, sound_config: &SoundConfig) -> Result<MultiSample, EngineError> {
+    use crate::errors::FileSnafu;
     use snafu::ResultExt;
 
     use std::fs::File;
-    use symphonia::core::probe::Hint;
 
     let file = Box::new(File::open(location).context(FileSnafu)?);
+
+    decode_sample(file, location)
+}
+
+/// Like `load_sample`, but for a file that's already been read. `location` is only used to
+/// guess the format.
+pub fn decode_sample(source: Box<dyn MediaSource>, location: &Path) -> Result<MultiSample, EngineError> {
+    use super::decode_audio::decode_audio;
+    use crate::resource::util::deinterleave;
+
+    use symphonia::core::probe::Hint;
+
     let mut hint = Hint::new();
 
     if let Some(extension) = location.extension() {
         hint.with_extension(&extension.to_string_lossy());
     }
 
-    let (audio, spec) = decode_audio(file, hint)?;
+    let (audio, spec) = decode_audio(source, hint)?;
 
-    let audio = first_channel_only(&audio, spec.channels.count());
+    let channels = deinterleave(&audio, spec.channels.count().max(1));
 
-    // TODO: add lowpass on audio at current sample rate (don't
-    // resample though, as loop points are based on the file's
-    // original sample rate)
+    // kept at the file's sample rate, as loop points are based on it. Projects can
+    // have samples resampled after loading (see `ResamplingSettings`)
 
-    Ok(MonoSample {
-        audio_raw: audio,
-        sample_rate: spec.rate,
+    Ok(MultiSample {
+        channels: channels
+            .into_iter()
+            .map(|audio| MonoSample {
+                audio_raw: audio,
+                sample_rate: spec.rate,
+            })
+            .collect(),
+        tail: None,
+        source_sample_rate: None,
     })
 }
 
+/// Like `load_sample`, but WAV files only have their start loaded, and the rest is streamed
+/// from disk when played. Other formats can't be seeked cheaply, so they're loaded completely.
+pub fn load_sample_streamed(
+    location: &Path,
+    resident_frames: usize,
+    preload_ms: u32,
+    sound_config: &SoundConfig,
+) -> Result<MultiSample, EngineError> {
+    use super::wav_stream::load_wav_streamed;
+
+    let is_wav = location
+        .extension()
+        .map(|extension| extension.eq_ignore_ascii_case("wav"))
+        .unwrap_or(false);
+
+    if is_wav {
+        load_wav_streamed(location, resident_frames, preload_ms)
+    } else {
+        load_sample(location, sound_config)
+    }
+}
+
 pub fn check_for_note_number(file_prefix: &str) -> Option<u8> {
     let get_numbers = Regex::new(r"([0-9]+)").unwrap();
     let remove_leading_zeroes = Regex::new(r"^0+").unwrap();
@@ -0,0 +1,541 @@
+//! Converting between SFZ files and rank configs
+//!
+//! A rank has one sample per note and doesn't pitch shift, so a region only ever fills in
+//! the note at its `pitch_keycenter`. Release triggers, velocity layers and the like are
+//! left out.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use common::resource_manager::{ResourceId, ResourceManager};
+use log::warn;
+use regex::Regex;
+use snafu::ResultExt;
+use sound_engine::{sampling::rank::RankType, MultiSample};
+
+use crate::{
+    errors::{EngineError, TomlParserDeSnafu, TomlParserSerSnafu},
+    resource::rank::{
+        entry_resource, parse_rank, PercussionRankConfig, PercussionRankEntry, PipeRankConfig, PipesRankEntry,
+        RankInfo, Uncached,
+    },
+};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SfzRegion {
+    /// path of the sample relative to the SFZ file, with `/` separators
+    pub sample: String,
+    pub lokey: u8,
+    pub hikey: u8,
+    pub pitch_keycenter: u8,
+    pub loop_mode: Option<String>,
+    pub loop_start: Option<usize>,
+    pub loop_end: Option<usize>,
+    /// in cents
+    pub tune: i32,
+    /// in semitones
+    pub transpose: i32,
+    /// in dB
+    pub volume: f32,
+    /// in seconds
+    pub ampeg_release: f32,
+}
+
+impl SfzRegion {
+    pub fn is_looped(&self) -> bool {
+        match self.loop_mode.as_deref() {
+            Some("loop_continuous") | Some("loop_sustain") => true,
+            Some(_) => false,
+            None => self.loop_start.is_some() && self.loop_end.is_some(),
+        }
+    }
+
+    fn cents(&self) -> i16 {
+        // `tune` corrects the sample, so the sample itself is off by the opposite
+        (-(self.tune + self.transpose * 100)).clamp(i16::MIN as i32, i16::MAX as i32) as i16
+    }
+}
+
+const NOTE_NAMES: [&str; 7] = ["c", "d", "e", "f", "g", "a", "b"];
+const NOTE_OFFSETS: [i32; 7] = [0, 2, 4, 5, 7, 9, 11];
+
+/// Parses a key, either as a MIDI note number or as a name like `c#4` (where `c4` is 60)
+fn parse_key(value: &str) -> Option<u8> {
+    if let Ok(number) = value.parse::<i32>() {
+        return u8::try_from(number).ok().filter(|note| *note < 128);
+    }
+
+    let value = value.to_lowercase();
+    let name = NOTE_NAMES.iter().position(|name| value.starts_with(name))?;
+    let mut rest = &value[1..];
+
+    let mut note = NOTE_OFFSETS[name];
+    if let Some(stripped) = rest.strip_prefix('#') {
+        note += 1;
+        rest = stripped;
+    } else if let Some(stripped) = rest.strip_prefix('b') {
+        note -= 1;
+        rest = stripped;
+    }
+
+    let octave: i32 = rest.parse().ok()?;
+
+    u8::try_from((octave + 1) * 12 + note).ok().filter(|note| *note < 128)
+}
+
+fn strip_comments(source: &str) -> String {
+    let block_comments = Regex::new(r"(?s)/\*.*?\*/").unwrap();
+    let without_blocks = block_comments.replace_all(source, "");
+
+    without_blocks
+        .lines()
+        .map(|line| line.split("//").next().unwrap_or(""))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Splits an SFZ file into its headers and the opcodes under each one. Values can contain
+/// spaces (sample paths often do), so a value runs until the next opcode or header.
+fn tokenize(source: &str) -> Vec<(String, Vec<(String, String)>)> {
+    let source = strip_comments(source);
+
+    let defines = Regex::new(r"(?m)^\s*#define\s+(\$\w+)\s+(\S+)\s*$").unwrap();
+    let mut definitions: Vec<(String, String)> = defines
+        .captures_iter(&source)
+        .map(|captures| (captures[1].to_string(), captures[2].to_string()))
+        .collect();
+    // longest first, so `$NOTE` doesn't clobber `$NOTE_2`
+    definitions.sort_by_key(|(name, _)| std::cmp::Reverse(name.len()));
+
+    let mut source = defines.replace_all(&source, "").into_owned();
+    for (name, value) in definitions {
+        source = source.replace(&name, &value);
+    }
+
+    let tokens = Regex::new(r"<(\w+)>|([\w$]+)=").unwrap();
+    let matches: Vec<_> = tokens.captures_iter(&source).collect();
+
+    let mut headers: Vec<(String, Vec<(String, String)>)> = vec![];
+
+    for (i, captures) in matches.iter().enumerate() {
+        let whole = captures.get(0).unwrap();
+
+        if let Some(header) = captures.get(1) {
+            headers.push((header.as_str().to_lowercase(), vec![]));
+        } else if let (Some(opcode), Some((_, opcodes))) = (captures.get(2), headers.last_mut()) {
+            let value_end = matches
+                .get(i + 1)
+                .map(|next| next.get(0).unwrap().start())
+                .unwrap_or(source.len());
+
+            opcodes.push((
+                opcode.as_str().to_lowercase(),
+                source[whole.end()..value_end].trim().to_string(),
+            ));
+        }
+    }
+
+    headers
+}
+
+/// Parses the regions out of an SFZ file, with the opcodes from their `<global>`,
+/// `<master>` and `<group>` headers applied
+pub fn parse_sfz(source: &str) -> Result<Vec<SfzRegion>, EngineError> {
+    let mut default_path = String::new();
+    let mut global: BTreeMap<String, String> = BTreeMap::new();
+    let mut master: BTreeMap<String, String> = BTreeMap::new();
+    let mut group: BTreeMap<String, String> = BTreeMap::new();
+
+    let mut regions = vec![];
+
+    for (header, opcodes) in tokenize(source) {
+        match header.as_str() {
+            "control" => {
+                if let Some((_, path)) = opcodes.iter().find(|(opcode, _)| opcode == "default_path") {
+                    default_path = path.replace('\\', "/");
+                }
+            }
+            "global" => {
+                global = opcodes.into_iter().collect();
+                master.clear();
+                group.clear();
+            }
+            "master" => {
+                master = opcodes.into_iter().collect();
+                group.clear();
+            }
+            "group" => {
+                group = opcodes.into_iter().collect();
+            }
+            "region" => {
+                let mut merged = global.clone();
+                merged.extend(master.clone());
+                merged.extend(group.clone());
+                merged.extend(opcodes);
+
+                if let Some(region) = build_region(&merged, &default_path)? {
+                    regions.push(region);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    Ok(regions)
+}
+
+fn build_region(opcodes: &BTreeMap<String, String>, default_path: &str) -> Result<Option<SfzRegion>, EngineError> {
+    let get = |names: &[&str]| names.iter().find_map(|name| opcodes.get(*name)).map(|x| x.as_str());
+    let key = |names: &[&str]| -> Result<Option<u8>, EngineError> {
+        get(names)
+            .map(|value| {
+                parse_key(value).ok_or_else(|| EngineError::ParserError {
+                    error: format!("`{}` is not a valid key", value),
+                })
+            })
+            .transpose()
+    };
+    let number = |names: &[&str]| get(names).and_then(|value| value.parse::<f64>().ok());
+
+    let Some(sample) = get(&["sample"]) else {
+        return Ok(None);
+    };
+
+    if get(&["trigger"]).map(|trigger| trigger != "attack").unwrap_or(false) {
+        warn!("skipping region for {}, only attack triggers are supported", sample);
+
+        return Ok(None);
+    }
+
+    let single_key = key(&["key"])?;
+    let lokey = key(&["lokey"])?.or(single_key).unwrap_or(0);
+    let hikey = key(&["hikey"])?.or(single_key).unwrap_or(127);
+    let pitch_keycenter = key(&["pitch_keycenter"])?.or(single_key).unwrap_or(lokey);
+
+    Ok(Some(SfzRegion {
+        sample: format!("{}{}", default_path, sample.replace('\\', "/")),
+        lokey,
+        hikey,
+        pitch_keycenter,
+        loop_mode: get(&["loop_mode", "loopmode"]).map(|mode| mode.to_string()),
+        loop_start: number(&["loop_start", "loopstart"]).map(|start| start as usize),
+        loop_end: number(&["loop_end", "loopend"]).map(|end| end as usize),
+        tune: number(&["tune"]).unwrap_or(0.0) as i32,
+        transpose: number(&["transpose"]).unwrap_or(0.0) as i32,
+        volume: number(&["volume"]).unwrap_or(0.0) as f32,
+        ampeg_release: number(&["ampeg_release"]).unwrap_or(0.001) as f32,
+    }))
+}
+
+/// Builds a `[rank].toml` config out of SFZ regions. If any region loops, the result is a
+/// pipe rank (and regions without loop points are dropped), otherwise it's percussion.
+pub fn regions_to_config(
+    name: &str,
+    sample_location: ResourceId,
+    regions: &[SfzRegion],
+) -> Result<String, EngineError> {
+    for region in regions.iter().filter(|region| region.lokey != region.hikey) {
+        warn!(
+            "{} spans keys {}-{}, but it'll only be used for {}",
+            region.sample, region.lokey, region.hikey, region.pitch_keycenter
+        );
+    }
+
+    if regions.iter().any(|region| region.is_looped()) {
+        let pipes: BTreeMap<u8, PipesRankEntry> = regions
+            .iter()
+            .filter_map(|region| {
+                let (Some(loop_start), Some(loop_end)) = (region.loop_start, region.loop_end) else {
+                    warn!("skipping {}, it has no loop points", region.sample);
+
+                    return None;
+                };
+
+                Some((
+                    region.pitch_keycenter,
+                    PipesRankEntry {
+                        cents: region.cents(),
+                        // the envelope isn't known without looking at the sample, so assume
+                        // it settles by the loop and releases after it
+                        decay_index: loop_start,
+                        loop_start,
+                        loop_end,
+                        release_index: loop_end,
+                        extra_loops: vec![],
+                        crossfade: None,
+                        file: Some(region.sample.clone()),
+                        attenuation: -region.volume,
+                        even_harm_atten: 0.0,
+                        brightness: 0.0,
+                        low_cut: None,
+                    },
+                ))
+            })
+            .collect();
+
+        toml_edit::ser::to_string_pretty(&PipeRankConfig::new(name.into(), sample_location, 0.0, pipes))
+            .context(TomlParserSerSnafu)
+    } else {
+        let percussion: BTreeMap<u8, PercussionRankEntry> = regions
+            .iter()
+            .map(|region| {
+                (
+                    region.pitch_keycenter,
+                    PercussionRankEntry {
+                        file: Some(region.sample.clone()),
+                        attenuation: -region.volume,
+                        release: region.ampeg_release,
+                        layer: vec![],
+                        choke_group: None,
+                    },
+                )
+            })
+            .collect();
+
+        toml_edit::ser::to_string_pretty(&PercussionRankConfig::new(
+            name.into(),
+            sample_location,
+            0.0,
+            percussion,
+        ))
+        .context(TomlParserSerSnafu)
+    }
+}
+
+/// Parses an SFZ file straight into a rank. `sample_location` is where the SFZ's sample
+/// paths are relative to.
+pub fn parse_sfz_rank(
+    source: &str,
+    name: &str,
+    sample_location: ResourceId,
+    samples: &ResourceManager<MultiSample>,
+) -> Result<RankType, EngineError> {
+    let regions = parse_sfz(source)?;
+
+    parse_rank(&regions_to_config(name, sample_location, &regions)?, samples, &Uncached)
+}
+
+#[derive(Debug, Default)]
+pub struct SfzExport {
+    pub sfz: String,
+    /// the samples to copy next to the SFZ: (resource key in `samples`, path under `default_path`)
+    pub samples: Vec<(String, String)>,
+}
+
+fn sample_file_name(resource: &ResourceId) -> String {
+    resource.resource.rsplit('/').next().unwrap_or_default().to_string()
+}
+
+/// Names the exported samples, numbering any that would overwrite a different sample's file
+#[derive(Default)]
+struct SampleNames {
+    by_resource: BTreeMap<String, String>,
+    /// lowercased, as not every file system is case sensitive
+    taken: BTreeSet<String>,
+}
+
+impl SampleNames {
+    fn name(&mut self, resource: &ResourceId, samples: &mut Vec<(String, String)>) -> String {
+        if let Some(file) = self.by_resource.get(&resource.resource) {
+            return file.clone();
+        }
+
+        let original = sample_file_name(resource);
+        let mut file = original.clone();
+        let mut suffix = 2;
+
+        while self.taken.contains(&file.to_lowercase()) {
+            file = match original.rsplit_once('.') {
+                Some((stem, extension)) => format!("{stem}-{suffix}.{extension}"),
+                None => format!("{original}-{suffix}"),
+            };
+            suffix += 1;
+        }
+
+        self.taken.insert(file.to_lowercase());
+        self.by_resource.insert(resource.resource.clone(), file.clone());
+        samples.push((resource.resource.clone(), file.clone()));
+
+        file
+    }
+}
+
+/// Writes a `[rank].toml` config out as SFZ, with its samples expected under `default_path`
+pub fn export_sfz(config: &str, default_path: &str) -> Result<SfzExport, EngineError> {
+    let info: RankInfo = toml_edit::de::from_str(config).context(TomlParserDeSnafu)?;
+
+    let mut export = SfzExport::default();
+    let mut names = SampleNames::default();
+    let mut regions = vec![];
+
+    match info.rank_type.as_ref() {
+        "pipes" => {
+            let parsed: PipeRankConfig = toml_edit::de::from_str(config).context(TomlParserDeSnafu)?;
+
+            for (note, entry) in &parsed.pipe {
+                let Ok(note) = note.parse::<u8>() else {
+                    continue;
+                };
+
+                let resource = entry_resource(&parsed.sample_location, &parsed.sample_format, note, &entry.file);
+                let file = names.name(&resource, &mut export.samples);
+
+                regions.push(format!(
+                    "<region> sample={} key={} tune={} volume={} loop_mode=loop_continuous loop_start={} loop_end={}",
+                    file,
+                    note,
+                    -(entry.cents as i32),
+                    -(entry.attenuation + parsed.attenuation),
+                    entry.loop_start,
+                    entry.loop_end
+                ));
+            }
+
+            export.sfz.push_str(&format!("// {}\n", parsed.name));
+        }
+        "percussion" => {
+            let parsed: PercussionRankConfig = toml_edit::de::from_str(config).context(TomlParserDeSnafu)?;
+
+            for (note, entry) in &parsed.percussion {
+                let Ok(note) = note.parse::<u8>() else {
+                    continue;
+                };
+
+                let resource = entry_resource(&parsed.sample_location, &parsed.sample_format, note, &entry.file);
+                let file = names.name(&resource, &mut export.samples);
+
+                regions.push(format!(
+                    "<region> sample={} key={} volume={} loop_mode=one_shot ampeg_release={}",
+                    file,
+                    note,
+                    -(entry.attenuation + parsed.attenuation),
+                    entry.release
+                ));
+            }
+
+            export.sfz.push_str(&format!("// {}\n", parsed.name));
+        }
+        _ => {
+            return Err(EngineError::ParserError {
+                error: "rank must be of type 'pipes' or 'percussion'".to_owned(),
+            })
+        }
+    }
+
+    export
+        .sfz
+        .push_str(&format!("<control> default_path={}\n\n<group>\n", default_path));
+
+    for region in regions {
+        export.sfz.push_str(&region);
+        export.sfz.push('\n');
+    }
+
+    Ok(export)
+}
+
+#[cfg(test)]
+mod tests {
+    use common::resource_manager::{ResourceId, ResourceManager};
+    use sound_engine::{sampling::rank::RankType, MonoSample, MultiSample};
+
+    use super::{export_sfz, parse_sfz, parse_sfz_rank, regions_to_config};
+
+    const SFZ: &str = r"
+// a small test rank
+#define $RELEASE 0.5
+
+<control> default_path=Principal 8\
+<global> volume=-3
+<group> ampeg_release=$RELEASE
+<region> sample=036 C.wav key=36 loop_start=100 loop_end=900 tune=-5
+<region> sample=037 C#.wav lokey=c#2 hikey=37 pitch_keycenter=37 loop_mode=loop_continuous loop_start=120 loop_end=800
+<region> sample=release.wav key=36 trigger=release
+";
+
+    fn location() -> ResourceId {
+        ResourceId {
+            namespace: "samples".into(),
+            resource: "principal".into(),
+        }
+    }
+
+    #[test]
+    fn parses_regions() {
+        let regions = parse_sfz(SFZ).unwrap();
+
+        assert_eq!(regions.len(), 2);
+        assert_eq!(regions[0].sample, "Principal 8/036 C.wav");
+        assert_eq!(regions[0].pitch_keycenter, 36);
+        assert_eq!(regions[0].volume, -3.0);
+        assert_eq!(regions[0].ampeg_release, 0.5);
+        assert!(regions[0].is_looped());
+        assert_eq!(regions[1].lokey, 37);
+        assert_eq!(regions[1].loop_end, Some(800));
+    }
+
+    #[test]
+    fn builds_pipe_rank() {
+        let mut samples: ResourceManager<MultiSample> = ResourceManager::new();
+
+        for file in ["principal/Principal 8/036 C.wav", "principal/Principal 8/037 C#.wav"] {
+            samples.add_resource(
+                file.into(),
+                MultiSample::from_mono(MonoSample {
+                    audio_raw: vec![0.0; 48_000],
+                    sample_rate: 48_000,
+                }),
+            );
+        }
+
+        let RankType::Pipes(rank) = parse_sfz_rank(SFZ, "Principal", location(), &samples).unwrap() else {
+            panic!("expected a pipe rank");
+        };
+
+        assert_eq!(rank.notes.len(), 2);
+        assert_eq!(rank.notes[&36].loop_start, 100);
+        assert_eq!(rank.notes[&37].loop_end, 800);
+    }
+
+    #[test]
+    fn export_round_trip() {
+        let config = regions_to_config("Principal", location(), &parse_sfz(SFZ).unwrap()).unwrap();
+        let export = export_sfz(&config, "principal/").unwrap();
+
+        assert_eq!(
+            export.samples[0],
+            ("principal/Principal 8/036 C.wav".to_string(), "036 C.wav".to_string())
+        );
+
+        let regions = parse_sfz(&export.sfz).unwrap();
+
+        assert_eq!(regions.len(), 2);
+        assert_eq!(regions[0].sample, "principal/036 C.wav");
+        assert_eq!(regions[0].tune, -5);
+        assert_eq!(regions[0].volume, -3.0);
+        assert_eq!((regions[1].loop_start, regions[1].loop_end), (Some(120), Some(800)));
+    }
+
+    #[test]
+    fn export_numbers_colliding_sample_names() {
+        let sfz = "
+<region> sample=Bass/036 C.wav key=36
+<region> sample=Treble/036 C.wav key=37
+<region> sample=Bass/036 C.wav key=38
+";
+        let config = regions_to_config("Principal", location(), &parse_sfz(sfz).unwrap()).unwrap();
+        let export = export_sfz(&config, "principal/").unwrap();
+
+        assert_eq!(
+            export.samples,
+            vec![
+                ("principal/Bass/036 C.wav".to_string(), "036 C.wav".to_string()),
+                ("principal/Treble/036 C.wav".to_string(), "036 C-2.wav".to_string()),
+            ]
+        );
+
+        let regions = parse_sfz(&export.sfz).unwrap();
+
+        assert_eq!(regions[1].sample, "principal/036 C-2.wav");
+        assert_eq!(regions[2].sample, "principal/036 C.wav");
+    }
+}
@@ -16,6 +16,32 @@ pub fn _mix_to_mono(audio: &Vec<f32>, channel_count: usize) -> Vec<f32> {
     result
 }
 
-pub fn first_channel_only(audio: &Vec<f32>, channel_count: usize) -> Vec<f32> {
-    audio.iter().step_by(channel_count).copied().collect()
+pub fn deinterleave(audio: &Vec<f32>, channel_count: usize) -> Vec<Vec<f32>> {
+    (0..channel_count)
+        .map(|channel| audio.iter().skip(channel).step_by(channel_count).copied().collect())
+        .collect()
+}
+
+/// Turns a rank or stop name into something that's safe to use as a file name
+pub fn slug(name: &str) -> String {
+    let slug: String = name
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() {
+                c.to_ascii_lowercase()
+            } else {
+                '_'
+            }
+        })
+        .collect::<String>()
+        .split('_')
+        .filter(|part| !part.is_empty())
+        .collect::<Vec<_>>()
+        .join("_");
+
+    if slug.is_empty() {
+        "untitled".into()
+    } else {
+        slug
+    }
 }
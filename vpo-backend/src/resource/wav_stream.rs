@@ -0,0 +1,108 @@
+use std::{
+    fmt::Debug,
+    fs::File,
+    io::BufReader,
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+use hound::{SampleFormat, WavReader, WavSpec};
+use snafu::ResultExt;
+use sound_engine::{
+    sampling::stream::{SampleReader, StreamedTail},
+    MonoSample, MultiSample,
+};
+
+use crate::{errors::EngineError, resource::util::deinterleave};
+
+/// Reads frames out of a WAV file on demand, for streaming
+pub struct WavStreamReader {
+    reader: Mutex<WavReader<BufReader<File>>>,
+    spec: WavSpec,
+    len: usize,
+}
+
+impl Debug for WavStreamReader {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "[WAV stream, {} channels, {} frames]", self.spec.channels, self.len)
+    }
+}
+
+impl WavStreamReader {
+    pub fn open(location: &Path) -> Result<WavStreamReader, EngineError> {
+        let reader = WavReader::open(location).whatever_context(format!("Could not open {:?}", location))?;
+
+        Ok(WavStreamReader {
+            spec: reader.spec(),
+            len: reader.duration() as usize,
+            reader: Mutex::new(reader),
+        })
+    }
+}
+
+impl SampleReader for WavStreamReader {
+    fn read(&self, start: usize, out: &mut [f32]) -> usize {
+        let mut reader = self.reader.lock().unwrap();
+
+        if reader.seek(start as u32).is_err() {
+            return 0;
+        }
+
+        let channels = self.spec.channels as usize;
+        let wanted = (out.len() / channels) * channels;
+
+        let read = match self.spec.sample_format {
+            SampleFormat::Float => out[..wanted]
+                .iter_mut()
+                .zip(reader.samples::<f32>().map_while(Result::ok))
+                .map(|(out, sample)| *out = sample)
+                .count(),
+            SampleFormat::Int => {
+                let scale = 1.0 / (1_i64 << (self.spec.bits_per_sample - 1)) as f32;
+
+                out[..wanted]
+                    .iter_mut()
+                    .zip(reader.samples::<i32>().map_while(Result::ok))
+                    .map(|(out, sample)| *out = sample as f32 * scale)
+                    .count()
+            }
+        };
+
+        read / channels
+    }
+}
+
+/// Loads the start of a WAV file into memory, leaving the rest to be streamed. What's kept is
+/// `resident_frames` (what the rank needs to jump around in) plus `preload_ms` of extra audio.
+pub fn load_wav_streamed(location: &Path, resident_frames: usize, preload_ms: u32) -> Result<MultiSample, EngineError> {
+    let reader = WavStreamReader::open(location)?;
+
+    let channels = reader.spec.channels as usize;
+    let sample_rate = reader.spec.sample_rate;
+    let len = reader.len;
+
+    let head_frames = resident_frames + (preload_ms as usize * sample_rate as usize) / 1000;
+
+    let mut head = vec![0.0; head_frames.min(len) * channels];
+    let read = reader.read(0, &mut head);
+    head.truncate(read * channels);
+
+    Ok(MultiSample {
+        channels: deinterleave(&head, channels)
+            .into_iter()
+            .map(|audio| MonoSample {
+                audio_raw: audio,
+                sample_rate,
+            })
+            .collect(),
+        tail: if read < len {
+            Some(StreamedTail {
+                reader: Arc::new(reader),
+                len,
+            })
+        } else {
+            None
+        },
+        source_sample_rate: None,
+    })
+}
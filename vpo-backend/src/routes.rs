@@ -1,8 +1,16 @@
 pub mod graph;
+pub mod history;
+pub mod module;
 pub mod prelude;
 
 #[cfg(any(windows, unix))]
 pub mod io;
+#[cfg(any(windows, unix))]
+pub mod preset;
+#[cfg(any(windows, unix))]
+pub mod rank;
+#[cfg(any(windows, unix))]
+pub mod sample;
 
 use std::sync::RwLock;
 
@@ -53,10 +61,14 @@ pub async fn route(
                 "graph/commit" => graph::commit::route(route_state),
                 "graph/undo" => graph::undo::route(route_state),
                 "graph/redo" => graph::redo::route(route_state),
+                "graph/lint" => graph::lint::route(route_state),
                 "graph/copy" => graph::copy::route(route_state),
                 "graph/paste" => graph::paste::route(route_state),
                 "graph/updateNodeUi" => graph::update_node_ui::route(route_state),
                 "graph/updateNodeState" => graph::update_node_state::route(route_state),
+                "history/list" => history::list::route(route_state),
+                "history/checkpoint" => history::checkpoint::route(route_state),
+                "history/jump" => history::jump::route(route_state),
                 #[cfg(any(unix, windows))]
                 "io/save" => io::save::route(route_state).await,
                 #[cfg(any(unix, windows))]
@@ -64,9 +76,40 @@ pub async fn route(
                 #[cfg(any(unix, windows))]
                 "io/create" => io::create::route(route_state).await,
                 #[cfg(any(unix, windows))]
+                "io/restoreRecovery" => io::recovery::route_restore(route_state),
+                #[cfg(any(unix, windows))]
+                "io/discardRecovery" => io::recovery::route_discard(route_state),
+                #[cfg(any(unix, windows))]
                 "io/importRank" => io::import_rank::route(route_state).await,
                 #[cfg(any(unix, windows))]
+                "io/importRankPaths" => io::import_rank::route_paths(route_state),
+                #[cfg(any(unix, windows))]
+                "io/cancelImport" => io::import_rank::route_cancel(route_state),
+                #[cfg(any(unix, windows))]
+                "io/importOrgan" => io::import_organ::route(route_state).await,
+                #[cfg(any(unix, windows))]
+                "io/importSfz" => io::import_sfz::route(route_state).await,
+                #[cfg(any(unix, windows))]
+                "io/exportSfz" => io::export_sfz::route(route_state).await,
+                #[cfg(any(unix, windows))]
                 "io/refresh" => io::refresh::route(route_state),
+                "module/list" => module::list::route(route_state),
+                #[cfg(any(unix, windows))]
+                "preset/list" => preset::list::route(route_state),
+                #[cfg(any(unix, windows))]
+                "preset/save" => preset::save::route(route_state),
+                #[cfg(any(unix, windows))]
+                "preset/apply" => preset::apply::route(route_state),
+                #[cfg(any(unix, windows))]
+                "preset/delete" => preset::delete::route(route_state),
+                #[cfg(any(unix, windows))]
+                "rank/list" => rank::list::route(route_state),
+                #[cfg(any(unix, windows))]
+                "rank/get" => rank::get::route(route_state),
+                #[cfg(any(unix, windows))]
+                "rank/updatePipes" => rank::update_pipes::route(route_state),
+                #[cfg(any(unix, windows))]
+                "sample/preview" => sample::preview::route(route_state),
                 _ => Ok(RouteReturn::default()),
             };
         }
@@ -33,7 +33,8 @@ pub fn route(mut ctx: RouteCtx) -> Result<RouteReturn, EngineError> {
             ActionInvalidation::GraphReindexNeeded(index)
             | ActionInvalidation::GraphModified(index)
             | ActionInvalidation::NewDefaults(GlobalNodeIndex { graph_index: index, .. }, _)
-            | ActionInvalidation::NewNode(GlobalNodeIndex { graph_index: index, .. }) => {
+            | ActionInvalidation::NewNode(GlobalNodeIndex { graph_index: index, .. })
+            | ActionInvalidation::NodeReinitNeeded(GlobalNodeIndex { graph_index: index, .. }) => {
                 touched_graphs.insert(index);
             }
             ActionInvalidation::NewRouteRules { .. } => {
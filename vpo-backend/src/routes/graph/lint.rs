@@ -0,0 +1,16 @@
+use ipc::ipc_message::IpcMessage;
+use serde_json::json;
+
+use crate::routes::prelude::*;
+
+/// Reports anything that looks wrong in the project
+pub fn route(ctx: RouteCtx) -> Result<RouteReturn, EngineError> {
+    let findings = ctx.state.lint(&*ctx.resources_lock.read().unwrap());
+
+    let _ = ctx.to_server.send(IpcMessage::Json(json! {{
+        "action": "graph/lintReport",
+        "payload": findings
+    }}));
+
+    Ok(RouteReturn::default())
+}
@@ -0,0 +1,25 @@
+use serde::Deserialize;
+use snafu::ResultExt;
+
+use crate::{
+    errors::{JsonParserSnafu, NodeSnafu},
+    routes::prelude::*,
+};
+
+#[derive(Deserialize)]
+struct Payload {
+    /// the step the checkpoint is after
+    place: usize,
+    /// `None` removes the checkpoint
+    name: Option<String>,
+}
+
+pub fn route(mut ctx: RouteCtx) -> Result<RouteReturn, EngineError> {
+    let payload: Payload = serde_json::from_value(ctx.msg["payload"].take()).context(JsonParserSnafu)?;
+
+    ctx.state
+        .set_checkpoint(payload.place, payload.name)
+        .context(NodeSnafu)?;
+
+    super::list::route(ctx)
+}
@@ -0,0 +1,58 @@
+use std::collections::HashSet;
+
+use node_engine::{graph_manager::GlobalNodeIndex, state::ActionInvalidation};
+use serde::Deserialize;
+use snafu::ResultExt;
+
+use crate::{
+    errors::{JsonParserSnafu, NodeSnafu},
+    routes::prelude::*,
+    util::{send_graph_updates, send_project_state_updates},
+};
+
+#[derive(Deserialize)]
+struct Payload {
+    place: usize,
+}
+
+/// Goes straight to a point in the history, rebuilding the engine once at the end
+pub fn route(mut ctx: RouteCtx) -> Result<RouteReturn, EngineError> {
+    let payload: Payload = serde_json::from_value(ctx.msg["payload"].take()).context(JsonParserSnafu)?;
+
+    let invalidations = ctx.state.jump_to(payload.place).context(NodeSnafu)?;
+
+    let mut touched_graphs = HashSet::new();
+
+    for invalidation in &invalidations {
+        match invalidation {
+            ActionInvalidation::GraphReindexNeeded(index)
+            | ActionInvalidation::GraphModified(index)
+            | ActionInvalidation::NewDefaults(GlobalNodeIndex { graph_index: index, .. }, _)
+            | ActionInvalidation::NewNode(GlobalNodeIndex { graph_index: index, .. })
+            | ActionInvalidation::NodeReinitNeeded(GlobalNodeIndex { graph_index: index, .. }) => {
+                touched_graphs.insert(*index);
+            }
+            ActionInvalidation::NewRouteRules { .. } => {}
+            ActionInvalidation::None => {}
+        }
+    }
+
+    for graph_index in touched_graphs {
+        // graphs made along the way might not exist anymore
+        if ctx.state.get_graph_manager().get_graph(graph_index).is_ok() {
+            send_graph_updates(ctx.state, graph_index, ctx.to_server)?;
+        }
+    }
+
+    send_project_state_updates(ctx.state, ctx.global_state, ctx.to_server)?;
+    state_invalidations(
+        ctx.state,
+        invalidations,
+        &mut ctx.global_state.device_manager,
+        &*ctx.resources_lock.read().unwrap(),
+        ctx.to_audio_thread,
+        ctx.to_server,
+    )?;
+
+    super::list::route(ctx)
+}
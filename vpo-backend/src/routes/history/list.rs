@@ -0,0 +1,41 @@
+use ipc::ipc_message::IpcMessage;
+use serde::Serialize;
+use serde_json::json;
+
+use crate::routes::prelude::*;
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct HistoryEntry {
+    description: String,
+    checkpoint: Option<String>,
+    /// whether this step has been undone (and can be redone)
+    undone: bool,
+}
+
+/// Lists the undo history, oldest first
+pub fn route(ctx: RouteCtx) -> Result<RouteReturn, EngineError> {
+    let place_in_history = ctx.state.get_place_in_history();
+
+    let entries: Vec<HistoryEntry> = ctx
+        .state
+        .get_history()
+        .iter()
+        .enumerate()
+        .map(|(i, bundle)| HistoryEntry {
+            description: bundle.description(),
+            checkpoint: bundle.checkpoint.clone(),
+            undone: i >= place_in_history,
+        })
+        .collect();
+
+    let _ = ctx.to_server.send(IpcMessage::Json(json! {{
+        "action": "history/history",
+        "payload": {
+            "entries": entries,
+            "placeInHistory": place_in_history
+        }
+    }}));
+
+    Ok(RouteReturn::default())
+}
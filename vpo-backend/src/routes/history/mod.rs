@@ -0,0 +1,3 @@
+pub mod checkpoint;
+pub mod jump;
+pub mod list;
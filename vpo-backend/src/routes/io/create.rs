@@ -2,7 +2,7 @@ use rfd::AsyncFileDialog;
 
 use crate::{
     errors::EngineError,
-    io::save,
+    io::{recovery::mark_saved, save, settings::ProjectSettings},
     routes::{prelude::*, RouteReturn},
 };
 
@@ -10,9 +10,12 @@ pub async fn route<'a>(state: RouteCtx<'a>) -> Result<RouteReturn, EngineError>
     let file = AsyncFileDialog::new().set_file_name("untitled.mjuo").save_file().await;
 
     if let Some(file) = file {
-        save(state.state, file.path())?;
+        save(state.state, file.path(), &ProjectSettings::default())?;
 
         state.global_state.active_project = Some(file.path().into());
+        state.global_state.settings = ProjectSettings::default();
+
+        mark_saved(state.state, state.global_state);
     }
 
     Ok(RouteReturn::default())
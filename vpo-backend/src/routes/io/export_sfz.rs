@@ -0,0 +1,57 @@
+use std::{fs, path::Path};
+
+use rfd::AsyncFileDialog;
+use serde::Deserialize;
+use snafu::{OptionExt, ResultExt};
+
+use crate::{
+    errors::{EngineError, IoSnafu, JsonParserSnafu},
+    resource::{sfz::export_sfz, util::slug},
+    routes::{prelude::*, RouteReturn},
+};
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Payload {
+    /// resource key of the rank to export
+    rank: String,
+}
+
+pub async fn route<'a>(mut state: RouteCtx<'a>) -> Result<RouteReturn, EngineError> {
+    let Payload { rank } = serde_json::from_value(state.msg["payload"].take()).context(JsonParserSnafu)?;
+
+    let project_directory = state
+        .global_state
+        .project_directory()
+        .whatever_context("A project must be open to export a rank")?;
+
+    let config = fs::read_to_string(project_directory.join("ranks").join(&rank)).context(IoSnafu)?;
+
+    let Some(folder) = AsyncFileDialog::new().pick_folder().await else {
+        return Ok(RouteReturn::default());
+    };
+
+    let name = slug(
+        &Path::new(&rank)
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().to_string())
+            .unwrap_or_default(),
+    );
+
+    let export = export_sfz(&config, &format!("{}/", name))?;
+
+    let sample_directory = folder.path().join(&name);
+    fs::create_dir_all(&sample_directory).context(IoSnafu)?;
+
+    for (resource, file) in &export.samples {
+        fs::copy(
+            project_directory.join("samples").join(resource),
+            sample_directory.join(file),
+        )
+        .context(IoSnafu)?;
+    }
+
+    fs::write(folder.path().join(format!("{}.sfz", name)), export.sfz).context(IoSnafu)?;
+
+    Ok(RouteReturn::default())
+}
@@ -0,0 +1,723 @@
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use common::{resource_manager::ResourceId, SeaHashMap};
+use log::{info, warn};
+use node_engine::{
+    connection::Socket,
+    graph_manager::{GlobalNodeIndex, GraphIndex},
+    node::NodeIndex,
+    node_graph::NodeConnectionData,
+    nodes::prelude::{int, value_input},
+    property::Property,
+    state::{Action, ActionInvalidation, GraphState},
+};
+use rayon::iter::{IndexedParallelIterator, IntoParallelRefIterator, ParallelIterator};
+use rfd::AsyncFileDialog;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use snafu::{OptionExt, ResultExt};
+use sound_engine::{
+    sampling::envelope::{calc_sample_metadata, AnalysisSettings},
+    MonoSample, MultiSample, SoundConfig,
+};
+
+use crate::{
+    errors::{AnalysisSnafu, EngineError, IoSnafu, JsonParserSnafu, NodeSnafu, TomlParserSerSnafu},
+    io::load_single,
+    resource::{
+        organ::{parse_organ, read_wav_markers, OdfOrgan, OdfPipe, OdfRank},
+        rank::{expected_sample_location, PercussionRankConfig, PercussionRankEntry, PipeRankConfig, PipesRankEntry},
+        sample::load_sample,
+        util::slug,
+    },
+    routes::{prelude::*, RouteReturn},
+    util::{send_graph_updates, send_resource_updates},
+};
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Payload {
+    /// hard link samples into the project instead of copying them, where possible
+    #[serde(default)]
+    link_samples: bool,
+    /// add a player for every rank and a toggle for every stop to the root graph
+    #[serde(default)]
+    generate_graph: bool,
+}
+
+struct ImportedPipe {
+    file: String,
+    cents: i16,
+    attenuation: f32,
+    decay_index: usize,
+    loop_start: usize,
+    loop_end: usize,
+    extra_loops: Vec<(usize, usize)>,
+    release_index: usize,
+    crossfade: usize,
+    length_seconds: f32,
+}
+
+struct ImportedRank {
+    /// resource key of the rank in the `ranks` namespace
+    resource: String,
+    percussive: bool,
+    rank_file: PathBuf,
+    sample_files: Vec<PathBuf>,
+}
+
+pub async fn route<'a>(mut state: RouteCtx<'a>) -> Result<RouteReturn, EngineError> {
+    let Payload {
+        link_samples,
+        generate_graph,
+    } = serde_json::from_value(state.msg["payload"].take()).context(JsonParserSnafu)?;
+
+    let Some(file) = AsyncFileDialog::new()
+        .add_filter("GrandOrgue organ", &["organ"])
+        .pick_file()
+        .await
+    else {
+        return Ok(RouteReturn::default());
+    };
+
+    let project_directory = state
+        .global_state
+        .project_directory()
+        .whatever_context("A project must be open to import an organ")?;
+
+    let odf_path = file.path();
+    let source = String::from_utf8_lossy(&fs::read(odf_path).context(IoSnafu)?).into_owned();
+    let organ = parse_organ(&source, odf_path.parent().unwrap_or(Path::new(".")))?;
+
+    info!("Importing organ \"{}\" ({} ranks)", organ.name, organ.ranks.len());
+
+    if organ.tremulant_count > 0 {
+        warn!("{} tremulant(s) were not imported", organ.tremulant_count);
+    }
+
+    let sound_config = state.state.get_sound_config();
+    let organ_key = slug(&organ.name);
+
+    let imported: Vec<Option<ImportedRank>> = organ
+        .ranks
+        .iter()
+        .enumerate()
+        .map(|(i, rank)| {
+            import_rank(
+                rank,
+                &format!("{}/{:03}_{}", organ_key, i + 1, slug(&rank.name)),
+                &project_directory,
+                link_samples,
+                &sound_config,
+            )
+        })
+        .collect::<Result<_, _>>()?;
+
+    // load everything now instead of waiting on the file watcher, so the new graph can find it
+    {
+        let mut resources = state.resources_lock.write().unwrap();
+
+        for rank in imported.iter().flatten() {
+            for sample in &rank.sample_files {
+                load_single(
+                    &project_directory,
+                    sample,
+                    &mut resources,
+                    sound_config.clone(),
+                    &state.global_state.settings,
+                )?;
+            }
+        }
+
+        for rank in imported.iter().flatten() {
+            load_single(
+                &project_directory,
+                &rank.rank_file,
+                &mut resources,
+                sound_config.clone(),
+                &state.global_state.settings,
+            )?;
+        }
+
+        send_resource_updates(&resources, state.to_server)?;
+    }
+
+    if generate_graph {
+        let root_index = state.state.get_root_graph_index();
+        let invalidations = build_organ_graph(state.state, root_index, &organ, &imported)?;
+
+        send_graph_updates(state.state, root_index, state.to_server)?;
+
+        state_invalidations(
+            state.state,
+            invalidations,
+            &mut state.global_state.device_manager,
+            &*state.resources_lock.read().unwrap(),
+            state.to_audio_thread,
+            state.to_server,
+        )?;
+    }
+
+    Ok(RouteReturn::default())
+}
+
+fn import_rank(
+    rank: &OdfRank,
+    key: &str,
+    project_directory: &Path,
+    link_samples: bool,
+    sound_config: &SoundConfig,
+) -> Result<Option<ImportedRank>, EngineError> {
+    let sample_directory = project_directory.join("samples").join(key);
+    let rank_file = project_directory.join("ranks").join(format!("{}.toml", key));
+
+    let pipes: Vec<(u8, ImportedPipe)> = rank
+        .pipes
+        .par_iter()
+        .enumerate()
+        .filter_map(|(i, pipe)| {
+            let note = u8::try_from(rank.pipe_note(i)).ok().filter(|note| *note < 128)?;
+            let pipe = pipe.as_ref()?;
+
+            match import_pipe(pipe, note, &sample_directory, link_samples, sound_config) {
+                Ok(imported) => Some((
+                    note,
+                    ImportedPipe {
+                        // GrandOrgue's tuning corrects the pipe, so the pipe itself is off by the opposite
+                        cents: -(pipe.tuning_cents + rank.tuning_cents).round() as i16,
+                        ..imported
+                    },
+                )),
+                Err(err) => {
+                    warn!("Could not import pipe {:?}: {}", pipe.attack, err);
+
+                    None
+                }
+            }
+        })
+        .collect();
+
+    if pipes.is_empty() {
+        warn!("Rank \"{}\" has no usable pipes, skipping it", rank.name);
+
+        return Ok(None);
+    }
+
+    let sample_files = pipes
+        .iter()
+        .map(|(_, pipe)| sample_directory.join(&pipe.file))
+        .collect();
+    let sample_location = ResourceId {
+        namespace: "samples".into(),
+        resource: key.to_string(),
+    };
+
+    let config = if rank.percussive {
+        let percussion: BTreeMap<u8, PercussionRankEntry> = pipes
+            .into_iter()
+            .map(|(note, pipe)| {
+                (
+                    note,
+                    PercussionRankEntry {
+                        file: Some(pipe.file),
+                        attenuation: pipe.attenuation,
+                        release: pipe.length_seconds,
+                        layer: vec![],
+                        choke_group: None,
+                    },
+                )
+            })
+            .collect();
+
+        toml_edit::ser::to_string_pretty(&PercussionRankConfig::new(
+            rank.name.clone(),
+            sample_location,
+            -rank.gain_db,
+            percussion,
+        ))
+    } else {
+        let entries: BTreeMap<u8, PipesRankEntry> = pipes
+            .into_iter()
+            .map(|(note, pipe)| {
+                (
+                    note,
+                    PipesRankEntry {
+                        cents: pipe.cents,
+                        decay_index: pipe.decay_index,
+                        loop_start: pipe.loop_start,
+                        loop_end: pipe.loop_end,
+                        release_index: pipe.release_index,
+                        extra_loops: pipe.extra_loops,
+                        crossfade: Some(pipe.crossfade),
+                        file: Some(pipe.file),
+                        attenuation: pipe.attenuation,
+                        even_harm_atten: 0.0,
+                        brightness: 0.0,
+                        low_cut: None,
+                    },
+                )
+            })
+            .collect();
+
+        toml_edit::ser::to_string_pretty(&PipeRankConfig::new(
+            rank.name.clone(),
+            sample_location,
+            -rank.gain_db,
+            entries,
+        ))
+    }
+    .context(TomlParserSerSnafu)?;
+
+    fs::create_dir_all(rank_file.parent().unwrap()).context(IoSnafu)?;
+    fs::write(&rank_file, config).context(IoSnafu)?;
+
+    Ok(Some(ImportedRank {
+        resource: format!("{}.toml", key),
+        percussive: rank.percussive,
+        rank_file,
+        sample_files,
+    }))
+}
+
+fn import_pipe(
+    pipe: &OdfPipe,
+    note: u8,
+    sample_directory: &Path,
+    link_samples: bool,
+    sound_config: &SoundConfig,
+) -> Result<ImportedPipe, EngineError> {
+    fs::create_dir_all(sample_directory).context(IoSnafu)?;
+
+    let attack = load_sample(&pipe.attack, sound_config)?;
+    let markers = read_wav_markers(&pipe.attack)?;
+
+    let freq = 440.0 * 2_f64.powf((note as f64 - 69.0) / 12.0);
+    let metadata = calc_sample_metadata(
+        &attack.first_channel().audio_raw,
+        attack.sample_rate(),
+        Some(freq),
+        &AnalysisSettings::default(),
+    )
+    .context(AnalysisSnafu)?;
+
+    let release = match pipe.releases.first() {
+        Some(path) => Some(load_sample(path, sound_config)?).filter(|release| {
+            let matches = release.sample_rate() == attack.sample_rate();
+
+            if !matches {
+                warn!("Release {:?} doesn't match its attack's sample rate, ignoring it", path);
+            }
+
+            matches
+        }),
+        None => None,
+    };
+
+    let (file, release_index, len) = match release {
+        Some(release) => {
+            // the release is stored separately, so it gets appended in place of the attack's own
+            let attack_len = markers.release.unwrap_or(attack.len()).min(attack.len());
+            let combined = append_release(&attack, attack_len, &release);
+
+            let file = expected_sample_location(note, "wav");
+            write_wav(&sample_directory.join(&file), &combined)?;
+
+            (file, attack_len, combined.len())
+        }
+        None => {
+            let extension = pipe
+                .attack
+                .extension()
+                .map(|extension| extension.to_string_lossy().to_lowercase())
+                .unwrap_or("wav".into());
+
+            let file = expected_sample_location(note, &extension);
+            copy_sample(&pipe.attack, &sample_directory.join(&file), link_samples)?;
+
+            (file, markers.release.unwrap_or(metadata.release_index), attack.len())
+        }
+    };
+
+    // the longest loop that ends before the release is the main one, the rest are hopped between
+    let mut loops: Vec<(usize, usize)> = if pipe.loops.is_empty() {
+        &markers.loops
+    } else {
+        &pipe.loops
+    }
+    .iter()
+    .copied()
+    .filter(|(start, end)| start < end && *end < release_index)
+    .collect();
+    loops.sort_by_key(|(start, end)| std::cmp::Reverse(end - start));
+
+    let (loop_start, loop_end, extra_loops) = if loops.is_empty() {
+        (metadata.loop_start, metadata.loop_end, metadata.extra_loops)
+    } else {
+        let (loop_start, loop_end) = loops.remove(0);
+
+        (loop_start, loop_end, loops)
+    };
+
+    Ok(ImportedPipe {
+        file,
+        cents: 0,
+        attenuation: -pipe.gain_db,
+        decay_index: metadata.decay_index.min(loop_start),
+        loop_start,
+        loop_end,
+        extra_loops,
+        release_index,
+        crossfade: 256.min(loop_start).min(loop_end.saturating_sub(loop_start) / 2),
+        length_seconds: len as f32 / attack.sample_rate() as f32,
+    })
+}
+
+fn append_release(attack: &MultiSample, attack_len: usize, release: &MultiSample) -> MultiSample {
+    MultiSample {
+        channels: attack
+            .channels
+            .iter()
+            .enumerate()
+            .map(|(i, channel)| {
+                let release_channel = &release.channels[i.min(release.channel_count() - 1)];
+
+                MonoSample {
+                    audio_raw: channel.audio_raw[..attack_len]
+                        .iter()
+                        .chain(release_channel.audio_raw.iter())
+                        .copied()
+                        .collect(),
+                    sample_rate: channel.sample_rate,
+                }
+            })
+            .collect(),
+        tail: None,
+        source_sample_rate: None,
+    }
+}
+
+fn write_wav(location: &Path, sample: &MultiSample) -> Result<(), EngineError> {
+    let spec = hound::WavSpec {
+        channels: sample.channel_count() as u16,
+        sample_rate: sample.sample_rate(),
+        bits_per_sample: 32,
+        sample_format: hound::SampleFormat::Float,
+    };
+
+    let mut writer = hound::WavWriter::create(location, spec).whatever_context("Could not create WAV file")?;
+
+    for i in 0..sample.len() {
+        for channel in &sample.channels {
+            writer
+                .write_sample(channel.audio_raw[i])
+                .whatever_context("Could not write WAV file")?;
+        }
+    }
+
+    writer.finalize().whatever_context("Could not write WAV file")?;
+
+    Ok(())
+}
+
+fn copy_sample(from: &Path, to: &Path, link: bool) -> Result<(), EngineError> {
+    if to.exists() {
+        fs::remove_file(to).context(IoSnafu)?;
+    }
+
+    // hard links can't cross filesystems, so copy if linking fails
+    if link && fs::hard_link(from, to).is_ok() {
+        return Ok(());
+    }
+
+    fs::copy(from, to).context(IoSnafu)?;
+
+    Ok(())
+}
+
+const COLUMN_WIDTH: f32 = 250.0;
+const ROW_HEIGHT: f32 = 150.0;
+
+/// Adds nodes to a graph one commit at a time, so their sockets can be looked up as it goes
+struct GraphBuilder<'a> {
+    state: &'a mut GraphState,
+    graph: GraphIndex,
+    invalidations: Vec<ActionInvalidation>,
+    first_commit: bool,
+}
+
+impl<'a> GraphBuilder<'a> {
+    fn commit(&mut self, actions: Vec<Action>) -> Result<Option<GlobalNodeIndex>, EngineError> {
+        let invalidations = self
+            .state
+            .commit(ActionBundle { actions }, !self.first_commit)
+            .context(NodeSnafu)?;
+        self.first_commit = false;
+
+        let new_node = invalidations.iter().find_map(|invalidation| match invalidation {
+            ActionInvalidation::NewNode(index) => Some(*index),
+            _ => None,
+        });
+
+        self.invalidations.extend(invalidations);
+
+        Ok(new_node)
+    }
+
+    fn add_node(
+        &mut self,
+        node_type: &str,
+        title: &str,
+        column: usize,
+        row: usize,
+        props: &[(&str, Property)],
+    ) -> Result<NodeIndex, EngineError> {
+        let mut ui_data: SeaHashMap<String, Value> = SeaHashMap::default();
+        ui_data.insert("x".into(), json!(column as f32 * COLUMN_WIDTH));
+        ui_data.insert("y".into(), json!(row as f32 * ROW_HEIGHT));
+        ui_data.insert("title".into(), json!(title));
+
+        let index = self
+            .commit(vec![Action::CreateNode {
+                graph: self.graph,
+                node_type: node_type.into(),
+                ui_data,
+            }])?
+            .whatever_context(format!("Creating a `{}` didn't add a node", node_type))?;
+
+        if !props.is_empty() {
+            let mut new_props = self.node_props(index.node_index)?;
+
+            for (name, value) in props {
+                new_props.insert(name.to_string(), value.clone());
+            }
+
+            self.commit(vec![Action::ChangeNodeProperties {
+                index,
+                props: new_props,
+            }])?;
+        }
+
+        Ok(index.node_index)
+    }
+
+    fn node_props(&mut self, node: NodeIndex) -> Result<SeaHashMap<String, Property>, EngineError> {
+        Ok(self
+            .state
+            .get_graph_manager()
+            .get_graph(self.graph)
+            .and_then(|graph| graph.get_node(node))
+            .context(NodeSnafu)?
+            .get_properties()
+            .clone())
+    }
+
+    fn socket(&mut self, node: NodeIndex, output: bool, name: &str, data: Option<&str>) -> Result<Socket, EngineError> {
+        let graph = self
+            .state
+            .get_graph_manager()
+            .get_graph(self.graph)
+            .context(NodeSnafu)?;
+        let instance = graph.get_node(node).context(NodeSnafu)?;
+
+        let sockets = if output {
+            instance.list_output_sockets()
+        } else {
+            instance.list_input_sockets()
+        };
+
+        sockets
+            .into_iter()
+            .find(|socket| match socket {
+                Socket::Simple(socket_name, ..) => socket_name == name && data.is_none(),
+                Socket::WithData(socket_name, socket_data, ..) => {
+                    socket_name == name && data == Some(socket_data.as_str())
+                }
+            })
+            .cloned()
+            .whatever_context(format!("Socket `{}` not found", name))
+    }
+
+    fn connect(
+        &mut self,
+        from: NodeIndex,
+        from_socket: &str,
+        to: NodeIndex,
+        to_socket: (&str, Option<&str>),
+    ) -> Result<(), EngineError> {
+        let from_socket = self.socket(from, true, from_socket, None)?;
+        let to_socket = self.socket(to, false, to_socket.0, to_socket.1)?;
+
+        self.commit(vec![Action::ConnectNodes {
+            graph: self.graph,
+            from,
+            to,
+            data: NodeConnectionData { from_socket, to_socket },
+        }])?;
+
+        Ok(())
+    }
+}
+
+/// Builds a starter graph for an imported organ: a MIDI input per manual, a toggle and MIDI
+/// switch per stop, a player per rank, and a mixer per windchest.
+fn build_organ_graph(
+    state: &mut GraphState,
+    graph: GraphIndex,
+    organ: &OdfOrgan,
+    imported: &[Option<ImportedRank>],
+) -> Result<Vec<ActionInvalidation>, EngineError> {
+    let mut builder = GraphBuilder {
+        state,
+        graph,
+        invalidations: vec![],
+        first_commit: true,
+    };
+
+    let output = builder.add_node(
+        "OutputsNode",
+        "Output",
+        8,
+        0,
+        &[("type", Property::MultipleChoice("stream".into()))],
+    )?;
+    let master = builder.add_node("MixerNode", "Master", 7, 0, &[])?;
+    builder.connect(master, "audio", output, ("audio", None))?;
+
+    let mut windchests = vec![];
+    for (i, name) in organ.windchests.iter().enumerate() {
+        let mixer = builder.add_node("MixerNode", name, 6, i, &[])?;
+        builder.connect(mixer, "audio", master, ("input_numbered", Some(&(i + 1).to_string())))?;
+
+        windchests.push((mixer, 0_usize));
+    }
+
+    let channels = builder.socket(master, true, "audio", None)?.channels();
+
+    // which switches (or transposers) feed each rank
+    let mut rank_feeds: BTreeMap<usize, Vec<NodeIndex>> = BTreeMap::new();
+    let mut row = 0;
+
+    for (manual_number, manual) in organ.manuals.iter().enumerate() {
+        let input = builder.add_node(
+            "InputsNode",
+            &manual.name,
+            0,
+            row,
+            &[
+                ("name", Property::String(manual.name.clone())),
+                ("type", Property::MultipleChoice("osc".into())),
+            ],
+        )?;
+
+        for stop in &manual.stops {
+            let stop_ranks: Vec<_> = stop
+                .ranks
+                .iter()
+                .filter(|stop_rank| imported[stop_rank.rank].is_some())
+                .collect();
+
+            if stop_ranks.is_empty() {
+                continue;
+            }
+
+            let toggle = builder.add_node(
+                "ToggleNode",
+                &stop.name,
+                1,
+                row,
+                &[("ui_name", Property::String(stop.name.clone()))],
+            )?;
+            let switch = builder.add_node("MidiSwitchNode", &format!("{} switch", stop.name), 2, row, &[])?;
+
+            builder.connect(toggle, "state", switch, ("engage", None))?;
+            builder.connect(input, "osc", switch, ("midi", None))?;
+
+            for stop_rank in stop_ranks {
+                let feed = if stop_rank.transpose != 0 {
+                    let transpose = builder.add_node("MidiTransposeNode", "Transpose", 3, row, &[])?;
+
+                    builder.commit(vec![Action::ChangeNodeOverrides {
+                        index: GlobalNodeIndex {
+                            graph_index: graph,
+                            node_index: transpose,
+                        },
+                        overrides: vec![value_input("transpose", int(stop_rank.transpose), 1)],
+                    }])?;
+                    builder.connect(switch, "midi", transpose, ("midi", None))?;
+
+                    transpose
+                } else {
+                    switch
+                };
+
+                rank_feeds.entry(stop_rank.rank).or_default().push(feed);
+                row += 1;
+            }
+        }
+
+        if manual_number + 1 < organ.manuals.len() {
+            row += 1;
+        }
+    }
+
+    for (rank_row, (rank_index, feeds)) in rank_feeds.into_iter().enumerate() {
+        let Some(rank) = &imported[rank_index] else {
+            continue;
+        };
+        let odf_rank = &organ.ranks[rank_index];
+
+        let player = builder.add_node(
+            "RankPlayerNode",
+            &odf_rank.name,
+            5,
+            rank_row,
+            &[
+                (
+                    "rank_type",
+                    Property::MultipleChoice(if rank.percussive { "percussion" } else { "pipe" }.into()),
+                ),
+                (
+                    "rank",
+                    Property::Resource(ResourceId {
+                        namespace: "ranks".into(),
+                        resource: rank.resource.clone(),
+                    }),
+                ),
+                ("channels", Property::Integer(channels as i32)),
+            ],
+        )?;
+
+        if feeds.len() == 1 {
+            builder.connect(feeds[0], "midi", player, ("midi", None))?;
+        } else {
+            let merger = builder.add_node(
+                "NoteMergerNode",
+                "Merge",
+                4,
+                rank_row,
+                &[("input_count", Property::Integer(feeds.len() as i32))],
+            )?;
+
+            for (i, feed) in feeds.iter().enumerate() {
+                builder.connect(*feed, "midi", merger, ("input_numbered", Some(&(i + 1).to_string())))?;
+            }
+
+            builder.connect(merger, "midi", player, ("midi", None))?;
+        }
+
+        let (mixer, connected) = &mut windchests[odf_rank.windchest];
+        *connected += 1;
+        builder.connect(
+            player,
+            "audio",
+            *mixer,
+            ("input_numbered", Some(&connected.to_string())),
+        )?;
+    }
+
+    Ok(builder.invalidations)
+}
@@ -1,22 +1,36 @@
 use std::{
     collections::BTreeMap,
     fs::{self, remove_file},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    },
+    thread,
 };
 
 use common::resource_manager::ResourceId;
+use ipc::ipc_message::IpcMessage;
+use log::{info, warn};
 use rayon::iter::{IntoParallelIterator, ParallelIterator};
 use rfd::AsyncFileDialog;
 use serde::{Deserialize, Serialize};
-use snafu::ResultExt;
-use sound_engine::sampling::{
-    envelope::calc_sample_metadata,
-    phase_calculator::PhaseCalculator,
-    pipe_player::{envelope_indexes, EnvelopeType},
-    rank::{Pipe, Rank},
+use serde_json::json;
+use snafu::{ensure_whatever, OptionExt, ResultExt};
+use sound_engine::{
+    sampling::{
+        envelope::{calc_sample_metadata, AnalysisSettings, SampleMetadata},
+        phase_calculator::PhaseCalculator,
+        pipe_player::{envelope_indexes, EnvelopeType},
+        rank::{Pipe, Rank, Voicing},
+    },
+    MultiSample, SoundConfig,
 };
+use walkdir::WalkDir;
 
 use crate::{
-    errors::{EngineError, IoSnafu, JsonParserSnafu},
+    errors::{AnalysisSnafu, EngineError, IoSnafu, JsonParserSnafu, TomlParserSerSnafu},
+    io::{is_relative_name, AUDIO_EXTENSIONS},
     resource::{
         rank::{expected_sample_location, PipeRankConfig},
         sample::{check_for_note_number, load_sample},
@@ -29,144 +43,456 @@ use crate::{
 struct Payload {
     file_name: String,
     rank_name: String,
+    /// used to tell apart progress events (and to cancel), defaults to `file_name`
+    #[serde(default)]
+    import_id: Option<String>,
+    #[serde(default)]
+    analysis: AnalysisSettings,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct PathsPayload {
+    file_name: String,
+    rank_name: String,
+    #[serde(default)]
+    import_id: Option<String>,
+    #[serde(default)]
+    analysis: AnalysisSettings,
+    /// audio files, or directories to search for audio files
+    paths: Vec<PathBuf>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct CancelPayload {
+    import_id: String,
+}
+
+struct AnalyzedSample {
+    path: PathBuf,
+    metadata: SampleMetadata,
+    filename: String,
+    sample: MultiSample,
+    note: u8,
+}
+
+struct ImportJob {
+    import_id: String,
+    files: Vec<PathBuf>,
+    sample_directory: PathBuf,
+    rank_file: PathBuf,
+    file_name: String,
+    rank_name: String,
+    sound_config: SoundConfig,
+    analysis: AnalysisSettings,
+    cancelled: Arc<AtomicBool>,
 }
 
+/// Picks samples with a file dialog and imports them as a rank
 pub async fn route<'a>(mut state: RouteCtx<'a>) -> Result<RouteReturn, EngineError> {
-    let Payload { file_name, rank_name } =
-        serde_json::from_value(state.msg["payload"].take()).context(JsonParserSnafu)?;
+    let Payload {
+        file_name,
+        rank_name,
+        import_id,
+        analysis,
+    } = serde_json::from_value(state.msg["payload"].take()).context(JsonParserSnafu)?;
 
     let files = AsyncFileDialog::new().set_file_name("untitled.mjuo").pick_files().await;
 
-    let sample_directory = state
-        .global_state
-        .project_directory()
-        .unwrap()
-        .join("samples")
-        .join(&file_name);
+    if let Some(files) = files {
+        let files = files.into_iter().map(|file| file.path().to_path_buf()).collect();
+        let import_id = import_id.unwrap_or_else(|| file_name.clone());
+
+        start_import(&mut state, import_id, files, file_name, rank_name, analysis)?;
+    }
+
+    Ok(RouteReturn::default())
+}
+
+/// Imports a rank from the given paths, without any dialogs. Returns right away; progress
+/// is sent as `io/importProgress` events and the end as `io/importFinished`.
+pub fn route_paths(mut state: RouteCtx) -> Result<RouteReturn, EngineError> {
+    let PathsPayload {
+        file_name,
+        rank_name,
+        import_id,
+        analysis,
+        paths,
+    } = serde_json::from_value(state.msg["payload"].take()).context(JsonParserSnafu)?;
+
+    let files = paths
+        .iter()
+        .flat_map(|path| WalkDir::new(path).follow_links(true).into_iter())
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.file_type().is_file())
+        .map(|entry| entry.into_path())
+        .filter(|path| {
+            path.extension()
+                .map(|extension| AUDIO_EXTENSIONS.contains(&extension.to_string_lossy().to_lowercase().as_str()))
+                .unwrap_or(false)
+        })
+        .collect();
+
+    let import_id = import_id.unwrap_or_else(|| file_name.clone());
+
+    start_import(&mut state, import_id, files, file_name, rank_name, analysis)?;
+
+    Ok(RouteReturn::default())
+}
+
+/// Stops an import that's in progress. Samples already analyzed are thrown away, and their files removed.
+pub fn route_cancel(mut state: RouteCtx) -> Result<RouteReturn, EngineError> {
+    let CancelPayload { import_id } = serde_json::from_value(state.msg["payload"].take()).context(JsonParserSnafu)?;
+
+    if let Some(cancelled) = state.global_state.imports.get(&import_id) {
+        cancelled.store(true, Ordering::Relaxed);
+    }
 
-    let rank_file = state
+    Ok(RouteReturn::default())
+}
+
+fn start_import(
+    state: &mut RouteCtx,
+    import_id: String,
+    files: Vec<PathBuf>,
+    file_name: String,
+    rank_name: String,
+    analysis: AnalysisSettings,
+) -> Result<(), EngineError> {
+    let project_directory = state
         .global_state
         .project_directory()
-        .unwrap()
-        .join("ranks")
-        .join(format!("{}.toml", file_name));
+        .whatever_context("A project must be open to import a rank")?;
+
+    // folders are fine, as long as everything stays in the project
+    ensure_whatever!(is_relative_name(&file_name), "Invalid rank file name `{}`", file_name);
+
+    let sample_directory = project_directory.join("samples").join(&file_name);
+    let rank_file = project_directory.join("ranks").join(format!("{}.toml", file_name));
 
     fs::create_dir_all(&sample_directory).context(IoSnafu)?;
 
-    if let Some(files) = files {
-        let mut samples: Vec<_> = files
-            .into_par_iter()
-            .map(|file| {
-                let path = file.path();
-                let sample = load_sample(path, &state.state.get_sound_config());
-
-                if let Ok(sample) = sample {
-                    let note_number = path
-                        .file_stem()
-                        .and_then(|stem| check_for_note_number(&stem.to_string_lossy()));
-                    let possible_freq = note_number.map(|note| 440.0 * 2_f64.powf((note as i16 - 69) as f64 / 12.0));
-
-                    let metadata = calc_sample_metadata(&sample.audio_raw, sample.sample_rate, possible_freq);
-                    let note = note_number.unwrap_or(metadata.closest_note);
-
-                    // write the file as wav
-                    let spec = hound::WavSpec {
-                        channels: 1,
-                        sample_rate: sample.sample_rate,
-                        bits_per_sample: 32,
-                        sample_format: hound::SampleFormat::Float,
-                    };
-
-                    let filename = expected_sample_location(note, "wav");
-                    let file_location = sample_directory.join(&filename);
-
-                    if file_location.exists() {
-                        remove_file(&file_location).unwrap();
-                    }
-
-                    let mut writer = hound::WavWriter::create(file_location, spec).unwrap();
-
-                    for frame in &sample.audio_raw {
-                        writer.write_sample(*frame).unwrap();
-                    }
-
-                    writer.finalize().unwrap();
-
-                    Some((metadata, filename, sample, note))
-                } else {
-                    None
+    // finished imports have dropped their end of the flag
+    let imports = &mut state.global_state.imports;
+    imports.retain(|_, cancelled| Arc::strong_count(cancelled) > 1);
+
+    let cancelled = Arc::new(AtomicBool::new(false));
+    imports.insert(import_id.clone(), cancelled.clone());
+
+    let job = ImportJob {
+        import_id,
+        files,
+        sample_directory,
+        rank_file,
+        file_name,
+        rank_name,
+        sound_config: state.state.get_sound_config(),
+        analysis,
+        cancelled,
+    };
+
+    let to_server = state.to_server.clone();
+    thread::spawn(move || run_import(job, to_server));
+
+    Ok(())
+}
+
+fn run_import(job: ImportJob, to_server: flume::Sender<IpcMessage>) {
+    let total = job.files.len();
+    let done = AtomicUsize::new(0);
+
+    info!("Importing {} samples into \"{}\"", total, job.rank_name);
+
+    let results: Vec<Result<AnalyzedSample, (PathBuf, EngineError)>> = job
+        .files
+        .into_par_iter()
+        .filter(|_| !job.cancelled.load(Ordering::Relaxed))
+        .map(|path| {
+            let result = analyze_sample(&path, &job.sound_config, &job.analysis);
+            let done = done.fetch_add(1, Ordering::Relaxed) + 1;
+
+            let _ = to_server.send(IpcMessage::Json(json! {{
+                "action": "io/importProgress",
+                "payload": {
+                    "importId": job.import_id,
+                    "file": path,
+                    "done": done,
+                    "total": total,
+                    "error": result.as_ref().err().map(|err| err.to_string()),
+                    "confidence": result.as_ref().ok().map(|sample| sample.metadata.confidence),
                 }
-            })
-            .filter_map(|x| x)
-            .collect();
-
-        let mut pipes: BTreeMap<u8, Pipe> = BTreeMap::new();
-
-        samples.sort_by_key(|sample| sample.3);
-
-        for (metadata, filename, sample, note) in samples.into_iter() {
-            let buffer_rate = sample.sample_rate;
-            let amp_window_size = (buffer_rate as f32 / metadata.freq as f32) as usize * 2;
-
-            let phase_calculator = PhaseCalculator::new(metadata.freq as f32, buffer_rate);
-
-            let attack_envelope = envelope_indexes(
-                metadata.decay_index,
-                metadata.release_index,
-                &sample,
-                amp_window_size,
-                EnvelopeType::Attack,
-            );
-            let release_envelope = envelope_indexes(
-                metadata.decay_index,
-                metadata.release_index,
-                &sample,
-                amp_window_size,
-                EnvelopeType::Release,
-            );
-
-            let pipe = Pipe {
-                freq: metadata.freq as f32,
-                resource: ResourceId {
-                    namespace: "samples".into(),
-                    resource: filename,
-                },
-
-                amplitude: 1.0,
-                comb_coeff: 0.0,
-
-                crossfade: 256,
-                loop_start: metadata.loop_start,
-                loop_end: metadata.loop_end,
-                decay_index: metadata.decay_index,
-                release_index: metadata.release_index,
-
-                amp_window_size,
-                phase_calculator,
-                attack_envelope: attack_envelope,
-                release_envelope: release_envelope,
-            };
-
-            pipes.insert(note, pipe);
+            }}));
+
+            result.map_err(|err| (path, err))
+        })
+        .collect();
+
+    let mut analyzed = vec![];
+    let mut failed = 0;
+
+    for result in results {
+        match result {
+            Ok(sample) => analyzed.push(sample),
+            Err((path, err)) => {
+                warn!("Could not import {:?}: {}", path, err);
+                failed += 1;
+            }
+        }
+    }
+
+    let (takes, duplicates) = pick_takes(analyzed);
+
+    for (duplicate, kept) in duplicates {
+        warn!("Not importing {:?}, {:?} is the same note", duplicate.path, kept);
+        failed += 1;
+
+        let _ = to_server.send(IpcMessage::Json(json! {{
+            "action": "io/importProgress",
+            "payload": {
+                "importId": job.import_id,
+                "file": duplicate.path,
+                "done": total,
+                "total": total,
+                "error": format!("Detected as note {}, same as {:?} which was a better take", duplicate.note, kept),
+                "confidence": duplicate.metadata.confidence,
+            }
+        }}));
+    }
+
+    // only write anything once it's known which take goes where
+    let written: Vec<Result<AnalyzedSample, (PathBuf, EngineError)>> = takes
+        .into_par_iter()
+        .filter(|_| !job.cancelled.load(Ordering::Relaxed))
+        .map(|sample| match write_sample(&sample, &job.sample_directory) {
+            Ok(()) => Ok(sample),
+            Err(err) => Err((sample.path, err)),
+        })
+        .collect();
+
+    let cancelled = job.cancelled.load(Ordering::Relaxed);
+    let mut samples = vec![];
+
+    for result in written {
+        match result {
+            Ok(sample) => samples.push(sample),
+            Err((path, err)) => {
+                warn!("Could not write {:?}: {}", path, err);
+                failed += 1;
+            }
         }
+    }
 
-        let rank = Rank {
-            notes: pipes,
-            name: rank_name,
-        };
-        let config = PipeRankConfig::from_pipes_rank(
-            rank,
-            ResourceId {
+    let imported = samples.len();
+
+    let error = if cancelled {
+        remove_partial_import(&samples, &job.sample_directory);
+
+        None
+    } else if samples.is_empty() {
+        None
+    } else {
+        write_rank(samples, job.rank_name, job.file_name, &job.rank_file)
+            .err()
+            .map(|err| err.to_string())
+    };
+
+    let _ = to_server.send(IpcMessage::Json(json! {{
+        "action": "io/importFinished",
+        "payload": {
+            "importId": job.import_id,
+            "cancelled": cancelled,
+            "imported": imported,
+            "failed": failed,
+            "error": error,
+        }
+    }}));
+}
+
+/// Keeps the take with the most confident pitch for each note. The others are returned along
+/// with the file that was kept in their place.
+fn pick_takes(samples: Vec<AnalyzedSample>) -> (Vec<AnalyzedSample>, Vec<(AnalyzedSample, PathBuf)>) {
+    let mut takes: BTreeMap<u8, AnalyzedSample> = BTreeMap::new();
+    let mut duplicates = vec![];
+
+    for sample in samples {
+        match takes.get_mut(&sample.note) {
+            Some(take) if take.metadata.confidence.pitch >= sample.metadata.confidence.pitch => {
+                duplicates.push((sample, take.path.clone()));
+            }
+            Some(take) => {
+                let replaced = std::mem::replace(take, sample);
+                let kept = take.path.clone();
+
+                duplicates.push((replaced, kept));
+            }
+            None => {
+                takes.insert(sample.note, sample);
+            }
+        }
+    }
+
+    (takes.into_values().collect(), duplicates)
+}
+
+/// Removes the WAVs a cancelled import already wrote, and the sample directory if nothing else is in it
+fn remove_partial_import(samples: &[AnalyzedSample], sample_directory: &Path) {
+    for sample in samples {
+        let file_location = sample_directory.join(&sample.filename);
+
+        if let Err(err) = remove_file(&file_location) {
+            warn!("Could not remove {:?}: {}", file_location, err);
+        }
+    }
+
+    // fails if the directory has other samples in it, which should be kept
+    let _ = fs::remove_dir(sample_directory);
+}
+
+/// Loads and analyzes a sample, working out which note it is
+fn analyze_sample(
+    path: &Path,
+    sound_config: &SoundConfig,
+    analysis: &AnalysisSettings,
+) -> Result<AnalyzedSample, EngineError> {
+    let sample = load_sample(path, sound_config)?;
+
+    let note_number = path
+        .file_stem()
+        .and_then(|stem| check_for_note_number(&stem.to_string_lossy()));
+    let possible_freq = note_number.map(|note| 440.0 * 2_f64.powf((note as i16 - 69) as f64 / 12.0));
+
+    let metadata = calc_sample_metadata(
+        &sample.first_channel().audio_raw,
+        sample.sample_rate(),
+        possible_freq,
+        analysis,
+    )
+    .context(AnalysisSnafu)?;
+    let note = note_number.unwrap_or(metadata.closest_note);
+
+    Ok(AnalyzedSample {
+        path: path.to_path_buf(),
+        metadata,
+        filename: expected_sample_location(note, "wav"),
+        sample,
+        note,
+    })
+}
+
+/// Writes an analyzed sample into `sample_directory` as a WAV named after its note
+fn write_sample(analyzed: &AnalyzedSample, sample_directory: &Path) -> Result<(), EngineError> {
+    let sample = &analyzed.sample;
+
+    let spec = hound::WavSpec {
+        channels: sample.channel_count() as u16,
+        sample_rate: sample.sample_rate(),
+        bits_per_sample: 32,
+        sample_format: hound::SampleFormat::Float,
+    };
+
+    let file_location = sample_directory.join(&analyzed.filename);
+
+    if file_location.exists() {
+        remove_file(&file_location).context(IoSnafu)?;
+    }
+
+    let mut writer = hound::WavWriter::create(file_location, spec).whatever_context("Could not create WAV file")?;
+
+    for i in 0..sample.len() {
+        for channel in &sample.channels {
+            writer
+                .write_sample(channel.audio_raw[i])
+                .whatever_context("Could not write WAV file")?;
+        }
+    }
+
+    writer.finalize().whatever_context("Could not write WAV file")?;
+
+    Ok(())
+}
+
+fn write_rank(
+    mut samples: Vec<AnalyzedSample>,
+    rank_name: String,
+    file_name: String,
+    rank_file: &Path,
+) -> Result<(), EngineError> {
+    let mut pipes: BTreeMap<u8, Pipe> = BTreeMap::new();
+
+    samples.sort_by_key(|sample| sample.note);
+
+    for AnalyzedSample {
+        metadata,
+        filename,
+        sample,
+        note,
+        ..
+    } in samples.into_iter()
+    {
+        let buffer_rate = sample.sample_rate();
+        let amp_window_size = (buffer_rate as f32 / metadata.freq as f32) as usize * 2;
+
+        let phase_calculator = PhaseCalculator::new(metadata.freq as f32, buffer_rate);
+
+        let attack_envelope = envelope_indexes(
+            metadata.decay_index,
+            metadata.release_index,
+            sample.first_channel(),
+            amp_window_size,
+            EnvelopeType::Attack,
+        );
+        let release_envelope = envelope_indexes(
+            metadata.decay_index,
+            metadata.release_index,
+            sample.first_channel(),
+            amp_window_size,
+            EnvelopeType::Release,
+        );
+
+        let pipe = Pipe {
+            freq: metadata.freq as f32,
+            resource: ResourceId {
                 namespace: "samples".into(),
-                resource: file_name,
+                resource: filename,
             },
-        );
 
-        println!("\n\nnew config: {:?}\n\n", config);
+            amplitude: 1.0,
+            voicing: Voicing::default(),
+
+            crossfade: 256,
+            loop_start: metadata.loop_start,
+            loop_end: metadata.loop_end,
+            extra_loops: metadata.extra_loops,
+            decay_index: metadata.decay_index,
+            release_index: metadata.release_index,
 
-        let rank = toml_edit::ser::to_string_pretty(&config).unwrap();
-        fs::write(rank_file, rank).unwrap();
+            amp_window_size,
+            phase_calculator,
+            attack_envelope: attack_envelope,
+            release_envelope: release_envelope,
+        };
+
+        pipes.insert(note, pipe);
     }
 
-    Ok(RouteReturn::default())
+    let rank = Rank {
+        notes: pipes,
+        name: rank_name,
+        mics: vec![],
+        layout: BTreeMap::new(),
+        revision: 0,
+    };
+    let config = PipeRankConfig::from_pipes_rank(
+        rank,
+        ResourceId {
+            namespace: "samples".into(),
+            resource: file_name,
+        },
+    );
+
+    let rank = toml_edit::ser::to_string_pretty(&config).context(TomlParserSerSnafu)?;
+    fs::write(rank_file, rank).context(IoSnafu)?;
+
+    Ok(())
 }
@@ -0,0 +1,80 @@
+use std::{
+    fs,
+    path::{Component, Path, PathBuf},
+};
+
+use common::resource_manager::ResourceId;
+use rfd::AsyncFileDialog;
+use snafu::{OptionExt, ResultExt};
+
+use crate::{
+    errors::{EngineError, IoSnafu},
+    resource::{
+        organ::read_wav_markers,
+        sfz::{parse_sfz, regions_to_config},
+        util::slug,
+    },
+    routes::{prelude::*, RouteReturn},
+};
+
+pub async fn route<'a>(state: RouteCtx<'a>) -> Result<RouteReturn, EngineError> {
+    let Some(file) = AsyncFileDialog::new().add_filter("SFZ", &["sfz"]).pick_file().await else {
+        return Ok(RouteReturn::default());
+    };
+
+    let project_directory = state
+        .global_state
+        .project_directory()
+        .whatever_context("A project must be open to import an SFZ")?;
+
+    let sfz_path = file.path();
+    let sfz_directory = sfz_path.parent().unwrap_or(Path::new("."));
+    let name = sfz_path
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let key = slug(&name);
+
+    let source = String::from_utf8_lossy(&fs::read(sfz_path).context(IoSnafu)?).into_owned();
+    let mut regions = parse_sfz(&source)?;
+
+    let sample_directory = project_directory.join("samples").join(&key);
+
+    for region in &mut regions {
+        let from = sfz_directory.join(&region.sample);
+
+        // keep the SFZ's folder structure, but don't let it climb out of the sample directory
+        let relative: PathBuf = Path::new(&region.sample)
+            .components()
+            .filter(|component| matches!(component, Component::Normal(_)))
+            .collect();
+        let to = sample_directory.join(&relative);
+
+        fs::create_dir_all(to.parent().unwrap()).context(IoSnafu)?;
+        fs::copy(&from, &to).context(IoSnafu)?;
+
+        region.sample = relative.to_string_lossy().replace('\\', "/");
+
+        // without a loop mode, SFZ loops the sample if the file itself has loop points
+        if region.loop_mode.is_none() && region.loop_start.is_none() {
+            if let Some((start, end)) = read_wav_markers(&from)?.loops.first() {
+                region.loop_start = Some(*start);
+                region.loop_end = Some(*end);
+            }
+        }
+    }
+
+    let config = regions_to_config(
+        &name,
+        ResourceId {
+            namespace: "samples".into(),
+            resource: key.clone(),
+        },
+        &regions,
+    )?;
+
+    let rank_file = project_directory.join("ranks").join(format!("{}.toml", key));
+    fs::write(rank_file, config).context(IoSnafu)?;
+
+    Ok(RouteReturn::default())
+}
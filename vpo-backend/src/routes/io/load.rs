@@ -6,13 +6,17 @@ use node_engine::{
     state::{ActionInvalidation, GraphState},
 };
 use rfd::AsyncFileDialog;
-use snafu::ResultExt;
+use snafu::{OptionExt, ResultExt};
 use sound_engine::SoundConfig;
 
 use crate::{
     engine::ToAudioThread,
     errors::EngineError,
-    io::load_state,
+    io::{
+        load_state,
+        recovery::{find_recovery, state_hash},
+        settings::load_settings,
+    },
     routes::{prelude::*, RouteReturn},
     util::{send_graph_updates, send_project_state_updates, send_resource_updates},
 };
@@ -32,7 +36,23 @@ pub async fn route(mut ctx: RouteCtx<'_>) -> Result<RouteReturn, EngineError> {
         ctx.global_state.device_manager.reset();
         resources.reset();
 
-        load_state(Path::new(path), ctx.state.get_sound_config(), &mut ctx.state, resources)?;
+        let project_directory = path.parent().whatever_context("project file has no parent")?;
+        ctx.global_state.settings = load_settings(project_directory)?;
+
+        // loading can rewrite the project (when migrating it), which would make it look newer
+        // than its recovery file
+        let recovery = find_recovery(Some(path));
+
+        load_state(
+            Path::new(path),
+            ctx.state.get_sound_config(),
+            &ctx.global_state.settings,
+            &mut ctx.state,
+            resources,
+        )?;
+
+        ctx.global_state.state_hash = Some(state_hash(ctx.state));
+        ctx.global_state.recovery = recovery;
 
         send_project_state_updates(&ctx.state, &ctx.global_state, ctx.to_server)?;
         send_graph_updates(ctx.state, ctx.state.get_root_graph_index(), ctx.to_server)?;
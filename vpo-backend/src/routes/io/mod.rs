@@ -1,5 +1,9 @@
 pub mod create;
+pub mod export_sfz;
+pub mod import_organ;
 pub mod import_rank;
+pub mod import_sfz;
 pub mod load;
+pub mod recovery;
 pub mod refresh;
 pub mod save;
@@ -0,0 +1,61 @@
+use std::fs;
+
+use node_engine::state::ActionInvalidation;
+use snafu::{OptionExt, ResultExt};
+
+use crate::{
+    errors::IoSnafu,
+    io::recovery::load_recovery,
+    routes::prelude::*,
+    util::{send_graph_updates, send_project_state_updates},
+};
+
+/// Replaces the current state with the autosaved one offered when the project was opened
+pub fn route_restore(ctx: RouteCtx) -> Result<RouteReturn, EngineError> {
+    let recovery = ctx
+        .global_state
+        .recovery
+        .take()
+        .whatever_context("There is no autosave to restore")?;
+    let resources = &*ctx.resources_lock.read().unwrap();
+
+    let last_rules = ctx.state.get_route_rules();
+    load_recovery(&recovery.path, ctx.global_state.active_project.as_deref(), ctx.state)?;
+
+    // the restored state isn't saved yet, so the next autosave should write it again
+    ctx.global_state.state_hash = None;
+
+    send_project_state_updates(ctx.state, ctx.global_state, ctx.to_server)?;
+    send_graph_updates(ctx.state, ctx.state.get_root_graph_index(), ctx.to_server)?;
+
+    let new_rules = ctx.state.get_route_rules();
+    state_invalidations(
+        ctx.state,
+        vec![ActionInvalidation::NewRouteRules { last_rules, new_rules }],
+        &mut ctx.global_state.device_manager,
+        resources,
+        ctx.to_audio_thread,
+        ctx.to_server,
+    )?;
+
+    ctx.to_audio_thread
+        .send(ToAudioThread::NewTraverser(
+            ctx.state
+                .create_traverser(resources)
+                .whatever_context("could not create traverser")?
+                .1,
+        ))
+        .unwrap();
+
+    Ok(RouteReturn::default())
+}
+
+pub fn route_discard(ctx: RouteCtx) -> Result<RouteReturn, EngineError> {
+    if let Some(recovery) = ctx.global_state.recovery.take() {
+        fs::remove_file(recovery.path).context(IoSnafu)?;
+    }
+
+    send_project_state_updates(ctx.state, ctx.global_state, ctx.to_server)?;
+
+    Ok(RouteReturn::default())
+}
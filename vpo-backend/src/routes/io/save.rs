@@ -2,7 +2,10 @@ use rfd::AsyncFileDialog;
 
 use crate::{
     errors::EngineError,
-    io::save,
+    io::{
+        recovery::{discard_recovery, mark_saved},
+        save,
+    },
     routes::{prelude::*, RouteReturn},
 };
 
@@ -10,15 +13,22 @@ pub async fn route<'a>(state: RouteCtx<'a>) -> Result<RouteReturn, EngineError>
     let mut new_project = false;
 
     if let Some(file_path) = &state.global_state.active_project {
-        save(state.state, file_path)?;
+        save(state.state, file_path, &state.global_state.settings)?;
+
+        mark_saved(state.state, state.global_state);
     } else {
         let file = AsyncFileDialog::new().set_file_name("untitled.mjuo").save_file().await;
 
         if let Some(file) = file {
-            save(state.state, file.path())?;
+            save(state.state, file.path(), &state.global_state.settings)?;
+
+            // the autosave of the untitled project isn't needed anymore
+            discard_recovery(None);
 
             state.global_state.active_project = Some(file.path().into());
             new_project = true;
+
+            mark_saved(state.state, state.global_state);
         }
     }
 
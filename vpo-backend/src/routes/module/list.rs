@@ -0,0 +1,40 @@
+use ipc::ipc_message::IpcMessage;
+use node_engine::graph_manager::{GlobalNodeIndex, GraphIndex};
+use serde::Serialize;
+use serde_json::json;
+use snafu::ResultExt;
+
+use crate::{errors::NodeSnafu, routes::prelude::*};
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ModuleSummary {
+    name: String,
+    graph_index: GraphIndex,
+    /// nodes using the module
+    instances: Vec<GlobalNodeIndex>,
+}
+
+/// Lists the modules in the project
+pub fn route(ctx: RouteCtx) -> Result<RouteReturn, EngineError> {
+    let graph_manager = ctx.state.get_graph_manager();
+
+    let modules = graph_manager
+        .modules()
+        .iter()
+        .map(|(name, graph_index)| {
+            Ok(ModuleSummary {
+                name: name.clone(),
+                graph_index: *graph_index,
+                instances: graph_manager.get_graph_parents(*graph_index).context(NodeSnafu)?,
+            })
+        })
+        .collect::<Result<Vec<ModuleSummary>, EngineError>>()?;
+
+    let _ = ctx.to_server.send(IpcMessage::Json(json! {{
+        "action": "module/modules",
+        "payload": modules
+    }}));
+
+    Ok(RouteReturn::default())
+}
@@ -26,6 +26,7 @@ pub fn state_invalidations(
 ) -> Result<(), EngineError> {
     let mut to_client = vec![];
     let mut new_engine_needed = false;
+    let mut reinit_nodes = vec![];
     let mut new_defaults = vec![];
     let mut updates = vec![];
     let mut errors = vec![];
@@ -35,6 +36,14 @@ pub fn state_invalidations(
             ActionInvalidation::GraphReindexNeeded(_) => {
                 new_engine_needed = true;
             }
+            ActionInvalidation::NodeReinitNeeded(index) => {
+                if index.graph_index == state.get_root_graph_index() {
+                    reinit_nodes.push(index.node_index);
+                } else {
+                    // nodes in child graphs run inside of other nodes, so those have to be rebuilt
+                    new_engine_needed = true;
+                }
+            }
             ActionInvalidation::NewDefaults(index, defaults) => {
                 if index.graph_index == state.get_root_graph_index() {
                     new_defaults.extend(defaults.into_iter().filter_map(|(socket, value)| {
@@ -250,6 +259,21 @@ pub fn state_invalidations(
         }
     }
 
+    if !new_engine_needed {
+        for node_index in reinit_nodes {
+            match state.create_node_replacement(node_index, resources) {
+                Ok(replacement) if replacement.warnings.is_empty() => {
+                    updates.push(ToAudioThread::ReplaceNode(replacement.value));
+                }
+                // let a full rebuild report what went wrong
+                _ => {
+                    new_engine_needed = true;
+                    break;
+                }
+            }
+        }
+    }
+
     if new_engine_needed {
         let (errors_and_warnings, traverser) = state.create_traverser(resources).context(NodeSnafu)?;
 
@@ -0,0 +1,65 @@
+use node_engine::{graph_manager::GlobalNodeIndex, state::Action};
+use serde::Deserialize;
+use snafu::ResultExt;
+
+use crate::{
+    errors::{JsonParserSnafu, NodeSnafu},
+    io::presets::{load_preset, presets_directory, PresetLocation},
+    routes::prelude::*,
+    util::send_graph_updates,
+};
+
+#[derive(Deserialize)]
+struct Payload {
+    index: GlobalNodeIndex,
+    name: String,
+    location: PresetLocation,
+}
+
+/// Applies a preset to a node as one undoable change
+pub fn route(mut ctx: RouteCtx) -> Result<RouteReturn, EngineError> {
+    let payload: Payload = serde_json::from_value(ctx.msg["payload"].take()).context(JsonParserSnafu)?;
+
+    let node = ctx
+        .state
+        .get_graph_manager()
+        .get_node(payload.index)
+        .context(NodeSnafu)?;
+
+    let directory = presets_directory(payload.location, ctx.global_state.project_directory().as_deref())?;
+    let preset = load_preset(&directory, &node.get_node_type(), &payload.name)?;
+
+    // properties the preset doesn't know about (added since it was saved) stay as they are
+    let mut props = node.get_properties().clone();
+    props.extend(preset.properties);
+
+    let invalidations = ctx
+        .state
+        .commit(
+            ActionBundle::new(vec![
+                Action::ChangeNodeProperties {
+                    index: payload.index,
+                    props,
+                },
+                Action::ChangeNodeOverrides {
+                    index: payload.index,
+                    overrides: preset.default_overrides,
+                },
+            ]),
+            false,
+        )
+        .context(NodeSnafu)?;
+
+    send_graph_updates(ctx.state, payload.index.graph_index, ctx.to_server)?;
+
+    state_invalidations(
+        ctx.state,
+        invalidations,
+        &mut ctx.global_state.device_manager,
+        &*ctx.resources_lock.read().unwrap(),
+        ctx.to_audio_thread,
+        ctx.to_server,
+    )?;
+
+    Ok(RouteReturn::default())
+}
@@ -0,0 +1,27 @@
+use serde::Deserialize;
+use snafu::ResultExt;
+
+use crate::{
+    errors::JsonParserSnafu,
+    io::presets::{delete_preset, presets_directory, PresetLocation},
+    routes::prelude::*,
+};
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Payload {
+    node_type: String,
+    name: String,
+    location: PresetLocation,
+}
+
+pub fn route(mut ctx: RouteCtx) -> Result<RouteReturn, EngineError> {
+    let payload: Payload = serde_json::from_value(ctx.msg["payload"].take()).context(JsonParserSnafu)?;
+
+    let directory = presets_directory(payload.location, ctx.global_state.project_directory().as_deref())?;
+    delete_preset(&directory, &payload.node_type, &payload.name)?;
+
+    super::list::send_presets(&ctx, Some(&payload.node_type))?;
+
+    Ok(RouteReturn::default())
+}
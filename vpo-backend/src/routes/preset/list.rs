@@ -0,0 +1,42 @@
+use ipc::ipc_message::IpcMessage;
+use serde_json::json;
+use snafu::ResultExt;
+
+use crate::{
+    errors::JsonParserSnafu,
+    io::presets::{list_presets, presets_directory, user_presets_directory, PresetLocation},
+    routes::prelude::*,
+};
+
+/// Lists the user's and the project's presets, optionally only for one node type
+pub fn route(mut ctx: RouteCtx) -> Result<RouteReturn, EngineError> {
+    let node_type: Option<String> =
+        serde_json::from_value(ctx.msg["payload"]["nodeType"].take()).context(JsonParserSnafu)?;
+
+    send_presets(&ctx, node_type.as_deref())?;
+
+    Ok(RouteReturn::default())
+}
+
+pub(super) fn send_presets(ctx: &RouteCtx, node_type: Option<&str>) -> Result<(), EngineError> {
+    let mut presets = vec![];
+
+    if let Some(directory) = user_presets_directory() {
+        presets.extend(list_presets(&directory, PresetLocation::User, node_type));
+    }
+
+    let project_directory = ctx.global_state.project_directory();
+    if let Ok(directory) = presets_directory(PresetLocation::Project, project_directory.as_deref()) {
+        presets.extend(list_presets(&directory, PresetLocation::Project, node_type));
+    }
+
+    let _ = ctx.to_server.send(IpcMessage::Json(json! {{
+        "action": "preset/presets",
+        "payload": {
+            "nodeType": node_type,
+            "presets": presets
+        }
+    }}));
+
+    Ok(())
+}
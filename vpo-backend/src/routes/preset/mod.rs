@@ -0,0 +1,4 @@
+pub mod apply;
+pub mod delete;
+pub mod list;
+pub mod save;
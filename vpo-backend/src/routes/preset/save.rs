@@ -0,0 +1,35 @@
+use node_engine::graph_manager::GlobalNodeIndex;
+use serde::Deserialize;
+use snafu::ResultExt;
+
+use crate::{
+    errors::{JsonParserSnafu, NodeSnafu},
+    io::presets::{presets_directory, save_preset, Preset, PresetLocation},
+    routes::prelude::*,
+};
+
+#[derive(Deserialize)]
+struct Payload {
+    index: GlobalNodeIndex,
+    name: String,
+    location: PresetLocation,
+}
+
+/// Saves a node's properties and default overrides as a preset for its node type
+pub fn route(mut ctx: RouteCtx) -> Result<RouteReturn, EngineError> {
+    let payload: Payload = serde_json::from_value(ctx.msg["payload"].take()).context(JsonParserSnafu)?;
+
+    let node = ctx
+        .state
+        .get_graph_manager()
+        .get_node(payload.index)
+        .context(NodeSnafu)?;
+    let preset = Preset::from_node(node);
+
+    let directory = presets_directory(payload.location, ctx.global_state.project_directory().as_deref())?;
+    save_preset(&directory, &payload.name, &preset)?;
+
+    super::list::send_presets(&ctx, Some(&preset.node_type))?;
+
+    Ok(RouteReturn::default())
+}
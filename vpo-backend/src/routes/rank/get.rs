@@ -0,0 +1,39 @@
+use std::fs;
+
+use serde::Deserialize;
+use snafu::{ensure_whatever, OptionExt, ResultExt};
+
+use crate::{
+    errors::{IoSnafu, JsonParserSnafu},
+    routes::prelude::*,
+};
+
+use super::send_pipe_table;
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Payload {
+    /// resource key of the rank
+    rank: String,
+}
+
+/// Sends a pipe rank's pipe table as `rank/pipes`
+pub fn route(mut ctx: RouteCtx) -> Result<RouteReturn, EngineError> {
+    let Payload { rank } = serde_json::from_value(ctx.msg["payload"].take()).context(JsonParserSnafu)?;
+
+    let project_directory = ctx
+        .global_state
+        .project_directory()
+        .whatever_context("A project must be open to read a rank")?;
+
+    let resources = ctx.resources_lock.read().unwrap();
+
+    // only ranks that were loaded from the project can be read, which keeps the path inside of it
+    ensure_whatever!(resources.ranks.get_index(&rank).is_some(), "Unknown rank `{}`", rank);
+
+    let config = fs::read_to_string(project_directory.join("ranks").join(&rank)).context(IoSnafu)?;
+
+    send_pipe_table(&rank, &config, &resources, ctx.to_server)?;
+
+    Ok(RouteReturn::default())
+}
@@ -0,0 +1,49 @@
+use ipc::ipc_message::IpcMessage;
+use serde::Serialize;
+use serde_json::json;
+use sound_engine::sampling::rank::RankType;
+
+use crate::routes::prelude::*;
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct RankSummary {
+    /// resource key in the `ranks` namespace
+    rank: String,
+    name: String,
+    rank_type: &'static str,
+    notes: usize,
+}
+
+/// Lists the loaded ranks
+pub fn route(ctx: RouteCtx) -> Result<RouteReturn, EngineError> {
+    let resources = ctx.resources_lock.read().unwrap();
+
+    let mut ranks: Vec<RankSummary> = resources
+        .ranks
+        .as_keys()
+        .into_iter()
+        .filter_map(|key| {
+            let summary = match resources.ranks.borrow_resource_by_id(&key)? {
+                RankType::Pipes(rank) => (rank.name.clone(), "pipes", rank.notes.len()),
+                RankType::Percussion(rank) => (rank.name.clone(), "percussion", rank.notes.len()),
+            };
+
+            Some(RankSummary {
+                rank: key,
+                name: summary.0,
+                rank_type: summary.1,
+                notes: summary.2,
+            })
+        })
+        .collect();
+
+    ranks.sort_by(|a, b| a.rank.cmp(&b.rank));
+
+    let _ = ctx.to_server.send(IpcMessage::Json(json! {{
+        "action": "rank/ranks",
+        "payload": ranks
+    }}));
+
+    Ok(RouteReturn::default())
+}
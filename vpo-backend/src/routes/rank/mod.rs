@@ -0,0 +1,93 @@
+pub mod get;
+pub mod list;
+pub mod update_pipes;
+
+use ipc::ipc_message::IpcMessage;
+use node_engine::resources::Resources;
+use serde::Serialize;
+use serde_json::json;
+use snafu::ResultExt;
+
+use crate::{
+    errors::{EngineError, TomlParserDeSnafu},
+    resource::rank::{entry_resource, PipeRankConfig},
+    Sender,
+};
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct PipeRow {
+    note: u8,
+    /// resource key of the pipe's sample
+    sample: String,
+    /// in the file's frames, if the sample is loaded
+    sample_length: Option<usize>,
+    cents: i16,
+    decay_index: usize,
+    loop_start: usize,
+    loop_end: usize,
+    extra_loops: Vec<(usize, usize)>,
+    release_index: usize,
+    crossfade: usize,
+    attenuation: f32,
+    even_harm_atten: f32,
+    brightness: f32,
+    low_cut: Option<f32>,
+}
+
+/// Sends the pipe table of a pipe rank's `[rank].toml` to the client
+fn send_pipe_table(
+    rank: &str,
+    config: &str,
+    resources: &Resources,
+    to_server: &Sender<IpcMessage>,
+) -> Result<(), EngineError> {
+    let parsed: PipeRankConfig = toml_edit::de::from_str(config).context(TomlParserDeSnafu)?;
+
+    let pipes: Vec<PipeRow> = parsed
+        .pipe
+        .iter()
+        .filter_map(|(note, entry)| {
+            let note: u8 = note.parse().ok()?;
+            let sample = entry_resource(&parsed.sample_location, &parsed.sample_format, note, &entry.file).resource;
+
+            Some(PipeRow {
+                note,
+                sample_length: resources
+                    .samples
+                    .borrow_resource_by_id(&sample)
+                    .map(|sample| sample.to_file_position(sample.len())),
+                sample,
+                cents: entry.cents,
+                decay_index: entry.decay_index,
+                loop_start: entry.loop_start,
+                loop_end: entry.loop_end,
+                extra_loops: entry.extra_loops.clone(),
+                release_index: entry.release_index,
+                crossfade: entry.crossfade.unwrap_or(parsed.crossfade),
+                attenuation: entry.attenuation,
+                even_harm_atten: entry.even_harm_atten,
+                brightness: entry.brightness,
+                low_cut: entry.low_cut,
+            })
+        })
+        .collect();
+
+    let _ = to_server.send(IpcMessage::Json(json! {{
+        "action": "rank/pipes",
+        "payload": {
+            "rank": rank,
+            "name": parsed.name,
+            "attenuation": parsed.attenuation,
+            "voicing": {
+                "brightness": parsed.brightness,
+                "brightnessHarmonic": parsed.brightness_harmonic,
+                "lowCut": parsed.low_cut,
+                "evenHarmAtten": parsed.even_harm_atten,
+            },
+            "pipes": pipes,
+        }
+    }}));
+
+    Ok(())
+}
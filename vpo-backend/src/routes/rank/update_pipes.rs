@@ -0,0 +1,110 @@
+use std::{collections::BTreeMap, fs};
+
+use log::warn;
+use serde::Deserialize;
+use snafu::{ensure_whatever, OptionExt, ResultExt};
+
+use crate::{
+    errors::{IoSnafu, JsonParserSnafu, NodeSnafu},
+    io::load_single,
+    resource::rank::{edit_pipes, edit_rank_voicing, resident_frames, PipeEdit, RankVoicingEdit},
+    routes::prelude::*,
+    util::send_resource_updates,
+};
+
+use super::send_pipe_table;
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Payload {
+    /// resource key of the rank
+    rank: String,
+    /// edits by note
+    #[serde(default)]
+    pipes: BTreeMap<u8, PipeEdit>,
+    /// voicing of the whole rank
+    #[serde(default)]
+    voicing: Option<RankVoicingEdit>,
+}
+
+/// Edits pipes of a pipe rank (and optionally its voicing), writes the rank back to disk and
+/// reloads it. Running rank players pick up the changes without the graph being rebuilt.
+pub fn route(mut ctx: RouteCtx) -> Result<RouteReturn, EngineError> {
+    let Payload { rank, pipes, voicing } =
+        serde_json::from_value(ctx.msg["payload"].take()).context(JsonParserSnafu)?;
+
+    let project_directory = ctx
+        .global_state
+        .project_directory()
+        .whatever_context("A project must be open to edit a rank")?;
+
+    let sound_config = ctx.state.get_sound_config();
+
+    let mut resources = ctx.resources_lock.write().unwrap();
+
+    // only ranks that were loaded from the project can be edited, which keeps the path inside of it
+    ensure_whatever!(resources.ranks.get_index(&rank).is_some(), "Unknown rank `{}`", rank);
+    let rank_file = project_directory.join("ranks").join(&rank);
+
+    let mut config = fs::read_to_string(&rank_file).context(IoSnafu)?;
+    if let Some(voicing) = &voicing {
+        config = edit_rank_voicing(&config, voicing)?;
+    }
+
+    let config = edit_pipes(&config, &pipes, |sample| {
+        resources
+            .samples
+            .borrow_resource_by_id(&sample.resource)
+            .map(|sample| sample.to_file_position(sample.len()))
+    })?;
+
+    fs::write(&rank_file, &config).context(IoSnafu)?;
+
+    // streamed samples only keep up to the old loop and release points in memory, so any
+    // that now need more have to be loaded again
+    let reload: Vec<String> = resident_frames(&config)?
+        .into_iter()
+        .filter(|(key, needed)| {
+            resources
+                .samples
+                .borrow_resource_by_id(key)
+                .map(|sample| sample.tail.is_some() && sample.head_len() < *needed)
+                .unwrap_or(false)
+        })
+        .map(|(key, _)| key)
+        .collect();
+
+    for key in &reload {
+        load_single(
+            &project_directory,
+            &project_directory.join("samples").join(key),
+            &mut resources,
+            sound_config.clone(),
+            &ctx.global_state.settings,
+        )?;
+    }
+
+    load_single(
+        &project_directory,
+        &rank_file,
+        &mut resources,
+        sound_config,
+        &ctx.global_state.settings,
+    )?;
+
+    // reloaded samples moved, so the traverser needs to find them again
+    if !reload.is_empty() {
+        let (errors_and_warnings, traverser) = ctx.state.create_traverser(&resources).context(NodeSnafu)?;
+
+        if errors_and_warnings.any() {
+            warn!("Traverser warnings: {:?}", errors_and_warnings);
+        }
+
+        let _ = ctx.to_audio_thread.send(ToAudioThread::NewTraverser(traverser));
+    }
+
+    send_resource_updates(&resources, ctx.to_server)?;
+    send_pipe_table(&rank, &config, &resources, ctx.to_server)?;
+
+    Ok(RouteReturn::default())
+}
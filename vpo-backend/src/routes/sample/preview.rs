@@ -0,0 +1,210 @@
+use ipc::ipc_message::IpcMessage;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use snafu::{ensure_whatever, OptionExt, ResultExt};
+use sound_engine::{
+    sampling::{
+        envelope::{calc_sample_metadata, AnalysisSettings},
+        overview::{envelope_overview, peak_overview},
+        rank::RankType,
+    },
+    MonoSample, MultiSample,
+};
+
+use crate::{errors::JsonParserSnafu, resource::sample::load_sample, routes::prelude::*};
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Payload {
+    /// resource key in the `samples` namespace
+    sample: String,
+    /// how many points to send back
+    resolution: usize,
+    #[serde(default)]
+    channel: usize,
+    /// rank and note the sample is used by, to send along its current markers
+    #[serde(default)]
+    pipe: Option<PipeRef>,
+    /// the sample is only analyzed if these are given
+    #[serde(default)]
+    analysis: Option<AnalysisSettings>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct PipeRef {
+    rank: String,
+    note: u8,
+}
+
+/// Sample indexes worth drawing over the waveform
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Markers {
+    decay_index: usize,
+    /// only known from analysis, ranks don't store it
+    sustain_index: Option<usize>,
+    loop_start: usize,
+    loop_end: usize,
+    extra_loops: Vec<(usize, usize)>,
+    release_index: usize,
+}
+
+impl Markers {
+    /// Converts to the positions rank files use, which differ if the sample was resampled when loading
+    fn in_file(self, sample: &MultiSample) -> Markers {
+        let position = |position| sample.to_file_position(position);
+
+        Markers {
+            decay_index: position(self.decay_index),
+            sustain_index: self.sustain_index.map(position),
+            loop_start: position(self.loop_start),
+            loop_end: position(self.loop_end),
+            extra_loops: self
+                .extra_loops
+                .into_iter()
+                .map(|(start, end)| (position(start), position(end)))
+                .collect(),
+            release_index: position(self.release_index),
+        }
+    }
+}
+
+/// Sends a sample's waveform and amplitude envelope, downsampled to `resolution` points, as
+/// `sample/preview`. Along with it go the markers of the pipe using the sample if one was given,
+/// and those found by analysis if it was asked for.
+pub fn route(mut ctx: RouteCtx) -> Result<RouteReturn, EngineError> {
+    let Payload {
+        sample: sample_key,
+        resolution,
+        channel,
+        pipe,
+        analysis,
+    } = serde_json::from_value(ctx.msg["payload"].take()).context(JsonParserSnafu)?;
+
+    // copy out what's needed, so the lock isn't held while reading from disk or analyzing
+    let (in_memory, source_sample_rate, pipe) = {
+        let resources = ctx.resources_lock.read().unwrap();
+
+        let loaded = resources
+            .samples
+            .borrow_resource_by_id(&sample_key)
+            .whatever_context(format!("Sample \"{}\" is not loaded", sample_key))?;
+
+        // streamed samples only have their start in memory, the rest is read from disk below
+        let in_memory = if loaded.tail.is_none() {
+            let mono = loaded
+                .channels
+                .get(channel)
+                .whatever_context(format!("Sample has no channel {}", channel))?;
+
+            Some(MonoSample {
+                audio_raw: mono.audio_raw.clone(),
+                sample_rate: mono.sample_rate,
+            })
+        } else {
+            None
+        };
+
+        let pipe = pipe
+            .and_then(
+                |PipeRef { rank, note }| match resources.ranks.borrow_resource_by_id(&rank)? {
+                    RankType::Pipes(rank) => rank.notes.get(&note),
+                    RankType::Percussion(_) => None,
+                },
+            )
+            .map(|pipe| {
+                let markers = Markers {
+                    decay_index: pipe.decay_index,
+                    sustain_index: None,
+                    loop_start: pipe.loop_start,
+                    loop_end: pipe.loop_end,
+                    extra_loops: pipe.extra_loops.clone(),
+                    release_index: pipe.release_index,
+                };
+
+                (pipe.freq as f64, pipe.amp_window_size, markers)
+            });
+
+        (in_memory, loaded.source_sample_rate, pipe)
+    };
+
+    let mono = match in_memory {
+        Some(mono) => mono,
+        None => {
+            let project_directory = ctx
+                .global_state
+                .project_directory()
+                .whatever_context("A project must be open to preview a streamed sample")?;
+
+            let mut full = load_sample(
+                &project_directory.join("samples").join(&sample_key),
+                &ctx.state.get_sound_config(),
+            )?;
+
+            ensure_whatever!(channel < full.channel_count(), "Sample has no channel {}", channel);
+
+            full.channels.swap_remove(channel)
+        }
+    };
+
+    // only the channel being previewed, which is all the markers need to be converted
+    let sample = MultiSample {
+        channels: vec![mono],
+        tail: None,
+        source_sample_rate,
+    };
+    let mono = sample.first_channel();
+
+    // analysis reads the whole sample, so it's only done when asked for
+    let metadata = analysis.map(|analysis| {
+        calc_sample_metadata(
+            &mono.audio_raw,
+            mono.sample_rate,
+            pipe.as_ref().map(|(freq, _, _)| *freq),
+            &analysis,
+        )
+    });
+
+    let window_width = match (&metadata, &pipe) {
+        (_, Some((_, amp_window_size, _))) => amp_window_size / 2,
+        (Some(Ok(metadata)), None) => (mono.sample_rate as f64 / metadata.freq) as usize,
+        // about 10 ms, if nothing is known about the pitch
+        _ => mono.sample_rate as usize / 100,
+    };
+
+    let analyzed = metadata
+        .as_ref()
+        .and_then(|metadata| metadata.as_ref().ok())
+        .map(|metadata| {
+            Markers {
+                decay_index: metadata.decay_index,
+                sustain_index: Some(metadata.sustain_index),
+                loop_start: metadata.loop_start,
+                loop_end: metadata.loop_end,
+                extra_loops: metadata.extra_loops.clone(),
+                release_index: metadata.release_index,
+            }
+            .in_file(&sample)
+        });
+
+    let current = pipe.map(|(_, _, markers)| markers.in_file(&sample));
+
+    let _ = ctx.to_server.send(IpcMessage::Json(json! {{
+        "action": "sample/preview",
+        "payload": {
+            "sample": sample_key,
+            "channel": channel,
+            "length": sample.to_file_position(mono.audio_raw.len()),
+            "sampleRate": sample.source_sample_rate.unwrap_or(mono.sample_rate),
+            "peaks": peak_overview(&mono.audio_raw, resolution),
+            "envelope": envelope_overview(&mono.audio_raw, window_width, resolution),
+            "analysis": analyzed,
+            "analysisError": metadata.as_ref().and_then(|metadata| metadata.as_ref().err()).map(|err| err.to_string()),
+            "confidence": metadata.as_ref().and_then(|metadata| metadata.as_ref().ok()).map(|metadata| metadata.confidence),
+            "pipe": current,
+        }
+    }}));
+
+    Ok(RouteReturn::default())
+}
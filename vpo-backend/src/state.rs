@@ -1,14 +1,25 @@
-use std::path::PathBuf;
+use std::{
+    collections::BTreeMap,
+    path::PathBuf,
+    sync::{atomic::AtomicBool, Arc},
+};
 
 use serde_json::json;
 
-use crate::io::clocked::DeviceManager;
+use crate::io::{clocked::DeviceManager, recovery::RecoveryInfo, settings::ProjectSettings};
 
 #[derive(Debug)]
 pub struct GlobalState {
     pub active_project: Option<PathBuf>,
     pub import_folder: Option<PathBuf>,
     pub device_manager: DeviceManager,
+    pub settings: ProjectSettings,
+    /// cancellation flags of rank imports, by import id
+    pub imports: BTreeMap<String, Arc<AtomicBool>>,
+    /// hash of the graph state when it was last written to disk, by saving or autosaving
+    pub state_hash: Option<u64>,
+    /// unsaved work from a previous session, waiting to be restored or discarded
+    pub recovery: Option<RecoveryInfo>,
 }
 
 impl GlobalState {
@@ -17,6 +28,10 @@ impl GlobalState {
             active_project: None,
             import_folder: None,
             device_manager: DeviceManager::new(),
+            settings: ProjectSettings::default(),
+            imports: BTreeMap::new(),
+            state_hash: None,
+            recovery: None,
         }
     }
 
@@ -31,6 +46,7 @@ impl GlobalState {
         json!({
             "activeProject": self.active_project,
             "devices": self.device_manager.devices_as_json(),
+            "recovery": self.recovery,
         })
     }
 }
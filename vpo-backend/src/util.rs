@@ -1,10 +1,19 @@
+use std::collections::HashSet;
+
 use ipc::ipc_message::IpcMessage;
-use node_engine::{graph_manager::GraphIndex, resources::Resources, state::GraphState};
+use node_engine::{
+    graph_manager::GraphIndex,
+    node::PropertyChange,
+    resources::Resources,
+    state::{ActionInvalidation, GraphState},
+};
 use serde_json::json;
 use snafu::ResultExt;
 
 use crate::{
+    engine::ToAudioThread,
     errors::{EngineError, NodeSnafu},
+    routes::prelude::state_invalidations,
     state::GlobalState,
     Sender,
 };
@@ -56,3 +65,43 @@ pub fn send_resource_updates(resources: &Resources, to_server: &Sender<IpcMessag
 
     Ok(())
 }
+
+/// Stores property changes coming from inputs. These aren't undo steps, and only the changed nodes
+/// that couldn't apply them in place are initialized again, unless their rows changed.
+pub fn apply_property_changes(
+    state: &mut GraphState,
+    global_state: &mut GlobalState,
+    changes: Vec<PropertyChange>,
+    resources: &Resources,
+    to_audio_thread: &flume::Sender<ToAudioThread>,
+    to_server: &Sender<IpcMessage>,
+) -> Result<(), EngineError> {
+    let invalidations = state.apply_property_changes(changes).context(NodeSnafu)?;
+
+    if invalidations.is_empty() {
+        return Ok(());
+    }
+
+    let touched_graphs: HashSet<GraphIndex> = invalidations
+        .iter()
+        .filter_map(|invalidation| match invalidation {
+            ActionInvalidation::GraphReindexNeeded(index) => Some(*index),
+            ActionInvalidation::NodeReinitNeeded(index) => Some(index.graph_index),
+            ActionInvalidation::GraphModified(index) => Some(*index),
+            _ => None,
+        })
+        .collect();
+
+    for graph_index in touched_graphs {
+        send_graph_updates(state, graph_index, to_server)?;
+    }
+
+    state_invalidations(
+        state,
+        invalidations,
+        &mut global_state.device_manager,
+        resources,
+        to_audio_thread,
+        to_server,
+    )
+}